@@ -0,0 +1,90 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate::{AsyncMap, CHashMap};
+
+/// Wrapper storing values as `Arc<V>`, whose lookups return clones of the `Arc` rather than handles.
+///
+/// Nothing returned holds a shard lock, so values can be kept across `.await`s, at the cost of an atomic
+/// reference count increment per lookup. Works over any [`AsyncMap`], e.g. `CHashMap<K, Arc<V>>` (the default)
+/// or `LruCache<K, Arc<V>>`.
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use quick_hash_cache::{lru::LruCache, ArcCache};
+/// # #[tokio::main] async fn main() {
+/// let cache = ArcCache::from(LruCache::<u32, Arc<String>>::new(4));
+/// cache.insert(1, "one".to_owned()).await;
+///
+/// let one: Arc<String> = cache.get(&1).await.unwrap();
+/// tokio::task::yield_now().await;
+/// assert_eq!(*one, "one");
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ArcCache<K, V, M = CHashMap<K, Arc<V>>> {
+    inner: M,
+    _marker: PhantomData<fn(K) -> V>,
+}
+
+impl<K, V, M: Default> Default for ArcCache<K, V, M> {
+    fn default() -> Self {
+        ArcCache::from(M::default())
+    }
+}
+
+impl<K, V, M> From<M> for ArcCache<K, V, M> {
+    fn from(inner: M) -> Self {
+        ArcCache {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, M> ArcCache<K, V, M>
+where
+    M: AsyncMap<K, Arc<V>>,
+{
+    /// Clones the `Arc` of the value, if present
+    pub async fn get(&self, key: &K) -> Option<Arc<V>> {
+        self.inner.get_cloned(key).await
+    }
+
+    /// Inserts a value, returning the previous one if one existed
+    pub async fn insert(&self, key: K, value: V) -> Option<Arc<V>> {
+        self.inner.insert(key, Arc::new(value)).await
+    }
+
+    /// Inserts a value that is already shared, returning the previous one if one existed
+    pub async fn insert_arc(&self, key: K, value: Arc<V>) -> Option<Arc<V>> {
+        self.inner.insert(key, value).await
+    }
+
+    pub async fn remove(&self, key: &K) -> Option<Arc<V>> {
+        self.inner.remove(key).await
+    }
+
+    pub async fn contains(&self, key: &K) -> bool {
+        self.inner.contains(key).await
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// The wrapped map, for operations not covered by the wrapper, e.g. eviction
+    #[inline]
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+}
@@ -0,0 +1,144 @@
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+
+#[cfg(feature = "lockfree")]
+use crate::lockfree::LockFreeMap;
+use crate::lru::{AtomicTimestamp, LruCache};
+use crate::tinylfu::WTinyLfuCache;
+use crate::CHashMap;
+
+/// Common interface of the crate's concurrent async maps, so code can be generic over
+/// "some concurrent async map" and implementations can be swapped, e.g. in tests.
+pub trait AsyncMap<K, V> {
+    /// Clones the value out of the map, if present
+    fn get_cloned(&self, key: &K) -> impl Future<Output = Option<V>> + Send;
+
+    /// Inserts a value, returning the previous value if one existed
+    fn insert(&self, key: K, value: V) -> impl Future<Output = Option<V>> + Send;
+
+    /// Removes a value, returning it if it existed
+    fn remove(&self, key: &K) -> impl Future<Output = Option<V>> + Send;
+
+    fn contains(&self, key: &K) -> impl Future<Output = bool> + Send;
+
+    /// Number of entries in the map
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, V, S> AsyncMap<K, V> for CHashMap<K, V, S>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher + Send + Sync,
+{
+    async fn get_cloned(&self, key: &K) -> Option<V> {
+        CHashMap::get_cloned(self, key).await
+    }
+
+    async fn insert(&self, key: K, value: V) -> Option<V> {
+        CHashMap::insert(self, key, value).await
+    }
+
+    async fn remove(&self, key: &K) -> Option<V> {
+        CHashMap::remove(self, key).await
+    }
+
+    async fn contains(&self, key: &K) -> bool {
+        CHashMap::contains(self, key).await
+    }
+
+    fn len(&self) -> usize {
+        self.size()
+    }
+}
+
+impl<K, V, T, S> AsyncMap<K, V> for LruCache<K, V, T, S>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Clone + Send + Sync,
+    T: AtomicTimestamp + Send + Sync,
+    S: BuildHasher + Send + Sync,
+{
+    /// Clones the value out of the cache, updating its timestamp
+    async fn get_cloned(&self, key: &K) -> Option<V> {
+        LruCache::get_cloned(self, key).await
+    }
+
+    async fn insert(&self, key: K, value: V) -> Option<V> {
+        LruCache::insert(self, key, value).await
+    }
+
+    async fn remove(&self, key: &K) -> Option<V> {
+        LruCache::remove(self, key).await
+    }
+
+    /// Checks if the key is present, without updating its timestamp
+    async fn contains(&self, key: &K) -> bool {
+        LruCache::contains(self, key).await
+    }
+
+    fn len(&self) -> usize {
+        self.size()
+    }
+}
+
+impl<K, V, S> AsyncMap<K, V> for WTinyLfuCache<K, V, S>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Clone + Send + Sync,
+    S: BuildHasher + Send + Sync,
+{
+    /// Clones the value out of the cache, recording the access
+    async fn get_cloned(&self, key: &K) -> Option<V> {
+        WTinyLfuCache::get_cloned(self, key).await
+    }
+
+    async fn insert(&self, key: K, value: V) -> Option<V> {
+        WTinyLfuCache::insert(self, key, value).await
+    }
+
+    async fn remove(&self, key: &K) -> Option<V> {
+        WTinyLfuCache::remove(self, key).await
+    }
+
+    /// Checks if the key is present, without recording the access
+    async fn contains(&self, key: &K) -> bool {
+        WTinyLfuCache::contains(self, key).await
+    }
+
+    fn len(&self) -> usize {
+        self.size()
+    }
+}
+
+#[cfg(feature = "lockfree")]
+impl<K, V, S> AsyncMap<K, V> for LockFreeMap<K, V, S>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    S: BuildHasher + Send + Sync,
+{
+    async fn get_cloned(&self, key: &K) -> Option<V> {
+        LockFreeMap::get_cloned(self, key).await
+    }
+
+    async fn insert(&self, key: K, value: V) -> Option<V> {
+        LockFreeMap::insert(self, key, value).await
+    }
+
+    async fn remove(&self, key: &K) -> Option<V> {
+        LockFreeMap::remove(self, key).await
+    }
+
+    async fn contains(&self, key: &K) -> bool {
+        LockFreeMap::contains(self, key).await
+    }
+
+    fn len(&self) -> usize {
+        self.size()
+    }
+}
@@ -0,0 +1,247 @@
+//! Backing stores of tiered and persistent caches, see [`AsyncCacheBackend`].
+
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use hashbrown::HashMap;
+
+use crate::clock::{Clock, DefaultClock};
+
+/// Slower store behind an in-memory cache, e.g. Redis or disk, that tiered and persistent caches read through
+/// on misses and write to, so that the store can be swapped, e.g. for a [`MockBackend`] in tests.
+pub trait AsyncCacheBackend<K, V> {
+    type Error;
+
+    /// Value stored for the key, unless absent or expired
+    fn get(&self, key: &K) -> impl Future<Output = Result<Option<V>, Self::Error>> + Send;
+
+    /// Stores the value, expiring after `ttl` if given, replacing any previous one
+    fn set(&self, key: K, value: V, ttl: Option<Duration>) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Deletes the value stored for the key, returning whether there was one
+    fn delete(&self, key: &K) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+}
+
+/// Operation of an [`AsyncCacheBackend`], to tell apart the calls of a [`MockBackend`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BackendOp {
+    Get,
+    Set,
+    Delete,
+}
+
+impl BackendOp {
+    #[inline]
+    fn index(self) -> usize {
+        match self {
+            BackendOp::Get => 0,
+            BackendOp::Set => 1,
+            BackendOp::Delete => 2,
+        }
+    }
+}
+
+/// Failure of a [`MockBackend`] call, as injected by the test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InjectedFailure {
+    pub op: BackendOp,
+}
+
+impl fmt::Display for InjectedFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "injected failure of {:?}", self.op)
+    }
+}
+
+impl std::error::Error for InjectedFailure {}
+
+type FailureHook<K> = Box<dyn Fn(BackendOp, &K) -> bool + Send + Sync>;
+
+struct Failures<K> {
+    /// Number of upcoming calls to fail regardless of the hook
+    fail_next: usize,
+    hook: Option<FailureHook<K>>,
+    /// Delay of every call, simulating a remote store
+    latency: Duration,
+}
+
+/// In-memory [`AsyncCacheBackend`] to unit-test tiered-cache wiring without a real store,
+/// counting its calls and failing the ones the test asks it to.
+///
+/// ```
+/// # use quick_hash_cache::{AsyncCacheBackend, BackendOp, InjectedFailure, MockBackend};
+/// # #[tokio::main] async fn main() {
+/// let backend = MockBackend::new();
+/// backend.set("user:1", 1, None).await.unwrap();
+///
+/// backend.fail_next(1);
+/// assert_eq!(backend.get(&"user:1").await, Err(InjectedFailure { op: BackendOp::Get }));
+/// assert_eq!(backend.get(&"user:1").await, Ok(Some(1)));
+///
+/// backend.fail_when(|op, key| op == BackendOp::Set && key.starts_with("user:"));
+/// assert!(backend.set("user:2", 2, None).await.is_err());
+///
+/// assert_eq!(backend.calls(BackendOp::Get), 2);
+/// assert_eq!(backend.peek(&"user:2"), None);
+/// # }
+/// ```
+pub struct MockBackend<K, V> {
+    /// Values along with the `DefaultClock` reading they expire at, or `u64::MAX`
+    entries: Mutex<HashMap<K, (V, u64)>>,
+    failures: Mutex<Failures<K>>,
+    /// Number of calls of each `BackendOp`, failed ones included
+    calls: [AtomicUsize; 3],
+}
+
+impl<K, V> Default for MockBackend<K, V> {
+    fn default() -> Self {
+        MockBackend {
+            entries: Mutex::new(HashMap::new()),
+            failures: Mutex::new(Failures {
+                fail_next: 0,
+                hook: None,
+                latency: Duration::ZERO,
+            }),
+            calls: Default::default(),
+        }
+    }
+}
+
+impl<K, V> MockBackend<K, V> {
+    pub fn new() -> Self {
+        MockBackend::default()
+    }
+
+    fn entries(&self) -> MutexGuard<'_, HashMap<K, (V, u64)>> {
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn failures(&self) -> MutexGuard<'_, Failures<K>> {
+        self.failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Fails the next `count` calls, whatever their operation
+    pub fn fail_next(&self, count: usize) {
+        self.failures().fail_next = count;
+    }
+
+    /// Fails every call for which the hook returns `true`, e.g. every `Set`, to simulate a read-only replica,
+    /// or every call, to simulate an outage
+    pub fn fail_when(&self, hook: impl Fn(BackendOp, &K) -> bool + Send + Sync + 'static) {
+        self.failures().hook = Some(Box::new(hook));
+    }
+
+    /// Stops failing calls, undoing `fail_next` and `fail_when`
+    pub fn clear_failures(&self) {
+        let mut failures = self.failures();
+
+        failures.fail_next = 0;
+        failures.hook = None;
+    }
+
+    /// Delays every call by `latency`, e.g. to test timeouts
+    pub fn set_latency(&self, latency: Duration) {
+        self.failures().latency = latency;
+    }
+
+    /// Number of calls of the operation so far, failed ones included
+    pub fn calls(&self, op: BackendOp) -> usize {
+        self.calls[op.index()].load(Ordering::Relaxed)
+    }
+
+    /// Number of values stored, including expired ones
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Counts the call, waits for the latency, then checks whether to fail it
+    async fn begin(&self, op: BackendOp, key: &K) -> Result<(), InjectedFailure> {
+        self.calls[op.index()].fetch_add(1, Ordering::Relaxed);
+
+        let latency = self.failures().latency;
+
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        let mut failures = self.failures();
+
+        if failures.fail_next > 0 {
+            failures.fail_next -= 1;
+            return Err(InjectedFailure { op });
+        }
+
+        match failures.hook {
+            Some(ref hook) if hook(op, key) => Err(InjectedFailure { op }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<K, V> MockBackend<K, V>
+where
+    K: Hash + Eq,
+    V: Clone,
+{
+    /// Value stored for the key, unless expired, without counting a call or failing, e.g. to assert on what
+    /// the cache under test wrote through
+    pub fn peek(&self, key: &K) -> Option<V> {
+        match self.entries().get(key) {
+            Some((value, expires_at)) if *expires_at > DefaultClock::now() => Some(value.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<K, V> AsyncCacheBackend<K, V> for MockBackend<K, V>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Clone + Send + Sync,
+{
+    type Error = InjectedFailure;
+
+    async fn get(&self, key: &K) -> Result<Option<V>, InjectedFailure> {
+        self.begin(BackendOp::Get, key).await?;
+
+        Ok(self.peek(key))
+    }
+
+    async fn set(&self, key: K, value: V, ttl: Option<Duration>) -> Result<(), InjectedFailure> {
+        self.begin(BackendOp::Set, &key).await?;
+
+        let expires_at = match ttl {
+            Some(ttl) => DefaultClock::now().saturating_add(ttl.as_nanos().min(u64::MAX as u128) as u64),
+            None => u64::MAX,
+        };
+
+        self.entries().insert(key, (value, expires_at));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &K) -> Result<bool, InjectedFailure> {
+        self.begin(BackendOp::Delete, key).await?;
+
+        let now = DefaultClock::now();
+
+        Ok(self.entries().remove(key).is_some_and(|(_, expires_at)| expires_at > now))
+    }
+}
+
+impl<K, V> fmt::Debug for MockBackend<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockBackend")
+            .field("len", &self.entries().len())
+            .field("gets", &self.calls(BackendOp::Get))
+            .field("sets", &self.calls(BackendOp::Set))
+            .field("deletes", &self.calls(BackendOp::Delete))
+            .finish()
+    }
+}
@@ -0,0 +1,225 @@
+//! Reusable buffers for the batch operations of a `CHashMap`, see `CHashMap::batch`.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Range;
+
+use hashbrown::hash_map::RawEntryMut;
+
+use crate::deadline::{Deadline, Progress};
+use crate::scan::Cursor;
+use crate::CHashMap;
+
+/// Key of a batch, by its position in the batch, along with its hash and shard
+pub(crate) type BatchEntry = (usize, u64, usize);
+
+/// Reorders a batch so that entries of the same shard are next to each other, in shard order,
+/// through `scratch`, leaving the end of each shard's range in `ends`.
+///
+/// Rather than comparison-sorting the batch, entries are counted per shard, then copied straight into
+/// their shard's range, which takes linear time, as there are few shards and their indices are known.
+pub(crate) fn group_by_shard<E: Copy>(
+    batch: &mut [E],
+    scratch: &mut Vec<E>,
+    ends: &mut Vec<usize>,
+    num_shards: usize,
+    shard_of: impl Fn(&E) -> usize,
+) {
+    // number of entries of each shard, then the next position to fill in its range, which ends up as its end
+    ends.clear();
+    ends.resize(num_shards, 0);
+
+    let first = match batch.first() {
+        Some(first) => *first,
+        None => return,
+    };
+
+    for entry in batch.iter() {
+        ends[shard_of(entry)] += 1;
+    }
+
+    let mut start = 0;
+
+    for next in ends.iter_mut() {
+        let len = *next;
+        *next = start;
+        start += len;
+    }
+
+    scratch.clear();
+    scratch.resize(batch.len(), first);
+
+    for entry in batch.iter() {
+        let next = &mut ends[shard_of(entry)];
+        scratch[*next] = *entry;
+        *next += 1;
+    }
+
+    batch.copy_from_slice(scratch);
+}
+
+/// Buffers of the batch operations of a [`CHashMap`], kept between batches so that, once grown to the size
+/// of the largest batch, running a batch through [`CHashMap::batch`] allocates nothing.
+///
+/// ```
+/// # use quick_hash_cache::{BatchContext, CHashMap};
+/// # #[tokio::main] async fn main() {
+/// let map = CHashMap::new(4);
+/// map.insert(1, "one").await;
+///
+/// let mut ctx = BatchContext::new();
+/// let mut found = Vec::new();
+///
+/// let _ = map.batch(&mut ctx).read(&[1, 2], |_, entry| found.extend(entry.map(|(_, v)| *v))).await;
+///
+/// assert_eq!(found, ["one"]);
+/// assert_eq!(ctx.missing(), [1]);
+/// # }
+/// ```
+#[derive(Default)]
+pub struct BatchContext {
+    /// Keys of the current batch, grouped by shard
+    entries: Vec<BatchEntry>,
+    /// Buffer the keys are grouped through
+    scratch: Vec<BatchEntry>,
+    /// End of each shard's group in `entries`
+    ends: Vec<usize>,
+    /// Positions of the keys of the last read that were not found
+    missing: Vec<usize>,
+}
+
+impl BatchContext {
+    pub fn new() -> Self {
+        BatchContext::default()
+    }
+
+    /// Context with room for batches of up to `num_keys` keys
+    pub fn with_capacity(num_keys: usize) -> Self {
+        BatchContext {
+            entries: Vec::with_capacity(num_keys),
+            scratch: Vec::with_capacity(num_keys),
+            ends: Vec::new(),
+            missing: Vec::new(),
+        }
+    }
+
+    /// Positions in the batch of the keys the last batch found no entry for, if it was a `Batch::read`,
+    /// in no particular order, e.g. for loading them from the source
+    pub fn missing(&self) -> &[usize] {
+        &self.missing
+    }
+
+    /// Groups the entries hashed into `entries` by shard
+    pub(crate) fn group(&mut self, num_shards: usize) {
+        self.missing.clear();
+
+        group_by_shard(&mut self.entries, &mut self.scratch, &mut self.ends, num_shards, |&(_, _, shard)| shard);
+    }
+
+    /// Buffer to hash the keys of a batch into, before grouping them
+    pub(crate) fn entries_mut(&mut self) -> &mut Vec<BatchEntry> {
+        self.entries.clear();
+        &mut self.entries
+    }
+
+    /// Range of `entries` holding the keys of the shard, once grouped
+    #[inline]
+    pub(crate) fn shard_range(&self, shard_idx: usize) -> Range<usize> {
+        let start = match shard_idx {
+            0 => 0,
+            _ => self.ends[shard_idx - 1],
+        };
+
+        start..self.ends[shard_idx]
+    }
+
+    #[inline]
+    pub(crate) fn entry(&self, idx: usize) -> BatchEntry {
+        self.entries[idx]
+    }
+
+    #[inline]
+    pub(crate) fn push_missing(&mut self, key_idx: usize) {
+        self.missing.push(key_idx);
+    }
+}
+
+impl fmt::Debug for BatchContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BatchContext")
+            .field("capacity", &self.entries.capacity())
+            .field("missing", &self.missing)
+            .finish()
+    }
+}
+
+/// Batch operation on a [`CHashMap`] with the buffers of a [`BatchContext`], as returned by `CHashMap::batch`.
+///
+/// Keys are hashed and grouped by shard into the context, then each shard is locked once for all its keys.
+pub struct Batch<'m, 'c, K, T, S> {
+    map: &'m CHashMap<K, T, S>,
+    ctx: &'c mut BatchContext,
+    from: Cursor,
+    deadline: Deadline,
+}
+
+impl<'m, 'c, K, T, S> Batch<'m, 'c, K, T, S> {
+    pub(crate) fn new(map: &'m CHashMap<K, T, S>, ctx: &'c mut BatchContext) -> Self {
+        Batch {
+            map,
+            ctx,
+            from: Cursor::START,
+            deadline: Deadline::NEVER,
+        }
+    }
+
+    /// Skips the keys of shards before the cursor's, to resume a batch interrupted by its deadline
+    pub fn from(mut self, from: Cursor) -> Self {
+        self.from = from;
+        self
+    }
+
+    /// Gives up between shards once the deadline is reached, see `Deadline`
+    pub fn until(mut self, deadline: &Deadline) -> Self {
+        self.deadline = deadline.clone();
+        self
+    }
+}
+
+impl<'m, 'c, K, T, S> Batch<'m, 'c, K, T, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// Runs `f` on each key along with its entry, if any, recording the keys not found in `BatchContext::missing`
+    pub async fn read<'k, Q, F>(self, keys: &'k [Q], f: F) -> Progress
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+        F: FnMut(&'k Q, Option<(&K, &T)>),
+    {
+        self.map.batch_read_ctx(keys, self.ctx, self.from, &self.deadline, f).await
+    }
+
+    /// Runs `f` on each key along with its raw entry, e.g. to insert or update values in bulk.
+    ///
+    /// As with `batch_write`, inserting or removing entries through `f` does not update `size`.
+    pub async fn write<'k, Q, F>(self, keys: &'k [Q], f: F) -> Progress
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+        F: FnMut(&'k Q, RawEntryMut<K, T, S>),
+    {
+        self.map.batch_write_ctx(keys, self.ctx, self.from, &self.deadline, f).await
+    }
+}
+
+impl<'m, 'c, K, T, S> fmt::Debug for Batch<'m, 'c, K, T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Batch")
+            .field("from", &self.from)
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
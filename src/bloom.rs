@@ -0,0 +1,152 @@
+//! Per-shard Bloom filters, letting batch reads skip shards that hold none of their keys, see
+//! `CHashMap::with_bloom_filters`.
+
+use std::fmt;
+
+use crate::sync::{AtomicU64, AtomicUsize, Ordering};
+
+/// Bits of the filter per entry it is sized for, for about 1% of false positives
+const BITS_PER_ENTRY: usize = 10;
+
+/// The filter holds every key of its shard
+const CLEAN: usize = 0;
+/// The shard was written to in a way the filter can't follow, so it may miss keys until rebuilt
+const DIRTY: usize = 1;
+/// A reader is rebuilding the filter
+const REBUILDING: usize = 2;
+
+/// Blocked Bloom filter over the hashes of a shard's keys, setting 4 bits of a single word per key,
+/// so a lookup only loads one word.
+///
+/// Keys are added as they are written, removed keys staying in the filter, so once as many writes as the filter
+/// is sized for went by since it was built, it is marked dirty to be rebuilt from the shard and shed them.
+struct ShardFilter {
+    words: Box<[AtomicU64]>,
+    state: AtomicUsize,
+    /// Keys written since the filter was built, removed ones included
+    added: AtomicUsize,
+}
+
+impl ShardFilter {
+    fn new(num_words: usize) -> Self {
+        ShardFilter {
+            words: (0..num_words).map(|_| AtomicU64::new(0)).collect(),
+            state: AtomicUsize::new(DIRTY),
+            added: AtomicUsize::new(0),
+        }
+    }
+
+    /// Word of the hash and the bits to set in it
+    #[inline]
+    fn locate(&self, hash: u64) -> (usize, u64) {
+        // shards are selected from the hash, so its bits are mixed again before being relied on
+        let mut mixed = hash ^ (hash >> 30);
+        mixed = mixed.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        mixed ^= mixed >> 27;
+        mixed = mixed.wrapping_mul(0x94d0_49bb_1331_11eb);
+        mixed ^= mixed >> 31;
+
+        let word = ((mixed as u32 as u64 * self.words.len() as u64) >> 32) as usize;
+        let bits = (0..4).fold(0, |bits, i| bits | 1 << ((mixed >> (32 + 6 * i)) & 63));
+
+        (word, bits)
+    }
+
+    #[inline]
+    fn add(&self, hash: u64) {
+        let (word, bits) = self.locate(hash);
+        self.words[word].fetch_or(bits, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn may_contain(&self, hash: u64) -> bool {
+        let (word, bits) = self.locate(hash);
+        self.words[word].load(Ordering::Relaxed) & bits == bits
+    }
+}
+
+/// Bloom filter of each shard of a `CHashMap`.
+///
+/// Filters are written under the write lock of their shard, and rebuilt under its read lock,
+/// so batch reads can check them without locking the shard.
+pub(crate) struct ShardFilters {
+    filters: Box<[ShardFilter]>,
+    /// Number of entries per shard the filters are sized for
+    expected_len: usize,
+}
+
+impl ShardFilters {
+    pub(crate) fn new(num_shards: usize, expected_len: usize) -> Self {
+        let expected_len = expected_len.max(1);
+        let num_words = (expected_len * BITS_PER_ENTRY).div_ceil(64);
+
+        ShardFilters {
+            filters: (0..num_shards).map(|_| ShardFilter::new(num_words)).collect(),
+            expected_len,
+        }
+    }
+
+    /// Filters of the same size for a map with `num_shards` shards, to be rebuilt before use
+    pub(crate) fn renew(&self, num_shards: usize) -> Self {
+        ShardFilters::new(num_shards, self.expected_len)
+    }
+
+    /// Adds a key written to the shard, to be called under its write lock
+    #[inline]
+    pub(crate) fn add(&self, shard_idx: usize, hash: u64) {
+        let filter = &self.filters[shard_idx];
+        filter.add(hash);
+
+        if filter.added.fetch_add(1, Ordering::Relaxed) >= self.expected_len {
+            filter.state.store(DIRTY, Ordering::Relaxed);
+        }
+    }
+
+    /// Marks the shard's filter for rebuilding, to be called under its write lock before a write
+    /// that may touch any of its keys
+    #[inline]
+    pub(crate) fn mark_dirty(&self, shard_idx: usize) {
+        self.filters[shard_idx].state.store(DIRTY, Ordering::Relaxed);
+    }
+
+    /// Whether the shard may hold a key of any of the hashes, always `true` while its filter is dirty
+    pub(crate) fn may_contain_any(&self, shard_idx: usize, mut hashes: impl Iterator<Item = u64>) -> bool {
+        let filter = &self.filters[shard_idx];
+
+        filter.state.load(Ordering::Acquire) != CLEAN || hashes.any(|hash| filter.may_contain(hash))
+    }
+
+    /// Rebuilds the shard's filter from the hashes of its keys if it is dirty, to be called under its read lock
+    pub(crate) fn refresh(&self, shard_idx: usize, hashes: impl Iterator<Item = u64>) {
+        let filter = &self.filters[shard_idx];
+
+        // other readers of the shard may be at it already
+        if filter
+            .state
+            .compare_exchange(DIRTY, REBUILDING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+
+        for word in filter.words.iter() {
+            word.store(0, Ordering::Relaxed);
+        }
+
+        for hash in hashes {
+            filter.add(hash);
+        }
+
+        filter.added.store(0, Ordering::Relaxed);
+        filter.state.store(CLEAN, Ordering::Release);
+    }
+}
+
+impl fmt::Debug for ShardFilters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardFilters")
+            .field("num_shards", &self.filters.len())
+            .field("expected_len", &self.expected_len)
+            .finish()
+    }
+}
@@ -0,0 +1,236 @@
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use std::sync::Arc;
+
+use crate::hash::{self, AHashState, NoHash, ShardSelect, Sip13State};
+use crate::lru::LruCache;
+use crate::numa::{NumaPlacement, NumaTopology};
+use crate::tinylfu::WTinyLfuCache;
+use crate::CHashMap;
+
+/// Configures the shards and hashing shared by all the maps, then builds one of them.
+///
+/// ```
+/// # use quick_hash_cache::{Builder, CHashMap};
+/// // keys come from the network, so make sure they can't be crafted to collide
+/// let map: CHashMap<String, u32, _> = Builder::new().num_shards(16).random_seeded().per_shard_seeds().build_map();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Builder<S = DefaultHashBuilder> {
+    num_shards: Option<usize>,
+    hash_builder: S,
+    shard_select: ShardSelect,
+    /// Placement of the shards on NUMA nodes, and the number of entries to allocate room for
+    numa: Option<(NumaPlacement, usize)>,
+    /// Number of entries to allocate room for, and share of them each shard of a `build_map` map is sized for
+    presize: Option<(usize, Vec<f64>)>,
+    /// Times single-key operations of a `build_map` map try a busy shard lock before awaiting it
+    spins: u32,
+}
+
+impl Builder<DefaultHashBuilder> {
+    pub fn new() -> Self {
+        Builder {
+            num_shards: None,
+            hash_builder: DefaultHashBuilder::default(),
+            shard_select: ShardSelect::Modulo,
+            numa: None,
+            presize: None,
+            spins: 0,
+        }
+    }
+}
+
+impl Default for Builder<DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Builder<S> {
+    /// Number of shards, defaulting to four per physical core, or to the value of the `QUICK_HASH_CACHE_SHARDS`
+    /// environment variable if set, see `shards_per_core`
+    pub fn num_shards(mut self, num_shards: usize) -> Self {
+        self.num_shards = Some(num_shards);
+        self
+    }
+
+    /// Sizes the map with `factor` shards per physical core, rounded up to a power of two and capped at 1024,
+    /// e.g. more for write-heavy workloads, ignoring the `QUICK_HASH_CACHE_SHARDS` environment variable
+    pub fn shards_per_core(mut self, factor: usize) -> Self {
+        self.num_shards = Some(crate::shards_per_core(factor));
+        self
+    }
+
+    /// Use the given hasher
+    pub fn hasher<H>(self, hash_builder: H) -> Builder<H> {
+        Builder {
+            num_shards: self.num_shards,
+            hash_builder,
+            shard_select: self.shard_select,
+            numa: self.numa,
+            presize: self.presize,
+            spins: self.spins,
+        }
+    }
+
+    /// Use aHash with random keys unique to this map, see [`hash::random_state`]
+    pub fn random_seeded(self) -> Builder<AHashState> {
+        self.hasher(hash::random_state())
+    }
+
+    /// Use SipHash 1-3 with random keys, see [`Sip13State`]
+    pub fn siphash13(self) -> Builder<Sip13State> {
+        self.hasher(Sip13State::new())
+    }
+
+    /// Use FxHash, for trusted keys only, see [`hash::FxBuildHasher`]
+    #[cfg(feature = "fxhash")]
+    pub fn fxhash(self) -> Builder<hash::FxBuildHasher> {
+        self.hasher(hash::FxBuildHasher::default())
+    }
+
+    /// Use Fibonacci hashing for integer keys that are already well-distributed, see [`NoHash`],
+    /// and select shards by the high bits of the hash, where it is best mixed.
+    pub fn nohash(self) -> Builder<NoHash> {
+        let mut builder = self.hasher(NoHash::default());
+        builder.shard_select = ShardSelect::HighBits;
+        builder
+    }
+
+    /// Select shards with a random seed mixed into each hash, so that keys in the same shard
+    /// don't share hash bits, and which keys share a shard differs between maps.
+    pub fn per_shard_seeds(mut self) -> Self {
+        self.shard_select = ShardSelect::Seeded(hash::random_u64());
+        self
+    }
+
+    /// Select shards by consistent hashing, so that [`CHashMap::resize_shards`] only relocates
+    /// about `1/N` of the keys, instead of nearly all of them with the default modulo selection.
+    ///
+    /// Lookups binary-search the shards' points on the hash ring, so selecting a shard is slightly slower.
+    pub fn consistent_hashing(mut self) -> Self {
+        self.shard_select = ShardSelect::Ring(Default::default());
+        self
+    }
+
+    /// Allocate each shard of a `build_map` map on a NUMA node, with room for `capacity` entries in total,
+    /// see [`numa`](crate::numa).
+    ///
+    /// Shards are split into one contiguous group per node. Tables that later grow beyond their share of
+    /// `capacity` are reallocated by whichever thread inserts into them, so size `capacity` generously.
+    pub fn numa(mut self, topology: impl NumaTopology + 'static, capacity: usize) -> Self {
+        let placement = NumaPlacement {
+            topology: Arc::new(topology),
+        };
+        self.numa = Some((placement, capacity));
+        self
+    }
+
+    /// Allocate the shards of a `build_map` map with room for `capacity` entries in total, spread over the shards
+    /// proportionally to `weights`, e.g. a measured histogram of entries per shard of a multi-tenant workload
+    /// with known hot tenants, so the hot shards don't rehash over and over while filling up.
+    ///
+    /// Sets the number of shards to the number of weights. Non-positive or non-finite weights count as zero, and
+    /// all-zero weights as equal ones. See [`CHashMap::rebalance_hint`] for checking the spread against them later.
+    ///
+    /// ```
+    /// # use quick_hash_cache::{Builder, CHashMap};
+    /// # #[tokio::main] async fn main() {
+    /// let map: CHashMap<u32, u32> = Builder::new().presize_shards(1000, [2.0, 1.0, 1.0]).build_map();
+    /// assert_eq!(map.num_shards(), 3);
+    ///
+    /// for key in 0..300 {
+    ///     map.insert(key, key).await;
+    /// }
+    ///
+    /// // keys are spread evenly, unlike the weights
+    /// assert!(map.rebalance_hint().await.unwrap().exceeds(0.2));
+    /// # }
+    /// ```
+    pub fn presize_shards(mut self, capacity: usize, weights: impl IntoIterator<Item = f64>) -> Self {
+        let weights: Vec<f64> = weights
+            .into_iter()
+            .map(|weight| if weight.is_finite() && weight > 0.0 { weight } else { 0.0 })
+            .collect();
+
+        assert!(!weights.is_empty(), "a map needs at least one shard");
+
+        self.num_shards = Some(weights.len());
+        self.presize = Some((capacity, weights));
+        self
+    }
+
+    /// Have single-key operations of a `build_map` map (`get`, `insert`, `remove`, ...) try a busy shard lock up to
+    /// `spins` times before awaiting it, which skips registering a waker and being rescheduled when critical sections
+    /// are very short, so that locks are released within a few spins. Defaults to 0, awaiting right away.
+    ///
+    /// Spinning holds up the thread, and every other task on it, so keep `spins` small, in the tens, and leave it
+    /// at 0 for maps whose handles are held across awaits or whose values are expensive to update.
+    pub fn spin_before_await(mut self, spins: u32) -> Self {
+        self.spins = spins;
+        self
+    }
+
+    /// Share of the entries each of the `num_shards` shards is sized for, from `presize_shards`
+    fn shard_shares(weights: &[f64], num_shards: usize) -> Box<[f64]> {
+        assert_eq!(weights.len(), num_shards, "`presize_shards` was given one weight per shard");
+
+        let total: f64 = weights.iter().sum();
+
+        weights
+            .iter()
+            .map(|weight| match total > 0.0 {
+                true => weight / total,
+                false => 1.0 / num_shards as f64,
+            })
+            .collect()
+    }
+
+    fn get_num_shards(&self) -> usize {
+        self.num_shards.unwrap_or_else(crate::default_num_shards)
+    }
+
+    pub fn build_map<K, T>(self) -> CHashMap<K, T, S>
+    where
+        S: Clone,
+    {
+        let num_shards = self.get_num_shards();
+        let mut map = CHashMap::with_hasher(num_shards, self.hash_builder);
+        map.shard_select = self.shard_select.with_num_shards(num_shards);
+        map.spins = self.spins;
+
+        let mut capacities = None;
+
+        if let Some((capacity, weights)) = self.presize {
+            let shares = Self::shard_shares(&weights, num_shards);
+            capacities = Some(shares.iter().map(|share| (capacity as f64 * share).ceil() as usize).collect());
+            map.set_expected_shares(shares);
+        }
+
+        match (self.numa, capacities) {
+            (Some((placement, capacity)), capacities) => {
+                let capacities = capacities.unwrap_or_else(|| vec![capacity.div_ceil(num_shards.max(1)); num_shards]);
+                map.place_shards(placement, &capacities);
+            }
+            (None, Some(capacities)) => map.presize_shards(&capacities),
+            (None, None) => {}
+        }
+
+        map
+    }
+
+    pub fn build_lru<K, V, T>(self) -> LruCache<K, V, T, S> {
+        let num_shards = self.get_num_shards();
+        let mut cache = LruCache::with_hasher(num_shards, self.hash_builder);
+        cache.shard_select = self.shard_select.with_num_shards(num_shards);
+        cache
+    }
+
+    pub fn build_tinylfu<K, V>(self, capacity: usize) -> WTinyLfuCache<K, V, S> {
+        let num_shards = self.get_num_shards();
+        let mut cache = WTinyLfuCache::with_hasher(num_shards, capacity, self.hash_builder);
+        cache.shard_select = self.shard_select.with_num_shards(num_shards);
+        cache
+    }
+}
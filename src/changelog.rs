@@ -0,0 +1,128 @@
+//! Per-shard logs of the keys written, for replicas pulling incremental changes, see `CHashMap::diff_since`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+
+/// Keys written to a shard, each with the shard's generation after the write, oldest first
+struct ChangeLog<K> {
+    changes: VecDeque<(u64, K)>,
+    /// Latest generation whose changes are no longer all in the log,
+    /// either dropped to stay within capacity, or by writes that don't tell which keys they touched
+    floor: u64,
+}
+
+pub(crate) struct ChangeLogs<K> {
+    shards: Box<[Mutex<ChangeLog<K>>]>,
+    /// Maximum number of changes kept per shard
+    capacity: usize,
+    /// Keys are only logged for maps with `K: Clone`, which the write paths can't require
+    clone_key: fn(&K) -> K,
+}
+
+impl<K> ChangeLogs<K> {
+    pub(crate) fn new(num_shards: usize, capacity: usize) -> Self
+    where
+        K: Clone,
+    {
+        Self::with_clone_key(num_shards, capacity, K::clone)
+    }
+
+    fn with_clone_key(num_shards: usize, capacity: usize, clone_key: fn(&K) -> K) -> Self {
+        ChangeLogs {
+            shards: (0..num_shards)
+                .map(|_| {
+                    Mutex::new(ChangeLog {
+                        changes: VecDeque::new(),
+                        floor: 0,
+                    })
+                })
+                .collect(),
+            capacity,
+            clone_key,
+        }
+    }
+
+    /// Empty logs of the same capacity, for a map with `num_shards` shards
+    pub(crate) fn renew(&self, num_shards: usize) -> Self {
+        Self::with_clone_key(num_shards, self.capacity, self.clone_key)
+    }
+
+    #[inline]
+    fn shard(&self, shard_idx: usize) -> MutexGuard<'_, ChangeLog<K>> {
+        self.shards[shard_idx].lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Logs a write of the key, to be called under the shard's write lock
+    pub(crate) fn record(&self, shard_idx: usize, generation: u64, key: &K) {
+        let mut log = self.shard(shard_idx);
+
+        if log.changes.len() >= self.capacity {
+            match log.changes.pop_front() {
+                Some((dropped, _)) => log.floor = dropped,
+                None => {
+                    log.floor = generation;
+                    return;
+                }
+            }
+        }
+
+        let key = (self.clone_key)(key);
+        log.changes.push_back((generation, key));
+    }
+
+    /// Forgets the shard's changes up to `generation`, for writes that may touch any key of the shard
+    pub(crate) fn truncate(&self, shard_idx: usize, generation: u64) {
+        let mut log = self.shard(shard_idx);
+
+        log.changes.clear();
+        log.floor = generation;
+    }
+
+    /// Clones the keys written after `generation`, in the order they were written,
+    /// or returns `None` if some of them are no longer logged
+    pub(crate) fn keys_since(&self, shard_idx: usize, generation: u64) -> Option<Vec<K>> {
+        let log = self.shard(shard_idx);
+
+        if generation < log.floor {
+            return None;
+        }
+
+        let first = log.changes.partition_point(|(at, _)| *at <= generation);
+
+        Some(
+            log.changes
+                .range(first..)
+                .map(|(_, key)| (self.clone_key)(key))
+                .collect(),
+        )
+    }
+}
+
+impl<K> fmt::Debug for ChangeLogs<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangeLogs").field("capacity", &self.capacity).finish()
+    }
+}
+
+/// Changes to a shard of a [`CHashMap`](crate::CHashMap) since a generation, as returned by `diff_since`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardDiff<K, T> {
+    /// Current value of each key written since, `None` if it was removed, in the order of their first write
+    pub changes: Vec<(K, Option<T>)>,
+    /// Generation of the shard these values are at, to pass to the next `diff_since`
+    pub generation: u64,
+}
+
+/// Error of `CHashMap::diff_since` when the changes since the generation are no longer all logged,
+/// so the replica needs a full copy of the shard, e.g. from `snapshot_shard`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResyncNeeded;
+
+impl fmt::Display for ResyncNeeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("changes since the generation are no longer logged, a full resync is needed")
+    }
+}
+
+impl std::error::Error for ResyncNeeded {}
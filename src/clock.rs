@@ -0,0 +1,82 @@
+//! Monotonic clock sources backing [`AtomicInstant`](crate::lru::AtomicInstant).
+
+/// A monotonic clock, read as a `u64` number of nanoseconds since an arbitrary origin.
+///
+/// LRU bookkeeping only relies on the ordering of readings, but entry expiration adds durations to them,
+/// so readings must be in nanoseconds.
+pub trait Clock {
+    fn now() -> u64;
+}
+
+/// TSC-backed clock via `quanta`, the fastest option on native targets.
+#[cfg(all(feature = "quanta", not(target_arch = "wasm32")))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuantaClock;
+
+#[cfg(all(feature = "quanta", not(target_arch = "wasm32")))]
+impl Clock for QuantaClock {
+    #[inline]
+    fn now() -> u64 {
+        quanta::Instant::now().as_u64()
+    }
+}
+
+/// Clock based on `std::time::Instant`, in nanoseconds since the first reading.
+///
+/// Note that `std::time::Instant` is unavailable on `wasm32-unknown-unknown`, use `JsClock` there.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdClock;
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    #[inline]
+    fn now() -> u64 {
+        use std::sync::OnceLock;
+        use std::time::Instant;
+
+        static BASE: OnceLock<Instant> = OnceLock::new();
+
+        BASE.get_or_init(Instant::now).elapsed().as_nanos() as u64
+    }
+}
+
+/// Browser/Node.js clock, using `performance.now()` where available and falling back to `Date.now()`.
+#[cfg(all(feature = "js", target_arch = "wasm32"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsClock;
+
+#[cfg(all(feature = "js", target_arch = "wasm32"))]
+impl Clock for JsClock {
+    #[inline]
+    fn now() -> u64 {
+        use js_sys::{global, Date, Function, Reflect};
+        use wasm_bindgen::{JsCast, JsValue};
+
+        let millis = Reflect::get(&global(), &JsValue::from_str("performance"))
+            .ok()
+            .filter(|performance| performance.is_object())
+            .and_then(|performance| {
+                let now = Reflect::get(&performance, &JsValue::from_str("now")).ok()?;
+                now.dyn_into::<Function>().ok()?.call0(&performance).ok()?.as_f64()
+            })
+            .unwrap_or_else(Date::now);
+
+        (millis * 1_000_000.0) as u64
+    }
+}
+
+/// The clock used by `AtomicInstant` unless otherwise specified:
+/// `JsClock` on `wasm32` with the `js` feature, `QuantaClock` with the `quanta` feature, otherwise `StdClock`.
+#[cfg(all(feature = "js", target_arch = "wasm32"))]
+pub type DefaultClock = JsClock;
+
+#[cfg(all(feature = "quanta", not(target_arch = "wasm32")))]
+pub type DefaultClock = QuantaClock;
+
+#[cfg(all(
+    feature = "std",
+    not(all(feature = "quanta", not(target_arch = "wasm32"))),
+    not(all(feature = "js", target_arch = "wasm32"))
+))]
+pub type DefaultClock = StdClock;
@@ -0,0 +1,253 @@
+//! Copy-on-write snapshots of a `CHashMap`, see `CHashMap::duplicate_cow`.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::sync::{Mutex, MutexGuard, OnceLock, Weak};
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::hash::ShardSelect;
+use crate::pins::ShardPins;
+use crate::sync::{Arc, AtomicUsize, Ordering, RwLock};
+use crate::Shard;
+
+type CloneShard<K, T, S> = fn(&Shard<K, T, S>) -> Shard<K, T, S>;
+
+/// Snapshots sharing a shard, dropped ones being pruned on the shard's next write
+type Shares<K, T, S> = Vec<Weak<CowShard<K, T, S>>>;
+
+/// Shard of a snapshot, read from the map's shard until the map first writes to it and copies it here
+pub(crate) struct CowShard<K, T, S> {
+    source: Arc<RwLock<Shard<K, T, S>>>,
+    copy: OnceLock<Arc<Shard<K, T, S>>>,
+    /// Snapshots are only taken of maps with `K: Clone, T: Clone`, which the write paths can't require
+    clone_shard: CloneShard<K, T, S>,
+}
+
+impl<K, T, S> CowShard<K, T, S> {
+    pub(crate) fn new(source: Arc<RwLock<Shard<K, T, S>>>) -> Self
+    where
+        K: Clone,
+        T: Clone,
+        S: Clone,
+    {
+        CowShard {
+            source,
+            copy: OnceLock::new(),
+            clone_shard: Shard::clone,
+        }
+    }
+}
+
+/// Snapshots still sharing each shard of a map, which copies a shard into them before it first writes to it
+pub(crate) struct CowShares<K, T, S> {
+    shards: Box<[Mutex<Shares<K, T, S>>]>,
+    /// Number of shares left across the shards, so writes to maps without snapshots skip the registry
+    num_shared: AtomicUsize,
+}
+
+impl<K, T, S> CowShares<K, T, S> {
+    pub(crate) fn new(num_shards: usize) -> Self {
+        CowShares {
+            shards: (0..num_shards).map(|_| Mutex::new(Vec::new())).collect(),
+            num_shared: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    fn shard(&self, shard_idx: usize) -> MutexGuard<'_, Shares<K, T, S>> {
+        self.shards[shard_idx].lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Shares the shard with a snapshot, to be called under the shard's read lock
+    pub(crate) fn share(&self, shard_idx: usize, cow_shard: &Arc<CowShard<K, T, S>>) {
+        self.shard(shard_idx).push(Arc::downgrade(cow_shard));
+        self.num_shared.fetch_add(1, Ordering::Release);
+    }
+
+    /// Copies the shard into the snapshots still sharing it, to be called under its write lock before writing to it
+    #[inline]
+    pub(crate) fn unshare(&self, shard_idx: usize, shard: &Shard<K, T, S>) {
+        if self.num_shared.load(Ordering::Acquire) != 0 {
+            self.unshare_slow(shard_idx, shard);
+        }
+    }
+
+    /// Like `unshare`, unless `writes` tells that the shard won't be written after all, e.g. when removing an absent
+    /// key, so that misses don't copy the shard. `writes` is only asked while snapshots share any shard.
+    #[inline]
+    pub(crate) fn unshare_if(
+        &self,
+        shard_idx: usize,
+        shard: &Shard<K, T, S>,
+        writes: impl FnOnce(&Shard<K, T, S>) -> bool,
+    ) {
+        if self.num_shared.load(Ordering::Acquire) != 0 && writes(shard) {
+            self.unshare_slow(shard_idx, shard);
+        }
+    }
+
+    #[cold]
+    fn unshare_slow(&self, shard_idx: usize, shard: &Shard<K, T, S>) {
+        let shares = mem::take(&mut *self.shard(shard_idx));
+
+        if shares.is_empty() {
+            return;
+        }
+
+        self.num_shared.fetch_sub(shares.len(), Ordering::Release);
+
+        // a single copy for every snapshot taken since the last write
+        let mut copy = None;
+
+        for cow_shard in shares.iter().filter_map(Weak::upgrade) {
+            let copy = copy.get_or_insert_with(|| Arc::new((cow_shard.clone_shard)(shard)));
+            let _ = cow_shard.copy.set(copy.clone());
+        }
+    }
+}
+
+impl<K, T, S> fmt::Debug for CowShares<K, T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CowShares")
+            .field("num_shared", &self.num_shared.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// Read-only copy of a [`CHashMap`](crate::CHashMap) as it was when taken with `duplicate_cow`.
+///
+/// Shards are shared with the map rather than cloned up front, and the map only clones a shard into its snapshots
+/// the first time it writes to it afterwards, so periodic snapshots for analysis cost nothing for shards that
+/// are left alone in the meantime. Shards not yet copied are read under the map's read lock on them.
+///
+/// ```
+/// # use quick_hash_cache::CHashMap;
+/// # #[tokio::main] async fn main() {
+/// let map = CHashMap::new(4);
+/// map.insert("a", 1).await;
+///
+/// let snapshot = map.duplicate_cow().await;
+/// map.insert("a", 2).await;
+///
+/// assert_eq!(snapshot.get_cloned(&"a").await, Some(1));
+/// assert_eq!(snapshot.num_copied_shards(), 1);
+/// # }
+/// ```
+pub struct CowSnapshot<K, T, S = DefaultHashBuilder> {
+    hash_builder: S,
+    shard_select: ShardSelect,
+    pins: ShardPins,
+    shards: Box<[Arc<CowShard<K, T, S>>]>,
+    size: usize,
+}
+
+impl<K, T, S> CowSnapshot<K, T, S> {
+    pub(crate) fn new(
+        hash_builder: S,
+        shard_select: ShardSelect,
+        pins: ShardPins,
+        shards: Vec<Arc<CowShard<K, T, S>>>,
+        size: usize,
+    ) -> Self {
+        CowSnapshot {
+            hash_builder,
+            shard_select,
+            pins,
+            shards: shards.into_boxed_slice(),
+            size,
+        }
+    }
+
+    /// Number of entries in the map when the snapshot was taken
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Number of shards the map has copied into the snapshot so far, by writing to them since it was taken
+    pub fn num_copied_shards(&self) -> usize {
+        self.shards.iter().filter(|shard| shard.copy.get().is_some()).count()
+    }
+
+    /// Runs the closure on a shard as it was when the snapshot was taken
+    ///
+    /// Panics if `shard_idx` is out of bounds.
+    pub async fn with_shard<F, R>(&self, shard_idx: usize, f: F) -> R
+    where
+        F: FnOnce(&Shard<K, T, S>) -> R,
+    {
+        let cow_shard = &self.shards[shard_idx];
+
+        if let Some(copy) = cow_shard.copy.get() {
+            return f(copy);
+        }
+
+        let source = cow_shard.source.read().await;
+
+        // the map copies the shard under its write lock before writing to it,
+        // so while read-locked, it is either copied already or still unchanged
+        match cow_shard.copy.get() {
+            Some(copy) => f(copy),
+            None => f(&source),
+        }
+    }
+}
+
+impl<K, T, S> CowSnapshot<K, T, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    fn hash_and_shard<Q>(&self, key: &Q) -> (u64, usize)
+    where
+        Q: ?Sized + Hash,
+    {
+        let hash = self.hash_builder.hash_one(key);
+        let shard_idx = match self.pins.get(hash) {
+            Some(shard_idx) => shard_idx,
+            None => self.shard_select.shard_of(hash, self.shards.len()),
+        };
+
+        (hash, shard_idx)
+    }
+
+    pub async fn get_cloned<Q>(&self, key: &Q) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        T: Clone,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+
+        self.with_shard(shard_idx, |shard| {
+            shard.raw_entry().from_key_hashed_nocheck(hash, key).map(|(_, value)| value.clone())
+        })
+        .await
+    }
+
+    pub async fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+
+        self.with_shard(shard_idx, |shard| shard.raw_entry().from_key_hashed_nocheck(hash, key).is_some())
+            .await
+    }
+}
+
+impl<K, T, S> fmt::Debug for CowSnapshot<K, T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CowSnapshot")
+            .field("num_shards", &self.shards.len())
+            .field("size", &self.size)
+            .finish()
+    }
+}
@@ -0,0 +1,105 @@
+//! Deadlines and cancellation of the operations walking every shard, see `CHashMap::retain_until`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{Clock, DefaultClock};
+use crate::scan::Cursor;
+
+/// Flag cancelling the operations given a [`Deadline`] with it, shared by its clones,
+/// e.g. to abort maintenance from another task on shutdown
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Cancels the operations using the token, which stop at the next shard
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// When an operation such as `CHashMap::retain_until` should give up, checked between shards,
+/// so entries are never left half-processed and the map's counters stay in sync.
+///
+/// Either a point in time, a [`CancelToken`], or both, whichever comes first.
+#[derive(Debug, Default, Clone)]
+pub struct Deadline {
+    /// In nanoseconds of the `DefaultClock`
+    at: Option<u64>,
+    token: Option<CancelToken>,
+}
+
+impl Deadline {
+    /// Deadline that is never reached, which the operations without one use
+    pub const NEVER: Deadline = Deadline { at: None, token: None };
+
+    pub fn after(timeout: Duration) -> Self {
+        Deadline {
+            at: Some(DefaultClock::now().saturating_add(timeout.as_nanos().min(u64::MAX as u128) as u64)),
+            token: None,
+        }
+    }
+
+    /// Also reaches the deadline once the token is cancelled
+    pub fn with_token(mut self, token: CancelToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Checks if the time is up or the token cancelled
+    pub fn is_reached(&self) -> bool {
+        self.token.as_ref().is_some_and(CancelToken::is_cancelled)
+            || self.at.is_some_and(|at| DefaultClock::now() >= at)
+    }
+
+    /// Time left until the deadline, if it has a point in time, regardless of its token
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at.map(|at| Duration::from_nanos(at.saturating_sub(DefaultClock::now())))
+    }
+}
+
+impl From<CancelToken> for Deadline {
+    /// Deadline reached only once the token is cancelled
+    fn from(token: CancelToken) -> Self {
+        Deadline::NEVER.with_token(token)
+    }
+}
+
+/// How far an operation given a [`Deadline`] got
+#[must_use]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    /// Every shard was processed
+    Done,
+    /// The deadline was reached first. Passing the cursor back along with the same arguments carries on
+    /// from the first shard not yet processed.
+    Interrupted(Cursor),
+}
+
+impl Progress {
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        matches!(self, Progress::Done)
+    }
+
+    /// Progress of an operation reaching its deadline before the shard at `shard_idx`
+    #[inline]
+    pub(crate) fn interrupted_at(shard_idx: usize) -> Self {
+        Progress::Interrupted(Cursor::from_raw(shard_idx, 0))
+    }
+}
+
+/// First shard an operation resuming from the cursor processes
+#[inline]
+pub(crate) fn first_shard(from: Cursor) -> usize {
+    from.into_raw().0
+}
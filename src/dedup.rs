@@ -0,0 +1,241 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use hashbrown::hash_map::{DefaultHashBuilder, HashMap, RawEntryMut};
+
+use crate::clock::{Clock, DefaultClock};
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    InFlight,
+    /// `DefaultClock` reading of when the work completed
+    Completed(u64),
+}
+
+/// Set of keys with work in flight, for suppressing duplicate work, e.g. concurrent refreshes of the same entry.
+///
+/// [`try_begin`](Self::try_begin) claims a key until the returned [`WorkToken`] is completed or dropped, so cleanup
+/// can't be forgotten on early returns or cancellation. With [`with_completed_ttl`](Self::with_completed_ttl), keys
+/// whose work completed recently are also suppressed.
+///
+/// Unlike the other maps, shards are behind synchronous locks held only for a lookup, so that tokens can release
+/// their key from `Drop`.
+///
+/// ```
+/// # use quick_hash_cache::DedupMap;
+/// let refreshes = DedupMap::new(4);
+///
+/// let token = refreshes.try_begin("user:1").unwrap();
+/// assert!(refreshes.try_begin("user:1").is_none());
+///
+/// drop(token);
+/// assert!(refreshes.try_begin("user:1").is_some());
+/// ```
+pub struct DedupMap<K, S = DefaultHashBuilder> {
+    hash_builder: S,
+    shards: Vec<Mutex<HashMap<K, State, S>>>,
+    /// How long completed work keeps suppressing its key, in nanoseconds
+    completed_ttl: u64,
+}
+
+impl<K> DedupMap<K, DefaultHashBuilder> {
+    pub fn new(num_shards: usize) -> Self {
+        Self::with_hasher(num_shards, DefaultHashBuilder::default())
+    }
+}
+
+impl<K> Default for DedupMap<K, DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new(crate::default_num_shards())
+    }
+}
+
+impl<K, S> DedupMap<K, S>
+where
+    S: Clone,
+{
+    pub fn with_hasher(num_shards: usize, hash_builder: S) -> Self {
+        DedupMap {
+            shards: (0..num_shards.max(1))
+                .map(|_| Mutex::new(HashMap::with_hasher(hash_builder.clone())))
+                .collect(),
+            hash_builder,
+            completed_ttl: 0,
+        }
+    }
+}
+
+impl<K, S> DedupMap<K, S> {
+    /// Keeps suppressing keys for `ttl` after their work completes with `WorkToken::complete`,
+    /// to ignore repeats of work just done. Dropped tokens never suppress their key.
+    ///
+    /// Completed keys are only forgotten once claimed again after `ttl`, or by `purge_completed`.
+    pub fn with_completed_ttl(mut self, ttl: Duration) -> Self {
+        self.completed_ttl = ttl.as_nanos().min(u64::MAX as u128) as u64;
+        self
+    }
+}
+
+impl<K, S> DedupMap<K, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    #[inline]
+    fn hash_and_shard<Q>(&self, key: &Q) -> (u64, MutexGuard<'_, HashMap<K, State, S>>)
+    where
+        Q: ?Sized + Hash,
+    {
+        let hash = self.hash_builder.hash_one(key);
+        let shard = &self.shards[(hash % self.shards.len() as u64) as usize];
+
+        // the map's invariants hold between lookups, so a panic elsewhere can't have broken them
+        (hash, shard.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    #[inline]
+    fn is_suppressing(&self, state: State, now: u64) -> bool {
+        match state {
+            State::InFlight => true,
+            State::Completed(at) => now < at.saturating_add(self.completed_ttl),
+        }
+    }
+
+    /// Claims the key, unless work for it is in flight or completed recently
+    pub fn try_begin(&self, key: K) -> Option<WorkToken<'_, K, S>>
+    where
+        K: Clone,
+    {
+        let (hash, mut shard) = self.hash_and_shard(&key);
+        let now = DefaultClock::now();
+
+        match shard.raw_entry_mut().from_key_hashed_nocheck(hash, &key) {
+            RawEntryMut::Occupied(occupied) if self.is_suppressing(*occupied.get(), now) => return None,
+            RawEntryMut::Occupied(mut occupied) => {
+                occupied.insert(State::InFlight);
+            }
+            RawEntryMut::Vacant(vacant) => {
+                vacant.insert_hashed_nocheck(hash, key.clone(), State::InFlight);
+            }
+        }
+
+        Some(WorkToken {
+            map: self,
+            key,
+            completed: false,
+        })
+    }
+
+    /// Whether work for the key is in flight
+    pub fn is_in_flight<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard) = self.hash_and_shard(key);
+
+        matches!(shard.raw_entry().from_key_hashed_nocheck(hash, key), Some((_, State::InFlight)))
+    }
+
+    /// Number of keys with work in flight
+    pub fn num_in_flight(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let shard = shard.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                shard.values().filter(|state| matches!(state, State::InFlight)).count()
+            })
+            .sum()
+    }
+
+    /// Forgets keys whose work completed over `completed_ttl` ago, returning how many were forgotten
+    pub fn purge_completed(&self) -> usize {
+        let now = DefaultClock::now();
+        let mut purged = 0;
+
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let len = shard.len();
+
+            shard.retain(|_, state| self.is_suppressing(*state, now));
+            purged += len - shard.len();
+        }
+
+        purged
+    }
+
+    /// Releases the key claimed by a token, marking it completed if `completed` and completed keys are suppressed
+    fn release(&self, key: &K, completed: bool) {
+        let (hash, mut shard) = self.hash_and_shard(key);
+
+        let occupied = match shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(occupied) => occupied,
+            RawEntryMut::Vacant(_) => return,
+        };
+
+        if completed && self.completed_ttl > 0 {
+            *occupied.into_mut() = State::Completed(DefaultClock::now());
+        } else {
+            occupied.remove();
+        }
+    }
+}
+
+impl<K, S> fmt::Debug for DedupMap<K, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DedupMap")
+            .field("num_shards", &self.shards.len())
+            .field("completed_ttl", &Duration::from_nanos(self.completed_ttl))
+            .finish()
+    }
+}
+
+/// Claim on a key of a [`DedupMap`], released when dropped, or marked completed with `complete`
+#[must_use = "the key is released as soon as the token is dropped"]
+pub struct WorkToken<'a, K, S = DefaultHashBuilder>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    map: &'a DedupMap<K, S>,
+    key: K,
+    completed: bool,
+}
+
+impl<K, S> WorkToken<'_, K, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Releases the key, which then stays suppressed for the map's `completed_ttl`, if any
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl<K, S> Drop for WorkToken<'_, K, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    fn drop(&mut self) {
+        self.map.release(&self.key, self.completed);
+    }
+}
+
+impl<K, S> fmt::Debug for WorkToken<'_, K, S>
+where
+    K: Hash + Eq + fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkToken").field("key", &self.key).finish()
+    }
+}
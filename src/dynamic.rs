@@ -0,0 +1,178 @@
+use std::any::Any;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::lru::{AtomicInstant, AtomicTimestamp, IndexedShard, LruCache, TimestampedValue};
+use crate::typed::AnyValue;
+use crate::{ReadHandle, WriteHandle};
+
+/// Key of a [`DynCache`], implemented for every `Hash + Eq` type, and compared and hashed across types through
+/// `dyn DynKey`, keys of different types never being equal.
+pub trait DynKey: Any + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn dyn_eq(&self, other: &dyn DynKey) -> bool;
+    fn dyn_hash(&self, state: &mut dyn Hasher);
+}
+
+impl<K> DynKey for K
+where
+    K: Hash + Eq + Any + Send + Sync,
+{
+    #[inline]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    #[inline]
+    fn dyn_eq(&self, other: &dyn DynKey) -> bool {
+        other.as_any().downcast_ref::<K>() == Some(self)
+    }
+
+    #[inline]
+    fn dyn_hash(&self, mut state: &mut dyn Hasher) {
+        self.hash(&mut state)
+    }
+}
+
+impl PartialEq for dyn DynKey {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.dyn_eq(other)
+    }
+}
+
+impl Eq for dyn DynKey {}
+
+impl Hash for dyn DynKey {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // so equal-looking keys of different types, e.g. `1u32` and `1u64`, tend to land apart
+        self.as_any().type_id().hash(state);
+        self.dyn_hash(state);
+    }
+}
+
+/// Type-erased key of a [`DynCache`]
+pub type AnyKey = Box<dyn DynKey>;
+
+/// Shard of a `DynCache<T, _>`, locked by its handles
+type DynShard<T> = IndexedShard<AnyKey, TimestampedValue<AnyValue, T>>;
+
+/// `ReadHandle` to a value of a `DynCache<T, _>`, as returned by `get::<V>` or, for `dyn Any`, `get_any`
+pub type DynReadHandle<V, T = AtomicInstant> = ReadHandle<DynShard<T>, V>;
+
+/// `WriteHandle` to a value of a `DynCache<T, _>`, as returned by `get_mut::<V>`
+pub type DynWriteHandle<V, T = AtomicInstant> = WriteHandle<DynShard<T>, V>;
+
+/// Cache whose key and value types are both chosen at runtime, so plugins or other dynamic systems can share
+/// a single instance, each with their own key and value types.
+///
+/// Unlike [`TypedCache`](crate::TypedCache), which is keyed by a single key type, keys of any `Hash + Eq` type
+/// can be mixed, and each key holds one value of any type. Typed accessors downcast the value to the type asked
+/// for, returning `None` if it is of another type, without `unsafe`. Entries are stored in an [`LruCache`],
+/// so sharding and eviction work as usual through [`inner`](Self::inner).
+///
+/// ```
+/// # use quick_hash_cache::DynCache;
+/// # #[tokio::main] async fn main() {
+/// let cache = DynCache::new(4);
+/// cache.insert("user:1", 42u32).await;
+/// cache.insert(7u64, String::from("Ferris")).await;
+///
+/// assert_eq!(cache.get_cloned::<u32>(&"user:1").await, Some(42));
+/// assert_eq!(*cache.get::<String>(&7u64).await.unwrap(), "Ferris");
+/// assert!(cache.get::<u64>(&"user:1").await.is_none());
+/// assert!(!cache.contains(&7u32).await);
+/// # }
+/// ```
+pub struct DynCache<T = AtomicInstant, S = DefaultHashBuilder> {
+    inner: LruCache<AnyKey, AnyValue, T, S>,
+}
+
+impl DynCache<AtomicInstant, DefaultHashBuilder> {
+    pub fn new(num_shards: usize) -> Self {
+        DynCache::from(LruCache::new(num_shards))
+    }
+}
+
+impl Default for DynCache<AtomicInstant, DefaultHashBuilder> {
+    fn default() -> Self {
+        DynCache::from(LruCache::default())
+    }
+}
+
+impl<T, S> From<LruCache<AnyKey, AnyValue, T, S>> for DynCache<T, S> {
+    /// Wraps a cache configured through its own builder methods, e.g. `with_max_capacity`
+    fn from(inner: LruCache<AnyKey, AnyValue, T, S>) -> Self {
+        DynCache { inner }
+    }
+}
+
+impl<T, S> DynCache<T, S>
+where
+    T: AtomicTimestamp,
+    S: BuildHasher,
+{
+    /// Number of values, of all types
+    pub fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    /// The value under the key, if it is of type `V`
+    pub async fn get<V: Any>(&self, key: &dyn DynKey) -> Option<DynReadHandle<V, T>> {
+        let value = self.inner.get(key).await?;
+        ReadHandle::try_map(value, |value| value.downcast_ref::<V>()).ok()
+    }
+
+    /// The value under the key, whatever its type, e.g. to inspect its `type_id`
+    pub async fn get_any(&self, key: &dyn DynKey) -> Option<DynReadHandle<dyn Any + Send + Sync, T>> {
+        let value = self.inner.get(key).await?;
+        Some(ReadHandle::map(value, |value| &**value))
+    }
+
+    /// The value under the key, if it is of type `V`
+    pub async fn get_mut<V: Any>(&self, key: &dyn DynKey) -> Option<DynWriteHandle<V, T>> {
+        let value = self.inner.get_mut(key).await?;
+        WriteHandle::try_map(value, |value| value.downcast_mut::<V>()).ok()
+    }
+
+    pub async fn get_cloned<V: Any + Clone>(&self, key: &dyn DynKey) -> Option<V> {
+        self.get::<V>(key).await.map(|value| value.clone())
+    }
+
+    /// Checks if the key is present, whatever the type of its value
+    pub async fn contains(&self, key: &dyn DynKey) -> bool {
+        self.inner.contains(key).await
+    }
+
+    /// Inserts the value under the key, returning the previous value, which may be of another type
+    pub async fn insert<K: DynKey, V: Any + Send + Sync>(&self, key: K, value: V) -> Option<AnyValue> {
+        self.inner.insert(Box::new(key), Box::new(value)).await
+    }
+
+    /// Removes the value under the key, whatever its type, see `AnyValue::downcast`
+    pub async fn remove(&self, key: &dyn DynKey) -> Option<AnyValue> {
+        self.inner.remove(key).await
+    }
+
+    /// The underlying `LruCache`, e.g. for eviction
+    pub fn inner(&self) -> &LruCache<AnyKey, AnyValue, T, S> {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> LruCache<AnyKey, AnyValue, T, S> {
+        self.inner
+    }
+}
+
+impl<T, S> fmt::Debug for DynCache<T, S>
+where
+    T: AtomicTimestamp,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynCache").field("size", &self.inner.size()).finish()
+    }
+}
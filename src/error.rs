@@ -0,0 +1,70 @@
+use std::convert::Infallible;
+use std::fmt;
+
+use crate::scan::Invalidated;
+use crate::CapacityFull;
+
+/// Why a fallible operation, such as `CHashMap::try_get` or `CHashMap::get_timeout`, did not complete,
+/// so callers can tell failures apart by matching on it instead of guessing from a bare `None`.
+///
+/// `E` is the error of the loader given to operations loading missing values, e.g. `CHashMap::try_get_or_load`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error<E = Infallible> {
+    /// The operation did not complete in the time allowed
+    Timeout,
+    /// The operation would have had to wait on a shard lock, and was asked not to
+    WouldBlock,
+    /// Inserting would have evicted an entry of a shard at capacity
+    CapacityExceeded,
+    /// The loader of a missing value failed, and nothing was inserted
+    LoaderFailed(E),
+    /// Entries moved while paging through them, see `OnInvalidated::Fail`
+    Invalidated,
+}
+
+impl<E> Error<E> {
+    /// The loader's error, if it is what failed
+    pub fn into_loader_error(self) -> Option<E> {
+        match self {
+            Error::LoaderFailed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Timeout => f.write_str("operation timed out"),
+            Error::WouldBlock => f.write_str("operation would block on a shard lock"),
+            Error::CapacityExceeded => f.write_str("shard is at capacity, inserting would evict an entry"),
+            Error::LoaderFailed(error) => write!(f, "loading the value failed: {}", error),
+            Error::Invalidated => f.write_str("entries moved since the previous page"),
+        }
+    }
+}
+
+impl<E> std::error::Error for Error<E>
+where
+    E: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::LoaderFailed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl<E> From<Invalidated> for Error<E> {
+    fn from(_: Invalidated) -> Self {
+        Error::Invalidated
+    }
+}
+
+impl<V, E> From<CapacityFull<V>> for Error<E> {
+    /// Drops the value that was not inserted, see `CapacityFull::into_inner` to keep it
+    fn from(_: CapacityFull<V>) -> Self {
+        Error::CapacityExceeded
+    }
+}
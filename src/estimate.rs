@@ -0,0 +1,73 @@
+//! Approximate statistics, cheap enough to gather continuously for monitoring.
+
+use crate::hash::mix64;
+
+/// Number of index bits, for `2^PRECISION` registers and a standard error of about 1.6%
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// HyperLogLog estimator of the number of distinct hashes added to it, in 4KiB of memory.
+///
+/// Hashes are mixed before use, so ones from weak hashers such as `hash::NoHash` still spread evenly.
+#[derive(Clone)]
+pub struct HyperLogLog {
+    registers: Box<[u8; REGISTERS]>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for HyperLogLog {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HyperLogLog").field("estimate", &self.estimate()).finish()
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        HyperLogLog {
+            registers: Box::new([0; REGISTERS]),
+        }
+    }
+
+    #[inline]
+    pub fn insert_hash(&mut self, hash: u64) {
+        let hash = mix64(hash);
+
+        let idx = (hash >> (64 - PRECISION)) as usize;
+        // the sentinel bit caps the rank for hashes whose remaining bits are all zero
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+
+        let register = &mut self.registers[idx];
+        *register = (*register).max(rank);
+    }
+
+    /// Combines the hashes seen by another estimator into this one, e.g. to estimate across several maps
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, &other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *register = (*register).max(other);
+        }
+    }
+
+    /// Estimated number of distinct hashes added
+    pub fn estimate(&self) -> f64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+
+        let (sum, zeros) = self.registers.iter().fold((0.0, 0usize), |(sum, zeros), &register| {
+            (sum + 1.0 / (1u64 << register) as f64, zeros + (register == 0) as usize)
+        });
+
+        let raw = alpha * m * m / sum;
+
+        // linear counting is more accurate while many registers are still empty
+        if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::hash::{BuildHasher, Hash};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::clock::{Clock, DefaultClock};
+use crate::sweeper;
+use crate::sync::Arc;
+use crate::{CHashMap, ReadHandle, Shard};
+
+/// `ReadHandle` to a value of an `ExpiringMap<K, V, S>`, as returned by `get`
+pub type ExpiringReadHandle<K, V, S = DefaultHashBuilder> = ReadHandle<Shard<K, Expiring<V>, S>, V>;
+
+/// Value of an [`ExpiringMap`] along with its deadline
+#[doc(hidden)]
+#[derive(Debug, Clone)]
+pub struct Expiring<V> {
+    value: V,
+    /// `DefaultClock` reading after which the value is expired
+    expires_at: u64,
+}
+
+impl<V> Expiring<V> {
+    #[inline]
+    fn is_expired(&self) -> bool {
+        DefaultClock::now() >= self.expires_at
+    }
+}
+
+/// `CHashMap` whose entries expire after a per-entry TTL, for caches that only need absolute deadlines.
+///
+/// Unlike `LruCache`, there is no recency tracking or eviction, so reads never write and entries are only
+/// ever dropped by expiring or being removed. Expired entries are treated as absent by lookups, but still count
+/// towards `size()` until purged, either with `purge_expired` or by a thread started with `spawn_purger`.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use quick_hash_cache::ExpiringMap;
+/// # #[tokio::main] async fn main() {
+/// let sessions = ExpiringMap::new(4);
+/// sessions.insert("ferris", "token", Duration::from_secs(60)).await;
+/// sessions.insert("corro", "token", Duration::ZERO).await;
+///
+/// assert_eq!(sessions.get_cloned(&"ferris").await, Some("token"));
+/// assert_eq!(sessions.get_cloned(&"corro").await, None);
+///
+/// assert_eq!(sessions.purge_expired().await, 1);
+/// assert_eq!(sessions.size(), 1);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ExpiringMap<K, V, S = DefaultHashBuilder> {
+    map: CHashMap<K, Expiring<V>, S>,
+}
+
+impl<K, V> ExpiringMap<K, V, DefaultHashBuilder> {
+    pub fn new(num_shards: usize) -> Self {
+        Self::with_hasher(num_shards, DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V> Default for ExpiringMap<K, V, DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new(crate::default_num_shards())
+    }
+}
+
+impl<K, V, S> ExpiringMap<K, V, S>
+where
+    S: Clone,
+{
+    pub fn with_hasher(num_shards: usize, hash_builder: S) -> Self {
+        ExpiringMap {
+            map: CHashMap::with_hasher(num_shards, hash_builder),
+        }
+    }
+}
+
+impl<K, V, S> ExpiringMap<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// Number of entries, including expired ones not purged yet
+    pub fn size(&self) -> usize {
+        self.map.size()
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.map.num_shards()
+    }
+
+    pub async fn get<Q>(&self, key: &Q) -> Option<ExpiringReadHandle<K, V, S>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let entry = self.map.get(key).await?;
+
+        ReadHandle::try_map(entry, |entry| match entry.is_expired() {
+            true => None,
+            false => Some(&entry.value),
+        })
+        .ok()
+    }
+
+    pub async fn get_cloned<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        self.get(key).await.map(|value| value.clone())
+    }
+
+    pub async fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.get(key).await.is_some()
+    }
+
+    /// Time left before the value expires, `None` if absent or already expired
+    pub async fn ttl<Q>(&self, key: &Q) -> Option<Duration>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let entry = self.map.get(key).await?;
+
+        match entry.expires_at.checked_sub(DefaultClock::now()) {
+            Some(0) | None => None,
+            Some(left) => Some(Duration::from_nanos(left)),
+        }
+    }
+
+    /// Inserts a value that expires after `ttl`, returning the previous value, if not expired
+    pub async fn insert(&self, key: K, value: V, ttl: Duration) -> Option<V> {
+        let ttl = ttl.as_nanos().min(u64::MAX as u128) as u64;
+
+        let value = Expiring {
+            value,
+            expires_at: DefaultClock::now().saturating_add(ttl),
+        };
+
+        match self.map.insert(key, value).await {
+            Some(previous) if !previous.is_expired() => Some(previous.value),
+            _ => None,
+        }
+    }
+
+    /// Removes the value, returning it if not expired
+    pub async fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        match self.map.remove(key).await {
+            Some(entry) if !entry.is_expired() => Some(entry.value),
+            _ => None,
+        }
+    }
+
+    /// Removes all expired entries, returning how many were removed
+    pub async fn purge_expired(&self) -> usize {
+        let purged = Cell::new(0);
+
+        self.map
+            .retain(|_, entry| match entry.is_expired() {
+                true => {
+                    purged.set(purged.get() + 1);
+                    false
+                }
+                false => true,
+            })
+            .await;
+
+        purged.get()
+    }
+
+    pub async fn clear(&self) {
+        self.map.clear().await
+    }
+
+    /// Starts a thread purging expired entries every `interval`, which exits once the map is dropped,
+    /// at its next wakeup. The thread takes the shard write locks one at a time, like `retain`.
+    pub fn spawn_purger(self: &Arc<Self>, interval: Duration) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        sweeper::spawn("quick-hash-cache-purger", self, interval, |map| {
+            sweeper::block_on(map.purge_expired());
+        })
+    }
+}
@@ -0,0 +1,187 @@
+use std::hash::{BuildHasher, Hash};
+
+use hashbrown::hash_map::DefaultHashBuilder;
+use hashbrown::HashSet;
+
+use crate::{CHashMap, MapReadHandle, MapWriteHandle};
+
+/// `CHashMap` of composite `(G, K)` keys, e.g. `(user, session)`, with operations on whole groups.
+///
+/// Each group's keys are also tracked in a set of their own, so a group can be listed or removed
+/// without scanning every shard of the map. Writes to a group are serialized on its key set,
+/// so its entries and key set always agree.
+#[derive(Debug)]
+pub struct GroupedMap<G, K, T, S = DefaultHashBuilder> {
+    map: CHashMap<(G, K), T, S>,
+    groups: CHashMap<G, HashSet<K, S>, S>,
+}
+
+impl<G, K, T> GroupedMap<G, K, T, DefaultHashBuilder> {
+    pub fn new(num_shards: usize) -> Self {
+        Self::with_hasher(num_shards, DefaultHashBuilder::default())
+    }
+}
+
+impl<G, K, T> Default for GroupedMap<G, K, T, DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new(crate::default_num_shards())
+    }
+}
+
+impl<G, K, T, S> GroupedMap<G, K, T, S>
+where
+    S: Clone,
+{
+    pub fn with_hasher(num_shards: usize, hash_builder: S) -> Self {
+        GroupedMap {
+            map: CHashMap::with_hasher(num_shards, hash_builder.clone()),
+            groups: CHashMap::with_hasher(num_shards, hash_builder),
+        }
+    }
+}
+
+impl<G, K, T, S> GroupedMap<G, K, T, S>
+where
+    G: Hash + Eq + Clone,
+    K: Hash + Eq + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Number of entries across all groups
+    pub fn size(&self) -> usize {
+        self.map.size()
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.map.num_shards()
+    }
+
+    #[inline]
+    fn composite(group: &G, key: &K) -> (G, K) {
+        (group.clone(), key.clone())
+    }
+
+    pub async fn get(&self, group: &G, key: &K) -> Option<MapReadHandle<(G, K), T, S>> {
+        self.map.get(&Self::composite(group, key)).await
+    }
+
+    pub async fn get_mut(&self, group: &G, key: &K) -> Option<MapWriteHandle<(G, K), T, S>> {
+        self.map.get_mut(&Self::composite(group, key)).await
+    }
+
+    pub async fn get_cloned(&self, group: &G, key: &K) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.map.get_cloned(&Self::composite(group, key)).await
+    }
+
+    pub async fn contains(&self, group: &G, key: &K) -> bool {
+        self.map.contains(&Self::composite(group, key)).await
+    }
+
+    pub async fn insert(&self, group: G, key: K, value: T) -> Option<T> {
+        let hash_builder = self.map.hash_builder().clone();
+        let mut keys = self
+            .groups
+            .get_mut_or_insert(&group, move || HashSet::with_hasher(hash_builder))
+            .await;
+
+        let previous = self.map.insert((group, key.clone()), value).await;
+
+        if previous.is_none() {
+            keys.insert(key);
+        }
+
+        previous
+    }
+
+    pub async fn remove(&self, group: &G, key: &K) -> Option<T> {
+        // a group without a key set has no entries
+        let mut keys = self.groups.get_mut(group).await?;
+
+        let value = self.map.remove(&Self::composite(group, key)).await;
+
+        if value.is_some() {
+            keys.remove(key);
+
+            // drop the key sets of groups that were emptied, so they don't accumulate
+            if keys.is_empty() {
+                drop(keys);
+                self.remove_if_empty(group).await;
+            }
+        }
+
+        value
+    }
+
+    /// Number of entries in the group
+    pub async fn group_len(&self, group: &G) -> usize {
+        match self.groups.get(group).await {
+            Some(keys) => keys.len(),
+            None => 0,
+        }
+    }
+
+    /// Calls `f` with every entry of the group, without collecting them.
+    ///
+    /// Writes to the group wait until this returns, and each entry's shard is read-locked while `f` runs on it.
+    pub async fn for_each_in_group<F>(&self, group: &G, mut f: F)
+    where
+        F: FnMut(&K, &T),
+    {
+        let keys = match self.groups.get(group).await {
+            Some(keys) => keys,
+            None => return,
+        };
+
+        for key in keys.iter() {
+            if let Some(value) = self.map.get(&Self::composite(group, key)).await {
+                f(key, &value);
+            }
+        }
+    }
+
+    /// Clones every entry of the group, see `for_each_in_group`
+    pub async fn get_group(&self, group: &G) -> Vec<(K, T)>
+    where
+        T: Clone,
+    {
+        let mut entries = Vec::new();
+
+        self.for_each_in_group(group, |key, value| entries.push((key.clone(), value.clone())))
+            .await;
+
+        entries
+    }
+
+    /// Removes every entry of the group by its key set, rather than by scanning the whole map, and returns them
+    pub async fn remove_group(&self, group: &G) -> Vec<(K, T)> {
+        let mut key_set = match self.groups.get_mut(group).await {
+            Some(key_set) => key_set,
+            None => return Vec::new(),
+        };
+
+        let hash_builder = key_set.hasher().clone();
+        let keys = std::mem::replace(&mut *key_set, HashSet::with_hasher(hash_builder));
+
+        let mut removed = Vec::with_capacity(keys.len());
+
+        for key in keys {
+            let composite = (group.clone(), key);
+
+            if let Some(value) = self.map.remove(&composite).await {
+                removed.push((composite.1, value));
+            }
+        }
+
+        drop(key_set);
+        self.remove_if_empty(group).await;
+
+        removed
+    }
+
+    /// Drops the key set of a group that was emptied, unless entries were inserted into it since
+    async fn remove_if_empty(&self, group: &G) {
+        self.groups.remove_if(group, |keys| keys.is_empty()).await;
+    }
+}
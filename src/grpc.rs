@@ -0,0 +1,332 @@
+//! Caching of unary gRPC responses, as a tower layer, e.g. of a tonic server, see [`GrpcCacheLayer`].
+
+use std::fmt;
+use std::future::Future;
+use std::iter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use hashbrown::HashMap;
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use http::{Request, Response, StatusCode};
+use http_body::{Body, Frame, SizeHint};
+use http_body_util::BodyExt;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::loader::{self, InFlight};
+use crate::lru::LruCache;
+
+/// Error of the services of a [`GrpcCacheLayer`], as usual for tower middleware
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Call of a cached method: its path, the values of the headers the cache varies on, and the request message
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CallKey {
+    method: String,
+    vary: Vec<Option<HeaderValue>>,
+    request: Bytes,
+}
+
+/// Successful response to a call, replayed to the calls with the same key
+#[derive(Debug)]
+struct CachedCall {
+    headers: HeaderMap,
+    data: Bytes,
+    trailers: HeaderMap,
+}
+
+struct Shared {
+    cache: LruCache<CallKey, Arc<CachedCall>>,
+    /// TTL of the responses of each cached method, by path
+    methods: HashMap<String, Duration>,
+    /// Request headers responses vary on, besides the method and request message
+    vary: Vec<HeaderName>,
+    /// Calls being made to the inner service, which identical calls wait on instead of calling it too
+    calls: InFlight<CallKey>,
+}
+
+/// Tower layer caching the responses of the unary gRPC methods given to `cache_method`, by the request message,
+/// for their TTL, built on an [`LruCache`].
+///
+/// Identical calls arriving while the first one is in flight are coalesced: they wait for its response
+/// instead of calling the inner service too, so a burst of the same call from fanned-in clients costs one call.
+/// Only successful responses (`grpc-status` 0) are cached. Calls of other methods pass through untouched.
+///
+/// Cached calls are buffered whole, so streaming methods should not be cached. The layer can be added to a tonic
+/// server with `Server::builder().layer(...)`, or to any tower stack of `http` services.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use quick_hash_cache::grpc::GrpcCacheLayer;
+/// let layer = GrpcCacheLayer::new(10_000)
+///     .cache_method("/catalog.Catalog/GetProduct", Duration::from_secs(30))
+///     .vary_on(http::header::AUTHORIZATION);
+///
+/// assert_eq!(layer.size(), 0);
+/// ```
+#[derive(Clone)]
+pub struct GrpcCacheLayer {
+    shared: Arc<Shared>,
+}
+
+impl GrpcCacheLayer {
+    /// Layer caching about `capacity` responses, see `LruCache::with_max_capacity`
+    pub fn new(capacity: usize) -> Self {
+        GrpcCacheLayer {
+            shared: Arc::new(Shared {
+                cache: LruCache::default().with_max_capacity(capacity),
+                methods: HashMap::new(),
+                vary: Vec::new(),
+                calls: InFlight::new(),
+            }),
+        }
+    }
+
+    fn shared_mut(&mut self) -> &mut Shared {
+        Arc::get_mut(&mut self.shared).expect("GrpcCacheLayer must be configured before it is cloned")
+    }
+
+    /// Caches the responses of the unary method at the path, e.g. `/helloworld.Greeter/SayHello`, for `ttl`
+    ///
+    /// # Panics
+    ///
+    /// If the layer was already cloned, e.g. into a service.
+    pub fn cache_method(mut self, path: impl Into<String>, ttl: Duration) -> Self {
+        self.shared_mut().methods.insert(path.into(), ttl);
+        self
+    }
+
+    /// Keys responses by the value of the request header as well, e.g. `authorization` for per-user responses
+    ///
+    /// # Panics
+    ///
+    /// If the layer was already cloned, e.g. into a service.
+    pub fn vary_on(mut self, header: HeaderName) -> Self {
+        let shared = self.shared_mut();
+
+        if !shared.vary.contains(&header) {
+            shared.vary.push(header);
+        }
+
+        self
+    }
+
+    /// Number of cached responses, including expired ones not yet evicted
+    pub fn size(&self) -> usize {
+        self.shared.cache.size()
+    }
+
+    /// Drops the cached responses of the method at the path, e.g. after the data it serves changed
+    pub async fn invalidate_method(&self, path: &str) {
+        self.shared.cache.retain(|key, _| key.method != path).await;
+    }
+
+    pub async fn clear(&self) {
+        self.shared.cache.clear().await;
+    }
+}
+
+impl<S> Layer<S> for GrpcCacheLayer {
+    type Service = GrpcCache<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcCache {
+            inner,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for GrpcCacheLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcCacheLayer")
+            .field("size", &self.shared.cache.size())
+            .field("methods", &self.shared.methods)
+            .field("vary", &self.shared.vary)
+            .finish()
+    }
+}
+
+/// Service of a [`GrpcCacheLayer`], wrapping the inner service
+#[derive(Clone)]
+pub struct GrpcCache<S> {
+    inner: S,
+    shared: Arc<Shared>,
+}
+
+impl<S> fmt::Debug for GrpcCache<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcCache").field("size", &self.shared.cache.size()).finish()
+    }
+}
+
+impl<S, B, ResBody> Service<Request<B>> for GrpcCache<S>
+where
+    S: Service<Request<CallBody<B>>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+    B: Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    ResBody: Body<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = Response<CallBody<ResBody>>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        // the clone may not be ready, so call the instance that was polled
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let ttl = match self.shared.methods.get(request.uri().path()) {
+            Some(&ttl) => ttl,
+            None => {
+                let future = inner.call(request.map(CallBody::streaming));
+                return Box::pin(async move { Ok(future.await.map_err(Into::into)?.map(CallBody::streaming)) });
+            }
+        };
+
+        let shared = self.shared.clone();
+
+        Box::pin(async move {
+            let (parts, body) = request.into_parts();
+
+            let key = CallKey {
+                method: parts.uri.path().to_owned(),
+                vary: shared.vary.iter().map(|name| parts.headers.get(name).cloned()).collect(),
+                request: body.collect().await.map_err(Into::into)?.to_bytes(),
+            };
+
+            loop {
+                if let Some(cached) = shared.cache.get_cloned(&key).await {
+                    return Ok(cached.to_response());
+                }
+
+                let (call, mut waiting) = shared.calls.claim(iter::once(&key));
+
+                match waiting.pop() {
+                    // whether the other call's response was cached or not, look again
+                    Some((_, other_call)) => {
+                        drop(call);
+                        loader::wait(other_call).await;
+                    }
+                    None => {
+                        let request = Request::from_parts(parts, CallBody::buffered(key.request.clone(), None));
+                        let response = inner.call(request).await.map_err(Into::into)?;
+
+                        let (parts, body) = response.into_parts();
+                        let collected = body.collect().await.map_err(Into::into)?;
+                        let trailers = collected.trailers().cloned().unwrap_or_default();
+
+                        let cached = Arc::new(CachedCall {
+                            headers: parts.headers,
+                            data: collected.to_bytes(),
+                            trailers,
+                        });
+
+                        if parts.status == StatusCode::OK && cached.is_ok() {
+                            shared.cache.insert_with_ttl(key, cached.clone(), ttl).await;
+                        }
+
+                        call.finish();
+                        return Ok(cached.to_response());
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl CachedCall {
+    /// Whether the call succeeded, as only told by the trailers of unary responses
+    fn is_ok(&self) -> bool {
+        !self.headers.contains_key("grpc-status")
+            && self.trailers.get("grpc-status").is_some_and(|status| status.as_bytes() == b"0")
+    }
+
+    fn to_response<B>(&self) -> Response<CallBody<B>> {
+        let mut response = Response::new(CallBody::buffered(self.data.clone(), Some(self.trailers.clone())));
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+enum CallBodyInner<B> {
+    Streaming(B),
+    Buffered {
+        data: Option<Bytes>,
+        trailers: Option<HeaderMap>,
+    },
+}
+
+/// Body of the requests a [`GrpcCache`] passes to its inner service, and of the responses it returns:
+/// either the original body, or one buffered for caching
+pub struct CallBody<B>(CallBodyInner<B>);
+
+impl<B> CallBody<B> {
+    fn streaming(body: B) -> Self {
+        CallBody(CallBodyInner::Streaming(body))
+    }
+
+    fn buffered(data: Bytes, trailers: Option<HeaderMap>) -> Self {
+        CallBody(CallBodyInner::Buffered {
+            data: Some(data).filter(|data| !data.is_empty()),
+            trailers,
+        })
+    }
+}
+
+/// Buffered bodies never fail, so the error is that of the original body
+impl<B> Body for CallBody<B>
+where
+    B: Body<Data = Bytes>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        // SAFETY: the streaming body is never moved out, so it stays pinned
+        match unsafe { &mut self.get_unchecked_mut().0 } {
+            CallBodyInner::Streaming(body) => unsafe { Pin::new_unchecked(body) }.poll_frame(cx),
+            CallBodyInner::Buffered { data, trailers } => Poll::Ready(match data.take() {
+                Some(data) => Some(Ok(Frame::data(data))),
+                None => trailers.take().map(|trailers| Ok(Frame::trailers(trailers))),
+            }),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match &self.0 {
+            CallBodyInner::Streaming(body) => body.is_end_stream(),
+            CallBodyInner::Buffered { data, trailers } => data.is_none() && trailers.is_none(),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match &self.0 {
+            CallBodyInner::Streaming(body) => body.size_hint(),
+            CallBodyInner::Buffered { data, .. } => SizeHint::with_exact(data.as_ref().map_or(0, Bytes::len) as u64),
+        }
+    }
+}
+
+impl<B> fmt::Debug for CallBody<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            CallBodyInner::Streaming(_) => f.debug_tuple("CallBody::Streaming").finish(),
+            CallBodyInner::Buffered { data, .. } => f
+                .debug_struct("CallBody::Buffered")
+                .field("len", &data.as_ref().map_or(0, Bytes::len))
+                .finish(),
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Tracking of how long `ReadHandle`s and `WriteHandle`s are held, enabled by the `debug-guard-tracking` feature.
+//!
+//! With the feature, `ReadHandle` and `WriteHandle` are [`Tracked`] lock guards instead of tokio's.
+//!
+//! Handles hold their shard's lock, so one held across an `.await` starves every other key in the shard.
+//! With tracking enabled, a handle is reported (or panics, see `set_panic_on_violation`) when it is dropped
+//! after being held longer than `max_hold`, or on a different thread than it was acquired on.
+//! A background timer also reports handles while they are still held past `max_hold`, so guards that are
+//! never released show up too.
+//!
+//! Yield points are not observed: tokio's task IDs are unstable, so handles record the thread they were acquired
+//! on instead, and one held across an `.await` is only caught as such if its task resumed on another thread.
+//! Otherwise, e.g. on a current-thread runtime, it is only caught once held longer than `max_hold`.
+//!
+//! The feature also reports a task that waited `reentrant_wait` to lock a shard that one of its own handles still
+//! holds, which would otherwise deadlock silently, e.g. when calling `insert` while holding the `WriteHandle` from a
+//! `get_mut` on a key of the same shard.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedRwLockMappedWriteGuard, OwnedRwLockReadGuard};
+
+use crate::reentrancy::{self, HeldShard};
+
+static MAX_HOLD_NANOS: AtomicU64 = AtomicU64::new(100_000_000);
+static REENTRANT_WAIT_NANOS: AtomicU64 = AtomicU64::new(1_000_000_000);
+static PANIC_ON_VIOLATION: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Sets how long a handle may be held before being reported, 100ms by default
+pub fn set_max_hold(max_hold: Duration) {
+    MAX_HOLD_NANOS.store(max_hold.as_nanos().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+}
+
+pub fn max_hold() -> Duration {
+    Duration::from_nanos(MAX_HOLD_NANOS.load(Ordering::Relaxed))
+}
+
+/// Sets how long a task may wait to lock a shard that one of its own handles holds before being reported,
+/// 1s by default.
+///
+/// Branches of `join!`/`select!` may legitimately wait on each other's handles, so this should exceed how long
+/// such a handle is held.
+pub fn set_reentrant_wait(wait: Duration) {
+    REENTRANT_WAIT_NANOS.store(wait.as_nanos().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+}
+
+pub fn reentrant_wait() -> Duration {
+    Duration::from_nanos(REENTRANT_WAIT_NANOS.load(Ordering::Relaxed))
+}
+
+/// Panic when a handle that violated the limits is dropped, instead of only logging it to stderr.
+///
+/// Handles still held are always only logged by the background timer, as it cannot panic on their task's behalf.
+/// Tasks waiting on their own handles panic in their poll.
+pub fn set_panic_on_violation(panic: bool) {
+    PANIC_ON_VIOLATION.store(panic, Ordering::Relaxed);
+}
+
+struct Held {
+    acquired: Instant,
+    thread: ThreadId,
+    target: &'static str,
+    reported: bool,
+}
+
+fn registry() -> &'static Mutex<HashMap<u64, Held>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<u64, Held>>> = OnceLock::new();
+
+    REGISTRY.get_or_init(|| {
+        thread::Builder::new()
+            .name("quick-hash-cache-guard-watchdog".into())
+            .spawn(watchdog)
+            .expect("failed to spawn guard watchdog thread");
+
+        Mutex::new(HashMap::new())
+    })
+}
+
+fn watchdog() {
+    loop {
+        let max_hold = max_hold();
+        thread::sleep(max_hold);
+
+        let mut registry = registry().lock().unwrap_or_else(|e| e.into_inner());
+
+        for (id, held) in registry.iter_mut() {
+            if !held.reported && held.acquired.elapsed() > max_hold {
+                held.reported = true;
+
+                eprintln!(
+                    "quick_hash_cache: handle #{} to `{}` acquired on {:?} still held after {:?}",
+                    id,
+                    held.target,
+                    held.thread,
+                    held.acquired.elapsed()
+                );
+            }
+        }
+    }
+}
+
+/// Registration of a live handle, checked when dropped
+struct Token {
+    id: u64,
+    acquired: Instant,
+    thread: ThreadId,
+    target: &'static str,
+}
+
+impl Token {
+    fn new<U: ?Sized>() -> Self {
+        let token = Token {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            acquired: Instant::now(),
+            thread: thread::current().id(),
+            target: std::any::type_name::<U>(),
+        };
+
+        registry().lock().unwrap_or_else(|e| e.into_inner()).insert(
+            token.id,
+            Held {
+                acquired: token.acquired,
+                thread: token.thread,
+                target: token.target,
+                reported: false,
+            },
+        );
+
+        token
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        registry().lock().unwrap_or_else(|e| e.into_inner()).remove(&self.id);
+
+        let held_for = self.acquired.elapsed();
+        let current = thread::current().id();
+
+        let violation = if current != self.thread {
+            format!(
+                "handle #{} to `{}` was acquired on {:?} but dropped on {:?} after {:?}, e.g. held across an await",
+                self.id, self.target, self.thread, current, held_for
+            )
+        } else if held_for > max_hold() {
+            format!(
+                "handle #{} to `{}` was held for {:?}, longer than the maximum of {:?}",
+                self.id,
+                self.target,
+                held_for,
+                max_hold()
+            )
+        } else {
+            return;
+        };
+
+        report_violation(violation);
+    }
+}
+
+/// Panics with the violation if enabled, see `set_panic_on_violation`, or logs it to stderr
+pub(crate) fn report_violation(violation: String) {
+    if PANIC_ON_VIOLATION.load(Ordering::Relaxed) && !thread::panicking() {
+        panic!("quick_hash_cache: {}", violation);
+    }
+
+    eprintln!("quick_hash_cache: {}", violation);
+}
+
+/// What is tracked about a handle, checked when dropped
+struct Tracking {
+    _token: Token,
+    _held: HeldShard,
+}
+
+/// Lock guard along with the tracking of how long, and by which task, it is held
+pub struct Tracked<G> {
+    // declared first, so the lock is released before the hold time is checked
+    guard: G,
+    tracking: Tracking,
+}
+
+impl<G> Tracked<G> {
+    // `U` only names the target of the handle in reports
+    fn new<U: ?Sized>(guard: reentrancy::Owned<G>) -> Self {
+        let (guard, held) = reentrancy::Owned::into_parts(guard);
+
+        Tracked {
+            guard,
+            tracking: Tracking {
+                _token: Token::new::<U>(),
+                _held: held,
+            },
+        }
+    }
+
+    /// Keeps tracking the mapped guard, or the original one if mapping failed
+    #[inline]
+    fn try_with<H>(self, f: impl FnOnce(G) -> Result<H, G>) -> Result<Tracked<H>, Self> {
+        let Tracked { guard, tracking } = self;
+
+        match f(guard) {
+            Ok(guard) => Ok(Tracked { guard, tracking }),
+            Err(guard) => Err(Tracked { guard, tracking }),
+        }
+    }
+}
+
+impl<G: Deref> Deref for Tracked<G> {
+    type Target = G::Target;
+
+    #[inline]
+    fn deref(&self) -> &G::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for Tracked<G> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut G::Target {
+        &mut self.guard
+    }
+}
+
+impl<G: fmt::Debug> fmt::Debug for Tracked<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.guard.fmt(f)
+    }
+}
+
+impl<G: fmt::Display> fmt::Display for Tracked<G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.guard.fmt(f)
+    }
+}
+
+impl<T: ?Sized, U: ?Sized> Tracked<OwnedRwLockReadGuard<T, U>> {
+    pub(crate) fn read(guard: reentrancy::OwnedRwLockReadGuard<T, U>) -> Self {
+        Self::new::<U>(guard)
+    }
+
+    pub fn map<F, V: ?Sized>(this: Self, f: F) -> Tracked<OwnedRwLockReadGuard<T, V>>
+    where
+        F: FnOnce(&U) -> &V,
+    {
+        Tracked {
+            guard: OwnedRwLockReadGuard::map(this.guard, f),
+            tracking: this.tracking,
+        }
+    }
+
+    pub fn try_map<F, V: ?Sized>(this: Self, f: F) -> Result<Tracked<OwnedRwLockReadGuard<T, V>>, Self>
+    where
+        F: FnOnce(&U) -> Option<&V>,
+    {
+        this.try_with(|guard| OwnedRwLockReadGuard::try_map(guard, f))
+    }
+}
+
+impl<T: ?Sized, U: ?Sized> Tracked<OwnedRwLockMappedWriteGuard<T, U>> {
+    pub(crate) fn write(guard: reentrancy::OwnedRwLockMappedWriteGuard<T, U>) -> Self {
+        Self::new::<U>(guard)
+    }
+
+    pub fn map<F, V: ?Sized>(this: Self, f: F) -> Tracked<OwnedRwLockMappedWriteGuard<T, V>>
+    where
+        F: FnOnce(&mut U) -> &mut V,
+    {
+        Tracked {
+            guard: OwnedRwLockMappedWriteGuard::map(this.guard, f),
+            tracking: this.tracking,
+        }
+    }
+
+    pub fn try_map<F, V: ?Sized>(this: Self, f: F) -> Result<Tracked<OwnedRwLockMappedWriteGuard<T, V>>, Self>
+    where
+        F: FnOnce(&mut U) -> Option<&mut V>,
+    {
+        this.try_with(|guard| OwnedRwLockMappedWriteGuard::try_map(guard, f))
+    }
+}
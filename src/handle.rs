@@ -0,0 +1,218 @@
+//! Projecting handles into parts of their values, and erasing their types so they can be stored.
+//!
+//! Lookups return handles such as [`MapReadHandle<K, T, S>`](crate::MapReadHandle), whose type includes the
+//! locked shard. [`ReadHandleExt`] and [`WriteHandleExt`] map handles into parts of their value, and `erase`
+//! turns them into [`ErasedReadHandle`]/[`ErasedWriteHandle`], which only depend on the type of the value,
+//! e.g. to store handles from different kinds of maps in the same struct field.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+use crate::{ReadHandle, WriteHandle};
+
+/// Keeps a handle alive, along with the shard lock it holds
+trait Guard: Send + Sync {}
+impl<G: Send + Sync> Guard for G {}
+
+/// Mapping of `ReadHandle`s without naming the type of the locked shard, and erasing it with `erase`
+/// so the handle can be named, e.g. to keep it in a struct field.
+///
+/// ```
+/// # use quick_hash_cache::{CHashMap, ErasedReadHandle, ReadHandleExt};
+/// struct User {
+///     name: String,
+///     age: u32,
+/// }
+///
+/// struct Greeter {
+///     name: ErasedReadHandle<String>,
+/// }
+///
+/// # #[tokio::main] async fn main() {
+/// let users = CHashMap::new(4);
+/// users.insert(1, User { name: "Ferris".to_owned(), age: 8 }).await;
+///
+/// let greeter = Greeter {
+///     name: users.get(&1).await.unwrap().map_field(|user| &user.name).erase(),
+/// };
+///
+/// assert_eq!(*greeter.name, "Ferris");
+/// # }
+/// ```
+pub trait ReadHandleExt<T: ?Sized, U: ?Sized>: Sized {
+    /// Projects the handle into a part of the value, e.g. one of its fields
+    fn map_field<V: ?Sized, F>(self, f: F) -> ReadHandle<T, V>
+    where
+        F: FnOnce(&U) -> &V;
+
+    /// Projects the handle into a part of the value, if there is one
+    fn try_map_field<V: ?Sized, F>(self, f: F) -> Result<ReadHandle<T, V>, Self>
+    where
+        F: FnOnce(&U) -> Option<&V>;
+
+    /// Erases the type of the locked shard from the handle
+    fn erase(self) -> ErasedReadHandle<U>
+    where
+        Self: Send + Sync + 'static;
+}
+
+/// Mapping of `WriteHandle`s without naming the type of the locked shard, see [`ReadHandleExt`]
+pub trait WriteHandleExt<T: ?Sized, U: ?Sized>: Sized {
+    /// Projects the handle into a part of the value, e.g. one of its fields
+    fn map_field<V: ?Sized, F>(self, f: F) -> WriteHandle<T, V>
+    where
+        F: FnOnce(&mut U) -> &mut V;
+
+    /// Projects the handle into a part of the value, if there is one
+    fn try_map_field<V: ?Sized, F>(self, f: F) -> Result<WriteHandle<T, V>, Self>
+    where
+        F: FnOnce(&mut U) -> Option<&mut V>;
+
+    /// Erases the type of the locked shard from the handle
+    fn erase(self) -> ErasedWriteHandle<U>
+    where
+        Self: Send + Sync + 'static;
+}
+
+impl<T: ?Sized, U: ?Sized> ReadHandleExt<T, U> for ReadHandle<T, U> {
+    #[inline]
+    fn map_field<V: ?Sized, F>(self, f: F) -> ReadHandle<T, V>
+    where
+        F: FnOnce(&U) -> &V,
+    {
+        ReadHandle::map(self, f)
+    }
+
+    #[inline]
+    fn try_map_field<V: ?Sized, F>(self, f: F) -> Result<ReadHandle<T, V>, Self>
+    where
+        F: FnOnce(&U) -> Option<&V>,
+    {
+        ReadHandle::try_map(self, f)
+    }
+
+    fn erase(self) -> ErasedReadHandle<U>
+    where
+        Self: Send + Sync + 'static,
+    {
+        ErasedReadHandle {
+            value: NonNull::from(&*self),
+            _guard: Box::new(self),
+        }
+    }
+}
+
+impl<T: ?Sized, U: ?Sized> WriteHandleExt<T, U> for WriteHandle<T, U> {
+    #[inline]
+    fn map_field<V: ?Sized, F>(self, f: F) -> WriteHandle<T, V>
+    where
+        F: FnOnce(&mut U) -> &mut V,
+    {
+        WriteHandle::map(self, f)
+    }
+
+    #[inline]
+    fn try_map_field<V: ?Sized, F>(self, f: F) -> Result<WriteHandle<T, V>, Self>
+    where
+        F: FnOnce(&mut U) -> Option<&mut V>,
+    {
+        WriteHandle::try_map(self, f)
+    }
+
+    fn erase(mut self) -> ErasedWriteHandle<U>
+    where
+        Self: Send + Sync + 'static,
+    {
+        ErasedWriteHandle {
+            value: NonNull::from(&mut *self),
+            _guard: Box::new(self),
+        }
+    }
+}
+
+/// `ReadHandle` to a `U`, with the type of the locked shard erased
+pub struct ErasedReadHandle<U: ?Sized> {
+    // points into the shard, which stays put and locked for as long as the guard is alive
+    value: NonNull<U>,
+    _guard: Box<dyn Guard>,
+}
+
+/// `WriteHandle` to a `U`, with the type of the locked shard erased
+pub struct ErasedWriteHandle<U: ?Sized> {
+    // points into the shard, which stays put and locked for as long as the guard is alive
+    value: NonNull<U>,
+    _guard: Box<dyn Guard>,
+}
+
+// SAFETY: the handles only give access to the `U` like the guards they erase, which are `Send + Sync` themselves
+unsafe impl<U: ?Sized + Sync> Send for ErasedReadHandle<U> {}
+unsafe impl<U: ?Sized + Sync> Sync for ErasedReadHandle<U> {}
+unsafe impl<U: ?Sized + Send> Send for ErasedWriteHandle<U> {}
+unsafe impl<U: ?Sized + Sync> Sync for ErasedWriteHandle<U> {}
+
+impl<U: ?Sized> ErasedReadHandle<U> {
+    /// Projects the handle into a part of the value, e.g. one of its fields
+    pub fn map<V: ?Sized, F>(this: Self, f: F) -> ErasedReadHandle<V>
+    where
+        F: FnOnce(&U) -> &V,
+    {
+        ErasedReadHandle {
+            value: NonNull::from(f(&*this)),
+            _guard: this._guard,
+        }
+    }
+}
+
+impl<U: ?Sized> ErasedWriteHandle<U> {
+    /// Projects the handle into a part of the value, e.g. one of its fields
+    pub fn map<V: ?Sized, F>(mut this: Self, f: F) -> ErasedWriteHandle<V>
+    where
+        F: FnOnce(&mut U) -> &mut V,
+    {
+        ErasedWriteHandle {
+            value: NonNull::from(f(&mut *this)),
+            _guard: this._guard,
+        }
+    }
+}
+
+impl<U: ?Sized> Deref for ErasedReadHandle<U> {
+    type Target = U;
+
+    #[inline]
+    fn deref(&self) -> &U {
+        // SAFETY: the guard keeps the value alive and read-locked
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<U: ?Sized> Deref for ErasedWriteHandle<U> {
+    type Target = U;
+
+    #[inline]
+    fn deref(&self) -> &U {
+        // SAFETY: the guard keeps the value alive and write-locked
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<U: ?Sized> DerefMut for ErasedWriteHandle<U> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut U {
+        // SAFETY: the guard keeps the value alive and write-locked, and `&mut self` makes this access unique
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<U: ?Sized + fmt::Debug> fmt::Debug for ErasedReadHandle<U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<U: ?Sized + fmt::Debug> fmt::Debug for ErasedWriteHandle<U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
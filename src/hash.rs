@@ -0,0 +1,201 @@
+//! Hashers for the maps, and how hashes are mapped onto shards.
+//!
+//! The `DefaultHashBuilder` is aHash with fixed keys, so untrusted input could be crafted
+//! to collide, or to all land in one shard.
+//! When keys come from untrusted sources, prefer [`random_state`] or [`Sip13State`], optionally along with
+//! per-shard seeds (see [`Builder::per_shard_seeds`](crate::Builder::per_shard_seeds)).
+
+use core::hash::{BuildHasherDefault, Hasher};
+#[cfg(feature = "tokio")]
+use core::ops::RangeInclusive;
+
+#[cfg(feature = "tokio")]
+use alloc::{sync::Arc, vec::Vec};
+
+#[cfg(feature = "std")]
+use std::hash::BuildHasher;
+
+/// aHash with configurable keys, see [`random_state`]
+pub type AHashState = ahash::RandomState;
+
+/// SipHash 1-3 with random keys, as used by `std::collections::HashMap`.
+///
+/// Slower than aHash, but the most conservative choice for untrusted keys.
+#[cfg(feature = "std")]
+pub type Sip13State = std::collections::hash_map::RandomState;
+
+/// FxHash, as used by rustc. Very fast, but trivially attackable, so only use it for trusted keys.
+#[cfg(feature = "fxhash")]
+pub type FxBuildHasher = BuildHasherDefault<rustc_hash::FxHasher>;
+
+/// Fibonacci hashing of integer keys, for keys that are already well-distributed, such as random IDs,
+/// where hashing them again would be wasted work. See [`NoHasher`].
+pub type NoHash = BuildHasherDefault<NoHasher>;
+
+/// Hasher that takes integers as they are, and only multiplies them by the golden ratio in `finish`
+/// so both the low and high bits of the hash depend on the whole key.
+///
+/// Meant for `u32`/`u64`-like keys. Other keys are folded together byte by byte, which works, but distributes poorly.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoHasher(u64);
+
+impl NoHasher {
+    #[inline]
+    fn fold(&mut self, n: u64) {
+        self.0 = self.0.rotate_left(8) ^ n;
+    }
+}
+
+impl Hasher for NoHasher {
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+    }
+
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.fold(byte as u64);
+        }
+    }
+
+    #[inline]
+    fn write_u8(&mut self, n: u8) {
+        self.fold(n as u64)
+    }
+
+    #[inline]
+    fn write_u16(&mut self, n: u16) {
+        self.fold(n as u64)
+    }
+
+    #[inline]
+    fn write_u32(&mut self, n: u32) {
+        self.fold(n as u64)
+    }
+
+    #[inline]
+    fn write_u64(&mut self, n: u64) {
+        self.fold(n)
+    }
+
+    #[inline]
+    fn write_usize(&mut self, n: usize) {
+        self.fold(n as u64)
+    }
+}
+
+/// aHash with keys drawn from the operating system's randomness, unique to each call
+#[cfg(feature = "std")]
+pub fn random_state() -> AHashState {
+    AHashState::with_seeds(random_u64(), random_u64(), random_u64(), random_u64())
+}
+
+/// Random 64-bit value, taken from the randomly-keyed `std` hasher to avoid depending on an OS RNG crate
+#[cfg(feature = "std")]
+pub(crate) fn random_u64() -> u64 {
+    Sip13State::new().build_hasher().finish()
+}
+
+/// How a key's hash selects its shard
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ShardSelect {
+    /// Hash modulo the number of shards
+    Modulo,
+    /// Hash mixed with a per-map seed, then modulo the number of shards.
+    ///
+    /// Keys that share a shard then share no bits of their hash, so they are not clustered within the shard's table
+    /// by its `hash & mask` bucket selection, and the shard of a key cannot be derived from its hash.
+    Seeded(u64),
+    /// High bits of the hash, by multiply-shift range reduction.
+    ///
+    /// With Fibonacci hashing (`NoHash`) the high bits are the best mixed, and this keeps the low bits,
+    /// which the shards' tables select buckets by, independent of the shard.
+    HighBits,
+    /// Consistent hashing, so changing the number of shards relocates only the keys of the shards added or removed
+    Ring(Arc<HashRing>),
+}
+
+#[cfg(feature = "tokio")]
+impl ShardSelect {
+    #[inline]
+    pub(crate) fn shard_of(&self, hash: u64, num_shards: usize) -> usize {
+        match *self {
+            ShardSelect::Modulo => hash as usize % num_shards,
+            ShardSelect::Seeded(seed) => mix64(hash ^ seed) as usize % num_shards,
+            ShardSelect::HighBits => ((hash as u128 * num_shards as u128) >> 64) as usize,
+            ShardSelect::Ring(ref ring) => ring.shard_of(hash),
+        }
+    }
+
+    /// The same selection, for the given number of shards
+    pub(crate) fn with_num_shards(&self, num_shards: usize) -> Self {
+        match *self {
+            ShardSelect::Ring(_) => ShardSelect::Ring(Arc::new(HashRing::new(num_shards))),
+            _ => self.clone(),
+        }
+    }
+
+    /// Contiguous range of hashes selecting the shard, if shards are selected by ranges of hashes at all
+    pub(crate) fn hash_range(&self, shard_idx: usize, num_shards: usize) -> Option<RangeInclusive<u64>> {
+        match *self {
+            ShardSelect::HighBits => {
+                // smallest hash `h` with `h * num_shards >> 64 >= i`
+                let first = |i: usize| ((i as u128) << 64).div_ceil(num_shards as u128);
+
+                Some(first(shard_idx) as u64..=(first(shard_idx + 1) - 1) as u64)
+            }
+            ShardSelect::Modulo | ShardSelect::Seeded(_) | ShardSelect::Ring(_) => None,
+        }
+    }
+}
+
+/// Virtual nodes per shard on a [`HashRing`]. More nodes even out the shares of the ring each shard gets,
+/// at the cost of memory and a longer binary search.
+#[cfg(feature = "tokio")]
+const VIRTUAL_NODES: u64 = 128;
+
+/// Consistent-hash ring of shards, each placed at `VIRTUAL_NODES` points.
+///
+/// A hash belongs to the shard of the first point at or after it, wrapping around.
+/// The points of a shard only depend on its index, so adding or removing shards
+/// only moves the keys between the changed shards and their neighbors on the ring.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct HashRing {
+    points: Vec<(u64, usize)>,
+}
+
+#[cfg(feature = "tokio")]
+impl HashRing {
+    pub(crate) fn new(num_shards: usize) -> Self {
+        let mut points: Vec<_> = (0..num_shards)
+            .flat_map(|shard| (0..VIRTUAL_NODES).map(move |node| (mix64((shard as u64) << 32 | node), shard)))
+            .collect();
+
+        points.sort_unstable();
+
+        HashRing { points }
+    }
+
+    #[inline]
+    fn shard_of(&self, hash: u64) -> usize {
+        // the hasher's output may be poorly distributed (e.g. `NoHash` low bits), while the points are uniform
+        let hash = mix64(hash);
+
+        match self.points.partition_point(|&(point, _)| point < hash) {
+            idx if idx == self.points.len() => self.points[0].1,
+            idx => self.points[idx].1,
+        }
+    }
+}
+
+/// SplitMix64 finalizer
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -1,445 +1,291 @@
-use std::borrow::Borrow;
-use std::hash::{BuildHasher, Hash, Hasher};
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
-};
+#![cfg_attr(not(feature = "std"), no_std)]
 
-pub use hashbrown::hash_map::DefaultHashBuilder;
-use hashbrown::hash_map::{HashMap, RawEntryMut};
+extern crate alloc;
 
-use tokio::sync::{OwnedRwLockMappedWriteGuard, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+pub use hashbrown::hash_map::DefaultHashBuilder;
 
+#[cfg(feature = "tokio")]
+use hashbrown::hash_map::HashMap;
+
+#[cfg(feature = "tokio")]
+use crate::sync::{OwnedRwLockMappedWriteGuard, OwnedRwLockReadGuard};
+
+pub mod clock;
+#[cfg(feature = "std")]
+pub mod estimate;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "debug-guard-tracking")]
+pub mod guard;
+pub mod hash;
+#[cfg(feature = "lockfree")]
+pub mod lockfree;
 pub mod lru;
-
-#[derive(Debug)]
-pub struct CHashMap<K, T, S = DefaultHashBuilder> {
-    hash_builder: S,
-    shards: Vec<Arc<RwLock<HashMap<K, T, S>>>>,
-    size: AtomicUsize,
-}
-
-impl<K, T> CHashMap<K, T, DefaultHashBuilder> {
-    pub fn new(num_shards: usize) -> Self {
-        Self::with_hasher(num_shards, DefaultHashBuilder::default())
-    }
-}
-
-impl<K, T> Default for CHashMap<K, T, DefaultHashBuilder> {
-    fn default() -> Self {
-        Self::new(num_cpus::get())
-    }
-}
+#[cfg(feature = "tokio")]
+pub mod numa;
+#[cfg(feature = "tokio")]
+pub mod report;
+#[cfg(feature = "tokio")]
+pub mod tinylfu;
+#[cfg(feature = "web")]
+pub mod web;
+
+#[cfg(feature = "tokio")]
+mod arc_cache;
+#[cfg(feature = "tokio")]
+mod async_map;
+#[cfg(feature = "tokio")]
+mod backend;
+#[cfg(feature = "tokio")]
+mod batch;
+#[cfg(feature = "bloom")]
+mod bloom;
+#[cfg(feature = "tokio")]
+mod builder;
+#[cfg(feature = "tokio")]
+mod changelog;
+#[cfg(feature = "tokio")]
+mod cow;
+#[cfg(feature = "tokio")]
+mod deadline;
+#[cfg(feature = "tokio")]
+mod dedup;
+#[cfg(feature = "tokio")]
+mod dynamic;
+#[cfg(feature = "tokio")]
+mod error;
+#[cfg(feature = "tokio")]
+mod expiring;
+#[cfg(feature = "tokio")]
+mod group;
+#[cfg(feature = "tokio")]
+mod handle;
+#[cfg(feature = "tokio")]
+mod loader;
+#[cfg(feature = "tokio")]
+mod map;
+#[cfg(feature = "tokio")]
+mod microcache;
+#[cfg(feature = "tokio")]
+mod namespace;
+#[cfg(feature = "tokio")]
+mod nested;
+#[cfg(feature = "tokio")]
+mod persist;
+#[cfg(feature = "tokio")]
+mod pins;
+#[cfg(feature = "tokio")]
+mod scan;
+#[cfg(feature = "tokio")]
+mod shed;
+#[cfg(feature = "tokio")]
+mod sweeper;
+#[cfg(feature = "timing")]
+mod timed;
+#[cfg(feature = "tokio")]
+mod typed;
+#[cfg(feature = "tokio")]
+mod versioned;
+#[cfg(feature = "tokio")]
+mod view;
+#[cfg(feature = "tokio")]
+mod watch;
+#[cfg(feature = "tokio")]
+mod watermark;
+#[cfg(feature = "tokio")]
+mod windowed;
+#[cfg(feature = "debug-guard-tracking")]
+mod reentrancy;
+mod seqlock;
+mod sync;
+
+#[cfg(feature = "tokio")]
+pub use arc_cache::ArcCache;
+#[cfg(feature = "tokio")]
+pub use async_map::AsyncMap;
+#[cfg(feature = "tokio")]
+pub use backend::{AsyncCacheBackend, BackendOp, InjectedFailure, MockBackend};
+#[cfg(feature = "tokio")]
+pub use batch::{Batch, BatchContext};
+#[cfg(feature = "tokio")]
+pub use builder::Builder;
+#[cfg(feature = "tokio")]
+pub use changelog::{ResyncNeeded, ShardDiff};
+#[cfg(feature = "tokio")]
+pub use cow::CowSnapshot;
+#[cfg(feature = "tokio")]
+pub use deadline::{CancelToken, Deadline, Progress};
+#[cfg(feature = "tokio")]
+pub use dedup::{DedupMap, WorkToken};
+#[cfg(feature = "tokio")]
+pub use dynamic::{AnyKey, DynCache, DynKey, DynReadHandle, DynWriteHandle};
+#[cfg(feature = "tokio")]
+pub use error::Error;
+#[cfg(feature = "tokio")]
+pub use expiring::{ExpiringMap, ExpiringReadHandle};
+#[cfg(feature = "tokio")]
+pub use group::GroupedMap;
+#[cfg(feature = "tokio")]
+pub use handle::{ErasedReadHandle, ErasedWriteHandle, ReadHandleExt, WriteHandleExt};
+#[cfg(feature = "tokio")]
+pub use map::{
+    AllShardsGuard, AllShardsReadGuard, CHashMap, LoadCancellation, LoadCancelled, MapReadHandle, MapWriteHandle,
+    RacePolicy, ReplaceHandle, ShardGuard,
+};
+#[cfg(feature = "tokio")]
+pub use namespace::{Namespace, Namespaced, NamespacedMap};
+#[cfg(feature = "tokio")]
+pub use nested::NestedMap;
+#[cfg(feature = "tokio")]
+pub use persist::SnapshotError;
+#[cfg(feature = "tokio")]
+pub use scan::{Cursor, Invalidated, OnInvalidated, PositionCursor};
+#[cfg(feature = "timing")]
+pub use timed::{LatencyReport, OpLatency, Operation, TimedCache};
+#[cfg(feature = "tokio")]
+pub use typed::{AnyValue, TypedCache, TypedKey, TypedReadHandle, TypedWriteHandle};
+#[cfg(feature = "tokio")]
+pub use versioned::{VersionWinner, VersionedMap, VersionedReadHandle};
+#[cfg(feature = "tokio")]
+pub use view::ReadOnlyCHashMap;
+#[cfg(feature = "tokio")]
+pub use watch::Watch;
+#[cfg(feature = "tokio")]
+pub use watermark::SizeCrossing;
+#[cfg(feature = "tokio")]
+pub use windowed::{Samples, WindowedMap};
+pub use seqlock::SeqLock;
 
 #[doc(hidden)]
 pub trait Erased {}
 impl<T> Erased for T {}
 
+#[cfg(all(feature = "tokio", not(feature = "debug-guard-tracking")))]
 pub type ReadHandle<T, U> = OwnedRwLockReadGuard<T, U>;
+#[cfg(all(feature = "tokio", not(feature = "debug-guard-tracking")))]
 pub type WriteHandle<T, U> = OwnedRwLockMappedWriteGuard<T, U>;
 
-pub type Shard<K, T, S> = HashMap<K, T, S>;
-
-impl<K, T, S> CHashMap<K, T, S>
-where
-    S: Clone,
-{
-    pub fn with_hasher(num_shards: usize, hash_builder: S) -> Self {
-        CHashMap {
-            shards: (0..num_shards)
-                .into_iter()
-                .map(|_| Arc::new(RwLock::new(HashMap::with_hasher(hash_builder.clone()))))
-                .collect(),
-            hash_builder,
-            size: AtomicUsize::new(0),
-        }
-    }
-}
-
-impl<K, T, S> CHashMap<K, T, S>
-where
-    K: Clone,
-    T: Clone,
-    S: Clone,
-{
-    /// Duplicates/Clones the CHashMap. A CHashMap cannot be cloned regularly due to internal async locking.
-    pub async fn duplicate(&self) -> Self {
-        let mut shards = Vec::with_capacity(self.shards.len());
-        let mut size = 0;
+// `debug-guard-tracking` wraps handles to track who holds them and for how long, see `guard`
+#[cfg(feature = "debug-guard-tracking")]
+pub type ReadHandle<T, U> = guard::Tracked<tokio::sync::OwnedRwLockReadGuard<T, U>>;
+#[cfg(feature = "debug-guard-tracking")]
+pub type WriteHandle<T, U> = guard::Tracked<tokio::sync::OwnedRwLockMappedWriteGuard<T, U>>;
 
-        for shard in &self.shards {
-            let shard = shard.read().await.clone();
-            size += shard.len();
-            shards.push(Arc::new(RwLock::new(shard)));
-        }
+#[cfg(feature = "tokio")]
+#[inline]
+pub(crate) fn read_handle<T: ?Sized, U: ?Sized>(guard: OwnedRwLockReadGuard<T, U>) -> ReadHandle<T, U> {
+    #[cfg(feature = "debug-guard-tracking")]
+    return guard::Tracked::read(guard);
 
-        CHashMap {
-            shards,
-            hash_builder: self.hash_builder.clone(),
-            size: AtomicUsize::new(size),
-        }
-    }
+    #[cfg(not(feature = "debug-guard-tracking"))]
+    return guard;
 }
 
-impl<K, T, S> CHashMap<K, T, S>
-where
-    K: Hash + Eq,
-    S: BuildHasher,
-{
-    pub fn hash_builder(&self) -> &S {
-        &self.hash_builder
-    }
-
-    #[inline]
-    fn hash_and_shard<Q: ?Sized>(&self, key: &Q) -> (u64, usize)
-    where
-        Q: Hash + Eq,
-    {
-        let mut hasher = self.hash_builder.build_hasher();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
-        (hash, hash as usize % self.shards.len())
-    }
-
-    pub async fn clear(&self) {
-        for shard in &self.shards {
-            let mut shard = shard.write().await;
+#[cfg(feature = "tokio")]
+#[inline]
+pub(crate) fn write_handle<T: ?Sized, U: ?Sized>(guard: OwnedRwLockMappedWriteGuard<T, U>) -> WriteHandle<T, U> {
+    #[cfg(feature = "debug-guard-tracking")]
+    return guard::Tracked::write(guard);
 
-            let len = shard.len();
-            shard.clear();
-
-            self.size.fetch_sub(len, Ordering::SeqCst);
-        }
-    }
-
-    pub async fn retain<F>(&self, f: F)
-    where
-        F: Fn(&K, &mut T) -> bool,
-    {
-        for shard in &self.shards {
-            let mut shard = shard.write().await;
-
-            let len = shard.len();
-            shard.retain(&f);
-
-            self.size.fetch_sub(len - shard.len(), Ordering::SeqCst);
-        }
-    }
-
-    pub fn iter_shards<'a>(&'a self) -> impl Iterator<Item = &'a RwLock<Shard<K, T, S>>> {
-        self.shards.iter().map(|s| &**s)
-    }
-
-    pub fn size(&self) -> usize {
-        self.size.load(Ordering::SeqCst)
-    }
-
-    pub fn num_shards(&self) -> usize {
-        self.shards.len()
-    }
-
-    pub fn try_maybe_contains_hash(&self, hash: u64) -> bool {
-        let shard_idx = hash as usize % self.shards.len();
-        let shard = unsafe { self.shards.get_unchecked(shard_idx) };
-
-        if let Ok(shard) = shard.try_read() {
-            shard.raw_entry().from_hash(hash, |_| true).is_some()
-        } else {
-            false
-        }
-    }
-
-    pub async fn contains_hash(&self, hash: u64) -> bool {
-        let shard_idx = hash as usize % self.shards.len();
-        let shard = unsafe { self.shards.get_unchecked(shard_idx) };
-
-        shard.read().await.raw_entry().from_hash(hash, |_| true).is_some()
-    }
-
-    pub async fn contains<Q: ?Sized>(&self, key: &Q) -> bool
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        self.contains_hash(self.hash_and_shard(key).0).await
-    }
-
-    pub async fn remove<Q: ?Sized>(&self, key: &Q) -> Option<T>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        let (hash, shard_idx) = self.hash_and_shard(&key);
-        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).write().await };
-
-        match shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
-            RawEntryMut::Occupied(occupied) => {
-                let value = occupied.remove();
-                self.size.fetch_sub(1, Ordering::SeqCst);
-                Some(value)
-            }
-            RawEntryMut::Vacant(_) => None,
-        }
-    }
-
-    pub async fn insert(&self, key: K, value: T) -> Option<T> {
-        let (hash, shard_idx) = self.hash_and_shard(&key);
-        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).write().await };
-
-        match shard.raw_entry_mut().from_key_hashed_nocheck(hash, &key) {
-            RawEntryMut::Occupied(mut occupied) => Some(occupied.insert(value)),
-            RawEntryMut::Vacant(vacant) => {
-                self.size.fetch_add(1, Ordering::SeqCst);
-                vacant.insert_hashed_nocheck(hash, key, value);
-                None
-            }
-        }
-    }
-
-    pub async fn get<Q: ?Sized>(&self, key: &Q) -> Option<ReadHandle<impl Erased, T>>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        let (hash, shard_idx) = self.hash_and_shard(key);
-        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone().read_owned().await };
-
-        OwnedRwLockReadGuard::try_map(shard, |shard| {
-            match shard.raw_entry().from_key_hashed_nocheck(hash, key) {
-                Some((_, value)) => Some(value),
-                None => None,
-            }
-        })
-        .ok()
-    }
-
-    pub async fn get_cloned<Q: ?Sized>(&self, key: &Q) -> Option<T>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-        T: Clone,
-    {
-        let (hash, shard_idx) = self.hash_and_shard(key);
-        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone().read_owned().await };
-
-        match shard.raw_entry().from_key_hashed_nocheck(hash, key) {
-            Some((_, value)) => Some(value.clone()),
-            None => None,
-        }
-    }
-
-    pub async fn get_mut<Q: ?Sized>(&self, key: &Q) -> Option<WriteHandle<impl Erased, T>>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        let (hash, shard_idx) = self.hash_and_shard(key);
-        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone().write_owned().await };
-
-        OwnedRwLockWriteGuard::try_map(shard, |shard| {
-            match shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
-                RawEntryMut::Occupied(occupied) => Some(occupied.into_mut()),
-                RawEntryMut::Vacant(_) => None,
-            }
-        })
-        .ok()
-    }
-
-    pub async fn get_or_insert(&self, key: &K, on_insert: impl FnOnce() -> T) -> ReadHandle<impl Erased, T>
-    where
-        K: Clone,
-    {
-        let (hash, shard_idx) = self.hash_and_shard(key);
-        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).clone().write_owned().await };
-
-        if let RawEntryMut::Vacant(vacant) = shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
-            self.size.fetch_add(1, Ordering::SeqCst);
-
-            vacant.insert_hashed_nocheck(hash, key.clone(), on_insert());
-        }
-
-        // TODO: Having to do another lookup for a read-reference is wasteful, maybe use an alternate custom ReadHandle?
-        OwnedRwLockReadGuard::map(OwnedRwLockWriteGuard::downgrade(shard), |shard| {
-            match shard.raw_entry().from_key_hashed_nocheck(hash, key) {
-                Some((_, value)) => value,
-                None => unreachable!(),
-            }
-        })
-    }
-
-    pub async fn get_mut_or_insert(
-        &self,
-        key: &K,
-        on_insert: impl FnOnce() -> T,
-    ) -> WriteHandle<impl Erased, T>
-    where
-        K: Clone,
-    {
-        let (hash, shard_idx) = self.hash_and_shard(key);
-        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone().write_owned().await };
-
-        OwnedRwLockWriteGuard::map(shard, |shard| {
-            shard
-                .raw_entry_mut()
-                .from_key_hashed_nocheck(hash, key)
-                .or_insert_with(|| {
-                    self.size.fetch_add(1, Ordering::SeqCst);
-
-                    (key.clone(), on_insert())
-                })
-                .1
-        })
-    }
-
-    pub async fn get_or_default(&self, key: &K) -> ReadHandle<impl Erased, T>
-    where
-        K: Clone,
-        T: Default,
-    {
-        self.get_or_insert(key, Default::default).await
-    }
+    #[cfg(not(feature = "debug-guard-tracking"))]
+    return guard;
+}
 
-    pub async fn get_mut_or_default(&self, key: &K) -> WriteHandle<impl Erased, T>
-    where
-        K: Clone,
-        T: Default,
-    {
-        self.get_mut_or_insert(key, Default::default).await
-    }
+#[cfg(feature = "tokio")]
+pub type Shard<K, T, S> = HashMap<K, T, S>;
 
-    /*
-    pub async fn shard_mut<Q: ?Sized>(&self, key: &Q) -> WriteLock<K, T, S, Shard<K, T, S>>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        let (_, shard_idx) = self.hash_and_shard(key);
-        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone().write_owned().await };
+/// Runs the closure when dropped, including while unwinding.
+///
+/// Used to settle size counters after user callbacks (`retain` predicates, ...), so one that panics
+/// cannot leave the counters out of sync with the entries removed before it did.
+#[cfg(feature = "tokio")]
+pub(crate) struct Defer<F: FnMut()>(pub F);
 
-        OwnedRwLockWriteGuard::map(shard, |shard| shard)
+#[cfg(feature = "tokio")]
+impl<F: FnMut()> Drop for Defer<F> {
+    #[inline]
+    fn drop(&mut self) {
+        (self.0)()
     }
+}
 
-    pub async fn entry<Q: ?Sized>(&self, key: &Q) -> WriteHandle<impl Erased, Entry<'_, K, T, S>>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        let (hash, shard_idx) = self.hash_and_shard(key);
-        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone().write_owned().await };
+/// Error of `try_insert_within_capacity` on a full shard or eviction domain, giving back the value not inserted
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapacityFull<V>(pub V);
 
-        OwnedRwLockWriteGuard::map(shard, |shard| {
-            shard.raw_entry_mut().from_key_hashed_nocheck(hash, key)
-        })
+#[cfg(feature = "tokio")]
+impl<V> CapacityFull<V> {
+    #[inline]
+    pub fn into_inner(self) -> V {
+        self.0
     }
-    */
-
-    /// Aggregates all the provided keys and batches together access to the underlying shards,
-    /// reducing locking overhead at the cost of memory to buffer keys/hashes.
-    pub async fn batch_read<'a, Q: 'a + ?Sized, I, F>(
-        &self,
-        keys: I,
-        cache: Option<&mut Vec<(&'a Q, u64, usize)>>,
-        mut f: F,
-    ) where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-        I: IntoIterator<Item = &'a Q>,
-        F: FnMut(&'a Q, Option<(&K, &T)>),
-    {
-        let mut own_cache = Vec::new();
-        let cache = match cache {
-            Some(cache) => {
-                cache.clear();
-                cache
-            }
-            None => &mut own_cache,
-        };
-
-        cache.extend(keys.into_iter().map(|key| {
-            let (hash, shard) = self.hash_and_shard(key);
-            (key, hash, shard)
-        }));
-
-        if cache.is_empty() {
-            return;
-        }
-
-        cache.sort_unstable_by_key(|(_, _, shard)| *shard);
-
-        let mut i = 0;
-        'outer: loop {
-            let current_shard = cache[i].2;
-            let shard = unsafe { self.shards.get_unchecked(current_shard).read().await };
-
-            while cache[i].2 == current_shard {
-                f(
-                    cache[i].0,
-                    shard.raw_entry().from_key_hashed_nocheck(cache[i].1, cache[i].0),
-                );
-                i += 1;
-
-                if i >= cache.len() {
-                    break 'outer;
-                }
-            }
-        }
+}
 
-        cache.clear();
+#[cfg(feature = "tokio")]
+impl<V> std::fmt::Display for CapacityFull<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("shard is at capacity, inserting would evict an entry")
     }
+}
 
-    /// Aggregates all the provided keys and batches together access to the underlying shards,
-    /// reducing locking overhead at the cost of memory to buffer keys/hashes
-    pub async fn batch_write<'a, Q: 'a + ?Sized, I, F>(
-        &self,
-        keys: I,
-        cache: Option<&mut Vec<(&'a Q, u64, usize)>>,
-        mut f: F,
-    ) where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-        I: IntoIterator<Item = &'a Q>,
-        F: FnMut(&'a Q, hashbrown::hash_map::RawEntryMut<K, T, S>),
-    {
-        let mut own_cache = Vec::new();
-        let cache = match cache {
-            Some(cache) => {
-                cache.clear();
-                cache
-            }
-            None => &mut own_cache,
-        };
-
-        cache.extend(keys.into_iter().map(|key| {
-            let (hash, shard) = self.hash_and_shard(key);
-            (key, hash, shard)
-        }));
-
-        if cache.is_empty() {
-            return;
-        }
-
-        cache.sort_unstable_by_key(|(_, _, shard)| *shard);
+#[cfg(feature = "tokio")]
+impl<V: std::fmt::Debug> std::error::Error for CapacityFull<V> {}
+
+/// Environment variable overriding the number of shards of the maps built without one, e.g. with `Default`,
+/// see [`Builder::shards_per_core`]
+#[cfg(any(feature = "tokio", feature = "lockfree"))]
+pub const NUM_SHARDS_ENV: &str = "QUICK_HASH_CACHE_SHARDS";
+
+/// Shards per physical core of the maps built without a number of shards.
+///
+/// With one shard per CPU, writers on a small machine collide on the same shard locks all the time,
+/// while a few more shards cost little memory.
+#[cfg(any(feature = "tokio", feature = "lockfree"))]
+const DEFAULT_SHARDS_PER_CORE: usize = 4;
+
+/// Cap on the number of shards from `shards_per_core`, as more shards than that only slow down whole-map operations
+#[cfg(any(feature = "tokio", feature = "lockfree"))]
+const MAX_SHARDS_PER_CORE_COUNT: usize = 1024;
+
+/// Number of CPUs the process can run threads on
+#[cfg(any(feature = "tokio", feature = "lockfree"))]
+pub(crate) fn num_cpus() -> usize {
+    #[cfg(feature = "num_cpus")]
+    return num_cpus::get();
+
+    // `available_parallelism` errors on platforms without threads, such as wasm32
+    #[cfg(not(feature = "num_cpus"))]
+    return std::thread::available_parallelism().map_or(1, |n| n.get());
+}
 
-        let mut i = 0;
-        'outer: loop {
-            let current_shard = cache[i].2;
-            let mut shard = unsafe { self.shards.get_unchecked(current_shard).write().await };
+/// Number of physical cores, or of CPUs without the `num_cpus` feature
+#[cfg(any(feature = "tokio", feature = "lockfree"))]
+fn num_physical_cores() -> usize {
+    #[cfg(feature = "num_cpus")]
+    return num_cpus::get_physical();
 
-            while cache[i].2 == current_shard {
-                f(
-                    cache[i].0,
-                    shard
-                        .raw_entry_mut()
-                        .from_key_hashed_nocheck(cache[i].1, cache[i].0),
-                );
-                i += 1;
+    #[cfg(not(feature = "num_cpus"))]
+    return num_cpus();
+}
 
-                if i >= cache.len() {
-                    break 'outer;
-                }
-            }
-        }
+/// `factor` shards per physical core, capped at `MAX_SHARDS_PER_CORE_COUNT` and rounded up to a power of two
+#[cfg(any(feature = "tokio", feature = "lockfree"))]
+pub(crate) fn shards_per_core(factor: usize) -> usize {
+    num_physical_cores().saturating_mul(factor).clamp(1, MAX_SHARDS_PER_CORE_COUNT).next_power_of_two()
+}
 
-        cache.clear();
-    }
+/// Shard count used by the `Default` implementations and builders without one: `NUM_SHARDS_ENV` if set
+/// to a positive number, or else `DEFAULT_SHARDS_PER_CORE` shards per physical core, see `shards_per_core`
+#[cfg(any(feature = "tokio", feature = "lockfree"))]
+pub(crate) fn default_num_shards() -> usize {
+    static NUM_SHARDS: std::sync::OnceLock<usize> = std::sync::OnceLock::new();
+
+    *NUM_SHARDS.get_or_init(|| {
+        std::env::var(NUM_SHARDS_ENV)
+            .ok()
+            .and_then(|num_shards| num_shards.trim().parse().ok())
+            .filter(|&num_shards| num_shards > 0)
+            .unwrap_or_else(|| shards_per_core(DEFAULT_SHARDS_PER_CORE))
+    })
 }
@@ -0,0 +1,127 @@
+//! Coalescing of concurrent loads of the same keys, see `CHashMap::get_or_load_batch`.
+
+use std::fmt;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use hashbrown::HashMap;
+use tokio::sync::watch;
+
+/// Keys currently being loaded, each with a receiver of the channel its load reports completion through.
+///
+/// All the keys of a batch share the batch's channel, which is only ever set to `true` once the loaded values
+/// are in the map. A load whose task was cancelled drops its sender while still `false`.
+pub(crate) struct InFlight<K> {
+    loads: Mutex<HashMap<K, watch::Receiver<bool>>>,
+}
+
+impl<K> InFlight<K> {
+    pub(crate) fn new() -> Self {
+        InFlight {
+            loads: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K> InFlight<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Claims the keys not already being loaded, to be loaded by the caller, and returns the others along with
+    /// the loads to wait on for them
+    pub(crate) fn claim<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a K>,
+    ) -> (Load<'_, K>, Vec<(&'a K, watch::Receiver<bool>)>)
+    where
+        K: 'a,
+    {
+        let (done, own) = watch::channel(false);
+        let mut claimed = Vec::new();
+        let mut waiting = Vec::new();
+
+        let mut loads = self.loads.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        for key in keys {
+            match loads.get(key) {
+                // a key listed twice in the same batch
+                Some(load) if load.same_channel(&own) => {}
+                Some(load) => waiting.push((key, load.clone())),
+                None => {
+                    loads.insert(key.clone(), own.clone());
+                    claimed.push(key.clone());
+                }
+            }
+        }
+
+        let load = Load {
+            in_flight: self,
+            keys: claimed,
+            done,
+        };
+
+        (load, waiting)
+    }
+}
+
+impl<K> fmt::Debug for InFlight<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let num_loading = self.loads.lock().map(|loads| loads.len()).unwrap_or(0);
+
+        f.debug_struct("InFlight").field("num_loading", &num_loading).finish()
+    }
+}
+
+/// Keys claimed by a task, released once it finishes loading them, or is cancelled
+pub(crate) struct Load<'a, K>
+where
+    K: Hash + Eq,
+{
+    in_flight: &'a InFlight<K>,
+    keys: Vec<K>,
+    done: watch::Sender<bool>,
+}
+
+impl<K> Load<'_, K>
+where
+    K: Hash + Eq,
+{
+    pub(crate) fn keys(&self) -> &[K] {
+        &self.keys
+    }
+
+    /// Wakes the tasks waiting on the load, to be called once the loaded values are in the map
+    pub(crate) fn finish(self) {
+        self.done.send_replace(true);
+    }
+}
+
+impl<K> Drop for Load<'_, K>
+where
+    K: Hash + Eq,
+{
+    fn drop(&mut self) {
+        if self.keys.is_empty() {
+            return;
+        }
+
+        let mut loads = self.in_flight.loads.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        for key in &self.keys {
+            loads.remove(key);
+        }
+    }
+}
+
+/// Waits for a load claimed by another task, returning whether it finished, or `false` if it was cancelled
+pub(crate) async fn wait(mut load: watch::Receiver<bool>) -> bool {
+    loop {
+        if *load.borrow_and_update() {
+            return true;
+        }
+
+        if load.changed().await.is_err() {
+            return *load.borrow();
+        }
+    }
+}
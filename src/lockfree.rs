@@ -0,0 +1,649 @@
+//! Lock-free alternative to [`CHashMap`](crate::CHashMap) for read-mostly workloads.
+//!
+//! [`LockFreeMap`] keeps the sharding and method names of `CHashMap`, but each shard is a table of linked buckets
+//! that readers traverse without taking any lock, with crossbeam's epoch-based reclamation keeping unlinked entries
+//! alive until no reader can still see them. Readers therefore never wait on, or wake, other tasks.
+//!
+//! Writers of a shard are serialized on a plain mutex, held only for the duration of the write itself, and
+//! never replace entries in place: a write links in a new entry and retires the old one. This makes writes more
+//! expensive than `CHashMap`'s, so this backend only pays off when reads dominate.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned, Shared};
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::sync::{AtomicUsize, Mutex, Ordering};
+
+/// Number of buckets in a new shard's table
+const INITIAL_BUCKETS: usize = 8;
+
+/// Entry of a bucket's linked list. Entries are immutable once published, apart from their link to the next entry.
+struct Node<K, V> {
+    hash: u64,
+    key: K,
+    value: V,
+    next: Link<K, V>,
+}
+
+/// Link to the next entry of a bucket, or the bucket's head
+type Link<K, V> = Atomic<Node<K, V>>;
+
+/// Power-of-two number of buckets, each the head of a linked list of entries
+struct Table<K, V> {
+    buckets: Box<[Link<K, V>]>,
+}
+
+struct LockFreeShard<K, V> {
+    table: Atomic<Table<K, V>>,
+    /// Serializes writers of the shard, and counts its entries to decide when to grow the table
+    writer: Mutex<usize>,
+}
+
+/// Concurrent hash map with lock-free reads, see the [module docs](self).
+///
+/// Methods mirror those of `CHashMap` and are `async` like them, so switching between the two is mostly a change
+/// of type, though they complete without ever yielding. The differences follow from readers sharing entries
+/// without locks:
+///
+/// * `get` returns a [`Ref`], which pins the current thread's epoch rather than holding a lock. It is not `Send`,
+///   so it cannot be held across an `.await` in a spawned task, which would hold back reclamation indefinitely.
+/// * There is no `get_mut`, as values are never mutated in place. Use `update` to replace a value with a modified copy.
+/// * Values replaced or removed are returned as clones, as concurrent readers may still be looking at the originals.
+/// * Only the methods below exist: there are no batch operations, watches, snapshots, loaders or shard access,
+///   and the map is not configured through `Builder`.
+///
+/// Keys and values must be `Send + 'static`, since retired entries are dropped later, possibly on another thread.
+///
+/// ```
+/// # use quick_hash_cache::lockfree::LockFreeMap;
+/// # #[tokio::main] async fn main() {
+/// let map = LockFreeMap::new(4);
+/// map.insert("hits", 1).await;
+///
+/// assert_eq!(map.update(&"hits", |hits| hits + 1).await, Some(2));
+/// assert_eq!(*map.get(&"hits").await.unwrap(), 2);
+/// # }
+/// ```
+pub struct LockFreeMap<K, V, S = DefaultHashBuilder> {
+    hash_builder: S,
+    shards: Box<[LockFreeShard<K, V>]>,
+    size: AtomicUsize,
+}
+
+/// Reference to a value of a `LockFreeMap`, which keeps it alive without blocking writers
+pub struct Ref<'a, K, V> {
+    // points into a node that the pinned guard keeps from being reclaimed
+    node: *const Node<K, V>,
+    _guard: Guard,
+    _map: PhantomData<&'a (K, V)>,
+}
+
+impl<K, V> Ref<'_, K, V> {
+    #[inline]
+    pub fn key(&self) -> &K {
+        // SAFETY: the node was reachable when the guard was pinned, so it is not reclaimed before the guard is dropped
+        unsafe { &(*self.node).key }
+    }
+
+    #[inline]
+    pub fn value(&self) -> &V {
+        // SAFETY: see `key`
+        unsafe { &(*self.node).value }
+    }
+}
+
+impl<K, V> Deref for Ref<'_, K, V> {
+    type Target = V;
+
+    #[inline]
+    fn deref(&self) -> &V {
+        self.value()
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for Ref<'_, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Ref").field(self.key()).field(self.value()).finish()
+    }
+}
+
+impl<K, V> Table<K, V> {
+    fn new(num_buckets: usize) -> Self {
+        Table {
+            buckets: (0..num_buckets).map(|_| Atomic::null()).collect(),
+        }
+    }
+
+    #[inline]
+    fn bucket(&self, hash: u64) -> &Link<K, V> {
+        // shards are selected by the hash modulo their number, so buckets use its other half
+        let idx = hash.rotate_left(32) as usize & (self.buckets.len() - 1);
+        unsafe { self.buckets.get_unchecked(idx) }
+    }
+
+    /// Entry of the key, or null, along with the link pointing to it.
+    ///
+    /// Readers must use the entry returned rather than load the link again, as writers may have relinked it since.
+    fn find<'g, Q>(&'g self, hash: u64, key: &Q, guard: &'g Guard) -> (&'g Link<K, V>, Shared<'g, Node<K, V>>)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let mut link = self.bucket(hash);
+
+        loop {
+            let node = link.load(Ordering::Acquire, guard);
+
+            // SAFETY: nodes are only reclaimed once unlinked and no pinned guard can still reach them
+            match unsafe { node.as_ref() } {
+                Some(entry) if entry.hash == hash && entry.key.borrow() == key => return (link, node),
+                Some(entry) => link = &entry.next,
+                None => return (link, node),
+            }
+        }
+    }
+}
+
+impl<K, V> Drop for Table<K, V> {
+    fn drop(&mut self) {
+        // SAFETY: a table is only dropped once no reader can reach it, along with the entries still linked into it
+        unsafe {
+            let guard = epoch::unprotected();
+
+            for bucket in self.buckets.iter() {
+                let mut node = bucket.load(Ordering::Relaxed, guard);
+
+                while !node.is_null() {
+                    let next = node.deref().next.load(Ordering::Relaxed, guard);
+                    drop(node.into_owned());
+                    node = next;
+                }
+            }
+        }
+    }
+}
+
+impl<K, V, S> Drop for LockFreeMap<K, V, S> {
+    fn drop(&mut self) {
+        for shard in self.shards.iter() {
+            // SAFETY: `&mut self` means no reader is left, and retired tables were unlinked before being retired
+            unsafe {
+                let table = shard.table.load(Ordering::Relaxed, epoch::unprotected());
+
+                if !table.is_null() {
+                    drop(table.into_owned());
+                }
+            }
+        }
+    }
+}
+
+impl<K, V> LockFreeMap<K, V, DefaultHashBuilder> {
+    pub fn new(num_shards: usize) -> Self {
+        Self::with_hasher(num_shards, DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V> Default for LockFreeMap<K, V, DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new(crate::default_num_shards())
+    }
+}
+
+impl<K, V, S> LockFreeMap<K, V, S> {
+    pub fn with_hasher(num_shards: usize, hash_builder: S) -> Self {
+        assert!(num_shards > 0, "a map needs at least one shard");
+
+        LockFreeMap {
+            hash_builder,
+            shards: (0..num_shards)
+                .map(|_| LockFreeShard {
+                    table: Atomic::new(Table::new(INITIAL_BUCKETS)),
+                    writer: Mutex::new(0),
+                })
+                .collect(),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn hash_builder(&self) -> &S {
+        &self.hash_builder
+    }
+
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Like `size`, but with a relaxed load, which may lag behind concurrent writes
+    #[inline]
+    pub fn estimate_len(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size() == 0
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+}
+
+impl<K, V, S> LockFreeMap<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    #[inline]
+    fn hash_and_shard<Q>(&self, key: &Q) -> (u64, &LockFreeShard<K, V>)
+    where
+        Q: ?Sized + Hash,
+    {
+        let hash = self.hash_builder.hash_one(key);
+        (hash, unsafe { self.shards.get_unchecked((hash % self.shards.len() as u64) as usize) })
+    }
+
+    /// Index of the shard that a key belongs to
+    pub fn shard_of<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        (self.hash_builder.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    pub async fn get<Q>(&self, key: &Q) -> Option<Ref<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard) = self.hash_and_shard(key);
+        let guard = epoch::pin();
+
+        // SAFETY: tables are only reclaimed once replaced and no pinned guard can still reach them
+        let table = unsafe { shard.table.load(Ordering::Acquire, &guard).deref() };
+        let node = table.find(hash, key, &guard).1.as_raw();
+
+        if node.is_null() {
+            return None;
+        }
+
+        Some(Ref {
+            node,
+            _guard: guard,
+            _map: PhantomData,
+        })
+    }
+
+    pub async fn get_cloned<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        self.get(key).await.map(|value| value.clone())
+    }
+
+    pub async fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.get(key).await.is_some()
+    }
+
+    /// Calls `f` with every entry, without collecting them.
+    ///
+    /// Each shard is visited as it was when its traversal started, so entries inserted or removed meanwhile
+    /// may or may not be seen, but none is seen twice.
+    pub async fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &V),
+    {
+        let guard = epoch::pin();
+
+        for shard in self.shards.iter() {
+            // SAFETY: see `get`
+            let table = unsafe { shard.table.load(Ordering::Acquire, &guard).deref() };
+
+            for bucket in table.buckets.iter() {
+                let mut node = bucket.load(Ordering::Acquire, &guard);
+
+                // SAFETY: see `Table::find`
+                while let Some(entry) = unsafe { node.as_ref() } {
+                    f(&entry.key, &entry.value);
+                    node = entry.next.load(Ordering::Acquire, &guard);
+                }
+            }
+        }
+    }
+}
+
+impl<K, V, S> LockFreeMap<K, V, S>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+    S: BuildHasher,
+{
+    /// Locks the shard for writing, and calls `f` with its current table and number of entries
+    fn write<'g, R>(
+        &self,
+        shard: &LockFreeShard<K, V>,
+        guard: &'g Guard,
+        f: impl FnOnce(&'g Table<K, V>, &mut usize) -> R,
+    ) -> R {
+        // a writer that panicked still left the table consistent, as every write is a single link swap
+        let mut len = shard.writer.lock().unwrap_or_else(|e| e.into_inner());
+
+        // SAFETY: only writers replace the table, and they are locked out
+        let table = unsafe { shard.table.load(Ordering::Acquire, guard).deref() };
+
+        f(table, &mut len)
+    }
+
+    /// Replaces the table of a locked shard by one twice as large, copying its entries over
+    fn grow<'g>(&self, shard: &LockFreeShard<K, V>, table: &Table<K, V>, guard: &'g Guard) -> Shared<'g, Table<K, V>> {
+        let grown = Table::new(table.buckets.len() * 2);
+
+        for bucket in table.buckets.iter() {
+            let mut node = bucket.load(Ordering::Acquire, guard);
+
+            // SAFETY: see `Table::find`
+            while let Some(entry) = unsafe { node.as_ref() } {
+                let head = grown.bucket(entry.hash);
+
+                // the grown table is not published yet, so it can be linked without ordering
+                head.store(
+                    Owned::new(Node {
+                        hash: entry.hash,
+                        key: entry.key.clone(),
+                        value: entry.value.clone(),
+                        next: Atomic::from(head.load(Ordering::Relaxed, guard)),
+                    }),
+                    Ordering::Relaxed,
+                );
+
+                node = entry.next.load(Ordering::Acquire, guard);
+            }
+        }
+
+        let grown = Owned::new(grown).into_shared(guard);
+        let retired = shard.table.swap(grown, Ordering::AcqRel, guard);
+
+        // SAFETY: the old table is unlinked, and readers still traversing it are pinned
+        unsafe { guard.defer_destroy(retired) };
+
+        grown
+    }
+
+    pub async fn insert(&self, key: K, value: V) -> Option<V> {
+        let (hash, shard) = self.hash_and_shard(&key);
+        let guard = &epoch::pin();
+
+        self.write(shard, guard, |table, len| {
+            let mut table = table;
+            let grown;
+
+            if *len >= table.buckets.len() {
+                grown = self.grow(shard, table, guard);
+                // SAFETY: the grown table was just published by this writer
+                table = unsafe { grown.deref() };
+            }
+
+            let (link, existing) = table.find(hash, &key, guard);
+
+            // SAFETY: see `Table::find`
+            match unsafe { existing.as_ref() } {
+                Some(node) => {
+                    let previous = node.value.clone();
+
+                    let replacement = Owned::new(Node {
+                        hash,
+                        key,
+                        value,
+                        next: Atomic::from(node.next.load(Ordering::Acquire, guard)),
+                    });
+                    link.store(replacement, Ordering::Release);
+
+                    // SAFETY: the node is unlinked, and readers still looking at it are pinned
+                    unsafe { guard.defer_destroy(existing) };
+
+                    Some(previous)
+                }
+                None => {
+                    let head = table.bucket(hash);
+                    let node = Owned::new(Node {
+                        hash,
+                        key,
+                        value,
+                        next: Atomic::from(head.load(Ordering::Acquire, guard)),
+                    });
+                    head.store(node, Ordering::Release);
+
+                    *len += 1;
+                    self.size.fetch_add(1, Ordering::SeqCst);
+
+                    None
+                }
+            }
+        })
+    }
+
+    pub async fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.remove_if(key, |_| true).await
+    }
+
+    /// Removes the value only if the predicate returns true for it, checked under the same lock as the removal
+    pub async fn remove_if<Q>(&self, key: &Q, predicate: impl FnOnce(&V) -> bool) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard) = self.hash_and_shard(key);
+        let guard = &epoch::pin();
+
+        self.write(shard, guard, |table, len| {
+            let (link, existing) = table.find(hash, key, guard);
+
+            // SAFETY: see `Table::find`
+            let node = unsafe { existing.as_ref() }?;
+
+            if !predicate(&node.value) {
+                return None;
+            }
+
+            link.store(node.next.load(Ordering::Acquire, guard), Ordering::Release);
+
+            *len -= 1;
+            self.size.fetch_sub(1, Ordering::SeqCst);
+
+            let value = node.value.clone();
+
+            // SAFETY: the node is unlinked, and readers still looking at it are pinned
+            unsafe { guard.defer_destroy(existing) };
+
+            Some(value)
+        })
+    }
+
+    /// Replaces the value with `f` applied to it, returning the new value, or `None` if the key is not present.
+    ///
+    /// Concurrent updates of the same shard are serialized, so none of them is lost.
+    pub async fn update<Q>(&self, key: &Q, f: impl FnOnce(&V) -> V) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard) = self.hash_and_shard(key);
+        let guard = &epoch::pin();
+
+        self.write(shard, guard, |table, _| {
+            let (link, existing) = table.find(hash, key, guard);
+
+            // SAFETY: see `Table::find`
+            let node = unsafe { existing.as_ref() }?;
+            let value = f(&node.value);
+
+            link.store(
+                Owned::new(Node {
+                    hash,
+                    key: node.key.clone(),
+                    value: value.clone(),
+                    next: Atomic::from(node.next.load(Ordering::Acquire, guard)),
+                }),
+                Ordering::Release,
+            );
+
+            // SAFETY: the node is unlinked, and readers still looking at it are pinned
+            unsafe { guard.defer_destroy(existing) };
+
+            Some(value)
+        })
+    }
+
+    /// Gets the value, or inserts the one returned by `on_insert` if the key is not present
+    pub async fn get_or_insert(&self, key: &K, on_insert: impl FnOnce() -> V) -> Ref<'_, K, V> {
+        self.get_or_insert_full(key, on_insert).await.0
+    }
+
+    /// Like `get_or_insert`, but also returns whether the value was inserted, i.e. `on_insert` was called
+    pub async fn get_or_insert_full(&self, key: &K, on_insert: impl FnOnce() -> V) -> (Ref<'_, K, V>, bool) {
+        if let Some(value) = self.get(key).await {
+            return (value, false);
+        }
+
+        let (hash, shard) = self.hash_and_shard(key);
+        let guard = epoch::pin();
+
+        let (node, inserted) = self.write(shard, &guard, |table, len| {
+            let guard = &guard;
+            let mut table = table;
+            let grown;
+
+            if *len >= table.buckets.len() {
+                grown = self.grow(shard, table, guard);
+                // SAFETY: the grown table was just published by this writer
+                table = unsafe { grown.deref() };
+            }
+
+            let (_, existing) = table.find(hash, key, guard);
+
+            // inserted by another writer since the lookup above
+            if !existing.is_null() {
+                return (existing.as_raw(), false);
+            }
+
+            let head = table.bucket(hash);
+            let node = Owned::new(Node {
+                hash,
+                key: key.clone(),
+                value: on_insert(),
+                next: Atomic::from(head.load(Ordering::Acquire, guard)),
+            })
+            .into_shared(guard);
+            head.store(node, Ordering::Release);
+
+            *len += 1;
+            self.size.fetch_add(1, Ordering::SeqCst);
+
+            (node.as_raw(), true)
+        });
+
+        // pinned since before the node was linked in, so it stays valid even if it is removed right away
+        let value = Ref {
+            node,
+            _guard: guard,
+            _map: PhantomData,
+        };
+
+        (value, inserted)
+    }
+
+    /// Removes every entry that `f` returns false for
+    pub async fn retain<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let guard = &epoch::pin();
+
+        for shard in self.shards.iter() {
+            self.write(shard, guard, |table, len| {
+                for bucket in table.buckets.iter() {
+                    let mut link = bucket;
+
+                    // SAFETY: see `Table::find`
+                    while let Some(node) = unsafe { link.load(Ordering::Acquire, guard).as_ref() } {
+                        if f(&node.key, &node.value) {
+                            link = &node.next;
+                            continue;
+                        }
+
+                        let existing = link.swap(node.next.load(Ordering::Acquire, guard), Ordering::AcqRel, guard);
+
+                        // counted as each entry goes, so a panicking `f` leaves the counts right
+                        *len -= 1;
+                        self.size.fetch_sub(1, Ordering::SeqCst);
+
+                        // SAFETY: the node is unlinked, and readers still looking at it are pinned
+                        unsafe { guard.defer_destroy(existing) };
+                    }
+                }
+            });
+        }
+    }
+
+    pub async fn clear(&self) {
+        let guard = &epoch::pin();
+
+        for shard in self.shards.iter() {
+            self.write(shard, guard, |_, len| {
+                let retired = shard
+                    .table
+                    .swap(Owned::new(Table::new(INITIAL_BUCKETS)), Ordering::AcqRel, guard);
+
+                self.size.fetch_sub(*len, Ordering::SeqCst);
+                *len = 0;
+
+                // SAFETY: the table is unlinked along with its entries, and readers still traversing it are pinned
+                unsafe { guard.defer_destroy(retired) };
+            });
+        }
+    }
+}
+
+impl<K, V, S> fmt::Debug for LockFreeMap<K, V, S>
+where
+    K: fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let guard = epoch::pin();
+        let mut map = f.debug_map();
+
+        for shard in self.shards.iter() {
+            // SAFETY: see `get`
+            let table = unsafe { shard.table.load(Ordering::Acquire, &guard).deref() };
+
+            for bucket in table.buckets.iter() {
+                let mut node = bucket.load(Ordering::Acquire, &guard);
+
+                // SAFETY: see `Table::find`
+                while let Some(entry) = unsafe { node.as_ref() } {
+                    map.entry(&entry.key, &entry.value);
+                    node = entry.next.load(Ordering::Acquire, &guard);
+                }
+            }
+        }
+
+        map.finish()
+    }
+}
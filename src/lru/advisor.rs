@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use hashbrown::HashMap;
+
+use crate::sync::{AtomicU64, Ordering};
+
+/// Number of capacity increments the ghost hits are counted in
+const INCREMENTS: usize = 16;
+
+/// Capacity change suggested by `LruCache::capacity_recommendation`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CapacityRecommendation {
+    /// Number of lookups the recommendation is based on
+    pub lookups: u64,
+    /// Ratio of those lookups that hit
+    pub hit_ratio: f64,
+    /// Number of entries to add to the capacity, `0` if growing is not worth it
+    pub extra_capacity: usize,
+    /// Ratio of the lookups that would have hit with `extra_capacity` more entries
+    pub expected_hit_ratio: f64,
+}
+
+/// Keys recently evicted for capacity, by hash, along with the eviction count when they were
+#[derive(Debug)]
+struct Ghosts {
+    evicted_at: HashMap<u64, u64>,
+    /// Ghosts in the order they were evicted, a key evicted again appearing twice
+    order: VecDeque<(u64, u64)>,
+}
+
+/// Estimates how much a larger capacity would raise the hit ratio of an `LruCache`, see `with_capacity_advisor`.
+///
+/// A miss on a key evicted `n` evictions ago, a ghost hit, would likely have been a hit with `n` more entries, as
+/// the key would have been pushed out by the `n`th entry beyond the capacity. Ghost hits are counted in increments
+/// of capacity, the marginal gain of each being its share of the lookups.
+#[derive(Debug)]
+pub(crate) struct CapacityAdvisor {
+    /// Extra capacity each increment stands for
+    step: usize,
+    /// Smallest gain in hit ratio per increment worth growing by
+    min_gain: f64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    ghost_hits: [AtomicU64; INCREMENTS],
+    ghosts: Mutex<Ghosts>,
+}
+
+impl CapacityAdvisor {
+    pub(crate) fn new(max_extra: usize, min_gain: f64) -> Self {
+        CapacityAdvisor {
+            step: max_extra.div_ceil(INCREMENTS).max(1),
+            min_gain,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            ghost_hits: [(); INCREMENTS].map(|_| AtomicU64::new(0)),
+            ghosts: Mutex::new(Ghosts {
+                evicted_at: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Advisor with the same settings and nothing recorded, for a duplicated cache
+    pub(crate) fn fresh(&self) -> Self {
+        CapacityAdvisor::new(self.step * INCREMENTS, self.min_gain)
+    }
+
+    #[inline]
+    pub(crate) fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_miss(&self, hash: u64) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let evicted_at = {
+            let mut ghosts = self.ghosts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+            match ghosts.evicted_at.remove(&hash) {
+                Some(evicted_at) => evicted_at,
+                None => return,
+            }
+        };
+
+        let distance = self.evictions.load(Ordering::Relaxed).saturating_sub(evicted_at) as usize;
+
+        if let Some(ghost_hits) = self.ghost_hits.get(distance / self.step) {
+            ghost_hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records the key of the hash as evicted to make room for another
+    pub(crate) fn record_eviction(&self, hash: u64) {
+        let evicted_at = self.evictions.fetch_add(1, Ordering::Relaxed);
+        let mut ghosts = self.ghosts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        ghosts.evicted_at.insert(hash, evicted_at);
+        ghosts.order.push_back((hash, evicted_at));
+
+        // ghosts evicted further back than the largest increment can't be counted anyway
+        while ghosts.order.len() > self.step * INCREMENTS {
+            let (hash, evicted_at) = match ghosts.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+
+            // unless the key was evicted again since
+            if ghosts.evicted_at.get(&hash) == Some(&evicted_at) {
+                ghosts.evicted_at.remove(&hash);
+            }
+        }
+    }
+
+    /// Grows by as many increments as still gain at least `min_gain` per increment on average, so a gain only
+    /// reached past a few unrewarding increments, e.g. once a whole working set fits, is still found
+    pub(crate) fn recommendation(&self) -> Option<CapacityRecommendation> {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let lookups = hits + self.misses.load(Ordering::Relaxed);
+
+        if lookups == 0 {
+            return None;
+        }
+
+        let ratio = |hits: u64| hits as f64 / lookups as f64;
+
+        let mut ghost_hits = 0;
+        let mut best = (0, 0);
+
+        for (increment, count) in self.ghost_hits.iter().enumerate() {
+            ghost_hits += count.load(Ordering::Relaxed);
+
+            if ghost_hits > best.1 && ratio(ghost_hits) >= self.min_gain * (increment + 1) as f64 {
+                best = (increment + 1, ghost_hits);
+            }
+        }
+
+        Some(CapacityRecommendation {
+            lookups,
+            hit_ratio: ratio(hits),
+            extra_capacity: best.0 * self.step,
+            expected_hit_ratio: ratio(hits + best.1),
+        })
+    }
+
+    /// Forgets the lookups recorded so far, keeping the ghosts
+    pub(crate) fn reset(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+
+        for ghost_hits in &self.ghost_hits {
+            ghost_hits.store(0, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,2344 @@
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use rand::Rng;
+
+use crate::clock::{Clock, DefaultClock};
+use crate::deadline::{self, Deadline, Progress};
+use crate::estimate::HyperLogLog;
+use crate::hash::ShardSelect;
+use crate::report::LoadReport;
+use crate::scan::{self, Cursor, Invalidated, OnInvalidated, PositionCursor};
+use crate::sync::{Arc, AtomicU64, AtomicUsize, Ordering, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+use crate::{read_handle, write_handle, CapacityFull, Defer, ReadHandle, WriteHandle};
+
+use super::advisor::{CapacityAdvisor, CapacityRecommendation};
+use super::domain::EvictionDomains;
+use super::shard::{self, IndexedShard};
+use super::view::ReadOnlyLruCache;
+use super::{
+    sample_victim, sample_victim_across, victim_in_domain, victim_in_window, AtomicInstant, AtomicTimestamp, EntryMeta,
+    EntryState, Evict, EvictLimit, EvictStop, EvictionOrder, ExpireAfter, Lookup, TimestampedValue, VictimOrder, NEVER,
+};
+
+type Shard<K, T> = Arc<RwLock<IndexedShard<K, T>>>;
+
+/// `ReadHandle` to a value of an `LruCache<K, V, T, _>`, as returned by `get`, `peek`, ...
+pub type LruReadHandle<K, V, T = AtomicInstant> = ReadHandle<IndexedShard<K, TimestampedValue<V, T>>, V>;
+
+/// `WriteHandle` to a value of an `LruCache<K, V, T, _>`, as returned by `get_mut` and `peek_mut`
+pub type LruWriteHandle<K, V, T = AtomicInstant> = WriteHandle<IndexedShard<K, TimestampedValue<V, T>>, V>;
+
+/// Handles to the whole `TimestampedValue` of an entry
+type RawReadHandle<K, V, T> = ReadHandle<IndexedShard<K, TimestampedValue<V, T>>, TimestampedValue<V, T>>;
+type RawWriteHandle<K, V, T> = WriteHandle<IndexedShard<K, TimestampedValue<V, T>>, TimestampedValue<V, T>>;
+
+/// Number of consecutive entries compared in a shard by RNG-free eviction,
+/// i.e. `evict_sweep` and enforcing the per-shard cap at insert
+const SAMPLE_WINDOW: usize = 8;
+
+/// Number of entries `retain_async` evaluates per acquisition of a shard's lock
+const RETAIN_CHUNK: usize = 64;
+
+/// A shard paired with its (approximate) length, which can be read without locking
+type SizedShard<K, V, T> = (Shard<K, TimestampedValue<V, T>>, AtomicUsize);
+
+#[derive(Debug)]
+pub struct LruCache<K, V, T = AtomicInstant, S = DefaultHashBuilder> {
+    hash_builder: S,
+    shards: Vec<SizedShard<K, V, T>>,
+    size: AtomicUsize,
+    /// Maximum deviation applied to TTLs at insert, in percent
+    ttl_jitter: u8,
+    /// Position of `evict_sweep` in its round-robin walk over the shards
+    sweep_cursor: AtomicUsize,
+    /// Weigher set by `with_weigher`, or `None` for a weight of 1 per entry without calling one
+    weigher: Option<fn(&K, &V) -> u32>,
+    /// Sum of the weights of all entries
+    weight: AtomicU64,
+    /// Number of writes to entries during a `retain_async`, each stamping the entry written with a new `version`
+    writes: AtomicU64,
+    /// Number of `retain_async` calls running, outside of which writes leave versions alone
+    retaining: AtomicUsize,
+    /// Maximum number of entries per shard, or `usize::MAX` for unbounded
+    max_shard_len: usize,
+    eviction_order: EvictionOrder,
+    /// Nanoseconds within which sampled entries are considered equally old, and the heavier one is evicted
+    weight_window: u64,
+    expire_after: ExpireAfter,
+    pub(crate) shard_select: ShardSelect,
+    advisor: Option<Box<CapacityAdvisor>>,
+    domains: Option<Box<EvictionDomains<K>>>,
+}
+
+impl<K, V, T> LruCache<K, V, T, DefaultHashBuilder> {
+    pub fn new(num_shards: usize) -> Self {
+        Self::with_hasher(num_shards, DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V> Default for LruCache<K, V, AtomicInstant, DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new(crate::default_num_shards())
+    }
+}
+
+impl<K, V, T, S> LruCache<K, V, T, S> {
+    pub fn with_hasher(num_shards: usize, hash_builder: S) -> Self {
+        LruCache {
+            shards: (0..num_shards)
+                .map(|_| (Arc::new(RwLock::new(IndexedShard::new())), AtomicUsize::new(0)))
+                .collect(),
+            hash_builder,
+            size: AtomicUsize::new(0),
+            ttl_jitter: 0,
+            sweep_cursor: AtomicUsize::new(0),
+            weigher: None,
+            weight: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            retaining: AtomicUsize::new(0),
+            max_shard_len: usize::MAX,
+            eviction_order: EvictionOrder::Lru,
+            weight_window: 0,
+            expire_after: ExpireAfter::Write,
+            shard_select: ShardSelect::Modulo,
+            advisor: None,
+            domains: None,
+        }
+    }
+
+    /// Peek-only view sharing the cache's shards, see [`ReadOnlyLruCache`]
+    pub fn read_only_view(&self) -> ReadOnlyLruCache<K, V, T, S>
+    where
+        S: Clone,
+    {
+        ReadOnlyLruCache::new(
+            self.hash_builder.clone(),
+            self.shard_select.clone(),
+            self.shards.iter().map(|(shard, _)| shard.clone()).collect(),
+        )
+    }
+
+    /// Randomly spreads the TTLs given to `insert_with_ttl` by up to ±`percent`% (clamped to 100),
+    /// so entries inserted together, e.g. during a bulk warm-up, do not all expire at the same moment.
+    pub fn with_ttl_jitter(mut self, percent: u8) -> Self {
+        self.ttl_jitter = percent.min(100);
+        self
+    }
+
+    #[inline]
+    pub fn ttl_jitter(&self) -> u8 {
+        self.ttl_jitter
+    }
+
+    /// Sets the function used to weigh entries, e.g. by their size in bytes, which `weight` and `evict_weight` are based on.
+    ///
+    /// Entries are weighed when inserted, so mutating a value in-place does not change its weight.
+    /// Defaults to a weight of 1 per entry, without calling a weigher.
+    ///
+    /// Like TTLs and eviction domains, weights other than 1 are kept in metadata allocated per entry (32 bytes),
+    /// which entries not using any of these features go without.
+    pub fn with_weigher(mut self, weigher: fn(&K, &V) -> u32) -> Self {
+        self.weigher = Some(weigher);
+        self
+    }
+
+    /// Caps each shard at `capacity / num_shards` entries plus 25% slack, evicting an old entry of the same shard
+    /// when inserting a new key into a full one.
+    ///
+    /// This is not a precise bound on the total size, but it keeps a pathological hash distribution
+    /// from ballooning a single shard, along with the latency of operations on it.
+    pub fn with_max_capacity(mut self, capacity: usize) -> Self {
+        let per_shard = capacity.div_ceil(self.shards.len().max(1));
+
+        self.max_shard_len = per_shard.saturating_add(per_shard / 4).max(1);
+        self
+    }
+
+    /// Maximum number of entries per shard, if capped with `with_max_capacity`
+    #[inline]
+    pub fn max_shard_len(&self) -> Option<usize> {
+        match self.max_shard_len {
+            usize::MAX => None,
+            max => Some(max),
+        }
+    }
+
+    /// Tracks the hit ratio of `get` and its variants, along with the keys recently evicted by `with_max_capacity`,
+    /// to estimate how much growing the capacity by up to `max_extra` entries would raise the hit ratio,
+    /// see `capacity_recommendation`. Growing is only recommended by increments of `max_extra / 16` entries that
+    /// each raise the hit ratio by at least `min_gain` on average, e.g. `0.01` for a percentage point.
+    ///
+    /// Misses and evictions take a mutex shared by the whole cache, so this is best enabled while tuning.
+    pub fn with_capacity_advisor(mut self, max_extra: usize, min_gain: f64) -> Self {
+        self.advisor = Some(Box::new(CapacityAdvisor::new(max_extra, min_gain)));
+        self
+    }
+
+    /// Extra capacity worth adding, going by the lookups since the advisor was enabled or last reset,
+    /// or `None` without an advisor (see `with_capacity_advisor`) or lookups yet.
+    ///
+    /// ```
+    /// # use quick_hash_cache::lru::LruCache;
+    /// # #[tokio::main] async fn main() {
+    /// let cache = LruCache::<u32, u32>::new(1).with_max_capacity(100).with_capacity_advisor(400, 0.01);
+    ///
+    /// // cycling through 200 keys, twice the capacity
+    /// for round in 0..10 {
+    ///     for key in 0..200 {
+    ///         if cache.get(&key).await.is_none() {
+    ///             cache.insert(key, round).await;
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let advice = cache.capacity_recommendation().unwrap();
+    /// assert!(advice.extra_capacity >= 75);
+    /// assert!(advice.expected_hit_ratio > advice.hit_ratio);
+    /// # }
+    /// ```
+    pub fn capacity_recommendation(&self) -> Option<CapacityRecommendation> {
+        self.advisor.as_ref()?.recommendation()
+    }
+
+    /// Forgets the lookups the capacity advisor recorded, e.g. after resizing the cache or once the workload shifts
+    pub fn reset_capacity_advisor(&self) {
+        if let Some(ref advisor) = self.advisor {
+            advisor.reset();
+        }
+    }
+
+    /// Splits the capacity of every shard (see `with_max_capacity`) between eviction domains, e.g. one per tenant,
+    /// so that one domain's traffic can't evict another's entries. `domain_of` gives the domain of each key,
+    /// as an index into `shares`, the relative share of the capacity of each domain. Keys of domains past the last
+    /// one count towards the last one.
+    ///
+    /// Inserting a new key of a domain holding its share of the shard already evicts an entry of the same domain,
+    /// whether or not the shard is full. Only a domain whose share amounts to less than one entry per shard,
+    /// which is rounded up to one, may push a shard past its capacity into evicting another domain's entries.
+    ///
+    /// Eviction outside of inserts, e.g. `evict_many`, is not limited to any domain.
+    ///
+    /// ```
+    /// # use quick_hash_cache::lru::LruCache;
+    /// # #[tokio::main] async fn main() {
+    /// // tenant 0 gets three quarters of the 100 entries of the shard, 80 plus slack, and tenant 1 a quarter
+    /// let cache = LruCache::<(u8, u32), ()>::new(1)
+    ///     .with_max_capacity(80)
+    ///     .with_eviction_domains(|&(tenant, _)| tenant as usize, &[3, 1]);
+    ///
+    /// for key in 0..75 {
+    ///     cache.insert((0, key), ()).await;
+    /// }
+    ///
+    /// // a burst from tenant 1 only evicts its own entries
+    /// for key in 0..1000 {
+    ///     cache.insert((1, key), ()).await;
+    /// }
+    ///
+    /// assert_eq!(cache.domain_len(0).await, 75);
+    /// assert_eq!(cache.domain_len(1).await, 25);
+    /// # }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `shares` is empty or has more than 65536 domains.
+    pub fn with_eviction_domains(mut self, domain_of: fn(&K) -> usize, shares: &[u32]) -> Self {
+        self.domains = Some(Box::new(EvictionDomains::new(domain_of, shares)));
+        self
+    }
+
+    /// Sets which of the entries sampled by eviction is evicted, the least recently used by default
+    pub fn with_eviction_order(mut self, order: EvictionOrder) -> Self {
+        self.eviction_order = order;
+        self
+    }
+
+    #[inline]
+    pub fn eviction_order(&self) -> EvictionOrder {
+        self.eviction_order
+    }
+
+    /// Makes eviction prefer heavier entries, by the weigher set with `with_weigher`, among those sampled whose
+    /// timestamps are at most `window` apart, so a single large entry is reclaimed rather than many small ones
+    /// of about the same age.
+    ///
+    /// The window trades recency for weight: zero, the default, compares timestamps alone, and the wider it is,
+    /// the more recently used a heavy entry can be and still be evicted before a light one. Entries outside the
+    /// window of each other are still ordered by `EvictionOrder`.
+    pub fn with_weight_preference(mut self, window: Duration) -> Self {
+        self.weight_window = window.as_nanos().min(u64::MAX as u128) as u64;
+        self
+    }
+
+    /// Window set with `with_weight_preference`, if any
+    #[inline]
+    pub fn weight_preference(&self) -> Option<Duration> {
+        match self.weight_window {
+            0 => None,
+            window => Some(Duration::from_nanos(window)),
+        }
+    }
+
+    #[inline]
+    fn victim_order(&self) -> VictimOrder {
+        VictimOrder {
+            order: self.eviction_order,
+            weight_window: self.weight_window,
+        }
+    }
+
+    /// Sets whether reads push back the deadline of entries inserted with a TTL, or only writes do, by default.
+    /// Overridable per entry with `insert_with_ttl_expiring_after`.
+    pub fn with_expire_after(mut self, expire_after: ExpireAfter) -> Self {
+        self.expire_after = expire_after;
+        self
+    }
+
+    #[inline]
+    pub fn expire_after(&self) -> ExpireAfter {
+        self.expire_after
+    }
+
+    /// Total weight of all entries, see `with_weigher`
+    #[inline]
+    pub fn weight(&self) -> u64 {
+        self.weight.load(Ordering::SeqCst)
+    }
+}
+
+impl<V, T> TimestampedValue<V, T> {
+    /// The entry's metadata, allocated with the defaults if it has none yet
+    #[inline]
+    fn meta_mut(&mut self) -> &mut EntryMeta {
+        self.meta.get_or_insert_with(Box::default)
+    }
+
+    /// Sets a field of the metadata, only allocating it if the value differs from the default
+    #[inline]
+    fn set_meta<F: PartialEq>(&mut self, value: F, default: F, field: impl FnOnce(&mut EntryMeta) -> &mut F) {
+        if self.meta.is_some() || value != default {
+            *field(self.meta_mut()) = value;
+        }
+    }
+
+    #[inline]
+    fn set_weight(&mut self, weight: u32) {
+        self.set_meta(weight, 1, |meta| &mut meta.weight);
+    }
+
+    #[inline]
+    fn set_domain(&mut self, domain: u16) {
+        self.set_meta(domain, 0, |meta| &mut meta.domain);
+    }
+
+    #[inline]
+    fn version(&self) -> u64 {
+        self.meta.as_ref().map_or(0, |meta| meta.version)
+    }
+
+    /// Sets the deadline `ttl` nanoseconds from now, pushed back by the same on access under `ExpireAfter::Access`
+    #[inline]
+    fn set_ttl(&mut self, ttl: u64, expire_after: ExpireAfter) {
+        let meta = self.meta_mut();
+
+        meta.expires_at = AtomicU64::new(DefaultClock::now().saturating_add(ttl));
+        meta.refresh_ttl = match expire_after {
+            ExpireAfter::Write => 0,
+            ExpireAfter::Access => ttl,
+        };
+    }
+
+    /// Removes the deadline, if any
+    #[inline]
+    fn clear_ttl(&mut self) {
+        if let Some(ref mut meta) = self.meta {
+            meta.expires_at = AtomicU64::new(NEVER);
+            meta.refresh_ttl = 0;
+        }
+    }
+
+    #[inline]
+    fn expires_at(&self) -> u64 {
+        self.meta.as_ref().map_or(NEVER, |meta| meta.expires_at.load(Ordering::Relaxed))
+    }
+
+    /// Records an access to the entry, updating its timestamp if `tracks_access`, and pushing back its deadline
+    /// if it expires after access
+    #[inline]
+    fn touch(&self, tracks_access: bool)
+    where
+        T: AtomicTimestamp,
+    {
+        if tracks_access {
+            self.timestamp.update();
+        }
+
+        match self.meta {
+            Some(ref meta) if meta.refresh_ttl != 0 => {
+                // concurrent readers may race, the latest deadline wins
+                meta.expires_at
+                    .fetch_max(DefaultClock::now().saturating_add(meta.refresh_ttl), Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    fn state(&self, now: u64) -> EntryState {
+        EntryState {
+            age: Duration::from_nanos(now.saturating_sub(self.inserted_at)),
+            ttl_remaining: match self.expires_at() {
+                NEVER => None,
+                expires_at => Some(Duration::from_nanos(expires_at.saturating_sub(now))),
+            },
+            weight: self.weight(),
+        }
+    }
+
+    #[inline]
+    pub(super) fn is_expired(&self) -> bool {
+        let expires_at = self.expires_at();
+        expires_at != NEVER && expires_at <= DefaultClock::now()
+    }
+
+    #[inline]
+    fn is_older_than(&self, max_age: Duration) -> bool {
+        let max_age = max_age.as_nanos().min(u64::MAX as u128) as u64;
+
+        DefaultClock::now().saturating_sub(self.inserted_at) > max_age
+    }
+}
+
+impl<K, V, T, S> LruCache<K, V, T, S>
+where
+    S: Clone,
+    K: Clone,
+    V: Clone,
+    T: AtomicTimestamp,
+{
+    /// Attempts to duplicate/clone the LruCache. An LruCache cannot be cloned regularly due to internal asynchronous locking.
+    pub async fn duplicate(&self) -> Self {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        let mut size = 0;
+        let mut weight = 0;
+
+        for shard in &self.shards {
+            let shard = shard.0.read().await.clone();
+
+            let shard_len = shard.len();
+            size += shard_len;
+            weight += shard.entries.iter().map(|bucket| bucket.value.weight() as u64).sum::<u64>();
+            shards.push((Arc::new(RwLock::new(shard)), AtomicUsize::new(shard_len)));
+        }
+
+        LruCache {
+            shards,
+            hash_builder: self.hash_builder.clone(),
+            size: AtomicUsize::new(size),
+            ttl_jitter: self.ttl_jitter,
+            sweep_cursor: AtomicUsize::new(self.sweep_cursor.load(Ordering::SeqCst)),
+            weigher: self.weigher,
+            weight: AtomicU64::new(weight),
+            writes: AtomicU64::new(self.writes.load(Ordering::SeqCst)),
+            retaining: AtomicUsize::new(0),
+            max_shard_len: self.max_shard_len,
+            eviction_order: self.eviction_order,
+            weight_window: self.weight_window,
+            expire_after: self.expire_after,
+            shard_select: self.shard_select.clone(),
+            advisor: self.advisor.as_ref().map(|advisor| Box::new(advisor.fresh())),
+            domains: self.domains.clone(),
+        }
+    }
+}
+
+impl<K, V, T, S> LruCache<K, V, T, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+    T: AtomicTimestamp,
+{
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Like `size`, but with a relaxed load, which may lag behind concurrent writes.
+    /// Meant for monitoring, where it is read often and exactness does not matter.
+    #[inline]
+    pub fn estimate_len(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Estimates the number of distinct values in the cache with a [`HyperLogLog`] over their hashes,
+    /// e.g. to monitor how much deduplicating values would save. Expired entries are skipped.
+    ///
+    /// Shards are read-locked one at a time, so the estimate is not of a single point in time.
+    pub async fn estimate_distinct_values(&self) -> f64
+    where
+        V: Hash,
+    {
+        let mut hll = HyperLogLog::new();
+
+        for (shard, _) in &self.shards {
+            for bucket in shard.read().await.entries.iter() {
+                if !bucket.value.is_expired() {
+                    hll.insert_hash(self.hash_builder.hash_one(&bucket.value.value));
+                }
+            }
+        }
+
+        hll.estimate()
+    }
+
+    #[cfg(test)]
+    pub async fn test_size(&self) -> usize {
+        let mut size = 0;
+        for shard in &self.shards {
+            size += shard.0.read().await.len();
+        }
+
+        size
+    }
+
+    #[inline]
+    pub fn hash_builder(&self) -> &S {
+        &self.hash_builder
+    }
+
+    #[inline]
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Index of the shard that a key belongs to, e.g. to route work on the key to a per-shard worker task
+    pub fn shard_of<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        self.hash_and_shard(key).1
+    }
+
+    /// Returns up to `limit` entries from the cursor, starting from `Cursor::START`, along with the cursor of the
+    /// next page, or `None` once every shard has been paged through. See [`Cursor`].
+    ///
+    /// Shards are read-locked one at a time, and only during the call. Expired entries are skipped, and
+    /// timestamps are not updated.
+    pub async fn scan(&self, cursor: Cursor, limit: usize) -> (Vec<(K, V)>, Option<Cursor>)
+    where
+        K: Clone,
+        V: Clone,
+    {
+        scan::scan(self.shards.len(), cursor, limit, |shard_idx, from, limit| async move {
+            let shard = self.shards[shard_idx].0.read().await;
+            let entries = shard
+                .entries
+                .iter()
+                .filter(|bucket| !bucket.value.is_expired())
+                .map(|bucket| (bucket.hash, &bucket.key, &bucket.value));
+
+            scan::page_by_hash(entries, from, limit, |key, tv| (key.clone(), tv.value.clone()))
+        })
+        .await
+    }
+
+    /// Like `scan`, but pages through each shard in the order entries are stored in, which is cheaper per page
+    /// than sorting by hash, at the cost of missing entries moved by removals between pages, see [`PositionCursor`].
+    ///
+    /// ```
+    /// # use quick_hash_cache::{lru::LruCache, OnInvalidated, PositionCursor};
+    /// # #[tokio::main] async fn main() {
+    /// let cache: LruCache<u32, u32> = LruCache::new(1);
+    /// cache.warm((0..4).map(|i| (i, i))).await;
+    ///
+    /// let (page, cursor) = cache.scan_positions(PositionCursor::START, 2, OnInvalidated::Fail).await.unwrap();
+    /// assert_eq!(page.len(), 2);
+    ///
+    /// cache.remove(&page[0].0).await;
+    /// assert!(cache.scan_positions(cursor.unwrap(), 2, OnInvalidated::Fail).await.is_err());
+    /// # }
+    /// ```
+    pub async fn scan_positions(
+        &self,
+        cursor: PositionCursor,
+        limit: usize,
+        on_invalidated: OnInvalidated,
+    ) -> Result<(Vec<(K, V)>, Option<PositionCursor>), Invalidated>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let mut entries = Vec::new();
+        let PositionCursor {
+            shard: mut shard_idx,
+            mut index,
+            mut generation,
+        } = cursor;
+
+        while shard_idx < self.shards.len() {
+            let shard = self.shards[shard_idx].0.read().await;
+
+            // a shard paged through from its start has nothing to be invalidated
+            if index > 0 && shard.generation != generation && on_invalidated == OnInvalidated::Fail {
+                return Err(Invalidated { shard: shard_idx });
+            }
+
+            generation = shard.generation;
+
+            while let Some(bucket) = shard.entries.get(index) {
+                if entries.len() >= limit {
+                    let cursor = PositionCursor {
+                        shard: shard_idx,
+                        index,
+                        generation,
+                    };
+
+                    return Ok((entries, Some(cursor)));
+                }
+
+                if !bucket.value.is_expired() {
+                    entries.push((bucket.key.clone(), bucket.value.value.clone()));
+                }
+
+                index += 1;
+            }
+
+            shard_idx += 1;
+            index = 0;
+        }
+
+        Ok((entries, None))
+    }
+
+    /// Entry counts and load factors of every shard, to check how evenly the hasher spreads keys
+    pub async fn load_report(&self) -> LoadReport {
+        let mut shards = Vec::with_capacity(self.shards.len());
+
+        for (shard, _) in &self.shards {
+            let shard = shard.read().await;
+            shards.push((shard.len(), shard.indices.capacity()));
+        }
+
+        LoadReport::new(shards)
+    }
+
+    pub async fn retain<F>(&self, f: F)
+    where
+        F: Fn(&K, &mut V) -> bool,
+    {
+        let _ = self.retain_until(Cursor::START, &Deadline::NEVER, f).await;
+    }
+
+    /// Like `retain`, but gives up between shards once the deadline is reached, starting from the cursor's shard.
+    pub async fn retain_until<F>(&self, from: Cursor, deadline: &Deadline, f: F) -> Progress
+    where
+        F: Fn(&K, &mut V) -> bool,
+    {
+        for (idx, (shard, _)) in self.shards.iter().enumerate().skip(deadline::first_shard(from)) {
+            if deadline.is_reached() {
+                return Progress::interrupted_at(idx);
+            }
+
+            let mut shard = shard.write().await;
+
+            let (removed, removed_weight) = (Cell::new(0), Cell::new(0));
+            let _sync = Defer(|| {
+                self.size.fetch_sub(removed.get(), Ordering::SeqCst);
+                self.weight.fetch_sub(removed_weight.get(), Ordering::SeqCst);
+            });
+
+            shard.retain(|k, tv| {
+                self.stamp_write(tv);
+
+                let keep = f(k, &mut tv.value);
+                if !keep {
+                    removed.set(removed.get() + 1);
+                    removed_weight.set(removed_weight.get() + tv.weight() as u64);
+                }
+                keep
+            });
+
+            self.recount_domains(&mut shard);
+        }
+
+        Progress::Done
+    }
+
+    /// Like `retain`, but with an async predicate, which is also given the `EntryState` of each entry,
+    /// e.g. to revalidate entries close to expiring against their source.
+    ///
+    /// The predicate is given clones of the key and value, and no lock is held while it runs:
+    /// each shard's keys are snapshotted, then evaluated in chunks, and the entries rejected in a chunk
+    /// are removed under a brief write lock, unless they were written in the meantime, e.g. overwritten
+    /// or lent out by `get_mut`.
+    /// Entries inserted after their shard's snapshot are not evaluated.
+    ///
+    /// To tell, entries written while any `retain_async` runs are stamped with a version in their metadata,
+    /// allocating it if they had none.
+    pub async fn retain_async<F, Fut>(&self, mut f: F)
+    where
+        K: Clone,
+        V: Clone,
+        F: FnMut(K, V, EntryState) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        // writes stamp versions from here on, until returning or being cancelled
+        self.retaining.fetch_add(1, Ordering::SeqCst);
+        let _retaining = Defer(|| {
+            self.retaining.fetch_sub(1, Ordering::SeqCst);
+        });
+
+        for (locked_shard, shard_size) in &self.shards {
+            let keys: Vec<K> = locked_shard.read().await.entries.iter().map(|bucket| bucket.key.clone()).collect();
+
+            for chunk in keys.chunks(RETAIN_CHUNK) {
+                let mut candidates = Vec::with_capacity(chunk.len());
+
+                {
+                    let shard = locked_shard.read().await;
+                    let now = DefaultClock::now();
+
+                    for key in chunk {
+                        let hash = self.hash_builder.hash_one(key);
+
+                        if let Some(tv) = shard.get(hash, key) {
+                            candidates.push((key, hash, tv.version(), tv.value.clone(), tv.state(now)));
+                        }
+                    }
+                }
+
+                let mut rejected = Vec::new();
+
+                for (key, hash, version, value, state) in candidates {
+                    if !f(key.clone(), value, state).await {
+                        rejected.push((key, hash, version));
+                    }
+                }
+
+                if rejected.is_empty() {
+                    continue;
+                }
+
+                let mut shard = locked_shard.write().await;
+
+                for (key, hash, version) in rejected {
+                    match shard.get_index_of(hash, key) {
+                        // otherwise it was written while the predicate ran, and the new value was not evaluated
+                        Some(idx) if shard.entries[idx].value.version() == version => {
+                            let (_, tv) = unsafe { shard.swap_remove_index_raw(idx) };
+
+                            self.size.fetch_sub(1, Ordering::SeqCst);
+                            self.weight.fetch_sub(tv.weight() as u64, Ordering::SeqCst);
+                            shard.uncount_domain(tv.domain());
+                        }
+                        _ => {}
+                    }
+                }
+
+                shard_size.store(shard.len(), Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub async fn clear(&self) {
+        let _ = self.clear_until(Cursor::START, &Deadline::NEVER).await;
+    }
+
+    /// Like `clear`, but gives up between shards once the deadline is reached, starting from the cursor's shard.
+    pub async fn clear_until(&self, from: Cursor, deadline: &Deadline) -> Progress {
+        for (idx, (shard, _)) in self.shards.iter().enumerate().skip(deadline::first_shard(from)) {
+            if deadline.is_reached() {
+                return Progress::interrupted_at(idx);
+            }
+
+            let mut shard = shard.write().await;
+            let len = shard.len();
+            let weight = shard.entries.iter().map(|bucket| bucket.value.weight() as u64).sum();
+            shard.clear();
+
+            self.size.fetch_sub(len, Ordering::SeqCst);
+            self.weight.fetch_sub(weight, Ordering::SeqCst);
+        }
+
+        Progress::Done
+    }
+
+    #[inline]
+    fn hash_and_shard<Q>(&self, key: &Q) -> (u64, usize)
+    where
+        Q: ?Sized + Hash,
+    {
+        let hash = self.hash_builder.hash_one(key);
+        (hash, self.shard_select.shard_of(hash, self.shards.len()))
+    }
+
+    /// Recounts the entries of each eviction domain of the locked shard, after removing entries in bulk
+    fn recount_domains(&self, shard: &mut IndexedShard<K, TimestampedValue<V, T>>) {
+        if self.domains.is_none() {
+            return;
+        }
+
+        let mut lens = core::mem::take(&mut shard.domain_lens);
+        lens.iter_mut().for_each(|len| *len = 0);
+
+        for bucket in &shard.entries {
+            let domain = bucket.value.domain() as usize;
+
+            if domain >= lens.len() {
+                lens.resize(domain + 1, 0);
+            }
+
+            lens[domain] += 1;
+        }
+
+        shard.domain_lens = lens;
+    }
+
+    /// Records a lookup of `get` or its variants with the capacity advisor, if any
+    #[inline]
+    fn record_lookup<Q>(&self, key: &Q, hit: bool)
+    where
+        Q: ?Sized + Hash,
+    {
+        if let Some(ref advisor) = self.advisor {
+            match hit {
+                true => advisor.record_hit(),
+                false => advisor.record_miss(self.hash_builder.hash_one(key)),
+            }
+        }
+    }
+
+    async fn get_mut_raw<Q>(
+        &self,
+        key: &Q,
+    ) -> Option<RawWriteHandle<K, V, T>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).0.clone().write_owned().await };
+
+        let tv = OwnedRwLockWriteGuard::try_map(shard, |shard| shard.get_mut(hash, key).filter(|tv| !tv.is_expired()));
+
+        tv.ok().map(|mut tv| {
+            self.stamp_write(&mut tv);
+            write_handle(tv)
+        })
+    }
+
+    async fn get_raw<Q>(&self, key: &Q) -> Option<RawReadHandle<K, V, T>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).0.clone().read_owned().await };
+
+        OwnedRwLockReadGuard::try_map(shard, |shard| shard.get(hash, key).filter(|tv| !tv.is_expired()))
+            .ok()
+            .map(read_handle)
+    }
+
+    pub async fn peek<Q>(&self, key: &Q) -> Option<LruReadHandle<K, V, T>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.get_raw(key)
+            .await
+            .map(|tv| ReadHandle::map(tv, |tv| &tv.value))
+    }
+
+    pub async fn peek_mut<Q>(&self, key: &Q) -> Option<LruWriteHandle<K, V, T>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.get_mut_raw(key)
+            .await
+            .map(|tv| WriteHandle::map(tv, |tv| &mut tv.value))
+    }
+
+    pub async fn get<Q>(&self, key: &Q) -> Option<LruReadHandle<K, V, T>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let tv = self.get_raw(key).await;
+        self.record_lookup(key, tv.is_some());
+
+        if let Some(ref tv) = tv {
+            tv.touch(self.eviction_order.tracks_access());
+        }
+
+        tv.map(|tv| ReadHandle::map(tv, |tv| &tv.value))
+    }
+
+    pub async fn get_mut<Q>(&self, key: &Q) -> Option<LruWriteHandle<K, V, T>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let mut tv = self.get_mut_raw(key).await;
+        self.record_lookup(key, tv.is_some());
+
+        if let Some(ref mut tv) = tv {
+            tv.touch(self.eviction_order.tracks_access());
+        }
+
+        tv.map(|tv| WriteHandle::map(tv, |tv| &mut tv.value))
+    }
+
+    pub async fn get_cloned<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        let tv = self.get_raw(key).await;
+        self.record_lookup(key, tv.is_some());
+
+        tv.map(|tv| {
+            tv.touch(self.eviction_order.tracks_access());
+            tv.value.clone()
+        })
+    }
+
+    /// Marks the entry as the most recently used, as `get` does, without reading it.
+    /// Returns whether the key is present.
+    pub async fn promote<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        match self.get_raw(key).await {
+            Some(tv) => {
+                tv.touch(self.eviction_order.tracks_access());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Marks the entries of all the given keys as the most recently used, like `promote`, read-locking each shard
+    /// once, e.g. to declare a working set as hot ahead of a latency-critical phase.
+    /// Returns the number of keys present.
+    pub async fn touch_batch<'a, Q>(&self, keys: impl IntoIterator<Item = &'a Q>) -> usize
+    where
+        K: Borrow<Q>,
+        Q: 'a + ?Sized + Hash + Eq,
+    {
+        let mut by_shard: Vec<Vec<(u64, &Q)>> = (0..self.shards.len()).map(|_| Vec::new()).collect();
+
+        for key in keys {
+            let (hash, shard_idx) = self.hash_and_shard(key);
+            by_shard[shard_idx].push((hash, key));
+        }
+
+        let tracks_access = self.eviction_order.tracks_access();
+        let mut touched = 0;
+
+        for ((locked_shard, _), keys) in self.shards.iter().zip(by_shard) {
+            if keys.is_empty() {
+                continue;
+            }
+
+            let shard = locked_shard.read().await;
+
+            for (hash, key) in keys {
+                if let Some(tv) = shard.get(hash, key).filter(|tv| !tv.is_expired()) {
+                    tv.touch(tracks_access);
+                    touched += 1;
+                }
+            }
+        }
+
+        touched
+    }
+
+    /// Marks the entry as the least recently used, so it is the preferred victim of eviction under
+    /// `EvictionOrder::Lru` and `Fifo`, e.g. once a value read by a one-shot scan was used, so streaming reads
+    /// don't push out the hot set. Under `EvictionOrder::Mru`, it is instead kept the longest.
+    ///
+    /// The entry is demoted until it is next accessed. Returns whether the key is present.
+    pub async fn demote<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        match self.get_raw(key).await {
+            Some(tv) => {
+                tv.timestamp.demote();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Like `get`, but also returns values whose TTL has passed, as `Lookup::Stale`, in the same lookup.
+    ///
+    /// Only hits update the timestamp of the entry.
+    pub async fn lookup<Q>(&self, key: &Q) -> Lookup<LruReadHandle<K, V, T>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).0.clone().read_owned().await };
+
+        let tv = match OwnedRwLockReadGuard::try_map(shard, |shard| shard.get(hash, key)) {
+            Ok(tv) => read_handle(tv),
+            Err(_) => {
+                self.record_lookup(key, false);
+                return Lookup::Miss;
+            }
+        };
+
+        self.record_lookup(key, !tv.is_expired());
+
+        if tv.is_expired() {
+            return Lookup::Stale(ReadHandle::map(tv, |tv| &tv.value));
+        }
+
+        tv.touch(self.eviction_order.tracks_access());
+
+        Lookup::Hit(ReadHandle::map(tv, |tv| &tv.value))
+    }
+
+    /// Like `get`, but treats a value inserted more than `max_age` ago as a miss, for callers with
+    /// their own freshness requirements. The stale value is left in place, see `remove_if_stale`.
+    pub async fn get_if_fresh<Q>(&self, key: &Q, max_age: Duration) -> Option<LruReadHandle<K, V, T>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let tv = ReadHandle::try_map(self.get_raw(key).await?, |tv| Some(tv).filter(|tv| !tv.is_older_than(max_age)))
+            .ok()?;
+
+        tv.touch(self.eviction_order.tracks_access());
+
+        Some(ReadHandle::map(tv, |tv| &tv.value))
+    }
+
+    /// Removes the value if it was inserted more than `max_age` ago, returning it
+    pub async fn remove_if_stale<Q>(&self, key: &Q, max_age: Duration) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let (locked_shard, shard_size) = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        let mut shard = locked_shard.write().await;
+
+        let idx = shard.get_index_of(hash, key)?;
+
+        if !shard.entries[idx].value.is_older_than(max_age) {
+            return None;
+        }
+
+        let (_, tv) = unsafe { shard.swap_remove_index_raw(idx) };
+
+        self.size.fetch_sub(1, Ordering::SeqCst);
+        self.weight.fetch_sub(tv.weight() as u64, Ordering::SeqCst);
+        shard.uncount_domain(tv.domain());
+        shard_size.store(shard.len(), Ordering::SeqCst);
+
+        match tv.is_expired() {
+            true => None,
+            false => Some(tv.value),
+        }
+    }
+
+    /// Returns the value if it is fresh by `max_age` (see `get_if_fresh`), or else replaces it with the value
+    /// from `on_insert`, expiring after `ttl` (see `insert_with_ttl`).
+    ///
+    /// `on_insert` is called with the shard locked.
+    pub async fn get_or_insert_with_ttl(
+        &self,
+        key: &K,
+        max_age: Duration,
+        ttl: Duration,
+        on_insert: impl FnOnce() -> V,
+    ) -> LruReadHandle<K, V, T>
+    where
+        K: Clone,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let (locked_shard, shard_size) = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        let mut shard = locked_shard.clone().write_owned().await;
+
+        let fresh = shard.get_index_of(hash, key).filter(|&idx| {
+            let tv = &shard.entries[idx].value;
+            !tv.is_expired() && !tv.is_older_than(max_age)
+        });
+
+        let idx = match fresh {
+            Some(idx) => {
+                shard.entries[idx].value.touch(self.eviction_order.tracks_access());
+
+                idx
+            }
+            None => {
+                let mut value = TimestampedValue::new(on_insert(), T::now());
+                value.set_ttl(self.jittered_ttl(hash, ttl), self.expire_after);
+
+                self.insert_locked(&mut shard, shard_size, hash, key.clone(), value).0
+            }
+        };
+
+        read_handle(OwnedRwLockReadGuard::map(OwnedRwLockWriteGuard::downgrade(shard), |shard| {
+            &shard.entries[idx].value.value
+        }))
+    }
+
+    /// Returns the value, or inserts the one from `on_insert` if absent or expired, evicting an entry of the shard
+    /// if full (see `with_max_capacity`).
+    ///
+    /// `on_insert` is called with the shard locked.
+    pub async fn get_or_insert(&self, key: &K, on_insert: impl FnOnce() -> V) -> LruReadHandle<K, V, T>
+    where
+        K: Clone,
+    {
+        self.get_or_insert_full(key, on_insert).await.0
+    }
+
+    /// Like `get_or_insert`, but also returns whether the value was inserted, i.e. `on_insert` was called,
+    /// for side effects to run only on first insertion
+    pub async fn get_or_insert_full(&self, key: &K, on_insert: impl FnOnce() -> V) -> (LruReadHandle<K, V, T>, bool)
+    where
+        K: Clone,
+    {
+        let (shard, idx, inserted) = self.get_or_insert_locked(key, on_insert).await;
+
+        let handle = read_handle(OwnedRwLockReadGuard::map(OwnedRwLockWriteGuard::downgrade(shard), |shard| {
+            &shard.entries[idx].value.value
+        }));
+
+        (handle, inserted)
+    }
+
+    /// Like `get_or_insert`, but returns a `WriteHandle`, e.g. to update a per-key accumulator in place
+    pub async fn get_mut_or_insert(&self, key: &K, on_insert: impl FnOnce() -> V) -> LruWriteHandle<K, V, T>
+    where
+        K: Clone,
+    {
+        self.get_mut_or_insert_full(key, on_insert).await.0
+    }
+
+    /// Like `get_mut_or_insert`, but also returns whether the value was inserted, i.e. `on_insert` was called
+    pub async fn get_mut_or_insert_full(
+        &self,
+        key: &K,
+        on_insert: impl FnOnce() -> V,
+    ) -> (LruWriteHandle<K, V, T>, bool)
+    where
+        K: Clone,
+    {
+        let (mut shard, idx, inserted) = self.get_or_insert_locked(key, on_insert).await;
+        self.stamp_write(&mut shard.entries[idx].value);
+
+        let handle = write_handle(OwnedRwLockWriteGuard::map(shard, |shard| &mut shard.entries[idx].value.value));
+
+        (handle, inserted)
+    }
+
+    pub async fn get_or_default(&self, key: &K) -> LruReadHandle<K, V, T>
+    where
+        K: Clone,
+        V: Default,
+    {
+        self.get_or_insert(key, Default::default).await
+    }
+
+    pub async fn get_mut_or_default(&self, key: &K) -> LruWriteHandle<K, V, T>
+    where
+        K: Clone,
+        V: Default,
+    {
+        self.get_mut_or_insert(key, Default::default).await
+    }
+
+    /// Write-locks the key's shard, inserting the value from `on_insert` if absent or expired,
+    /// and returns the lock along with the index of the entry and whether it was inserted
+    async fn get_or_insert_locked(
+        &self,
+        key: &K,
+        on_insert: impl FnOnce() -> V,
+    ) -> (OwnedRwLockWriteGuard<IndexedShard<K, TimestampedValue<V, T>>>, usize, bool)
+    where
+        K: Clone,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let (locked_shard, shard_size) = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        let mut shard = locked_shard.clone().write_owned().await;
+
+        let live = shard
+            .get_index_of(hash, key)
+            .filter(|&idx| !shard.entries[idx].value.is_expired());
+
+        match live {
+            Some(idx) => {
+                shard.entries[idx].value.touch(self.eviction_order.tracks_access());
+                (shard, idx, false)
+            }
+            None => {
+                let value = TimestampedValue::new(on_insert(), T::now());
+                let idx = self.insert_locked(&mut shard, shard_size, hash, key.clone(), value).0;
+                (shard, idx, true)
+            }
+        }
+    }
+
+    /// Number of entries of the eviction domain, see `with_eviction_domains`, or 0 without domains
+    pub async fn domain_len(&self, domain: usize) -> usize {
+        let domain = match self.domains {
+            Some(ref domains) if domain < domains.len() => domain as u16,
+            _ => return 0,
+        };
+
+        let mut len = 0;
+
+        for (shard, _) in &self.shards {
+            len += shard.read().await.domain_len(domain);
+        }
+
+        len
+    }
+
+    /// Checks if the key is present, without updating its timestamp
+    pub async fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.get_raw(key).await.is_some()
+    }
+
+    /// Inserts the value, returning the previous one unless it had expired.
+    ///
+    /// Overwriting a key updates its entry in place, resetting its timestamp and TTL rather than replacing them.
+    pub async fn insert(&self, key: K, value: V) -> Option<V> {
+        let (hash, shard_idx) = self.hash_and_shard(&key);
+        let (locked_shard, shard_size) = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        let mut shard = locked_shard.write().await;
+
+        match shard.get_index_of(hash, &key) {
+            Some(idx) => self.overwrite_locked(&mut shard, idx, value),
+            None => {
+                self.push_locked(&mut shard, shard_size, hash, key, TimestampedValue::new(value, T::now()));
+                None
+            }
+        }
+    }
+
+    /// Like `insert`, but if the key is new and its shard is full (see `with_max_capacity`), or its eviction domain
+    /// holds its share of the shard (see `with_eviction_domains`), gives the value back instead of evicting an entry,
+    /// for callers who would rather shed new work than drop warm entries.
+    ///
+    /// Expired entries still count towards the capacity, see `purge_expired`.
+    pub async fn try_insert_within_capacity(&self, key: K, value: V) -> Result<Option<V>, CapacityFull<V>> {
+        let (hash, shard_idx) = self.hash_and_shard(&key);
+        let (locked_shard, shard_size) = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        let mut shard = locked_shard.write().await;
+
+        let full = shard.len() >= self.max_shard_len || self.domain_full(&shard, &key);
+
+        if full && shard.get_index_of(hash, &key).is_none() {
+            return Err(CapacityFull(value));
+        }
+
+        let value = TimestampedValue::new(value, T::now());
+
+        Ok(match self.insert_locked(&mut shard, shard_size, hash, key, value).1 {
+            Some(previous) if !previous.is_expired() => Some(previous.value),
+            _ => None,
+        })
+    }
+
+    /// Bulk-loads entries, e.g. at startup, replacing existing values.
+    ///
+    /// Entries are grouped by shard first, so each shard is locked and grown only once,
+    /// instead of once per entry as with `insert`.
+    pub async fn warm(&self, pairs: impl IntoIterator<Item = (K, V)>) {
+        let mut by_shard: Vec<Vec<(u64, K, V)>> = (0..self.shards.len()).map(|_| Vec::new()).collect();
+
+        for (key, value) in pairs {
+            let (hash, shard_idx) = self.hash_and_shard(&key);
+            by_shard[shard_idx].push((hash, key, value));
+        }
+
+        for ((locked_shard, shard_size), entries) in self.shards.iter().zip(by_shard) {
+            if entries.is_empty() {
+                continue;
+            }
+
+            let mut shard = locked_shard.write().await;
+            shard.reserve(entries.len().min(self.max_shard_len));
+
+            for (hash, key, value) in entries {
+                let value = TimestampedValue::new(value, T::now());
+                self.insert_locked(&mut shard, shard_size, hash, key, value);
+            }
+        }
+    }
+
+    /// Inserts a value that expires after `ttl`, varied by the cache's `ttl_jitter`.
+    ///
+    /// Expired entries are treated as absent by lookups, but still count towards `size()`
+    /// until they are overwritten, removed, evicted or purged with `purge_expired`.
+    pub async fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) -> Option<V> {
+        self.insert_with_ttl_expiring_after(key, value, ttl, self.expire_after).await
+    }
+
+    /// Like `insert_with_ttl`, but with whether reads push back the deadline set for this entry only,
+    /// instead of the cache's `expire_after`
+    pub async fn insert_with_ttl_expiring_after(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+        expire_after: ExpireAfter,
+    ) -> Option<V> {
+        let (hash, shard_idx) = self.hash_and_shard(&key);
+
+        let mut value = TimestampedValue::new(value, T::now());
+        value.set_ttl(self.jittered_ttl(hash, ttl), expire_after);
+
+        self.insert_timestamped(hash, shard_idx, key, value).await
+    }
+
+    async fn insert_timestamped(
+        &self,
+        hash: u64,
+        shard_idx: usize,
+        key: K,
+        value: TimestampedValue<V, T>,
+    ) -> Option<V> {
+        let (locked_shard, shard_size) = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        let mut shard = locked_shard.write().await;
+
+        let previous = self.insert_locked(&mut shard, shard_size, hash, key, value).1?;
+
+        match previous.is_expired() {
+            true => None,
+            false => Some(previous.value),
+        }
+    }
+
+    /// Inserts into the locked shard, returning the index of the entry and the value it replaced
+    fn insert_locked(
+        &self,
+        shard: &mut IndexedShard<K, TimestampedValue<V, T>>,
+        shard_size: &AtomicUsize,
+        hash: u64,
+        key: K,
+        mut value: TimestampedValue<V, T>,
+    ) -> (usize, Option<TimestampedValue<V, T>>) {
+        match shard.get_index_of(hash, &key) {
+            Some(idx) => {
+                value.set_weight(self.weigh(&key, &value.value));
+                value.inserted_at = DefaultClock::now();
+                value.set_domain(shard.entries[idx].value.domain());
+                self.stamp_write(&mut value);
+
+                let weight = value.weight() as u64;
+                let previous = core::mem::replace(&mut shard.entries[idx].value, value);
+
+                self.weight.fetch_add(weight, Ordering::SeqCst);
+                self.weight.fetch_sub(previous.weight() as u64, Ordering::SeqCst);
+
+                (idx, Some(previous))
+            }
+            None => (self.push_locked(shard, shard_size, hash, key, value), None),
+        }
+    }
+
+    /// Whether the key's eviction domain holds its share of the locked shard, so a new key of it would evict another
+    #[inline]
+    fn domain_full(&self, shard: &IndexedShard<K, TimestampedValue<V, T>>, key: &K) -> bool {
+        match self.domains {
+            Some(ref domains) if self.max_shard_len != usize::MAX => {
+                let domain = domains.domain_of(key);
+                shard.domain_len(domain) >= domains.quota(domain, self.max_shard_len)
+            }
+            _ => false,
+        }
+    }
+
+    /// Weight of the value by the weigher, if any
+    #[inline]
+    fn weigh(&self, key: &K, value: &V) -> u32 {
+        self.weigher.map_or(1, |weigher| weigher(key, value))
+    }
+
+    /// Stamps a value written, or about to be lent out mutably, with a new version if a `retain_async` is running
+    #[inline]
+    fn stamp_write(&self, tv: &mut TimestampedValue<V, T>) {
+        // the shard's write lock held orders this after the increment by a `retain_async` that snapshots it
+        if self.retaining.load(Ordering::SeqCst) != 0 {
+            tv.meta_mut().version = self.writes.fetch_add(1, Ordering::Relaxed) + 1;
+        }
+    }
+
+    /// Inserts a key absent from the locked shard, first evicting an entry of the shard if full,
+    /// or of the key's eviction domain if it holds its share of the shard, returning the index of the new entry
+    fn push_locked(
+        &self,
+        shard: &mut IndexedShard<K, TimestampedValue<V, T>>,
+        shard_size: &AtomicUsize,
+        hash: u64,
+        key: K,
+        mut value: TimestampedValue<V, T>,
+    ) -> usize {
+        value.set_weight(self.weigh(&key, &value.value));
+        value.inserted_at = DefaultClock::now();
+        self.stamp_write(&mut value);
+
+        let weight = value.weight() as u64;
+
+        // the new key's hash is as good a random starting point as any
+        let victim = match self.domains {
+            Some(ref domains) if self.max_shard_len != usize::MAX => {
+                value.set_domain(domains.domain_of(&key));
+
+                match shard.domain_len(value.domain()) >= domains.quota(value.domain(), self.max_shard_len) {
+                    true => victim_in_domain(shard, self.victim_order(), hash as usize, SAMPLE_WINDOW, value.domain()),
+                    false => None,
+                }
+            }
+            Some(ref domains) => {
+                value.set_domain(domains.domain_of(&key));
+                None
+            }
+            None => None,
+        };
+
+        let victim = victim.or_else(|| {
+            (shard.len() >= self.max_shard_len)
+                .then(|| victim_in_window(shard, self.victim_order(), hash as usize, SAMPLE_WINDOW))
+        });
+
+        if let Some(idx) = victim {
+            if let Some(ref advisor) = self.advisor {
+                advisor.record_eviction(shard.entries[idx].hash);
+            }
+
+            let (_, evicted) = unsafe { shard.swap_remove_index_raw(idx) };
+
+            self.size.fetch_sub(1, Ordering::SeqCst);
+            self.weight.fetch_sub(evicted.weight() as u64, Ordering::SeqCst);
+            shard.uncount_domain(evicted.domain());
+            shard_size.store(shard.len(), Ordering::SeqCst);
+        }
+
+        if self.domains.is_some() {
+            shard.count_domain(value.domain());
+        }
+
+        let idx = shard.push_unique(hash, key, value);
+
+        self.size.fetch_add(1, Ordering::SeqCst);
+        shard_size.fetch_add(1, Ordering::SeqCst);
+        self.weight.fetch_add(weight, Ordering::SeqCst);
+
+        idx
+    }
+
+    /// Replaces the value of the entry at `idx` of the locked shard, resetting its metadata as a new entry would,
+    /// without constructing a new timestamp. Returns the previous value unless it had expired.
+    fn overwrite_locked(&self, shard: &mut IndexedShard<K, TimestampedValue<V, T>>, idx: usize, value: V) -> Option<V> {
+        let entry = &mut shard.entries[idx];
+        let weight = self.weigh(&entry.key, &value);
+        let tv = &mut entry.value;
+
+        let expired = tv.is_expired();
+        let previous = core::mem::replace(&mut tv.value, value);
+
+        tv.timestamp.reset();
+        tv.clear_ttl();
+        tv.inserted_at = DefaultClock::now();
+        self.stamp_write(tv);
+
+        self.weight.fetch_add(weight as u64, Ordering::SeqCst);
+        self.weight.fetch_sub(tv.weight() as u64, Ordering::SeqCst);
+        tv.set_weight(weight);
+
+        match expired {
+            true => None,
+            false => Some(previous),
+        }
+    }
+
+    /// Applies up to ±`ttl_jitter`% to the TTL, returning nanoseconds
+    fn jittered_ttl(&self, hash: u64, ttl: Duration) -> u64 {
+        let ttl = ttl.as_nanos().min(u64::MAX as u128);
+
+        if self.ttl_jitter == 0 {
+            return ttl as u64;
+        }
+
+        let span = ttl * self.ttl_jitter as u128 / 100;
+
+        // the key's hash mixed with the current time is random enough to spread out deadlines,
+        // without requiring an RNG at every insert
+        let mut z = hash ^ DefaultClock::now();
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        let offset = z as u128 % (2 * span + 1);
+
+        (ttl - span + offset).min(u64::MAX as u128) as u64
+    }
+
+    pub async fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.remove_entry(key).await.map(|(_, value)| value)
+    }
+
+    /// Like `remove`, also returning the key stored in the cache, e.g. to reuse its allocation.
+    /// Expired entries are removed all the same, but not returned.
+    pub async fn remove_entry<Q>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(&key);
+        let (locked_shard, shard_size) = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        let mut shard = locked_shard.write().await;
+
+        match shard.swap_remove_full(hash, key) {
+            Some((key, tv)) => {
+                self.size.fetch_sub(1, Ordering::SeqCst);
+                self.weight.fetch_sub(tv.weight() as u64, Ordering::SeqCst);
+                shard.uncount_domain(tv.domain());
+                // know the real size, so just store it
+                shard_size.store(shard.len(), Ordering::SeqCst);
+
+                if tv.is_expired() {
+                    None
+                } else {
+                    Some((key, tv.value))
+                }
+            }
+            None => None,
+        }
+    }
+
+    /// Removes all expired entries, returning them
+    pub async fn purge_expired(&self) -> Vec<(K, V)> {
+        let mut purged = Vec::new();
+
+        for (shard, shard_size) in &self.shards {
+            let mut shard = shard.write().await;
+
+            let mut i = 0;
+            while i < shard.len() {
+                if unsafe { shard.entries.get_unchecked(i).value.is_expired() } {
+                    let (key, tv) = unsafe { shard.swap_remove_index_raw(i) };
+                    self.size.fetch_sub(1, Ordering::SeqCst);
+                    self.weight.fetch_sub(tv.weight() as u64, Ordering::SeqCst);
+                    shard.uncount_domain(tv.domain());
+                    purged.push((key, tv.value));
+                } else {
+                    i += 1;
+                }
+            }
+
+            shard_size.store(shard.len(), Ordering::SeqCst);
+        }
+
+        purged
+    }
+
+    /// Removes every entry matching the predicate, which is given the time since each value was inserted,
+    /// returning the removed entries, e.g. to drop everything of a tenant older than 5 minutes.
+    ///
+    /// Unlike `evict`, which samples entries at random and may miss matches, every shard is swept once,
+    /// each under its write lock, so no entry present throughout the call is missed.
+    pub async fn evict_if<F>(&self, mut predicate: F) -> Vec<(K, V)>
+    where
+        F: FnMut(&K, &V, Duration) -> bool,
+    {
+        let mut evicted = Vec::new();
+
+        for (shard, shard_size) in &self.shards {
+            let mut shard = shard.write().await;
+            let now = DefaultClock::now();
+
+            let mut i = 0;
+            while i < shard.len() {
+                let bucket = unsafe { shard.entries.get_unchecked(i) };
+
+                if predicate(&bucket.key, &bucket.value.value, bucket.value.state(now).age) {
+                    let (key, tv) = unsafe { shard.swap_remove_index_raw(i) };
+                    self.size.fetch_sub(1, Ordering::SeqCst);
+                    self.weight.fetch_sub(tv.weight() as u64, Ordering::SeqCst);
+                    shard.uncount_domain(tv.domain());
+                    evicted.push((key, tv.value));
+                } else {
+                    i += 1;
+                }
+            }
+
+            shard_size.store(shard.len(), Ordering::SeqCst);
+        }
+
+        evicted
+    }
+
+    fn non_empty_shards(&self) -> impl Iterator<Item = &Shard<K, TimestampedValue<V, T>>> {
+        self.shards
+            .iter()
+            .filter_map(|(shard, shard_size)| match shard_size.load(Ordering::SeqCst) {
+                0 => None,
+                _ => Some(shard),
+            })
+    }
+
+    /// Fair element eviction based on 2-random sampling of two shards at once, and performs a random walk through
+    /// all shards as necessary to remain unbiased.
+    ///
+    /// NOTE: This method acquires one write lock per element, and can be inefficient for many evictions.
+    ///
+    /// If you want fair eviction of a handful of items, this is the method to use. For less-predictable bulk-eviction look at `evict_many_fast`
+    pub async fn evict<F>(&self, rng: impl Rng, predicate: F) -> Vec<(K, V)>
+    where
+        F: FnMut(&K, &mut V) -> Evict,
+    {
+        let mut evicted = Vec::new();
+
+        self.evict_with_sink(rng, predicate, |key, value| evicted.push((key, value)))
+            .await;
+
+        evicted
+    }
+
+    /// Same as `evict`, but passes evicted elements to `sink` as they are removed instead of collecting them.
+    pub async fn evict_with_sink<F, E>(&self, rng: impl Rng, mut predicate: F, mut sink: E)
+    where
+        F: FnMut(&K, &mut V) -> Evict,
+        E: FnMut(K, V),
+    {
+        self.evict_raw(
+            rng,
+            EvictLimit::default(),
+            &Deadline::NEVER,
+            |key, tv| {
+                self.stamp_write(tv);
+                predicate(key, &mut tv.value)
+            },
+            |key, tv| sink(key, tv.value),
+        )
+        .await;
+    }
+
+    /// Like `evict`, but also stops once `limit` is reached, returning why eviction stopped along with
+    /// the evicted elements, so that e.g. a memory-pressure handler whose predicate keeps returning `Evict::Skip`
+    /// or `Evict::Continue` while other tasks insert can't live-lock.
+    pub async fn evict_limited<F>(&self, rng: impl Rng, limit: EvictLimit, mut predicate: F) -> (Vec<(K, V)>, EvictStop)
+    where
+        F: FnMut(&K, &mut V) -> Evict,
+    {
+        let mut evicted = Vec::new();
+
+        let stop = self
+            .evict_raw(
+                rng,
+                limit,
+                &Deadline::NEVER,
+                |key, tv| {
+                    self.stamp_write(tv);
+                    predicate(key, &mut tv.value)
+                },
+                |key, tv| evicted.push((key, tv.value)),
+            )
+            .await;
+
+        (evicted, stop)
+    }
+
+    /// Evicts until total weight has dropped by at least `target_weight` or the cache is empty,
+    /// returning the evicted elements along with their weights. See `with_weigher`.
+    ///
+    /// Uses the same fair sampling as `evict`.
+    pub async fn evict_weight(&self, target_weight: u64, rng: impl Rng) -> Vec<(K, V, u32)> {
+        let mut evicted = Vec::new();
+
+        if target_weight == 0 {
+            return evicted;
+        }
+
+        let mut freed = 0;
+
+        self.evict_raw(
+            rng,
+            EvictLimit::default(),
+            &Deadline::NEVER,
+            |_, tv| {
+                freed += tv.weight() as u64;
+
+                match freed >= target_weight {
+                    true => Evict::Once,
+                    false => Evict::Continue,
+                }
+            },
+            |key, tv| {
+                let weight = tv.weight();
+                evicted.push((key, tv.value, weight))
+            },
+        )
+        .await;
+
+        evicted
+    }
+
+    async fn evict_raw<F, E>(
+        &self,
+        mut rng: impl Rng,
+        limit: EvictLimit,
+        until: &Deadline,
+        mut predicate: F,
+        mut sink: E,
+    ) -> EvictStop
+    where
+        F: FnMut(&K, &mut TimestampedValue<V, T>) -> Evict,
+        E: FnMut(K, TimestampedValue<V, T>),
+    {
+        use rand::seq::SliceRandom;
+
+        /* Algorithm:
+
+            Overall: Evict and collect items until the predicate returns false
+            The predicate will test the oldest of the two selected items at each iteration
+
+            To start with, collect all non-empty shards, then shuffle them.
+
+            Take one of them (pop) and lock it.
+
+            Then, pick another random shard (pop), and begin selecting two random elements from between those,
+            pass the oldest to the predicate, and if the predicate returns true then evict it.
+
+            Swap shard_a and shard_b, then continue. This forms a random-walk of sorts between non-empty shards,
+            where it goes from A->B, B->C, C->D, etc.
+
+            Doing a random walk avoids having to reacquire the locks on each shard each iteration.
+
+            When `non_empty` runs empty, refill it with the same method and shuffle it again
+
+        */
+
+        let mut non_empty = Vec::with_capacity(self.shards.len());
+
+        macro_rules! pop_shard {
+            () => {
+                loop {
+                    match non_empty.pop() {
+                        Some(shard) => {
+                            let shard = shard.write().await;
+                            // once locked, check if the shard is actually non-empty
+                            if shard.len() > 0 {
+                                break Some(shard);
+                            }
+                        }
+                        None => break None,
+                    }
+                }
+            };
+        }
+
+        let deadline = limit
+            .max_duration
+            .map(|max| DefaultClock::now().saturating_add(max.as_nanos().min(u64::MAX as u128) as u64));
+
+        let mut samples = 0;
+
+        macro_rules! check_deadline {
+            () => {
+                if deadline.map_or(false, |deadline| DefaultClock::now() >= deadline) {
+                    return EvictStop::MaxDuration;
+                }
+
+                if until.is_reached() {
+                    return EvictStop::Deadline;
+                }
+            };
+        }
+
+        // called before passing each sampled entry to the predicate
+        macro_rules! check_limit {
+            () => {
+                check_deadline!();
+
+                if limit.max_samples.map_or(false, |max| samples >= max) {
+                    return EvictStop::MaxSamples;
+                }
+
+                samples += 1;
+            };
+        }
+
+        'evict: while self.size() > 0 {
+            check_deadline!();
+
+            non_empty.extend(self.non_empty_shards());
+            non_empty.shuffle(&mut rng);
+
+            let mut shard_a = match pop_shard!() {
+                Some(shard) => shard,
+                // if we couldn't find an actual non-empty shard, go back to `while size > 0`, and if there is still one, sample it.
+                None => continue 'evict,
+            };
+
+            'walk: loop {
+                match pop_shard!() {
+                    None => {
+                        // single-shard case
+                        check_limit!();
+
+                        let res = match shard_a.len() {
+                            1 => unsafe {
+                                let shard::Bucket {
+                                    ref key,
+                                    ref mut value,
+                                    ..
+                                } = shard_a.entries.get_unchecked_mut(0);
+
+                                let res = predicate(key, value);
+
+                                if matches!(res, Evict::Continue | Evict::Once) {
+                                    shard_a.indices.clear();
+                                    shard_a.bump_generation();
+                                    let shard::Bucket { key, value, .. } = shard_a.entries.pop().unwrap();
+                                    self.size.fetch_sub(1, Ordering::SeqCst);
+                                    self.weight.fetch_sub(value.weight() as u64, Ordering::SeqCst);
+                                    shard_a.uncount_domain(value.domain());
+                                    sink(key, value);
+                                }
+
+                                res
+                            },
+                            _ => unsafe {
+                                let idx = sample_victim(&shard_a, self.victim_order(), &mut rng);
+
+                                let shard::Bucket {
+                                    ref key,
+                                    ref mut value,
+                                    ..
+                                } = shard_a.entries.get_unchecked_mut(idx);
+
+                                let res = predicate(key, value);
+
+                                if matches!(res, Evict::Continue | Evict::Once) {
+                                    let (key, value) = shard_a.swap_remove_index_raw(idx);
+                                    self.size.fetch_sub(1, Ordering::SeqCst);
+                                    self.weight.fetch_sub(value.weight() as u64, Ordering::SeqCst);
+                                    shard_a.uncount_domain(value.domain());
+                                    sink(key, value);
+                                }
+
+                                res
+                            },
+                        };
+
+                        if matches!(res, Evict::Once | Evict::None) {
+                            return EvictStop::Predicate;
+                        }
+
+                        // since pop_shard!() returned None, there is no point in looping again,
+                        // so try to refresh the non_empty shard list
+                        continue 'evict;
+                    }
+                    Some(mut shard_b) => unsafe {
+                        // two-shard case
+
+                        debug_assert!(shard_a.len() > 0);
+                        debug_assert!(shard_b.len() > 0);
+
+                        check_limit!();
+
+                        let order = self.victim_order();
+
+                        let (shard, idx) = match sample_victim_across(&shard_a, &shard_b, order, &mut rng) {
+                            (false, idx) => (&mut shard_a, idx),
+                            (true, idx) => (&mut shard_b, idx),
+                        };
+
+                        let shard::Bucket {
+                            ref key,
+                            ref mut value,
+                            ..
+                        } = shard.entries.get_unchecked_mut(idx);
+
+                        let res = predicate(key, value);
+
+                        if matches!(res, Evict::Continue | Evict::Once) {
+                            let (key, value) = shard.swap_remove_index_raw(idx);
+                            self.size.fetch_sub(1, Ordering::SeqCst);
+                            self.weight.fetch_sub(value.weight() as u64, Ordering::SeqCst);
+                            shard.uncount_domain(value.domain());
+                            sink(key, value);
+                        }
+
+                        if matches!(res, Evict::None | Evict::Once) {
+                            return EvictStop::Predicate;
+                        }
+
+                        shard_a = shard_b; // do random walk A->B, B->C, etc.
+                    },
+                }
+
+                // if the former shard_b was emptied by the eviction, then try to find a new one before continuing
+                if shard_a.len() == 0 {
+                    shard_a = match pop_shard!() {
+                        Some(shard) => shard,
+                        None => break 'walk,
+                    };
+                }
+            }
+        }
+
+        EvictStop::Empty
+    }
+
+    /// Fairly evict many elements, based on 2-random sampling of two shards at once, and performs a random walk through
+    /// all shards as necessary to remain unbiased.
+    ///
+    /// NOTE: This method acquires one write lock per element, and can be inefficient for many evictions.
+    ///
+    /// If you want fair eviction of a handful of items, this is the method to use. For less-predictable bulk-eviction look at `evict_many_fast`
+    pub async fn evict_many(&self, count: usize, rng: impl Rng) -> Vec<(K, V)> {
+        let mut evicted = Vec::new();
+
+        self.evict_many_raw(count, rng, &Deadline::NEVER, |key, tv| evicted.push((key, tv.value)))
+            .await;
+
+        evicted
+    }
+
+    /// Same as `evict_many`, but returns the evicted elements in the order the cache's `EvictionOrder` ranks them,
+    /// i.e. least recently used first by default, along with their state when evicted.
+    pub async fn evict_many_sorted(&self, count: usize, rng: impl Rng) -> Vec<(K, V, EntryState)> {
+        let mut evicted = Vec::new();
+
+        self.evict_many_raw(count, rng, &Deadline::NEVER, |key, tv| evicted.push((key, tv)))
+            .await;
+
+        self.sort_evicted(evicted)
+    }
+
+    /// Like `evict_many`, but gives up once the deadline is reached, returning why eviction stopped along with
+    /// the elements evicted so far, so a memory-pressure handler can be bounded or cancelled on shutdown.
+    /// Evicting the rest is a matter of calling it again with the count of elements still to evict.
+    pub async fn evict_many_until(&self, count: usize, rng: impl Rng, deadline: &Deadline) -> (Vec<(K, V)>, EvictStop) {
+        let mut evicted = Vec::new();
+
+        let stop = self
+            .evict_many_raw(count, rng, deadline, |key, tv| evicted.push((key, tv.value)))
+            .await;
+
+        (evicted, stop)
+    }
+
+    async fn evict_many_raw<E>(&self, mut count: usize, rng: impl Rng, deadline: &Deadline, sink: E) -> EvictStop
+    where
+        E: FnMut(K, TimestampedValue<V, T>),
+    {
+        count = count.min(self.size());
+
+        if count == 0 {
+            return EvictStop::Empty;
+        }
+
+        let mut cur = count;
+
+        self.evict_raw(
+            rng,
+            EvictLimit::default(),
+            deadline,
+            |_, _| {
+                cur -= 1;
+
+                match cur {
+                    0 => Evict::Once,
+                    _ => Evict::Continue,
+                }
+            },
+            sink,
+        )
+        .await
+    }
+
+    /// Sorts evicted elements by the cache's `EvictionOrder`, first to be evicted first
+    fn sort_evicted(&self, mut evicted: Vec<(K, TimestampedValue<V, T>)>) -> Vec<(K, V, EntryState)> {
+        let order = self.eviction_order;
+
+        evicted.sort_by(|(_, a), (_, b)| {
+            if order.evicts_before(&a.timestamp, &b.timestamp) {
+                core::cmp::Ordering::Less
+            } else if order.evicts_before(&b.timestamp, &a.timestamp) {
+                core::cmp::Ordering::Greater
+            } else {
+                core::cmp::Ordering::Equal
+            }
+        });
+
+        let now = DefaultClock::now();
+
+        evicted
+            .into_iter()
+            .map(|(key, tv)| {
+                let state = tv.state(now);
+                (key, tv.value, state)
+            })
+            .collect()
+    }
+
+    /// Fairly evict one element, based on 2-random sampling of two random shards.
+    ///
+    /// Unlike `evict`, this does not allocate.
+    pub async fn evict_one(&self, mut rng: impl Rng) -> Option<(K, V)> {
+        let num_shards = self.shards.len();
+
+        while self.size() > 0 {
+            let a = self.probe_non_empty(rng.gen_range(0..num_shards), None)?;
+            let b = self.probe_non_empty(rng.gen_range(0..num_shards), Some(a));
+
+            // always lock in index order, so concurrent callers cannot deadlock on each other
+            let (lo, hi) = match b {
+                Some(b) if b < a => (b, Some(a)),
+                _ => (a, b),
+            };
+
+            let mut shard_lo = unsafe { self.shards.get_unchecked(lo).0.write().await };
+            let mut shard_hi = match hi {
+                Some(hi) => Some(unsafe { self.shards.get_unchecked(hi).0.write().await }),
+                None => None,
+            };
+
+            // shards may have been emptied before they could be locked
+            let (shard_idx, shard, idx) = match hi.zip(shard_hi.as_deref_mut()) {
+                Some((hi, shard_hi)) if shard_hi.len() > 0 => match shard_lo.len() {
+                    0 => {
+                        let idx = sample_victim(shard_hi, self.victim_order(), &mut rng);
+                        (hi, shard_hi, idx)
+                    }
+                    _ => match sample_victim_across(&shard_lo, shard_hi, self.victim_order(), &mut rng) {
+                        (false, idx) => (lo, &mut *shard_lo, idx),
+                        (true, idx) => (hi, shard_hi, idx),
+                    },
+                },
+                _ if shard_lo.len() > 0 => {
+                    let idx = sample_victim(&shard_lo, self.victim_order(), &mut rng);
+                    (lo, &mut *shard_lo, idx)
+                }
+                _ => continue,
+            };
+
+            let (key, value) = unsafe { shard.swap_remove_index_raw(idx) };
+            self.size.fetch_sub(1, Ordering::SeqCst);
+            self.weight.fetch_sub(value.weight() as u64, Ordering::SeqCst);
+            shard.uncount_domain(value.domain());
+            // the shard is locked, so store its real size
+            unsafe { self.shards.get_unchecked(shard_idx).1.store(shard.len(), Ordering::SeqCst) };
+
+            return Some((key, value.value));
+        }
+
+        None
+    }
+
+    /// Samples up to `count` distinct entries that eviction would pick, without removing them, in no particular order.
+    ///
+    /// Meant for applications that decide what to evict with rules of their own, possibly async or spanning
+    /// several entries, and then remove the approved keys with `remove_batch`. Shards are picked in proportion
+    /// to their size, and each is only read-locked once.
+    pub async fn eviction_candidates(&self, count: usize, mut rng: impl Rng) -> Vec<(K, EntryState)>
+    where
+        K: Clone,
+    {
+        let sizes: Vec<usize> = self.shards.iter().map(|(_, size)| size.load(Ordering::SeqCst)).collect();
+        let total: usize = sizes.iter().sum();
+
+        if count == 0 || total == 0 {
+            return Vec::new();
+        }
+
+        // draws per shard, picking a random entry position across all shards for each
+        let mut draws = vec![0usize; sizes.len()];
+
+        for _ in 0..count {
+            let mut pos = rng.gen_range(0..total);
+
+            for (shard_idx, &size) in sizes.iter().enumerate() {
+                if pos < size {
+                    draws[shard_idx] += 1;
+                    break;
+                }
+
+                pos -= size;
+            }
+        }
+
+        let mut candidates = Vec::with_capacity(count);
+
+        for ((locked_shard, _), draws) in self.shards.iter().zip(draws) {
+            if draws == 0 {
+                continue;
+            }
+
+            let shard = locked_shard.read().await;
+
+            // shards may have been emptied since their size was read
+            if shard.len() == 0 {
+                continue;
+            }
+
+            let mut picked: Vec<usize> = (0..draws)
+                .map(|_| sample_victim(&shard, self.victim_order(), &mut rng))
+                .collect();
+
+            picked.sort_unstable();
+            picked.dedup();
+
+            let now = DefaultClock::now();
+
+            candidates.extend(picked.into_iter().map(|idx| {
+                let bucket = &shard.entries[idx];
+                (bucket.key.clone(), bucket.value.state(now))
+            }));
+        }
+
+        candidates
+    }
+
+    /// Removes all the given keys, locking each shard once, and returns the removed entries that had not expired
+    pub async fn remove_batch(&self, keys: impl IntoIterator<Item = K>) -> Vec<(K, V)> {
+        let mut by_shard: Vec<Vec<(u64, K)>> = (0..self.shards.len()).map(|_| Vec::new()).collect();
+
+        for key in keys {
+            let (hash, shard_idx) = self.hash_and_shard(&key);
+            by_shard[shard_idx].push((hash, key));
+        }
+
+        let mut removed = Vec::new();
+
+        for ((locked_shard, shard_size), keys) in self.shards.iter().zip(by_shard) {
+            if keys.is_empty() {
+                continue;
+            }
+
+            let mut shard = locked_shard.write().await;
+
+            for (hash, key) in keys {
+                if let Some((key, tv)) = shard.swap_remove_full(hash, &key) {
+                    self.size.fetch_sub(1, Ordering::SeqCst);
+                    self.weight.fetch_sub(tv.weight() as u64, Ordering::SeqCst);
+                    shard.uncount_domain(tv.domain());
+
+                    if !tv.is_expired() {
+                        removed.push((key, tv.value));
+                    }
+                }
+            }
+
+            shard_size.store(shard.len(), Ordering::SeqCst);
+        }
+
+        removed
+    }
+
+    /// Index of the first shard at or after `start` (wrapping around) that appears non-empty, other than `skip`
+    fn probe_non_empty(&self, start: usize, skip: Option<usize>) -> Option<usize> {
+        let num_shards = self.shards.len();
+
+        (0..num_shards)
+            .map(|i| (start + i) % num_shards)
+            .find(|&idx| Some(idx) != skip && self.shards[idx].1.load(Ordering::SeqCst) > 0)
+    }
+
+    /// Less-fair and less-predictable algorithm that only acquires shard locks once at most.
+    ///
+    /// The requested count is apportioned across shards by their size, using the largest-remainder method,
+    /// so exactly `count` elements are evicted as long as the cache holds that many.
+    ///
+    /// Compare to `evict` or `evict_many` that acquires a shard lock *per-item evicted*,
+    /// but is more fair and unbiased in doing so.
+    pub async fn evict_many_fast(&self, count: usize, rng: impl Rng) -> Vec<(K, V)> {
+        let mut evicted = Vec::new();
+
+        self.evict_many_fast_raw(count, rng, |key, tv| evicted.push((key, tv.value)))
+            .await;
+
+        evicted
+    }
+
+    /// Same as `evict_many_fast`, but returns the evicted elements in the order the cache's `EvictionOrder`
+    /// ranks them, i.e. least recently used first by default, along with their state when evicted.
+    pub async fn evict_many_fast_sorted(&self, count: usize, rng: impl Rng) -> Vec<(K, V, EntryState)> {
+        let mut evicted = Vec::new();
+
+        self.evict_many_fast_raw(count, rng, |key, tv| evicted.push((key, tv)))
+            .await;
+
+        self.sort_evicted(evicted)
+    }
+
+    async fn evict_many_fast_raw<E>(&self, mut count: usize, mut rng: impl Rng, mut sink: E)
+    where
+        E: FnMut(K, TimestampedValue<V, T>),
+    {
+        use rand::prelude::SliceRandom;
+
+        count = count.min(self.size());
+
+        if count == 0 {
+            return;
+        }
+
+        let mut evicted = 0;
+
+        // (shard, size hint, quota, remainder)
+        let mut non_empty = Vec::with_capacity(self.shards.len());
+        non_empty.extend(self.shards.iter().filter_map(|(shard, shard_size)| {
+            match shard_size.load(Ordering::SeqCst) {
+                0 => None,
+                len => Some((shard, shard_size, len, 0)),
+            }
+        }));
+        non_empty.shuffle(&mut rng);
+
+        let total = non_empty.iter().map(|&(_, _, len, _)| len as u128).sum::<u128>();
+
+        // `len / total` is the fraction this shard holds of the entire structure, between 0 and 1
+        // so `count * fraction` is the number of elements to be taken from this shard,
+        // reorganized to avoid floating point, at the cost of 128-bit ints
+        let mut assigned = 0;
+        for (_, _, quota, remainder) in non_empty.iter_mut() {
+            let scaled = count as u128 * *quota as u128;
+
+            *quota = (scaled / total) as usize;
+            *remainder = (scaled % total) as usize;
+            assigned += *quota;
+        }
+
+        // hand out what rounding down left over to the largest remainders,
+        // ties going to whichever shard the shuffle placed first
+        let mut order: Vec<usize> = (0..non_empty.len()).collect();
+        order.sort_by(|&a, &b| non_empty[b].3.cmp(&non_empty[a].3));
+
+        for &i in order.iter().take(count.saturating_sub(assigned)) {
+            non_empty[i].2 += 1;
+        }
+
+        // shards may have shrunk since their size hint was read, so any shortfall is carried over to the next ones
+        let mut carry = 0;
+        for (shard, shard_size, quota, _) in non_empty {
+            let mut shard = shard.write().await;
+
+            let sub_count = (quota + carry).min(count - evicted);
+
+            let take = sub_count.min(shard.len());
+            carry = sub_count - take;
+
+            if take == shard.len() {
+                // fast path for evicting all of this shard
+                let mut weight = 0;
+
+                for bucket in shard.entries.drain(..) {
+                    weight += bucket.value.weight() as u64;
+                    sink(bucket.key, bucket.value);
+                }
+
+                shard.indices.clear();
+                shard.domain_lens.clear();
+                shard.bump_generation();
+                self.size.fetch_sub(take, Ordering::SeqCst);
+                self.weight.fetch_sub(weight, Ordering::SeqCst);
+            } else {
+                for _ in 0..take {
+                    let idx = sample_victim(&shard, self.victim_order(), &mut rng);
+
+                    let (key, value) = unsafe { shard.swap_remove_index_raw(idx) };
+                    self.size.fetch_sub(1, Ordering::SeqCst);
+                    self.weight.fetch_sub(value.weight() as u64, Ordering::SeqCst);
+                    shard.uncount_domain(value.domain());
+                    sink(key, value);
+                }
+            }
+
+            evicted += take;
+            shard_size.store(shard.len(), Ordering::SeqCst);
+
+            if evicted == count {
+                break;
+            }
+        }
+    }
+
+    /// Deterministic eviction that walks the shards round-robin, evicting from each the oldest element
+    /// of a window of (at most) `SAMPLE_WINDOW` entries, until `count` elements are evicted or the cache is empty.
+    ///
+    /// No RNG is involved, so given the same sequence of operations the same elements are evicted,
+    /// e.g. when replaying an incident. The walk resumes where the previous call left off.
+    ///
+    /// NOTE: Like `evict`, this acquires one write lock per element.
+    pub async fn evict_sweep(&self, count: usize) -> Vec<(K, V)> {
+        let num_shards = self.shards.len();
+
+        let mut evicted = Vec::new();
+        let mut empty_streak = 0;
+
+        while evicted.len() < count && empty_streak < num_shards && self.size() > 0 {
+            let cursor = self.sweep_cursor.fetch_add(1, Ordering::SeqCst);
+            let (locked_shard, shard_size) = unsafe { self.shards.get_unchecked(cursor % num_shards) };
+
+            if shard_size.load(Ordering::SeqCst) == 0 {
+                empty_streak += 1;
+                continue;
+            }
+
+            let mut shard = locked_shard.write().await;
+
+            if shard.len() == 0 {
+                shard_size.store(0, Ordering::SeqCst);
+                empty_streak += 1;
+                continue;
+            }
+
+            empty_streak = 0;
+
+            // shift the window on every pass over the shards, so it does not keep sampling the same entries
+            let start = (cursor / num_shards).wrapping_mul(SAMPLE_WINDOW) % shard.len();
+            let idx = victim_in_window(&shard, self.victim_order(), start, SAMPLE_WINDOW);
+
+            let (key, value) = unsafe { shard.swap_remove_index_raw(idx) };
+            self.size.fetch_sub(1, Ordering::SeqCst);
+            self.weight.fetch_sub(value.weight() as u64, Ordering::SeqCst);
+            shard.uncount_domain(value.domain());
+            shard_size.store(shard.len(), Ordering::SeqCst);
+
+            evicted.push((key, value.value));
+        }
+
+        evicted
+    }
+}
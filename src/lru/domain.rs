@@ -0,0 +1,57 @@
+use std::fmt;
+
+/// Eviction domains of an `LruCache`, e.g. one per tenant, each allowed its share of the capacity of every shard,
+/// see `LruCache::with_eviction_domains`
+pub(crate) struct EvictionDomains<K> {
+    domain_of: fn(&K) -> usize,
+    /// Share of the capacity of each domain, relative to their sum
+    shares: Vec<u32>,
+    total: u64,
+}
+
+impl<K> EvictionDomains<K> {
+    pub(crate) fn new(domain_of: fn(&K) -> usize, shares: &[u32]) -> Self {
+        assert!(!shares.is_empty(), "eviction domains need at least one share");
+        assert!(shares.len() <= u16::MAX as usize + 1, "too many eviction domains");
+
+        EvictionDomains {
+            domain_of,
+            shares: shares.to_vec(),
+            total: shares.iter().map(|&share| share as u64).sum::<u64>().max(1),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.shares.len()
+    }
+
+    /// Domain of the key, keys of domains past the last one counting towards the last one
+    #[inline]
+    pub(crate) fn domain_of(&self, key: &K) -> u16 {
+        (self.domain_of)(key).min(self.shares.len() - 1) as u16
+    }
+
+    /// Maximum number of entries of the domain in a shard holding up to `max_shard_len` entries, at least one
+    #[inline]
+    pub(crate) fn quota(&self, domain: u16, max_shard_len: usize) -> usize {
+        let quota = max_shard_len as u128 * self.shares[domain as usize] as u128 / self.total as u128;
+        (quota as usize).max(1)
+    }
+}
+
+impl<K> Clone for EvictionDomains<K> {
+    fn clone(&self) -> Self {
+        EvictionDomains {
+            domain_of: self.domain_of,
+            shares: self.shares.clone(),
+            total: self.total,
+        }
+    }
+}
+
+impl<K> fmt::Debug for EvictionDomains<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EvictionDomains").field("shares", &self.shares).finish()
+    }
+}
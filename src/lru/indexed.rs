@@ -0,0 +1,273 @@
+use core::borrow::Borrow;
+use core::fmt;
+use core::hash::{BuildHasher, Hash};
+use core::iter::FromIterator;
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use rand::Rng;
+
+use super::shard::{Bucket, IndexedShard};
+
+/// Single-threaded map keeping its entries contiguous, in a `Vec` indexed by a hash table, which each shard
+/// of `LruCache` is built on.
+///
+/// Entries are removed by swapping the last one into their place, so removals take constant time, but move
+/// the last entry, and indices are only stable until the next removal. In exchange, entries can be accessed
+/// by index, e.g. to pick random ones in constant time for sampling, and iterating over them is as fast as
+/// over a `Vec`.
+///
+/// ```
+/// # use quick_hash_cache::lru::IndexedMap;
+/// let mut map = IndexedMap::new();
+/// map.insert("a", 1);
+/// map.insert("b", 2);
+/// map.insert("c", 3);
+///
+/// assert_eq!(map.swap_remove("a"), Some(1));
+/// // the last entry took the place of the removed one
+/// assert_eq!(map.get_index(0), Some((&"c", &3)));
+/// assert_eq!(map.get_index_of("c"), Some(0));
+///
+/// let (key, _) = map.get_random(rand::thread_rng()).unwrap();
+/// assert!(["b", "c"].contains(key));
+/// ```
+pub struct IndexedMap<K, V, S = DefaultHashBuilder> {
+    hash_builder: S,
+    shard: IndexedShard<K, V>,
+}
+
+impl<K, V> IndexedMap<K, V, DefaultHashBuilder> {
+    pub fn new() -> Self {
+        Self::with_hasher(DefaultHashBuilder::default())
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(capacity, DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V> Default for IndexedMap<K, V, DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, S> IndexedMap<K, V, S> {
+    pub const fn with_hasher(hash_builder: S) -> Self {
+        IndexedMap {
+            hash_builder,
+            shard: IndexedShard::new(),
+        }
+    }
+
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> Self {
+        let mut map = Self::with_hasher(hash_builder);
+        map.shard.reserve(capacity);
+        map
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.shard.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.shard.len() == 0
+    }
+
+    #[inline]
+    pub fn hash_builder(&self) -> &S {
+        &self.hash_builder
+    }
+
+    /// Reserves room for at least `additional` more entries
+    pub fn reserve(&mut self, additional: usize) {
+        self.shard.reserve(additional);
+    }
+
+    pub fn clear(&mut self) {
+        self.shard.clear();
+    }
+
+    /// Entry at the index, in `0..len()`
+    #[inline]
+    pub fn get_index(&self, index: usize) -> Option<(&K, &V)> {
+        self.shard.entries.get(index).map(|bucket| (&bucket.key, &bucket.value))
+    }
+
+    #[inline]
+    pub fn get_index_mut(&mut self, index: usize) -> Option<(&K, &mut V)> {
+        self.shard
+            .entries
+            .get_mut(index)
+            .map(|bucket| (&bucket.key, &mut bucket.value))
+    }
+
+    /// Uniformly random entry, or `None` if the map is empty
+    pub fn get_random(&self, mut rng: impl Rng) -> Option<(&K, &V)> {
+        match self.len() {
+            0 => None,
+            len => self.get_index(rng.gen_range(0..len)),
+        }
+    }
+
+    /// Removes the entry at the index, moving the last entry into its place
+    pub fn swap_remove_index(&mut self, index: usize) -> Option<(K, V)> {
+        if index >= self.len() {
+            return None;
+        }
+
+        // SAFETY: the index is in bounds
+        Some(unsafe { self.shard.swap_remove_index_raw(index) })
+    }
+
+    /// Entries in index order
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = (&K, &V)> + DoubleEndedIterator {
+        self.shard.entries.iter().map(|bucket| (&bucket.key, &bucket.value))
+    }
+
+    /// Entries in index order, with mutable values
+    pub fn iter_mut(&mut self) -> impl ExactSizeIterator<Item = (&K, &mut V)> + DoubleEndedIterator {
+        self.shard
+            .entries
+            .iter_mut()
+            .map(|bucket| (&bucket.key, &mut bucket.value))
+    }
+
+    pub fn keys(&self) -> impl ExactSizeIterator<Item = &K> + DoubleEndedIterator {
+        self.shard.entries.iter().map(|bucket| &bucket.key)
+    }
+
+    pub fn values(&self) -> impl ExactSizeIterator<Item = &V> + DoubleEndedIterator {
+        self.shard.entries.iter().map(|bucket| &bucket.value)
+    }
+
+    /// Keeps the entries for which the predicate returns `true`, moving entries into the place of removed ones
+    pub fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        self.shard.retain(f);
+    }
+}
+
+impl<K, V, S> IndexedMap<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard.get(self.hash_builder.hash_one(key), key)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard.get_mut(self.hash_builder.hash_one(key), key)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.get_index_of(key).is_some()
+    }
+
+    /// Index of the key's entry, valid until the next removal
+    pub fn get_index_of<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard.get_index_of(self.hash_builder.hash_one(key), key)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.insert_full(key, value).1
+    }
+
+    /// Inserts the entry, at the end if the key is new, returning its index along with the value replaced, if any
+    pub fn insert_full(&mut self, key: K, value: V) -> (usize, Option<V>) {
+        let hash = self.hash_builder.hash_one(&key);
+        self.shard.insert_full(hash, key, value, || {})
+    }
+
+    /// Removes the key's entry, moving the last entry into its place
+    pub fn swap_remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.swap_remove_entry(key).map(|(_, value)| value)
+    }
+
+    /// Like `swap_remove`, also returning the key stored in the map
+    pub fn swap_remove_entry<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard.swap_remove_full(self.hash_builder.hash_one(key), key)
+    }
+}
+
+impl<K, V, S> Clone for IndexedMap<K, V, S>
+where
+    K: Clone,
+    V: Clone,
+    S: Clone,
+{
+    fn clone(&self) -> Self {
+        IndexedMap {
+            hash_builder: self.hash_builder.clone(),
+            shard: self.shard.clone(),
+        }
+    }
+}
+
+impl<K, V, S> Extend<(K, V)> for IndexedMap<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, pairs: I) {
+        let pairs = pairs.into_iter();
+        self.reserve(pairs.size_hint().0);
+
+        for (key, value) in pairs {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<K, V> FromIterator<(K, V)> for IndexedMap<K, V, DefaultHashBuilder>
+where
+    K: Hash + Eq,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(pairs: I) -> Self {
+        let mut map = IndexedMap::new();
+        map.extend(pairs);
+        map
+    }
+}
+
+impl<K, V, S> fmt::Debug for IndexedMap<K, V, S>
+where
+    K: fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.shard.entries.iter().map(|Bucket { key, value, .. }| (key, value)))
+            .finish()
+    }
+}
@@ -1,652 +1,436 @@
-use std::borrow::Borrow;
-use std::hash::{BuildHasher, Hash, Hasher};
-use std::sync::{
-    atomic::{AtomicU64, AtomicUsize, Ordering},
-    Arc,
-};
+use core::time::Duration;
+use core::{fmt, marker::PhantomData};
 
-use tokio::sync::{OwnedRwLockWriteGuard, RwLock};
+use alloc::boxed::Box;
 
-use hashbrown::hash_map::DefaultHashBuilder;
+use crate::clock::Clock;
+use crate::sync::{AtomicU64, Ordering};
 
 use rand::Rng;
 
-use crate::{Erased, ReadHandle, WriteHandle};
-
+#[cfg(feature = "tokio")]
+mod advisor;
+#[cfg(feature = "tokio")]
+mod cache;
+#[cfg(feature = "tokio")]
+mod domain;
+mod indexed;
+mod sampled;
 mod shard;
+#[cfg(feature = "tokio")]
+mod view;
+
+#[cfg(feature = "tokio")]
+pub use advisor::CapacityRecommendation;
+#[cfg(feature = "tokio")]
+pub use cache::{LruCache, LruReadHandle, LruWriteHandle};
+pub use indexed::IndexedMap;
+pub use sampled::SampledLru;
+#[cfg(feature = "tokio")]
+pub use view::ReadOnlyLruCache;
 
-use shard::IndexedShard;
+pub(crate) use shard::IndexedShard;
 
 pub trait AtomicTimestamp {
     /// Create a new timestamp at the given time
     fn now() -> Self;
     /// Update the timestamp to `now` in-place
     fn update(&self);
+    /// Reset the timestamp in-place to as if just created with `now`, for entries overwritten in place
+    #[inline]
+    fn reset(&self) {
+        self.update();
+    }
     fn is_before(&self, other: &Self) -> bool;
+
+    /// Set the timestamp before any `now`, so the entry is the first evicted in LRU order.
+    ///
+    /// Does nothing by default, for timestamps that can't be moved back.
+    #[inline]
+    fn demote(&self) {}
+
+    /// Time between the two timestamps in nanoseconds, for weighing recency against entry weight,
+    /// or `None` if they aren't comparable that way
+    #[inline]
+    fn nanos_between(&self, other: &Self) -> Option<u64> {
+        let _ = other;
+        None
+    }
 }
 
-#[derive(Debug)]
-pub struct AtomicInstant(AtomicU64);
+/// Atomic timestamp read from the clock `C`
+pub struct ClockTimestamp<C>(AtomicU64, PhantomData<fn() -> C>);
+
+/// Atomic timestamp read from the platform's [`DefaultClock`](crate::clock::DefaultClock)
+#[cfg(feature = "std")]
+pub type AtomicInstant = ClockTimestamp<crate::clock::DefaultClock>;
+
+impl<C> fmt::Debug for ClockTimestamp<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ClockTimestamp").field(&self.0).finish()
+    }
+}
 
-impl AtomicTimestamp for AtomicInstant {
+impl<C: Clock> AtomicTimestamp for ClockTimestamp<C> {
     #[inline]
     fn now() -> Self {
-        AtomicInstant(AtomicU64::new(quanta::Instant::now().as_u64()))
+        ClockTimestamp(AtomicU64::new(C::now()), PhantomData)
     }
 
     #[inline]
     fn update(&self) {
-        self.0.store(quanta::Instant::now().as_u64(), Ordering::SeqCst);
+        self.0.store(C::now(), Ordering::SeqCst);
     }
 
     #[inline]
     fn is_before(&self, other: &Self) -> bool {
         self.0.load(Ordering::SeqCst) < other.0.load(Ordering::SeqCst)
     }
-}
-
-#[derive(Debug)]
-struct TimestampedValue<V, T> {
-    value: V,
-    timestamp: T,
-}
 
-impl<V, T> Clone for TimestampedValue<V, T>
-where
-    V: Clone,
-    T: AtomicTimestamp,
-{
-    fn clone(&self) -> Self {
-        TimestampedValue {
-            value: self.value.clone(),
-            timestamp: T::now(),
-        }
-    }
-}
-
-type Shard<K, T> = Arc<RwLock<IndexedShard<K, T>>>;
-
-#[derive(Debug)]
-pub struct LruCache<K, V, T = AtomicInstant, S = DefaultHashBuilder> {
-    hash_builder: S,
-    shards: Vec<(Shard<K, TimestampedValue<V, T>>, AtomicUsize)>,
-    size: AtomicUsize,
-}
-
-impl<K, V, T> LruCache<K, V, T, DefaultHashBuilder> {
-    pub fn new(num_shards: usize) -> Self {
-        Self::with_hasher(num_shards, DefaultHashBuilder::default())
+    #[inline]
+    fn demote(&self) {
+        self.0.store(0, Ordering::SeqCst);
     }
-}
 
-impl<K, V> Default for LruCache<K, V, AtomicInstant, DefaultHashBuilder> {
-    fn default() -> Self {
-        Self::new(num_cpus::get())
+    #[inline]
+    fn nanos_between(&self, other: &Self) -> Option<u64> {
+        Some(self.0.load(Ordering::SeqCst).abs_diff(other.0.load(Ordering::SeqCst)))
     }
 }
 
-impl<K, V, T, S> LruCache<K, V, T, S> {
-    pub fn with_hasher(num_shards: usize, hash_builder: S) -> Self {
-        LruCache {
-            shards: (0..num_shards)
-                .into_iter()
-                .map(|_| (Arc::new(RwLock::new(IndexedShard::new())), AtomicUsize::new(0)))
-                .collect(),
-            hash_builder,
-            size: AtomicUsize::new(0),
-        }
+/// Times of the last `K` accesses read from the clock `C`, for LRU-K eviction.
+///
+/// Entries are ordered by their `K`-th most recent access, so a burst of accesses to an entry that is otherwise
+/// rarely used (e.g. a scan) keeps it alive no longer than a single access would. Entries accessed fewer than
+/// `K` times are older than any accessed `K` times, and are ordered by their most recent access among themselves.
+///
+/// `K = 1` is plain LRU, like [`ClockTimestamp`], and `K = 2` is the usual choice.
+pub struct AccessHistory<C, const K: usize>([AtomicU64; K], PhantomData<fn() -> C>);
+
+/// LRU-2 timestamps read from the platform's [`DefaultClock`](crate::clock::DefaultClock)
+#[cfg(feature = "std")]
+pub type AtomicLru2 = AccessHistory<crate::clock::DefaultClock, 2>;
+
+impl<C, const K: usize> fmt::Debug for AccessHistory<C, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("AccessHistory").field(&self.0).finish()
     }
 }
 
-impl<K, V, T, S> LruCache<K, V, T, S>
-where
-    S: Clone,
-    K: Clone,
-    V: Clone,
-    T: AtomicTimestamp,
-{
-    /// Attempts to duplicate/clone the LruCache. An LruCache cannot be cloned regularly due to internal asynchronous locking.
-    pub async fn duplicate(&self) -> Self {
-        let mut shards = Vec::with_capacity(self.shards.len());
-        let mut size = 0;
-
-        for shard in &self.shards {
-            let shard = shard.0.read().await.clone();
-
-            let shard_len = shard.len();
-            size += shard_len;
-            shards.push((Arc::new(RwLock::new(shard)), AtomicUsize::new(shard_len)));
-        }
+impl<C: Clock, const K: usize> AtomicTimestamp for AccessHistory<C, K> {
+    #[inline]
+    fn now() -> Self {
+        // zero is before any clock reading, so accesses that haven't happened yet sort first
+        let history = core::array::from_fn(|i| AtomicU64::new(if i == 0 { C::now() } else { 0 }));
 
-        LruCache {
-            shards,
-            hash_builder: self.hash_builder.clone(),
-            size: AtomicUsize::new(size),
-        }
+        AccessHistory(history, PhantomData)
     }
-}
 
-impl<K, V, T, S> LruCache<K, V, T, S>
-where
-    K: Hash + Eq,
-    S: BuildHasher,
-    T: AtomicTimestamp,
-{
+    /// Shifts the history by one access. Concurrent updates may interleave, but each slot
+    /// still holds the time of some recent access, which is close enough for sampled eviction.
     #[inline]
-    pub fn size(&self) -> usize {
-        self.size.load(Ordering::SeqCst)
-    }
-
-    #[cfg(test)]
-    pub async fn test_size(&self) -> usize {
-        let mut size = 0;
-        for shard in &self.shards {
-            size += shard.0.read().await.len();
+    fn update(&self) {
+        for i in (1..K).rev() {
+            self.0[i].store(self.0[i - 1].load(Ordering::SeqCst), Ordering::SeqCst);
         }
 
-        size
-    }
-
-    #[inline]
-    pub fn hash_builder(&self) -> &S {
-        &self.hash_builder
+        if let Some(most_recent) = self.0.first() {
+            most_recent.store(C::now(), Ordering::SeqCst);
+        }
     }
 
+    /// Forgets every access, so the entry sorts before any accessed since
     #[inline]
-    pub fn num_shards(&self) -> usize {
-        self.shards.len()
-    }
-
-    pub async fn retain<F>(&self, f: F)
-    where
-        F: Fn(&K, &mut V) -> bool,
-    {
-        for (shard, _) in &self.shards {
-            let mut shard = shard.write().await;
-
-            let len = shard.len();
-            shard.retain(|k, tv| f(k, &mut tv.value));
-
-            self.size.fetch_sub(len - shard.len(), Ordering::SeqCst);
+    fn demote(&self) {
+        for access in &self.0 {
+            access.store(0, Ordering::SeqCst);
         }
     }
 
-    pub async fn clear(&self) {
-        for (shard, _) in &self.shards {
-            let mut shard = shard.write().await;
-            let len = shard.len();
-            shard.clear();
-
-            self.size.fetch_sub(len, Ordering::SeqCst);
+    /// Forgets every access but the one now, as for a new entry
+    #[inline]
+    fn reset(&self) {
+        for (i, access) in self.0.iter().enumerate() {
+            access.store(if i == 0 { C::now() } else { 0 }, Ordering::SeqCst);
         }
     }
 
     #[inline]
-    fn hash_and_shard<Q: ?Sized>(&self, key: &Q) -> (u64, usize)
-    where
-        Q: Hash + Eq,
-    {
-        let mut hasher = self.hash_builder.build_hasher();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
-        (hash, hash as usize % self.shards.len())
-    }
+    fn is_before(&self, other: &Self) -> bool {
+        let key = |history: &Self| match (history.0.last(), history.0.first()) {
+            (Some(kth), Some(most_recent)) => (kth.load(Ordering::SeqCst), most_recent.load(Ordering::SeqCst)),
+            _ => (0, 0),
+        };
 
-    async fn get_mut_raw<Q: ?Sized>(
-        &self,
-        key: &Q,
-    ) -> Option<WriteHandle<impl Erased, TimestampedValue<V, T>>>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        let (hash, shard_idx) = self.hash_and_shard(key);
-        let shard = unsafe { self.shards.get_unchecked(shard_idx).0.clone().write_owned().await };
-
-        OwnedRwLockWriteGuard::try_map(shard, |shard| shard.get_mut(hash, key)).ok()
+        key(self) < key(other)
     }
 
-    async fn get_raw<Q: ?Sized>(&self, key: &Q) -> Option<ReadHandle<impl Erased, TimestampedValue<V, T>>>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        let (hash, shard_idx) = self.hash_and_shard(key);
-        let shard = unsafe { self.shards.get_unchecked(shard_idx).0.clone().read_owned().await };
-
-        ReadHandle::try_map(shard, |shard| shard.get(hash, key)).ok()
+    /// Time between the `K`-th most recent accesses, or the most recent ones if neither was accessed `K` times
+    #[inline]
+    fn nanos_between(&self, other: &Self) -> Option<u64> {
+        let kth = |history: &Self| history.0.last().map_or(0, |kth| kth.load(Ordering::SeqCst));
+        let most_recent = |history: &Self| history.0.first().map_or(0, |first| first.load(Ordering::SeqCst));
+
+        match (kth(self), kth(other)) {
+            (0, 0) => Some(most_recent(self).abs_diff(most_recent(other))),
+            // one has been accessed `K` times and the other not, which outweighs any timing
+            (0, _) | (_, 0) => None,
+            (a, b) => Some(a.abs_diff(b)),
+        }
     }
+}
 
-    pub async fn peek<Q: ?Sized>(&self, key: &Q) -> Option<ReadHandle<impl Erased, V>>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        self.get_raw(key)
-            .await
-            .map(|tv| ReadHandle::map(tv, |tv| &tv.value))
-    }
+/// `EntryMeta::expires_at` of entries without a TTL
+const NEVER: u64 = u64::MAX;
 
-    pub async fn peek_mut<Q: ?Sized>(&self, key: &Q) -> Option<WriteHandle<impl Erased, V>>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        self.get_mut_raw(key)
-            .await
-            .map(|tv| WriteHandle::map(tv, |tv| &mut tv.value))
-    }
+/// Value of an `LruCache` entry along with its metadata.
+///
+/// Public only to appear in the type of handles (`LruReadHandle`, ...), its fields remain private.
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct TimestampedValue<V, T> {
+    value: V,
+    timestamp: T,
+    /// Clock reading when the value was inserted, for `LruCache::get_if_fresh` and `EntryState::age`
+    inserted_at: u64,
+    /// Metadata of the features the entry uses, allocated once one of them sets it to other than its default,
+    /// so entries of caches without TTLs, weighers or eviction domains only pay for the pointer
+    meta: Option<Box<EntryMeta>>,
+}
 
-    pub async fn get<Q: ?Sized>(&self, key: &Q) -> Option<ReadHandle<impl Erased, V>>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        let tv = self.get_raw(key).await;
+/// Metadata of an `LruCache` entry only set by optional features, see `TimestampedValue::meta`
+#[derive(Debug)]
+struct EntryMeta {
+    /// Clock reading after which the entry is considered expired, or `NEVER`,
+    /// atomic to be pushed back by reads under `ExpireAfter::Access`
+    expires_at: AtomicU64,
+    /// TTL the deadline is pushed back by on access, in nanoseconds, or 0 if only set by writes
+    refresh_ttl: u64,
+    /// Count of the cache's writes when the value was last written or lent out mutably during a
+    /// `LruCache::retain_async`, for it to tell whether the value was written while its predicate ran, or 0
+    version: u64,
+    /// Weight given to the entry by the cache's weigher when it was inserted
+    weight: u32,
+    /// Eviction domain of the entry's key, see `LruCache::with_eviction_domains`
+    domain: u16,
+}
 
-        if let Some(ref tv) = tv {
-            tv.timestamp.update();
+impl Default for EntryMeta {
+    fn default() -> Self {
+        EntryMeta {
+            expires_at: AtomicU64::new(NEVER),
+            refresh_ttl: 0,
+            version: 0,
+            weight: 1,
+            domain: 0,
         }
-
-        tv.map(|tv| ReadHandle::map(tv, |tv| &tv.value))
     }
+}
 
-    pub async fn get_mut<Q: ?Sized>(&self, key: &Q) -> Option<WriteHandle<impl Erased, V>>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        let mut tv = self.get_mut_raw(key).await;
-
-        // owned ref, don't bother with atomic overhead
-        if let Some(ref mut tv) = tv {
-            tv.timestamp = T::now();
+impl Clone for EntryMeta {
+    fn clone(&self) -> Self {
+        EntryMeta {
+            expires_at: AtomicU64::new(self.expires_at.load(Ordering::Relaxed)),
+            refresh_ttl: self.refresh_ttl,
+            version: self.version,
+            weight: self.weight,
+            domain: self.domain,
         }
-
-        tv.map(|tv| WriteHandle::map(tv, |tv| &mut tv.value))
     }
+}
 
-    pub async fn insert(&self, key: K, value: V) -> Option<V> {
-        let (hash, shard_idx) = self.hash_and_shard(&key);
-        let (locked_shard, shard_size) = unsafe { self.shards.get_unchecked(shard_idx) };
-
-        let mut shard = locked_shard.write().await;
-
-        let value = TimestampedValue {
+impl<V, T> TimestampedValue<V, T> {
+    #[inline]
+    fn new(value: V, timestamp: T) -> Self {
+        TimestampedValue {
             value,
-            timestamp: T::now(),
-        };
-
-        shard
-            .insert_full(hash, key, value, || {
-                self.size.fetch_add(1, Ordering::SeqCst);
-                shard_size.fetch_add(1, Ordering::SeqCst);
-            })
-            .1
-            .map(|tv| tv.value)
-    }
-
-    pub async fn remove<Q: ?Sized>(&self, key: &Q) -> Option<V>
-    where
-        K: Borrow<Q>,
-        Q: Hash + Eq,
-    {
-        let (hash, shard_idx) = self.hash_and_shard(&key);
-        let (locked_shard, shard_size) = unsafe { self.shards.get_unchecked(shard_idx) };
-
-        let mut shard = locked_shard.write().await;
-
-        match shard.swap_remove_full(hash, key) {
-            Some((_, tv)) => {
-                self.size.fetch_sub(1, Ordering::SeqCst);
-                // know the real size, so just store it
-                shard_size.store(shard.len(), Ordering::SeqCst);
-
-                Some(tv.value)
-            }
-            None => None,
+            timestamp,
+            inserted_at: 0,
+            meta: None,
         }
     }
 
-    fn non_empty_shards(&self) -> impl Iterator<Item = &Shard<K, TimestampedValue<V, T>>> {
-        self.shards
-            .iter()
-            .filter_map(|(shard, shard_size)| match shard_size.load(Ordering::SeqCst) {
-                0 => None,
-                _ => Some(shard),
-            })
+    #[inline]
+    fn weight(&self) -> u32 {
+        self.meta.as_ref().map_or(1, |meta| meta.weight)
     }
 
-    /// Fair element eviction based on 2-random sampling of two shards at once, and performs a random walk through
-    /// all shards as necessary to remain unbiased.
-    ///
-    /// NOTE: This method acquires one write lock per element, and can be inefficient for many evictions.
-    ///
-    /// If you want fair eviction of a handful of items, this is the method to use. For less-predictable bulk-eviction look at `evict_many_fast`
-    pub async fn evict<F>(&self, mut rng: impl Rng, mut predicate: F) -> Vec<(K, V)>
-    where
-        F: FnMut(&K, &mut V) -> Evict,
-    {
-        use rand::seq::SliceRandom;
-
-        /* Algorithm:
-
-            Overall: Evict and collect items until the predicate returns false
-            The predicate will test the oldest of the two selected items at each iteration
-
-            To start with, collect all non-empty shards, then shuffle them.
-
-            Take one of them (pop) and lock it.
-
-            Then, pick another random shard (pop), and begin selecting two random elements from between those,
-            pass the oldest to the predicate, and if the predicate returns true then evict it.
-
-            Swap shard_a and shard_b, then continue. This forms a random-walk of sorts between non-empty shards,
-            where it goes from A->B, B->C, C->D, etc.
-
-            Doing a random walk avoids having to reacquire the locks on each shard each iteration.
-
-            When `non_empty` runs empty, refill it with the same method and shuffle it again
-
-        */
-
-        let mut evicted = Vec::new();
-
-        let mut non_empty = Vec::with_capacity(self.shards.len());
-
-        macro_rules! pop_shard {
-            () => {
-                loop {
-                    match non_empty.pop() {
-                        Some(shard) => {
-                            let shard = shard.write().await;
-                            // once locked, check if the shard is actually non-empty
-                            if shard.len() > 0 {
-                                break Some(shard);
-                            }
-                        }
-                        None => break None,
-                    }
-                }
-            };
-        }
-
-        'evict: while self.size() > 0 {
-            non_empty.extend(self.non_empty_shards());
-            non_empty.shuffle(&mut rng);
-
-            let mut shard_a = match pop_shard!() {
-                Some(shard) => shard,
-                // if we couldn't find an actual non-empty shard, go back to `while size > 0`, and if there is still one, sample it.
-                None => continue 'evict,
-            };
-
-            'walk: loop {
-                match pop_shard!() {
-                    None => {
-                        // single-shard case
-                        let res = match shard_a.len() {
-                            1 => unsafe {
-                                let shard::Bucket {
-                                    ref key,
-                                    ref mut value,
-                                    ..
-                                } = shard_a.entries.get_unchecked_mut(0);
-
-                                let res = predicate(key, &mut value.value);
-
-                                if matches!(res, Evict::Continue | Evict::Once) {
-                                    shard_a.indices.clear();
-                                    let shard::Bucket { key, value, .. } = shard_a.entries.pop().unwrap();
-                                    self.size.fetch_sub(1, Ordering::SeqCst);
-                                    evicted.push((key, value.value));
-                                }
-
-                                res
-                            },
-                            len @ _ => unsafe {
-                                let (elem_a_idx, elem_b_idx) = pick_indices(len, &mut rng);
-
-                                let ts_a = &shard_a.entries.get_unchecked(elem_a_idx).value.timestamp;
-                                let ts_b = &shard_a.entries.get_unchecked(elem_b_idx).value.timestamp;
-                                let idx = if ts_a.is_before(ts_b) {
-                                    elem_a_idx
-                                } else {
-                                    elem_b_idx
-                                };
-
-                                let shard::Bucket {
-                                    ref key,
-                                    ref mut value,
-                                    ..
-                                } = shard_a.entries.get_unchecked_mut(idx);
-
-                                let res = predicate(key, &mut value.value);
-
-                                if matches!(res, Evict::Continue | Evict::Once) {
-                                    let (key, value) = shard_a.swap_remove_index_raw(idx);
-                                    self.size.fetch_sub(1, Ordering::SeqCst);
-                                    evicted.push((key, value.value));
-                                }
-
-                                res
-                            },
-                        };
-
-                        if matches!(res, Evict::Once | Evict::None) {
-                            break 'evict;
-                        }
-
-                        // since pop_shard!() returned None, there is no point in looping again,
-                        // so try to refresh the non_empty shard list
-                        continue 'evict;
-                    }
-                    Some(mut shard_b) => unsafe {
-                        // two-shard case
-
-                        let shard_a_len = shard_a.len();
-                        let shard_b_len = shard_b.len();
-
-                        debug_assert!(shard_a_len > 0);
-                        debug_assert!(shard_b_len > 0);
-
-                        let sample_range = shard_a_len + shard_b_len;
-
-                        let (elem_a_range_idx, elem_b_range_idx) = pick_indices(sample_range, &mut rng);
-
-                        let ts_a = if elem_a_range_idx < shard_a_len {
-                            &shard_a.entries.get_unchecked(elem_a_range_idx).value.timestamp
-                        } else {
-                            &shard_b
-                                .entries
-                                .get_unchecked(elem_a_range_idx - shard_a_len)
-                                .value
-                                .timestamp
-                        };
-
-                        let ts_b = if elem_b_range_idx < shard_a_len {
-                            &shard_a.entries.get_unchecked(elem_b_range_idx).value.timestamp
-                        } else {
-                            &shard_b
-                                .entries
-                                .get_unchecked(elem_b_range_idx - shard_a_len)
-                                .value
-                                .timestamp
-                        };
-
-                        let elem_range_idx = if ts_a.is_before(ts_b) {
-                            elem_a_range_idx
-                        } else {
-                            elem_b_range_idx
-                        };
-
-                        let (shard, idx) = if elem_range_idx < shard_a_len {
-                            (&mut shard_a, elem_range_idx)
-                        } else {
-                            (&mut shard_b, elem_range_idx - shard_a_len)
-                        };
-
-                        let shard::Bucket {
-                            ref key,
-                            ref mut value,
-                            ..
-                        } = shard.entries.get_unchecked_mut(idx);
-
-                        let res = predicate(key, &mut value.value);
-
-                        if matches!(res, Evict::Continue | Evict::Once) {
-                            let (key, value) = shard.swap_remove_index_raw(idx);
-                            self.size.fetch_sub(1, Ordering::SeqCst);
-                            evicted.push((key, value.value));
-                        }
-
-                        if matches!(res, Evict::None | Evict::Once) {
-                            break 'evict;
-                        }
-
-                        shard_a = shard_b; // do random walk A->B, B->C, etc.
-                    },
-                }
+    #[cfg(feature = "tokio")]
+    #[inline]
+    fn domain(&self) -> u16 {
+        self.meta.as_ref().map_or(0, |meta| meta.domain)
+    }
+}
 
-                // if the former shard_b was emptied by the eviction, then try to find a new one before continuing
-                if shard_a.len() == 0 {
-                    shard_a = match pop_shard!() {
-                        Some(shard) => shard,
-                        None => break 'walk,
-                    };
-                }
-            }
+impl<V, T> Clone for TimestampedValue<V, T>
+where
+    V: Clone,
+    T: AtomicTimestamp,
+{
+    fn clone(&self) -> Self {
+        TimestampedValue {
+            value: self.value.clone(),
+            timestamp: T::now(),
+            inserted_at: self.inserted_at,
+            meta: self.meta.clone(),
         }
-
-        evicted
     }
+}
 
-    /// Fairly evict many elements, based on 2-random sampling of two shards at once, and performs a random walk through
-    /// all shards as necessary to remain unbiased.
-    ///
-    /// NOTE: This method acquires one write lock per element, and can be inefficient for many evictions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Evict {
+    /// Continue to evict after this item
+    Continue,
+    /// Evict only this item and then no more
+    Once,
+    /// Do not evict this item nor any more others
+    None,
+    /// Do not evict this item, but keep sampling others, e.g. to protect certain entries.
     ///
-    /// If you want fair eviction of a handful of items, this is the method to use. For less-predictable bulk-eviction look at `evict_many_fast`
-    pub async fn evict_many(&self, mut count: usize, rng: impl Rng) -> Vec<(K, V)> {
-        count = count.min(self.size());
+    /// Eviction only stops once the predicate returns `Once` or `None`, or the cache is empty,
+    /// so a predicate that skips every entry never returns, unless bounded with `LruCache::evict_limited`.
+    Skip,
+}
 
-        if count == 0 {
-            return Vec::new();
-        }
+/// Bounds on how long `LruCache::evict_limited` keeps sampling, unbounded by default
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EvictLimit {
+    /// Maximum number of entries passed to the predicate
+    pub max_samples: Option<usize>,
+    /// Maximum time spent evicting, checked before each sample
+    pub max_duration: Option<Duration>,
+}
 
-        let mut cur = count;
+/// Why `LruCache::evict_limited` or `evict_many_until` stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictStop {
+    /// The predicate returned `Evict::Once` or `Evict::None`
+    Predicate,
+    /// The cache is empty
+    Empty,
+    /// `EvictLimit::max_samples` entries were sampled
+    MaxSamples,
+    /// `EvictLimit::max_duration` elapsed
+    MaxDuration,
+    /// The `Deadline` given to `LruCache::evict_many_until` was reached
+    Deadline,
+}
 
-        self.evict(rng, |_, _| {
-            cur -= 1;
+/// Which entry of those sampled for eviction is evicted
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionOrder {
+    /// The least recently used
+    #[default]
+    Lru,
+    /// The most recently used, for cyclic scans over more entries than are kept,
+    /// where the entry just used is the one that will be needed again last
+    Mru,
+    /// The first inserted. Accesses do not update timestamps, so they only record when entries were inserted.
+    Fifo,
+}
 
-            match cur {
-                0 => Evict::Once,
-                _ => Evict::Continue,
-            }
-        })
-        .await
+impl EvictionOrder {
+    /// Whether the entry timestamped `a` is evicted before the one timestamped `b`
+    #[inline]
+    fn evicts_before<T: AtomicTimestamp>(self, a: &T, b: &T) -> bool {
+        match self {
+            EvictionOrder::Lru | EvictionOrder::Fifo => a.is_before(b),
+            EvictionOrder::Mru => b.is_before(a),
+        }
     }
 
-    // Fairly evict one element
-    pub async fn evict_one(&self, rng: impl Rng) -> Option<(K, V)> {
-        self.evict(rng, |_, _| Evict::Once).await.pop()
+    /// Whether accesses update the timestamps of entries
+    #[inline]
+    fn tracks_access(self) -> bool {
+        self != EvictionOrder::Fifo
     }
+}
 
-    /// Less-fair and less-predictable algorithm that only acquires shard locks once at most,
-    /// but may not evict the exact number of requested elements (a couple more or less)
-    ///
-    /// Compare to `evict` or `evict_many` that acquires a shard lock *per-item evicted*,
-    /// but is more fair and unbiased in doing so.
-    pub async fn evict_many_fast(&self, mut count: usize, mut rng: impl Rng) -> Vec<(K, V)> {
-        use rand::prelude::SliceRandom;
-
-        count = count.min(self.size());
-
-        let mut evicted = Vec::new();
-
-        if count == 0 {
-            return evicted;
-        }
+/// How entries sampled for eviction are compared, by their `EvictionOrder`, and optionally by weight
+#[derive(Debug, Clone, Copy)]
+struct VictimOrder {
+    order: EvictionOrder,
+    /// Timestamps at most this many nanoseconds apart are considered tied, in which case the heavier entry is
+    /// evicted first, or 0 to only compare timestamps
+    weight_window: u64,
+}
 
-        let mut non_empty = Vec::with_capacity(self.shards.len());
-        non_empty.extend(self.non_empty_shards());
-        non_empty.shuffle(&mut rng);
+impl From<EvictionOrder> for VictimOrder {
+    #[inline]
+    fn from(order: EvictionOrder) -> Self {
+        VictimOrder { order, weight_window: 0 }
+    }
+}
 
-        fn proportion_of(size: usize, len: usize, count: usize) -> usize {
-            // `len / size` is the fraction this shard holds of the entire structure, between 0 and 1
-            // so `count * fraction` is the number of elements to be taken from this shard
-            // reorganize to avoid floating point, at the cost of 128-bit ints
-            ((count as u128 * len as u128) / size as u128) as usize + 1
+impl VictimOrder {
+    /// Whether the entry `a` is evicted before the entry `b`
+    #[inline]
+    fn evicts_before<V, T: AtomicTimestamp>(self, a: &TimestampedValue<V, T>, b: &TimestampedValue<V, T>) -> bool {
+        if self.weight_window != 0 && a.weight() != b.weight() {
+            match a.timestamp.nanos_between(&b.timestamp) {
+                Some(between) if between <= self.weight_window => return a.weight() > b.weight(),
+                _ => {}
+            }
         }
 
-        let size = self.size();
-
-        let mut sum = 0;
-        for shard in non_empty {
-            let mut shard = shard.write().await;
-
-            if shard.len() == 0 {
-                continue;
-            }
+        self.order.evicts_before(&a.timestamp, &b.timestamp)
+    }
+}
 
-            let mut sub_count = proportion_of(size, shard.len(), count);
-            sum += sub_count;
+/// What pushes back the deadline of an entry inserted with a TTL
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExpireAfter {
+    /// Only writes, i.e. the entry expires its TTL after being inserted
+    #[default]
+    Write,
+    /// Writes and reads hitting the entry, i.e. the entry expires once left unused for its TTL
+    Access,
+}
 
-            if sum > count {
-                sub_count = sum - count - 1;
-            }
+/// Result of `LruCache::lookup`, telling apart values that are still present but have expired
+#[derive(Debug)]
+pub enum Lookup<G> {
+    /// The value is present and has not expired
+    Hit(G),
+    /// The value is present, but its TTL has passed, so it may be served while a fresh one is fetched
+    Stale(G),
+    /// There is no value
+    Miss,
+}
 
-            if sub_count == shard.len() {
-                // fast path for evicting all of this shard
-                evicted.extend(
-                    shard
-                        .entries
-                        .drain(..)
-                        .map(|bucket| (bucket.key, bucket.value.value)),
-                );
-
-                shard.indices.clear();
-                self.size.fetch_sub(sub_count, Ordering::SeqCst); // sub_count == shard.len() here
-            } else {
-                for _ in 0..sub_count {
-                    let (elem_a_idx, elem_b_idx) = pick_indices(shard.len(), &mut rng);
-
-                    unsafe {
-                        let ts_a = &shard.entries.get_unchecked(elem_a_idx).value.timestamp;
-                        let ts_b = &shard.entries.get_unchecked(elem_b_idx).value.timestamp;
-
-                        let idx = if ts_a.is_before(ts_b) {
-                            elem_a_idx
-                        } else {
-                            elem_b_idx
-                        };
-
-                        evicted.push({
-                            let (key, value) = shard.swap_remove_index_raw(idx);
-                            self.size.fetch_sub(1, Ordering::SeqCst);
-                            (key, value.value)
-                        });
-                    }
-                }
-            }
+impl<G> Lookup<G> {
+    #[inline]
+    pub fn is_hit(&self) -> bool {
+        matches!(self, Lookup::Hit(_))
+    }
 
-            if sum > count {
-                break;
-            }
+    /// The value, whether fresh or stale
+    #[inline]
+    pub fn into_inner(self) -> Option<G> {
+        match self {
+            Lookup::Hit(value) | Lookup::Stale(value) => Some(value),
+            Lookup::Miss => None,
         }
-
-        evicted
     }
 }
 
+/// State of an entry, as passed to the predicate of `LruCache::retain_async` and returned by
+/// `LruCache::eviction_candidates`
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Evict {
-    /// Continue to evict after this item
-    Continue,
-    /// Evict only this item and then no more
-    Once,
-    /// Do not evict this item nor any more others
-    None,
+pub struct EntryState {
+    /// Time since the value was inserted
+    pub age: Duration,
+    /// Time left until the value expires, if it was inserted with a TTL, zero once expired
+    pub ttl_remaining: Option<Duration>,
+    /// Weight given to the entry by the cache's weigher
+    pub weight: u32,
+}
+
+impl EntryState {
+    #[inline]
+    pub fn is_expired(&self) -> bool {
+        self.ttl_remaining == Some(Duration::ZERO)
+    }
 }
 
 fn pick_indices(len: usize, mut rng: impl Rng) -> (usize, usize) {
@@ -667,3 +451,120 @@ fn pick_indices(len: usize, mut rng: impl Rng) -> (usize, usize) {
         }
     }
 }
+
+/// Samples two random entries of a non-empty shard and returns the index of the one to evict first
+#[inline]
+fn sample_victim<K, V, T>(
+    shard: &IndexedShard<K, TimestampedValue<V, T>>,
+    order: VictimOrder,
+    rng: impl Rng,
+) -> usize
+where
+    T: AtomicTimestamp,
+{
+    let (elem_a_idx, elem_b_idx) = pick_indices(shard.len(), rng);
+
+    // SAFETY: `pick_indices` only returns indices within the shard length
+    unsafe {
+        let elem_a = &shard.entries.get_unchecked(elem_a_idx).value;
+        let elem_b = &shard.entries.get_unchecked(elem_b_idx).value;
+
+        if order.evicts_before(elem_a, elem_b) {
+            elem_a_idx
+        } else {
+            elem_b_idx
+        }
+    }
+}
+
+/// Index of the entry to evict first among the (at most) `window` entries starting at `start`
+/// and wrapping around the non-empty shard
+#[cfg(feature = "tokio")]
+fn victim_in_window<K, V, T>(
+    shard: &IndexedShard<K, TimestampedValue<V, T>>,
+    order: VictimOrder,
+    start: usize,
+    window: usize,
+) -> usize
+where
+    T: AtomicTimestamp,
+{
+    let len = shard.len();
+
+    (1..window.min(len))
+        .map(|offset| (start + offset) % len)
+        .fold(start % len, |victim, idx| {
+            let elem = |idx: usize| &shard.entries[idx].value;
+
+            if order.evicts_before(elem(idx), elem(victim)) {
+                idx
+            } else {
+                victim
+            }
+        })
+}
+
+/// Like `victim_in_window`, but only compares the first `window` entries of the eviction domain from `start` on,
+/// or returns `None` if the shard holds none
+#[cfg(feature = "tokio")]
+fn victim_in_domain<K, V, T>(
+    shard: &IndexedShard<K, TimestampedValue<V, T>>,
+    order: VictimOrder,
+    start: usize,
+    window: usize,
+    domain: u16,
+) -> Option<usize>
+where
+    T: AtomicTimestamp,
+{
+    let len = shard.len();
+    let elem = |idx: usize| &shard.entries[idx].value;
+
+    (0..len)
+        .map(|offset| (start + offset) % len)
+        .filter(|&idx| elem(idx).domain() == domain)
+        .take(window)
+        .reduce(|victim, idx| match order.evicts_before(elem(idx), elem(victim)) {
+            true => idx,
+            false => victim,
+        })
+}
+
+/// Samples two random entries across the combined range of two non-empty shards, and returns
+/// whether the one to evict first is in `shard_b`, along with its index in that shard
+#[cfg(feature = "tokio")]
+#[inline]
+fn sample_victim_across<K, V, T>(
+    shard_a: &IndexedShard<K, TimestampedValue<V, T>>,
+    shard_b: &IndexedShard<K, TimestampedValue<V, T>>,
+    order: VictimOrder,
+    rng: impl Rng,
+) -> (bool, usize)
+where
+    T: AtomicTimestamp,
+{
+    let shard_a_len = shard_a.len();
+
+    let elem_at = |range_idx: usize| unsafe {
+        // SAFETY: `pick_indices` only returns indices within the combined length
+        if range_idx < shard_a_len {
+            &shard_a.entries.get_unchecked(range_idx).value
+        } else {
+            &shard_b.entries.get_unchecked(range_idx - shard_a_len).value
+        }
+    };
+
+    let (elem_a_range_idx, elem_b_range_idx) = pick_indices(shard_a_len + shard_b.len(), rng);
+
+    let elem_range_idx = if order.evicts_before(elem_at(elem_a_range_idx), elem_at(elem_b_range_idx)) {
+        elem_a_range_idx
+    } else {
+        elem_b_range_idx
+    };
+
+    if elem_range_idx < shard_a_len {
+        (false, elem_range_idx)
+    } else {
+        (true, elem_range_idx - shard_a_len)
+    }
+}
@@ -0,0 +1,206 @@
+use core::borrow::Borrow;
+use core::hash::{BuildHasher, Hash};
+
+use alloc::vec::Vec;
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use rand::Rng;
+
+use super::shard::{self, IndexedShard};
+use super::{sample_victim, AtomicTimestamp, Evict, EvictionOrder, TimestampedValue};
+
+/// A single, unsynchronized shard of sampled-LRU entries.
+///
+/// This is the same structure and 2-random sampling eviction each shard of `LruCache` uses, but without any
+/// locking of its own, so it can be used without `std`. Synchronization is left to the embedder
+/// (e.g. wrap it in an RTOS mutex), as are timestamps (implement [`AtomicTimestamp`] over a tick counter)
+/// and randomness (any `rand::Rng`).
+#[derive(Debug)]
+pub struct SampledLru<K, V, T, S = DefaultHashBuilder> {
+    hash_builder: S,
+    shard: IndexedShard<K, TimestampedValue<V, T>>,
+    eviction_order: EvictionOrder,
+}
+
+impl<K, V, T> SampledLru<K, V, T, DefaultHashBuilder> {
+    pub fn new() -> Self {
+        Self::with_hasher(DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V, T> Default for SampledLru<K, V, T, DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, T, S> SampledLru<K, V, T, S> {
+    pub const fn with_hasher(hash_builder: S) -> Self {
+        SampledLru {
+            hash_builder,
+            shard: IndexedShard::new(),
+            eviction_order: EvictionOrder::Lru,
+        }
+    }
+
+    /// Sets which of the entries sampled by eviction is evicted, the least recently used by default
+    pub const fn with_eviction_order(mut self, order: EvictionOrder) -> Self {
+        self.eviction_order = order;
+        self
+    }
+
+    #[inline]
+    pub fn eviction_order(&self) -> EvictionOrder {
+        self.eviction_order
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.shard.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.shard.len() == 0
+    }
+
+    #[inline]
+    pub fn hash_builder(&self) -> &S {
+        &self.hash_builder
+    }
+
+    pub fn clear(&mut self) {
+        self.shard.clear();
+    }
+}
+
+impl<K, V, T, S> SampledLru<K, V, T, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+    T: AtomicTimestamp,
+{
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard.get(self.hash_builder.hash_one(key), key).map(|tv| &tv.value)
+    }
+
+    pub fn peek_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard
+            .get_mut(self.hash_builder.hash_one(key), key)
+            .map(|tv| &mut tv.value)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard.get(self.hash_builder.hash_one(key), key).map(|tv| {
+            if self.eviction_order.tracks_access() {
+                tv.timestamp.update();
+            }
+            &tv.value
+        })
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let tracks_access = self.eviction_order.tracks_access();
+
+        self.shard.get_mut(self.hash_builder.hash_one(key), key).map(|tv| {
+            if tracks_access {
+                tv.timestamp.update();
+            }
+            &mut tv.value
+        })
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let hash = self.hash_builder.hash_one(&key);
+
+        let value = TimestampedValue::new(value, T::now());
+
+        self.shard.insert_full(hash, key, value, || {}).1.map(|tv| tv.value)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard
+            .swap_remove_full(self.hash_builder.hash_one(key), key)
+            .map(|(_, tv)| tv.value)
+    }
+
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        self.shard.retain(|k, tv| f(k, &mut tv.value));
+    }
+
+    /// Element eviction based on 2-random sampling, passing the oldest of each sampled pair to the predicate.
+    pub fn evict<F>(&mut self, mut rng: impl Rng, mut predicate: F) -> Vec<(K, V)>
+    where
+        F: FnMut(&K, &mut V) -> Evict,
+    {
+        let mut evicted = Vec::new();
+
+        while self.shard.len() > 0 {
+            let idx = sample_victim(&self.shard, self.eviction_order.into(), &mut rng);
+
+            let shard::Bucket {
+                ref key,
+                ref mut value,
+                ..
+            } = unsafe { self.shard.entries.get_unchecked_mut(idx) };
+
+            let res = predicate(key, &mut value.value);
+
+            if matches!(res, Evict::Continue | Evict::Once) {
+                let (key, value) = unsafe { self.shard.swap_remove_index_raw(idx) };
+                evicted.push((key, value.value));
+            }
+
+            if matches!(res, Evict::Once | Evict::None) {
+                break;
+            }
+        }
+
+        evicted
+    }
+
+    pub fn evict_many(&mut self, count: usize, rng: impl Rng) -> Vec<(K, V)> {
+        let mut cur = count.min(self.shard.len());
+
+        if cur == 0 {
+            return Vec::new();
+        }
+
+        self.evict(rng, |_, _| {
+            cur -= 1;
+
+            match cur {
+                0 => Evict::Once,
+                _ => Evict::Continue,
+            }
+        })
+    }
+
+    pub fn evict_one(&mut self, rng: impl Rng) -> Option<(K, V)> {
+        self.evict(rng, |_, _| Evict::Once).pop()
+    }
+}
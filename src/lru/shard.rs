@@ -1,4 +1,6 @@
-use std::{borrow::Borrow, fmt};
+use core::{borrow::Borrow, fmt};
+
+use alloc::vec::Vec;
 
 use hashbrown::raw::RawTable;
 
@@ -12,6 +14,10 @@ pub struct Bucket<K, V> {
 pub struct IndexedShard<K, V> {
     pub(crate) indices: RawTable<usize>,
     pub(crate) entries: Vec<Bucket<K, V>>,
+    /// Number of times entries were removed, which moves others back, so positional iteration can detect it
+    pub(crate) generation: u64,
+    /// Number of entries of each eviction domain, kept by an `LruCache` with domains, trailing zeros left out
+    pub(crate) domain_lens: Vec<usize>,
 }
 
 impl<K, V> Clone for IndexedShard<K, V>
@@ -23,7 +29,13 @@ where
         let indices = self.indices.clone();
         let mut entries = Vec::with_capacity(indices.len());
         entries.clone_from(&self.entries);
-        IndexedShard { indices, entries }
+
+        IndexedShard {
+            indices,
+            entries,
+            generation: self.generation,
+            domain_lens: self.domain_lens.clone(),
+        }
     }
 
     fn clone_from(&mut self, source: &Self) {
@@ -36,6 +48,8 @@ where
         }
 
         self.entries.clone_from(&source.entries);
+        self.generation = source.generation;
+        self.domain_lens.clone_from(&source.domain_lens);
     }
 }
 
@@ -57,6 +71,8 @@ where
         f.debug_struct("IndexMapCore")
             .field("indices", &DebugIndices(&self.indices))
             .field("entries", &self.entries)
+            .field("generation", &self.generation)
+            .field("domain_lens", &self.domain_lens)
             .finish()
     }
 }
@@ -67,6 +83,8 @@ impl<K, V> IndexedShard<K, V> {
         IndexedShard {
             indices: RawTable::new(),
             entries: Vec::new(),
+            generation: 0,
+            domain_lens: Vec::new(),
         }
     }
 
@@ -78,17 +96,54 @@ impl<K, V> IndexedShard<K, V> {
     pub(crate) fn clear(&mut self) {
         self.entries.clear();
         self.indices.clear();
+        self.domain_lens.clear();
+        self.bump_generation();
+    }
+
+    /// To be called after removing entries without going through `swap_remove_finish` or `clear`
+    #[inline]
+    pub(crate) fn bump_generation(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[inline]
+    pub(crate) fn domain_len(&self, domain: u16) -> usize {
+        self.domain_lens.get(domain as usize).copied().unwrap_or(0)
+    }
+
+    #[cfg(feature = "tokio")]
+    /// To be called after adding an entry of the eviction domain
+    #[inline]
+    pub(crate) fn count_domain(&mut self, domain: u16) {
+        let domain = domain as usize;
+
+        if domain >= self.domain_lens.len() {
+            self.domain_lens.resize(domain + 1, 0);
+        }
+
+        self.domain_lens[domain] += 1;
+    }
+
+    #[cfg(feature = "tokio")]
+    /// To be called after removing an entry of the eviction domain, doing nothing if domains aren't counted
+    #[inline]
+    pub(crate) fn uncount_domain(&mut self, domain: u16) {
+        if let Some(len) = self.domain_lens.get_mut(domain as usize) {
+            *len = len.saturating_sub(1);
+        }
     }
 
     /// Append a key-value pair, *without* checking whether it already exists,
     /// and return the pair's new index.
     #[inline]
-    fn push(&mut self, hash: u64, key: K, value: V) -> usize {
+    pub(crate) fn push_unique(&mut self, hash: u64, key: K, value: V) -> usize {
         let index = self.entries.len();
 
         let IndexedShard {
             ref mut indices,
             ref entries,
+            ..
         } = self;
 
         indices.insert(hash, index, |&idx| unsafe { entries.get_unchecked(idx).hash });
@@ -106,10 +161,10 @@ impl<K, V> IndexedShard<K, V> {
 
     /// Return the index in `entries` where an equivalent key can be found
     #[inline]
-    pub(crate) fn get_index_of<Q: ?Sized>(&self, hash: u64, key: &Q) -> Option<usize>
+    pub(crate) fn get_index_of<Q>(&self, hash: u64, key: &Q) -> Option<usize>
     where
         K: Borrow<Q>,
-        Q: Eq,
+        Q: ?Sized + Eq,
     {
         self.indices
             .get(hash, |&idx| self.entries[idx].key.borrow() == key)
@@ -117,20 +172,20 @@ impl<K, V> IndexedShard<K, V> {
     }
 
     #[inline]
-    pub(crate) fn get<Q: ?Sized>(&self, hash: u64, key: &Q) -> Option<&V>
+    pub(crate) fn get<Q>(&self, hash: u64, key: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
-        Q: Eq,
+        Q: ?Sized + Eq,
     {
         self.get_index_of(hash, key)
             .map(|idx| unsafe { &self.entries.get_unchecked(idx).value })
     }
 
     #[inline]
-    pub(crate) fn get_mut<Q: ?Sized>(&mut self, hash: u64, key: &Q) -> Option<&mut V>
+    pub(crate) fn get_mut<Q>(&mut self, hash: u64, key: &Q) -> Option<&mut V>
     where
         K: Borrow<Q>,
-        Q: Eq,
+        Q: ?Sized + Eq,
     {
         self.get_index_of(hash, key)
             .map(move |idx| unsafe { &mut self.entries.get_unchecked_mut(idx).value })
@@ -148,19 +203,19 @@ impl<K, V> IndexedShard<K, V> {
         K: Eq,
     {
         match self.get_index_of(hash, &key) {
-            Some(i) => (i, Some(std::mem::replace(&mut self.entries[i].value, value))),
+            Some(i) => (i, Some(core::mem::replace(&mut self.entries[i].value, value))),
             None => {
                 before_insert();
-                (self.push(hash, key, value), None)
+                (self.push_unique(hash, key, value), None)
             }
         }
     }
 
     #[inline]
-    pub(crate) fn swap_remove_full<Q: ?Sized>(&mut self, hash: u64, key: &Q) -> Option<(K, V)>
+    pub(crate) fn swap_remove_full<Q>(&mut self, hash: u64, key: &Q) -> Option<(K, V)>
     where
         K: Borrow<Q>,
-        Q: Eq,
+        Q: ?Sized + Eq,
     {
         match self.get_index_of(hash, key) {
             Some(index) => {
@@ -186,6 +241,7 @@ impl<K, V> IndexedShard<K, V> {
         // use swap_remove, but then we need to update the index that points
         // to the other entry that has to move
         let entry = self.entries.swap_remove(index);
+        self.bump_generation();
 
         // correct index that points to the entry that had to swap places
         if let Some(entry) = self.entries.get(index) {
@@ -208,6 +264,19 @@ impl<K, V> IndexedShard<K, V> {
         self.entries.reserve_exact(additional);
     }
 
+    /// Reserve capacity for at least `additional` more entries
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        let IndexedShard {
+            ref mut indices,
+            ref entries,
+            ..
+        } = self;
+
+        indices.reserve(additional, |&idx| unsafe { entries.get_unchecked(idx).hash });
+
+        self.reserve_entries();
+    }
+
     pub(crate) fn retain<F>(&mut self, mut f: F)
     where
         F: FnMut(&K, &mut V) -> bool,
@@ -0,0 +1,127 @@
+//! Peek-only views of an `LruCache`, see `LruCache::read_only_view`.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::hash::ShardSelect;
+use crate::sync::{Arc, OwnedRwLockReadGuard, RwLock};
+use crate::{read_handle, ReadHandle};
+
+use super::{AtomicInstant, IndexedShard, LruReadHandle, TimestampedValue};
+
+type Shard<K, V, T> = Arc<RwLock<IndexedShard<K, TimestampedValue<V, T>>>>;
+
+/// Lookup-only access to the entries of an [`LruCache`](super::LruCache), as returned by `read_only_view`,
+/// for handing to components that must not write to the cache.
+///
+/// Lookups are peeks: they leave the timestamps of entries alone, so the view's readers don't keep entries
+/// from being evicted. Expired entries are treated as absent, like in the cache. The view shares the cache's shards,
+/// so it sees every write to the cache, and can be cloned cheaply.
+///
+/// ```
+/// # use quick_hash_cache::lru::LruCache;
+/// # #[tokio::main] async fn main() {
+/// let cache = LruCache::<&str, u32>::new(4);
+/// let view = cache.read_only_view();
+///
+/// cache.insert("a", 1).await;
+///
+/// assert_eq!(view.peek_cloned(&"a").await, Some(1));
+/// # }
+/// ```
+pub struct ReadOnlyLruCache<K, V, T = AtomicInstant, S = DefaultHashBuilder> {
+    hash_builder: S,
+    shard_select: ShardSelect,
+    shards: Vec<Shard<K, V, T>>,
+}
+
+impl<K, V, T, S> ReadOnlyLruCache<K, V, T, S> {
+    pub(super) fn new(hash_builder: S, shard_select: ShardSelect, shards: Vec<Shard<K, V, T>>) -> Self {
+        ReadOnlyLruCache {
+            hash_builder,
+            shard_select,
+            shards,
+        }
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+}
+
+impl<K, V, T, S> ReadOnlyLruCache<K, V, T, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    fn hash_and_shard<Q>(&self, key: &Q) -> (u64, usize)
+    where
+        Q: ?Sized + Hash,
+    {
+        let hash = self.hash_builder.hash_one(key);
+        (hash, self.shard_select.shard_of(hash, self.shards.len()))
+    }
+
+    /// Reads the value without updating its timestamp
+    pub async fn peek<Q>(&self, key: &Q) -> Option<LruReadHandle<K, V, T>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone().read_owned().await };
+
+        OwnedRwLockReadGuard::try_map(shard, |shard| shard.get(hash, key).filter(|tv| !tv.is_expired()))
+            .ok()
+            .map(read_handle)
+            .map(|tv| ReadHandle::map(tv, |tv| &tv.value))
+    }
+
+    /// Clones the value out without updating its timestamp
+    pub async fn peek_cloned<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).read().await };
+
+        shard.get(hash, key).filter(|tv| !tv.is_expired()).map(|tv| tv.value.clone())
+    }
+
+    pub async fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).read().await };
+
+        shard.get(hash, key).is_some_and(|tv| !tv.is_expired())
+    }
+}
+
+impl<K, V, T, S> Clone for ReadOnlyLruCache<K, V, T, S>
+where
+    S: Clone,
+{
+    fn clone(&self) -> Self {
+        ReadOnlyLruCache {
+            hash_builder: self.hash_builder.clone(),
+            shard_select: self.shard_select.clone(),
+            shards: self.shards.clone(),
+        }
+    }
+}
+
+impl<K, V, T, S> fmt::Debug for ReadOnlyLruCache<K, V, T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadOnlyLruCache")
+            .field("num_shards", &self.shards.len())
+            .finish()
+    }
+}
@@ -0,0 +1,3033 @@
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::convert::Infallible;
+use std::future::{poll_fn, Future};
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::ops::{Range, RangeInclusive};
+use std::task::Poll;
+use std::time::Duration;
+
+use hashbrown::hash_map::{DefaultHashBuilder, HashMap, RawEntryMut};
+
+use crate::changelog::{ChangeLogs, ResyncNeeded, ShardDiff};
+use crate::clock::{Clock, DefaultClock};
+#[cfg(feature = "bloom")]
+use crate::bloom::ShardFilters;
+use crate::batch::{group_by_shard, Batch, BatchContext};
+use crate::cow::{CowShard, CowShares, CowSnapshot};
+use crate::deadline::{self, Deadline, Progress};
+use crate::error::Error;
+use crate::estimate::HyperLogLog;
+use crate::hash::ShardSelect;
+use crate::loader::{self, InFlight};
+use crate::microcache::MicroCache;
+use crate::numa::NumaPlacement;
+use crate::persist::{self, SnapshotError};
+use crate::pins::ShardPins;
+use crate::report::{LoadReport, RebalanceHint};
+use crate::scan::{self, Cursor};
+use crate::shed::LoadShedding;
+use crate::sweeper;
+use crate::sync::{
+    Arc, AtomicU64, AtomicUsize, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, Ordering, RwLock, SpinLock,
+};
+use crate::view::ReadOnlyCHashMap;
+use crate::watch::{Watch, Watchers};
+use crate::watermark::{SizeCrossing, Watermarks};
+
+use tokio::sync::{RwLockReadGuard, RwLockWriteGuard};
+use crate::{read_handle, write_handle, Defer, ReadHandle, SeqLock, Shard, WriteHandle};
+
+/// `ReadHandle` to a value of a `CHashMap<K, T, S>`, as returned by `get`, `get_or_insert`, ...
+pub type MapReadHandle<K, T, S = DefaultHashBuilder> = ReadHandle<Shard<K, T, S>, T>;
+
+/// `WriteHandle` to a value of a `CHashMap<K, T, S>`, as returned by `get_mut`, `get_mut_or_insert`, ...
+pub type MapWriteHandle<K, T, S = DefaultHashBuilder> = WriteHandle<Shard<K, T, S>, T>;
+
+/// Maximum number of shard locks `map_reduce` waits on at once
+const MAP_REDUCE_CONCURRENCY: usize = 8;
+
+/// Hints the CPU to load the memory behind the reference into its caches ahead of it being accessed
+#[cfg(feature = "batch-prefetch")]
+#[inline(always)]
+fn prefetch<T>(value: &T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+
+        _mm_prefetch::<_MM_HINT_T0>(value as *const T as *const i8);
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = value;
+}
+
+/// How `get_or_insert_with_race` resolves another task inserting the same key while the value was being computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RacePolicy {
+    /// Keep the value inserted first, and drop the one computed
+    KeepFirst,
+    /// Replace the existing value with the one computed
+    Replace,
+}
+
+/// What tasks waiting on a load started by `get_or_load_batch` do when the task loading it is cancelled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LoadCancellation {
+    /// One of the waiters loads the keys itself
+    #[default]
+    TakeOver,
+    /// The waiters give up too, returning `Err(LoadCancelled)`
+    Propagate,
+}
+
+/// Error of `get_or_load_batch` when a load it waited on was cancelled, with `LoadCancellation::Propagate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadCancelled;
+
+impl std::fmt::Display for LoadCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the task loading a key was cancelled")
+    }
+}
+
+impl std::error::Error for LoadCancelled {}
+
+#[derive(Debug)]
+pub struct CHashMap<K, T, S = DefaultHashBuilder> {
+    hash_builder: S,
+    shards: Vec<Arc<RwLock<HashMap<K, T, S>>>>,
+    size: AtomicUsize,
+    pub(crate) shard_select: ShardSelect,
+    /// Keys pinned to a shard other than the one `shard_select` selects, shared with read-only views
+    pins: Arc<ShardPins>,
+    numa: Option<NumaPlacement>,
+    /// Share of the entries each shard was pre-sized for, see `Builder::presize_shards`
+    expected_shares: Option<Box<[f64]>>,
+    /// Number of writes to each shard, for `rcu` to tell whether a value changed while computing its replacement
+    versions: Vec<AtomicU64>,
+    /// Hash of the key each shard's `ReplaceHandle` is replacing the value of, with the lowest bit set, or `0`
+    replacing: Vec<AtomicU64>,
+    microcache: Option<MicroCache<K, T>>,
+    /// Breakers shedding `get_cloned` calls on starved shards, if enabled with `with_load_shedding`
+    shedding: Option<LoadShedding>,
+    /// Keys being loaded by `get_or_load_batch`
+    loads: InFlight<K>,
+    load_cancellation: LoadCancellation,
+    watchers: Watchers<K, S>,
+    /// Thresholds of `size` to notify crossings of, see `on_size_threshold`
+    watermarks: Watermarks,
+    /// Keys written to each shard, if enabled with `with_change_log`
+    change_logs: Option<ChangeLogs<K>>,
+    /// Bloom filter of each shard's keys, if enabled with `with_bloom_filters`
+    #[cfg(feature = "bloom")]
+    filters: Option<ShardFilters>,
+    /// Snapshots taken with `duplicate_cow` still sharing each shard
+    cow: CowShares<K, T, S>,
+    /// Times single-key operations try a busy shard lock before awaiting it, see `Builder::spin_before_await`
+    pub(crate) spins: u32,
+}
+
+impl<K, T> CHashMap<K, T, DefaultHashBuilder> {
+    pub fn new(num_shards: usize) -> Self {
+        Self::with_hasher(num_shards, DefaultHashBuilder::default())
+    }
+}
+
+impl<K, T> Default for CHashMap<K, T, DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new(crate::default_num_shards())
+    }
+}
+
+/// Inserts the entries like `warm`, but without waiting on any shard lock, as the map is borrowed mutably:
+/// only handles into it can still hold its shards.
+///
+/// # Panics
+///
+/// Panics if a handle into a shard that entries go to is still alive, as waiting for it to be dropped would block
+/// the thread, and never return if the handle is held by a task of the same thread. Drop handles first, or call
+/// the async `CHashMap::extend` instead, which waits for them.
+impl<K, T, S> Extend<(K, T)> for CHashMap<K, T, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    fn extend<I: IntoIterator<Item = (K, T)>>(&mut self, pairs: I) {
+        self.warm_exclusive(pairs);
+    }
+}
+
+/// Clones the entries into the map, see the `Extend<(K, T)>` implementation, which panics the same way
+impl<'a, K, T, S> Extend<(&'a K, &'a T)> for CHashMap<K, T, S>
+where
+    K: Hash + Eq + Clone,
+    T: Clone,
+    S: BuildHasher,
+{
+    fn extend<I: IntoIterator<Item = (&'a K, &'a T)>>(&mut self, pairs: I) {
+        self.warm_exclusive(pairs.into_iter().map(|(key, value)| (key.clone(), value.clone())));
+    }
+}
+
+impl<K, T, S> CHashMap<K, T, S>
+where
+    S: Clone,
+{
+    pub fn with_hasher(num_shards: usize, hash_builder: S) -> Self {
+        CHashMap {
+            watchers: Watchers::new(num_shards, &hash_builder),
+            watermarks: Watermarks::default(),
+            shards: (0..num_shards)
+                .map(|_| Arc::new(RwLock::new(HashMap::with_hasher(hash_builder.clone()))))
+                .collect(),
+            hash_builder,
+            size: AtomicUsize::new(0),
+            shard_select: ShardSelect::Modulo,
+            pins: Arc::new(ShardPins::new()),
+            numa: None,
+            expected_shares: None,
+            versions: (0..num_shards).map(|_| AtomicU64::new(0)).collect(),
+            replacing: (0..num_shards).map(|_| AtomicU64::new(0)).collect(),
+            microcache: None,
+            shedding: None,
+            loads: InFlight::new(),
+            load_cancellation: LoadCancellation::TakeOver,
+            change_logs: None,
+            #[cfg(feature = "bloom")]
+            filters: None,
+            cow: CowShares::new(num_shards),
+            spins: 0,
+        }
+    }
+
+    /// Lookup-only view sharing the map's shards, see [`ReadOnlyCHashMap`]. Views must be taken again
+    /// after `resize_shards`, as they miss keys the resize moved.
+    pub fn read_only_view(&self) -> ReadOnlyCHashMap<K, T, S> {
+        ReadOnlyCHashMap::new(
+            self.hash_builder.clone(),
+            self.shard_select.clone(),
+            self.pins.clone(),
+            self.shards.clone(),
+        )
+    }
+
+    /// Reallocates each shard's table on its NUMA node, with room for `capacities[idx]` entries
+    pub(crate) fn place_shards(&mut self, placement: NumaPlacement, capacities: &[usize]) {
+        let num_shards = self.shards.len();
+        let hash_builder = &self.hash_builder;
+
+        for (idx, shard) in self.shards.iter_mut().enumerate() {
+            let mut table = None;
+
+            placement
+                .topology
+                .run_on_node(placement.node_of_shard(idx, num_shards), &mut || {
+                    table = Some(HashMap::with_capacity_and_hasher(capacities[idx], hash_builder.clone()));
+                });
+
+            if let Some(table) = table {
+                *shard = Arc::new(RwLock::new(table));
+            }
+        }
+
+        self.numa = Some(placement);
+    }
+
+    /// Reallocates each shard's table with room for `capacities[idx]` entries
+    pub(crate) fn presize_shards(&mut self, capacities: &[usize]) {
+        for (shard, &capacity) in self.shards.iter_mut().zip(capacities) {
+            *shard = Arc::new(RwLock::new(HashMap::with_capacity_and_hasher(capacity, self.hash_builder.clone())));
+        }
+    }
+
+    pub(crate) fn set_expected_shares(&mut self, shares: Box<[f64]>) {
+        self.expected_shares = Some(shares);
+    }
+}
+
+impl<K, T, S> CHashMap<K, T, S> {
+    /// Puts a tiny cache of the last `entries_per_thread` values each thread read with `get_cloned` in front of it,
+    /// for maps where a few keys take most of the reads, which then skip the shard lock.
+    ///
+    /// Every write to the map invalidates all cached entries, so this only pays off for read-mostly maps.
+    /// Writes bypassing the map's locks are not seen, such as writes through `iter_shards`, or values
+    /// with interior mutability written through a read handle, like `SeqLock::store` (`update_copy` is seen).
+    ///
+    /// ```
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let config = CHashMap::new(4).with_microcache(4);
+    /// config.insert("feature_flag", true).await;
+    ///
+    /// assert_eq!(config.get_cloned(&"feature_flag").await, Some(true));
+    /// // served from this thread's microcache
+    /// assert_eq!(config.get_cloned(&"feature_flag").await, Some(true));
+    ///
+    /// config.insert("feature_flag", false).await;
+    /// assert_eq!(config.get_cloned(&"feature_flag").await, Some(false));
+    /// # }
+    /// ```
+    pub fn with_microcache(mut self, entries_per_thread: usize) -> Self
+    where
+        K: Clone,
+        T: Clone,
+    {
+        self.microcache = Some(MicroCache::new(entries_per_thread));
+        self
+    }
+
+    /// Sets what `get_or_load_batch` calls waiting on a load do when the task loading it is cancelled,
+    /// `LoadCancellation::TakeOver` by default
+    pub fn with_load_cancellation(mut self, load_cancellation: LoadCancellation) -> Self {
+        self.load_cancellation = load_cancellation;
+        self
+    }
+
+    /// Logs the last `capacity` keys written to each shard, so replicas can pull incremental changes
+    /// with `diff_since` instead of copying the whole map.
+    ///
+    /// Writes that may touch any key of a shard, such as `retain`, `clear`, `warm`, `batch_write` or
+    /// `lock_all_shards`, are not logged key by key, and require replicas of the shard to resync.
+    pub fn with_change_log(mut self, capacity: usize) -> Self
+    where
+        K: Clone,
+    {
+        self.change_logs = Some(ChangeLogs::new(self.shards.len(), capacity));
+        self
+    }
+
+    /// Keeps a Bloom filter of the keys of each shard, sized for `expected_len_per_shard` entries, so that
+    /// `batch_read` and `Batch::read` skip the shards that definitely hold none of their keys without locking them,
+    /// making mostly-missing batches, e.g. of a cache in front of a sparse dataset, much cheaper.
+    ///
+    /// Filters are rebuilt from their shard by the first batch read after a write that may touch any of its keys,
+    /// such as `retain` or `clear`, or after as many single-key writes as they are sized for, to shed removed keys.
+    /// Writes bypassing the map's locks, like with `iter_shards`, are not seen by the filters, and may make
+    /// batch reads miss the keys they insert until the shard is written to through the map again.
+    ///
+    /// ```
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let map = CHashMap::new(8).with_bloom_filters(1000);
+    /// map.insert(1, "one").await;
+    ///
+    /// let mut found = Vec::new();
+    /// map.batch_read(&[1, 2, 3], None, |_, entry| found.extend(entry.map(|(_, v)| *v))).await;
+    ///
+    /// assert_eq!(found, ["one"]);
+    /// # }
+    /// ```
+    #[cfg(feature = "bloom")]
+    pub fn with_bloom_filters(mut self, expected_len_per_shard: usize) -> Self {
+        self.filters = Some(ShardFilters::new(self.shards.len(), expected_len_per_shard));
+        self
+    }
+
+    /// Sheds load off shards whose lock is starved, e.g. by a pathological key or a long `retain`: once a
+    /// `get_cloned` waited longer than `max_wait` for a shard's lock, `get_cloned` calls finding it busy
+    /// return `None` for the next `cooldown`, as if the key was missing, instead of queuing up behind it.
+    ///
+    /// Shed calls are counted by `num_shed`, so that a cache falling back to its source more than usual
+    /// can be told apart from one missing more.
+    pub fn with_load_shedding(mut self, max_wait: Duration, cooldown: Duration) -> Self {
+        self.shedding = Some(LoadShedding::new(self.shards.len(), max_wait, cooldown));
+        self
+    }
+
+    /// Number of `get_cloned` calls shed so far, see `with_load_shedding`
+    pub fn num_shed(&self) -> u64 {
+        self.shedding.as_ref().map_or(0, LoadShedding::num_shed)
+    }
+
+    /// Whether `get_cloned` calls finding the shard busy are currently shed, see `with_load_shedding`
+    pub fn is_shedding(&self, shard_idx: usize) -> bool {
+        self.shedding.as_ref().is_some_and(|shedding| shedding.is_open(shard_idx))
+    }
+
+    /// Runs `callback` with the direction and the new size whenever `size` crosses `threshold`: when it reaches
+    /// the threshold from below, and when it drops below it again, but not for writes that keep it on the same side,
+    /// e.g. to start evicting, alert, or apply back-pressure, without polling `size`.
+    ///
+    /// The callback runs in the task that crossed the threshold, under the lock of the shard it wrote to, so it
+    /// should only hand the event off, e.g. by sending it on a channel, and never access the map.
+    ///
+    /// ```
+    /// # use quick_hash_cache::{CHashMap, SizeCrossing};
+    /// # #[tokio::main] async fn main() {
+    /// let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    ///
+    /// let map = CHashMap::new(4).on_size_threshold(2, move |crossing, size| {
+    ///     let _ = tx.send((crossing, size));
+    /// });
+    ///
+    /// map.insert(1, "one").await;
+    /// map.insert(2, "two").await;
+    /// map.insert(3, "three").await;
+    /// map.remove(&3).await;
+    /// map.remove(&2).await;
+    ///
+    /// assert_eq!(rx.recv().await, Some((SizeCrossing::Rising, 2)));
+    /// assert_eq!(rx.recv().await, Some((SizeCrossing::Falling, 1)));
+    /// assert!(rx.try_recv().is_err());
+    /// # }
+    /// ```
+    pub fn on_size_threshold(
+        mut self,
+        threshold: usize,
+        callback: impl Fn(SizeCrossing, usize) + Send + Sync + 'static,
+    ) -> Self {
+        self.watermarks.add(threshold, Arc::new(callback));
+        self
+    }
+
+    /// Records a write to the shard, to be called under its write lock before writing to it,
+    /// or after writing a value in place
+    #[inline]
+    fn record_write(&self, shard_idx: usize, shard: &Shard<K, T, S>) {
+        self.cow.unshare(shard_idx, shard);
+
+        let generation = self.bump_version(shard_idx);
+        self.watchers.notify_shard(shard_idx);
+
+        if let Some(ref change_logs) = self.change_logs {
+            change_logs.truncate(shard_idx, generation);
+        }
+
+        #[cfg(feature = "bloom")]
+        if let Some(ref filters) = self.filters {
+            filters.mark_dirty(shard_idx);
+        }
+    }
+
+    /// Increments the shard's version, returning the new one
+    #[inline]
+    fn bump_version(&self, shard_idx: usize) -> u64 {
+        self.invalidate_microcache();
+
+        // ordered by the shard lock, which `rcu` compares versions under
+        self.versions[shard_idx].fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Logs a write of the key for `diff_since`, if enabled, to be called under the shard's write lock
+    /// after `record_key_write`
+    #[inline]
+    fn log_key_write(&self, shard_idx: usize, key: &K) {
+        if let Some(ref change_logs) = self.change_logs {
+            change_logs.record(shard_idx, self.versions[shard_idx].load(Ordering::Relaxed), key);
+        }
+    }
+
+    #[inline]
+    fn invalidate_microcache(&self) {
+        if let Some(ref microcache) = self.microcache {
+            microcache.invalidate();
+        }
+    }
+
+    /// Adds to `size`, notifying the thresholds crossed
+    #[inline]
+    fn grow_size(&self, added: usize) {
+        let before = self.size.fetch_add(added, Ordering::SeqCst);
+        self.watermarks.check(before, before + added);
+    }
+
+    /// Subtracts from `size`, notifying the thresholds crossed
+    #[inline]
+    fn shrink_size(&self, removed: usize) {
+        let before = self.size.fetch_sub(removed, Ordering::SeqCst);
+        self.watermarks.check(before, before - removed);
+    }
+}
+
+impl<K, T, S> CHashMap<K, T, S>
+where
+    K: Clone,
+    T: Clone,
+    S: Clone,
+{
+    /// Duplicates/Clones the CHashMap. A CHashMap cannot be cloned regularly due to internal async locking.
+    ///
+    /// Shards are cloned one at a time while the map keeps being written to, so the copy is not of a single point
+    /// in time, and each shard is cloned whole, see `duplicate_consistent` and `duplicate_chunked` for either.
+    pub async fn duplicate(&self) -> Self {
+        let mut shards = Vec::with_capacity(self.shards.len());
+
+        for shard in &self.shards {
+            shards.push(shard.read().await.clone());
+        }
+
+        self.duplicate_from(shards)
+    }
+
+    /// Like `duplicate`, but read-locks every shard until all are cloned, so the copy is of a single point in time,
+    /// at the cost of blocking writers to the whole map meanwhile
+    pub async fn duplicate_consistent(&self) -> Self {
+        let mut locked = Vec::with_capacity(self.shards.len());
+
+        // in shard order, as with `lock_all_shards`
+        for shard in &self.shards {
+            locked.push(shard.read().await);
+        }
+
+        let shards = locked.iter().map(|shard| Shard::clone(shard)).collect();
+
+        drop(locked);
+
+        self.duplicate_from(shards)
+    }
+
+    /// Like `duplicate`, but clones each shard a page of entries at a time, of at most about `max_bytes_in_flight`
+    /// bytes, releasing the shard's lock between pages, so large shards are neither cloned nor locked in one go.
+    ///
+    /// Entries are weighed by their shallow size, `size_of::<(K, T)>()`, as there is no knowing what they own.
+    /// Paging through a shard in the order of its hashes, as with `scan`, means every entry of the shard is hashed
+    /// once per page, so the smaller the pages, the longer it takes. Entries inserted or removed while their shard
+    /// is being copied may or may not be in the copy.
+    pub async fn duplicate_chunked(&self, max_bytes_in_flight: usize) -> Self
+    where
+        K: Hash + Eq,
+        S: BuildHasher,
+    {
+        let page_len = (max_bytes_in_flight / mem::size_of::<(K, T)>().max(1)).max(1);
+        let mut shards = Vec::with_capacity(self.shards.len());
+
+        for source in &self.shards {
+            let mut copy = HashMap::with_hasher(self.hash_builder.clone());
+            let mut from = Some(0);
+
+            while let Some(next_hash) = from {
+                let (page, next) = {
+                    let shard = source.read().await;
+                    let entries = shard.iter().map(|(key, value)| (self.hash_builder.hash_one(key), key, value));
+
+                    scan::page_by_hash(entries, next_hash, page_len, |key, value| (key.clone(), value.clone()))
+                };
+
+                copy.extend(page);
+                from = next;
+            }
+
+            shards.push(copy);
+        }
+
+        self.duplicate_from(shards)
+    }
+
+    /// Map of the shards cloned from this one, with the same configuration
+    fn duplicate_from(&self, shards: Vec<Shard<K, T, S>>) -> Self {
+        let size = shards.iter().map(|shard| shard.len()).sum();
+
+        CHashMap {
+            shards: shards.into_iter().map(|shard| Arc::new(RwLock::new(shard))).collect(),
+            hash_builder: self.hash_builder.clone(),
+            size: AtomicUsize::new(size),
+            shard_select: self.shard_select.clone(),
+            pins: Arc::new(self.pins.duplicate()),
+            numa: self.numa.clone(),
+            expected_shares: self.expected_shares.clone(),
+            versions: (0..self.shards.len()).map(|_| AtomicU64::new(0)).collect(),
+            replacing: (0..self.shards.len()).map(|_| AtomicU64::new(0)).collect(),
+            microcache: self.microcache.as_ref().map(MicroCache::fresh),
+            shedding: self.shedding.as_ref().map(|shedding| shedding.renew(self.shards.len())),
+            loads: InFlight::new(),
+            load_cancellation: self.load_cancellation,
+            watchers: Watchers::new(self.shards.len(), &self.hash_builder),
+            watermarks: self.watermarks.clone(),
+            change_logs: self.change_logs.as_ref().map(|logs| logs.renew(self.shards.len())),
+            #[cfg(feature = "bloom")]
+            filters: self.filters.as_ref().map(|filters| filters.renew(self.shards.len())),
+            cow: CowShares::new(self.shards.len()),
+            spins: self.spins,
+        }
+    }
+
+    /// Takes a read-only snapshot of the map, sharing its shards instead of cloning them like `duplicate`:
+    /// the first time the map writes to a shard afterwards, it clones the shard into the snapshot first.
+    ///
+    /// Writes bypassing the map's locks, like with `iter_shards`, are not seen coming and show in the snapshot.
+    pub async fn duplicate_cow(&self) -> CowSnapshot<K, T, S> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        let mut size = 0;
+
+        for (idx, source) in self.shards.iter().enumerate() {
+            let shard = source.read().await;
+            let cow_shard = Arc::new(CowShard::new(source.clone()));
+
+            self.cow.share(idx, &cow_shard);
+            size += shard.len();
+            shards.push(cow_shard);
+        }
+
+        CowSnapshot::new(
+            self.hash_builder.clone(),
+            self.shard_select.clone(),
+            self.pins.duplicate(),
+            shards,
+            size,
+        )
+    }
+}
+
+impl<K, T, S> CHashMap<K, T, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn hash_builder(&self) -> &S {
+        &self.hash_builder
+    }
+
+    #[inline]
+    fn hash_and_shard<Q>(&self, key: &Q) -> (u64, usize)
+    where
+        Q: ?Sized + Hash,
+    {
+        let hash = self.hash_builder.hash_one(key);
+        (hash, self.shard_of_hash(hash))
+    }
+
+    /// Like `record_write`, for a write to a single key, which only notifies the key's watchers. To be called under
+    /// the shard's write lock once the key is certainly written, so that misses leave no trace, but only after
+    /// `cow.unshare` was called before writing to the shard.
+    #[inline]
+    fn record_key_write<Q>(&self, shard_idx: usize, hash: u64, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.bump_version(shard_idx);
+        self.watchers.notify_key(shard_idx, hash, key);
+
+        #[cfg(feature = "bloom")]
+        if let Some(ref filters) = self.filters {
+            filters.add(shard_idx, hash);
+        }
+    }
+
+    /// Read-locks the shard for `get_cloned`, or returns `None` if the lock is busy and load is being shed off it,
+    /// see `with_load_shedding`
+    #[inline]
+    async fn read_or_shed(&self, shard_idx: usize) -> Option<RwLockReadGuard<'_, Shard<K, T, S>>> {
+        let shard = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        let shedding = match self.shedding {
+            Some(ref shedding) => shedding,
+            None => return Some(shard.spin_read(self.spins).read().await),
+        };
+
+        if let Ok(guard) = shard.spin_read(self.spins).try_read() {
+            return Some(guard);
+        }
+
+        if shedding.is_open(shard_idx) {
+            shedding.record_shed();
+            return None;
+        }
+
+        let waiting_since = DefaultClock::now();
+        let guard = shard.read().await;
+        shedding.record_wait(shard_idx, waiting_since);
+
+        Some(guard)
+    }
+
+    /// Whether the shard may hold a key of any of the hashes, as far as its Bloom filter tells, if any
+    #[cfg(feature = "bloom")]
+    #[inline]
+    fn shard_may_hold(&self, shard_idx: usize, hashes: impl Iterator<Item = u64>) -> bool {
+        match self.filters {
+            Some(ref filters) => filters.may_contain_any(shard_idx, hashes),
+            None => true,
+        }
+    }
+
+    #[cfg(not(feature = "bloom"))]
+    #[inline(always)]
+    fn shard_may_hold(&self, _shard_idx: usize, _hashes: impl Iterator<Item = u64>) -> bool {
+        true
+    }
+
+    /// Rebuilds the shard's Bloom filter, if any and dirty, to be called under its read lock
+    #[cfg(feature = "bloom")]
+    #[inline]
+    fn refresh_filter(&self, shard_idx: usize, shard: &Shard<K, T, S>) {
+        if let Some(ref filters) = self.filters {
+            filters.refresh(shard_idx, shard.keys().map(|key| self.hash_builder.hash_one(key)));
+        }
+    }
+
+    #[cfg(not(feature = "bloom"))]
+    #[inline(always)]
+    fn refresh_filter(&self, _shard_idx: usize, _shard: &Shard<K, T, S>) {}
+
+    /// Stream of the key's value, `None` while absent, yielding the current value first, then the value after
+    /// every write to the key, e.g. to reload a service when its configuration changes. Writes that leave the value
+    /// as it was are yielded too, as are writes to the whole shard that may have changed it, like `retain` or `clear`.
+    ///
+    /// Like `tokio::sync::watch`, values written faster than the stream is polled are skipped, only the latest
+    /// being yielded. Writes through a handle, such as `get_mut`, are seen once the handle is dropped, but not
+    /// writes bypassing the map's locks, like with `iter_shards`.
+    ///
+    /// ```
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let config = CHashMap::new(4);
+    /// let mut timeout = config.watch(&"timeout");
+    ///
+    /// assert_eq!(timeout.next().await, Some(None));
+    ///
+    /// config.insert("timeout", 30).await;
+    /// assert_eq!(timeout.next().await, Some(Some(30)));
+    ///
+    /// *config.get_mut(&"timeout").await.unwrap() = 60;
+    /// assert_eq!(timeout.next().await, Some(Some(60)));
+    /// # }
+    /// ```
+    pub fn watch(&self, key: &K) -> Watch<'_, K, T, S>
+    where
+        K: Clone + Send + Sync,
+        T: Clone + Send + Sync,
+        S: Send + Sync,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+
+        // subscribed before the first read, so no write after it is missed
+        let receiver = self.watchers.subscribe(shard_idx, hash, key);
+
+        Watch::new(self, receiver, key.clone())
+    }
+
+    /// Waits up to `timeout` for the key to be present, e.g. for values filled in by a separate producer task,
+    /// returning `None` on timeout.
+    ///
+    /// Requires the tokio runtime's timer, like `tokio::time::timeout`.
+    ///
+    /// ```
+    /// # use std::sync::Arc;
+    /// # use std::time::Duration;
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let results = Arc::new(CHashMap::new(4));
+    ///
+    /// let producer = results.clone();
+    /// tokio::spawn(async move { producer.insert("job:1", "done").await });
+    ///
+    /// let result = results.wait_for(&"job:1", Duration::from_secs(5)).await;
+    /// assert_eq!(result.as_deref(), Some(&"done"));
+    ///
+    /// assert!(results.wait_for(&"job:2", Duration::from_millis(10)).await.is_none());
+    /// # }
+    /// ```
+    pub async fn wait_for(&self, key: &K, timeout: Duration) -> Option<MapReadHandle<K, T, S>>
+    where
+        K: Clone,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+
+        // subscribed before the first read, so an insert right after it is not missed
+        let mut receiver = self.watchers.subscribe(shard_idx, hash, key);
+
+        let present = async {
+            loop {
+                if let Some(value) = self.get(key).await {
+                    return Some(value);
+                }
+
+                // the sender lives as long as the key is watched, which the receiver ensures
+                receiver.changed().await.ok()?;
+            }
+        };
+
+        tokio::time::timeout(timeout, present).await.ok().flatten()
+    }
+
+    pub async fn clear(&self) {
+        let _ = self.clear_until(Cursor::START, &Deadline::NEVER).await;
+    }
+
+    /// Like `clear`, but gives up between shards once the deadline is reached, starting from the cursor's shard.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use quick_hash_cache::{CHashMap, Cursor, Deadline, Progress};
+    /// # #[tokio::main] async fn main() {
+    /// let map = CHashMap::new(4);
+    /// map.insert(1, 1).await;
+    ///
+    /// let mut from = Cursor::START;
+    ///
+    /// // e.g. yielding to other work between slices of about a millisecond
+    /// loop {
+    ///     match map.clear_until(from, &Deadline::after(Duration::from_millis(1))).await {
+    ///         Progress::Done => break,
+    ///         Progress::Interrupted(cursor) => from = cursor,
+    ///     }
+    /// }
+    ///
+    /// assert_eq!(map.size(), 0);
+    /// # }
+    /// ```
+    pub async fn clear_until(&self, from: Cursor, deadline: &Deadline) -> Progress {
+        for idx in deadline::first_shard(from)..self.shards.len() {
+            if deadline.is_reached() {
+                return Progress::interrupted_at(idx);
+            }
+
+            let mut shard = self.shards[idx].write().await;
+            self.record_write(idx, &shard);
+
+            let len = shard.len();
+            shard.clear();
+
+            self.shrink_size(len);
+        }
+
+        Progress::Done
+    }
+
+    /// Bulk-loads entries, e.g. at startup, replacing existing values.
+    ///
+    /// Entries are grouped by shard first, so each shard is locked and grown only once,
+    /// instead of once per entry as with `insert`.
+    pub async fn warm(&self, pairs: impl IntoIterator<Item = (K, T)>) {
+        let mut by_shard: Vec<Vec<(u64, K, T)>> = (0..self.shards.len()).map(|_| Vec::new()).collect();
+
+        for (key, value) in pairs {
+            let (hash, shard_idx) = self.hash_and_shard(&key);
+            by_shard[shard_idx].push((hash, key, value));
+        }
+
+        for (idx, (shard, entries)) in self.shards.iter().zip(by_shard).enumerate() {
+            if entries.is_empty() {
+                continue;
+            }
+
+            let mut shard = shard.write().await;
+            self.warm_locked(idx, &mut shard, entries.len(), entries);
+        }
+    }
+
+    /// Like `warm`, locking each shard without waiting, for `Extend`, which holds the map exclusively.
+    ///
+    /// Panics if a handle into one of the shards is alive, before inserting anything.
+    fn warm_exclusive(&mut self, pairs: impl IntoIterator<Item = (K, T)>) {
+        let mut by_shard: Vec<Vec<(u64, K, T)>> = (0..self.shards.len()).map(|_| Vec::new()).collect();
+
+        for (key, value) in pairs {
+            let (hash, shard_idx) = self.hash_and_shard(&key);
+            by_shard[shard_idx].push((hash, key, value));
+        }
+
+        // every shard is locked before any is written, so that a panic leaves the map unchanged
+        let locked: Vec<_> = self
+            .shards
+            .iter()
+            .zip(by_shard)
+            .enumerate()
+            .filter(|(_, (_, entries))| !entries.is_empty())
+            .map(|(idx, (shard, entries))| {
+                let shard = shard.try_write().unwrap_or_else(|_| {
+                    panic!("`Extend` of a `CHashMap` while a handle into shard {} is alive, drop it first", idx)
+                });
+
+                (idx, shard, entries)
+            })
+            .collect();
+
+        for (idx, mut shard, entries) in locked {
+            self.warm_locked(idx, &mut shard, entries.len(), entries);
+        }
+    }
+
+    /// Inserts entries already hashed to the shard, replacing existing values, after reserving room for `len` more
+    fn warm_locked(
+        &self,
+        shard_idx: usize,
+        shard: &mut Shard<K, T, S>,
+        len: usize,
+        entries: impl IntoIterator<Item = (u64, K, T)>,
+    ) {
+        self.record_write(shard_idx, shard);
+        shard.reserve(len);
+
+        let mut added = 0;
+
+        for (hash, key, value) in entries {
+            match shard.raw_entry_mut().from_key_hashed_nocheck(hash, &key) {
+                RawEntryMut::Occupied(mut occupied) => {
+                    occupied.insert(value);
+                }
+                RawEntryMut::Vacant(vacant) => {
+                    vacant.insert_hashed_nocheck(hash, key, value);
+                    added += 1;
+                }
+            }
+        }
+
+        self.grow_size(added);
+    }
+
+    /// Inserts the entries, replacing existing values, with each shard locked only once, same as `warm`
+    pub async fn extend(&self, pairs: impl IntoIterator<Item = (K, T)>) {
+        self.warm(pairs).await
+    }
+
+    /// Serializes the map into a single buffer, with `serialize_shard` appending each shard's entries to a buffer
+    /// of its own, under the shard's read lock, on a thread per available CPU, so large maps are not encoded
+    /// through a single thread. Restore it with `deserialize_shards`.
+    ///
+    /// The snapshot is versioned and records the type of the map's hasher, and each shard's bytes are checksummed,
+    /// so restoring rejects corrupted snapshots, or ones taken with another hasher, see `SnapshotError`.
+    ///
+    /// Each shard is serialized as of when it was locked, but shards are locked one after the other, so the
+    /// snapshot is not of a single point in time under concurrent writes.
+    ///
+    /// Blocks the calling thread until every shard is serialized, so from async code, call it through
+    /// `tokio::task::spawn_blocking` or `block_in_place`.
+    ///
+    /// ```
+    /// # use quick_hash_cache::CHashMap;
+    /// # use tokio::task::block_in_place;
+    /// # #[tokio::main] async fn main() {
+    /// let map = CHashMap::<u32, u32>::new(4);
+    /// map.extend((0..100).map(|i| (i, i * 2))).await;
+    ///
+    /// let snapshot = block_in_place(|| {
+    ///     map.serialize_shards(|shard, buf| {
+    ///         for (key, value) in shard {
+    ///             buf.extend_from_slice(&key.to_le_bytes());
+    ///             buf.extend_from_slice(&value.to_le_bytes());
+    ///         }
+    ///         Ok::<_, ()>(())
+    ///     })
+    /// })
+    /// .unwrap();
+    ///
+    /// let restored = CHashMap::<u32, u32>::new(8);
+    /// block_in_place(|| {
+    ///     restored.deserialize_shards(&snapshot, |bytes| {
+    ///         let word = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    ///         Ok::<_, ()>(bytes.chunks(8).map(|entry| (word(&entry[..4]), word(&entry[4..]))).collect())
+    ///     })
+    /// })
+    /// .unwrap();
+    ///
+    /// assert_eq!(restored.size(), 100);
+    /// assert_eq!(restored.get_cloned(&21).await, Some(42));
+    /// # }
+    /// ```
+    pub fn serialize_shards<F, E>(&self, serialize_shard: F) -> Result<Vec<u8>, E>
+    where
+        F: Fn(&Shard<K, T, S>, &mut Vec<u8>) -> Result<(), E> + Sync,
+        E: Send,
+        K: Send + Sync,
+        T: Send + Sync,
+        S: Send + Sync,
+    {
+        let shards = persist::run_parallel(self.shards.iter().collect(), |shard| {
+            let shard = sweeper::block_on(shard.read());
+            let mut buf = Vec::new();
+
+            serialize_shard(&shard, &mut buf)?;
+            Ok((persist::checksum(&buf), buf))
+        });
+
+        let shards = shards.into_iter().collect::<Result<_, E>>()?;
+        Ok(persist::stitch(persist::hasher_id::<S>(), shards))
+    }
+
+    /// Inserts the entries of a snapshot taken with `serialize_shards`, replacing existing values, with each of its
+    /// shards deserialized by `deserialize_shard` on a thread per available CPU, and each shard of the map then
+    /// filled in parallel as well.
+    ///
+    /// The map need not have the same number of shards or hasher as the one the snapshot was taken of,
+    /// as entries are rehashed into the shards they belong to, but its hasher must be of the same type.
+    /// Nothing is inserted if any shard is corrupted or fails to deserialize.
+    ///
+    /// Blocks the calling thread until every entry is inserted, see `serialize_shards`.
+    pub fn deserialize_shards<F, E>(&self, snapshot: &[u8], deserialize_shard: F) -> Result<(), SnapshotError<E>>
+    where
+        F: Fn(&[u8]) -> Result<Vec<(K, T)>, E> + Sync,
+        E: Send,
+        K: Send + Sync,
+        T: Send + Sync,
+        S: Send + Sync,
+    {
+        let frames = persist::split(snapshot, persist::hasher_id::<S>())?;
+        let num_shards = self.shards.len();
+
+        // each frame's entries, grouped by the shard of the map they belong to
+        let frames = persist::run_parallel(frames.into_iter().enumerate().collect(), |(shard_idx, frame)| {
+            if persist::checksum(frame.bytes) != frame.checksum {
+                return Err(SnapshotError::Corrupted { shard_idx });
+            }
+
+            let entries = deserialize_shard(frame.bytes).map_err(|error| SnapshotError::Shard { shard_idx, error })?;
+            let mut by_shard: Vec<Vec<(u64, K, T)>> = (0..num_shards).map(|_| Vec::new()).collect();
+
+            for (key, value) in entries {
+                let (hash, shard_idx) = self.hash_and_shard(&key);
+                by_shard[shard_idx].push((hash, key, value));
+            }
+
+            Ok(by_shard)
+        });
+
+        let mut by_shard: Vec<Vec<Vec<(u64, K, T)>>> = (0..num_shards).map(|_| Vec::new()).collect();
+
+        for frame in frames {
+            for (shard_entries, entries) in by_shard.iter_mut().zip(frame?) {
+                shard_entries.push(entries);
+            }
+        }
+
+        persist::run_parallel(self.shards.iter().zip(by_shard).enumerate().collect(), |(idx, (shard, entries))| {
+            let len = entries.iter().map(Vec::len).sum();
+
+            if len != 0 {
+                let mut shard = sweeper::block_on(shard.write());
+                self.warm_locked(idx, &mut shard, len, entries.into_iter().flatten());
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Removes and returns every entry, with all shards locked at once, so that unlike `clear`,
+    /// no concurrent insert can land in a shard that was already drained: the map is empty
+    /// at the moment the locks are released, and every entry removed is returned.
+    pub async fn take_all(&self) -> Vec<(K, T)> {
+        self.lock_all_shards().await.drain()
+    }
+
+    /// Write-locks every shard, for operations that need a consistent view of the whole map,
+    /// such as an exact length, a consistent snapshot, or maintenance spanning several shards.
+    ///
+    /// Shards are locked in index order, which is the only order the map ever holds more than one shard lock in,
+    /// so concurrent calls to this, `read_all_shards` and `take_all` cannot deadlock each other.
+    /// Calling it while holding a handle into the map, however, deadlocks (and panics in debug builds).
+    pub async fn lock_all_shards(&self) -> AllShardsGuard<'_, K, T, S> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+
+        for (idx, shard) in self.shards.iter().enumerate() {
+            let shard = shard.write().await;
+            self.record_write(idx, &shard);
+            shards.push(shard);
+        }
+
+        let len = shards.iter().map(|shard| shard.len()).sum();
+
+        AllShardsGuard {
+            map: self,
+            shards,
+            len_at_lock: len,
+        }
+    }
+
+    /// Read-locks every shard, see `lock_all_shards`
+    pub async fn read_all_shards(&self) -> AllShardsReadGuard<'_, K, T, S> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+
+        for shard in &self.shards {
+            shards.push(shard.read().await);
+        }
+
+        AllShardsReadGuard { map: self, shards }
+    }
+
+    pub async fn retain<F>(&self, f: F)
+    where
+        F: Fn(&K, &mut T) -> bool,
+    {
+        let _ = self.retain_shards(0..self.shards.len(), &Deadline::NEVER, f).await;
+    }
+
+    /// Like `retain`, but gives up between shards once the deadline is reached, starting from the cursor's shard,
+    /// e.g. to bound the time spent on a periodic sweep and pick it back up on the next tick.
+    pub async fn retain_until<F>(&self, from: Cursor, deadline: &Deadline, f: F) -> Progress
+    where
+        F: Fn(&K, &mut T) -> bool,
+    {
+        self.retain_shards(deadline::first_shard(from)..self.shards.len(), deadline, f).await
+    }
+
+    /// Like `retain`, but only over the shards on the caller's NUMA node, see `local_shards`.
+    ///
+    /// Running this from one task per node, each on a thread of its node, splits maintenance so that
+    /// every shard's memory is only swept by its own node.
+    pub async fn retain_local<F>(&self, f: F)
+    where
+        F: Fn(&K, &mut T) -> bool,
+    {
+        let _ = self.retain_shards(self.local_shards(), &Deadline::NEVER, f).await;
+    }
+
+    async fn retain_shards<F>(&self, shards: Range<usize>, deadline: &Deadline, f: F) -> Progress
+    where
+        F: Fn(&K, &mut T) -> bool,
+    {
+        for idx in shards {
+            if deadline.is_reached() {
+                return Progress::interrupted_at(idx);
+            }
+
+            let mut shard = self.shards[idx].write().await;
+            self.record_write(idx, &shard);
+
+            let removed = Cell::new(0);
+            let _sync = Defer(|| {
+                self.shrink_size(removed.get());
+            });
+
+            shard.retain(|k, v| {
+                let keep = f(k, v);
+                if !keep {
+                    removed.set(removed.get() + 1);
+                }
+                keep
+            });
+        }
+
+        Progress::Done
+    }
+
+    pub fn iter_shards(&self) -> impl Iterator<Item = &tokio::sync::RwLock<Shard<K, T, S>>> {
+        self.shards.iter().map(|s| crate::sync::tokio_lock(s))
+    }
+
+    /// Like `iter_shards`, but yields owned references to the shards, which can be moved into spawned tasks
+    /// to process the shards in parallel.
+    ///
+    /// ```
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let map = CHashMap::<u32, u32>::new(4);
+    ///
+    /// let tasks: Vec<_> = map
+    ///     .iter_shards_owned()
+    ///     .map(|shard| tokio::spawn(async move { shard.read().await.values().sum::<u32>() }))
+    ///     .collect();
+    ///
+    /// for task in tasks {
+    ///     task.await.unwrap();
+    /// }
+    /// # }
+    /// ```
+    pub fn iter_shards_owned(&self) -> impl Iterator<Item = Arc<tokio::sync::RwLock<Shard<K, T, S>>>> + '_ {
+        self.shards.iter().map(|s| crate::sync::tokio_lock_owned(s.clone()))
+    }
+
+    /// Runs `map_fn` over each shard under its read lock, and folds the results with `reduce_fn`,
+    /// e.g. to compute sums, maxima or histograms over the whole map. Returns `None` if there are no shards.
+    ///
+    /// Up to 8 read locks are waited on at once, and shards are mapped in whichever
+    /// order their locks are acquired, so one contended shard doesn't hold up the rest, and `reduce_fn`
+    /// should not depend on the order of its arguments.
+    ///
+    /// Locks released by other tasks are granted to the waiting reads right away, even while `map_fn` runs on
+    /// another shard, so up to 8 read locks may be held at once, holding up writers of those shards until they
+    /// are mapped in turn. Keep `map_fn` short on maps with frequent writes.
+    pub async fn map_reduce<A, M, R>(&self, map_fn: M, mut reduce_fn: R) -> Option<A>
+    where
+        M: Fn(&Shard<K, T, S>) -> A,
+        R: FnMut(A, A) -> A,
+    {
+        let mut remaining = self.shards.iter();
+        let mut pending = Vec::with_capacity(MAP_REDUCE_CONCURRENCY.min(self.shards.len()));
+        let mut acc = None;
+
+        loop {
+            while pending.len() < MAP_REDUCE_CONCURRENCY {
+                match remaining.next() {
+                    Some(shard) => pending.push(Box::pin(shard.clone().read_owned())),
+                    None => break,
+                }
+            }
+
+            if pending.is_empty() {
+                return acc;
+            }
+
+            let shard = poll_fn(|cx| {
+                let ready = pending
+                    .iter_mut()
+                    .enumerate()
+                    .find_map(|(i, lock)| match lock.as_mut().poll(cx) {
+                        Poll::Ready(shard) => Some((i, shard)),
+                        Poll::Pending => None,
+                    });
+
+                match ready {
+                    Some((i, shard)) => {
+                        drop(pending.swap_remove(i));
+                        Poll::Ready(shard)
+                    }
+                    None => Poll::Pending,
+                }
+            })
+            .await;
+
+            let result = map_fn(&shard);
+            drop(shard);
+
+            acc = Some(match acc {
+                Some(acc) => reduce_fn(acc, result),
+                None => result,
+            });
+        }
+    }
+
+    /// Entry counts and load factors of every shard, to check how evenly the hasher spreads keys
+    pub async fn load_report(&self) -> LoadReport {
+        let mut shards = Vec::with_capacity(self.shards.len());
+
+        for shard in &self.shards {
+            let shard = shard.read().await;
+            shards.push((shard.len(), shard.capacity()));
+        }
+
+        LoadReport::new(shards)
+    }
+
+    /// Compares how entries are spread over the shards with the spread the map was pre-sized for with
+    /// `Builder::presize_shards`, returning the shard deviating the most, or `None` if the map was not pre-sized,
+    /// or is empty. A large deviation means the distribution given to the builder is out of date.
+    pub async fn rebalance_hint(&self) -> Option<RebalanceHint> {
+        let expected_shares = self.expected_shares.as_deref()?;
+        let report = self.load_report().await;
+
+        RebalanceHint::new(expected_shares, &report)
+    }
+
+    /// Returns up to `limit` entries from the cursor, starting from `Cursor::START`, along with the cursor of the
+    /// next page, or `None` once every shard has been paged through. See [`Cursor`].
+    ///
+    /// Shards are read-locked one at a time, and only during the call. Every entry of the shard the page ends in
+    /// is hashed, so larger pages are more efficient.
+    pub async fn scan(&self, cursor: Cursor, limit: usize) -> (Vec<(K, T)>, Option<Cursor>)
+    where
+        K: Clone,
+        T: Clone,
+    {
+        scan::scan(self.shards.len(), cursor, limit, |shard_idx, from, limit| async move {
+            let shard = self.shards[shard_idx].read().await;
+            let entries = shard.iter().map(|(key, value)| (self.hash_builder.hash_one(key), key, value));
+
+            scan::page_by_hash(entries, from, limit, |key, value| (key.clone(), value.clone()))
+        })
+        .await
+    }
+
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Like `size`, but with a relaxed load, which may lag behind concurrent writes.
+    /// Meant for monitoring, where it is read often and exactness does not matter.
+    #[inline]
+    pub fn estimate_len(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Estimates the number of distinct values in the map with a [`HyperLogLog`] over their hashes,
+    /// e.g. to monitor how much deduplicating values would save.
+    ///
+    /// Shards are read-locked one at a time, so the estimate is not of a single point in time.
+    pub async fn estimate_distinct_values(&self) -> f64
+    where
+        T: Hash,
+    {
+        let mut hll = HyperLogLog::new();
+
+        for shard in &self.shards {
+            for value in shard.read().await.values() {
+                hll.insert_hash(self.hash_builder.hash_one(value));
+            }
+        }
+
+        hll.estimate()
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Index of the shard that a key with the given hash belongs to, see also `insert_pinned_to_shard`
+    #[inline]
+    pub fn shard_of_hash(&self, hash: u64) -> usize {
+        match self.pins.get(hash) {
+            Some(shard_idx) => shard_idx,
+            None => self.shard_select.shard_of(hash, self.shards.len()),
+        }
+    }
+
+    /// Index of the shard that a key belongs to, e.g. to route work on the key to a per-shard worker task,
+    /// so each shard is only ever locked by one task, see `run_on_shard`
+    pub fn shard_of<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        self.hash_and_shard(key).1
+    }
+
+    /// NUMA node the shard was allocated on, if the map was built with [`Builder::numa`](crate::Builder::numa)
+    pub fn shard_node(&self, shard_idx: usize) -> Option<usize> {
+        let numa = self.numa.as_ref()?;
+        Some(numa.node_of_shard(shard_idx, self.shards.len()))
+    }
+
+    /// Indices of the shards on the NUMA node the calling thread runs on, or of all shards without NUMA placement.
+    ///
+    /// Keys are not placed by the node of the caller, as a key must be found in the same shard from every node.
+    /// To keep work on a key node-local, route it to a task on the node of `shard_node(shard_of(&key))` instead.
+    pub fn local_shards(&self) -> Range<usize> {
+        match self.numa {
+            Some(ref numa) => numa.shards_of_node(numa.topology.current_node(), self.shards.len()),
+            None => 0..self.shards.len(),
+        }
+    }
+
+    /// Runs the closure on a write-locked shard, for batches of operations on keys of the same shard
+    /// (see `shard_of`) under a single acquisition of its lock.
+    ///
+    /// Entries must only be inserted into the shard they belong to, or lookups will not find them.
+    pub async fn run_on_shard<F, R>(&self, shard_idx: usize, f: F) -> R
+    where
+        F: FnOnce(&mut Shard<K, T, S>) -> R,
+    {
+        assert!(shard_idx < self.shards.len(), "shard index out of bounds");
+
+        let shard = self.shards[shard_idx].write().await;
+        self.record_write(shard_idx, &shard);
+
+        let mut guard = ShardGuard {
+            map: self,
+            shard_idx,
+            len_at_lock: shard.len(),
+            shard,
+        };
+
+        f(&mut guard.shard)
+    }
+
+    /// The contiguous range of hashes that belong to a shard, when shards are selected by the high bits of hashes
+    /// (see [`Builder::nohash`](crate::Builder::nohash)). Otherwise, hashes are spread over the shards by modulo
+    /// and there is no such range, so use [`shard_of_hash`](Self::shard_of_hash) instead.
+    pub fn shard_hash_range(&self, shard_idx: usize) -> Option<RangeInclusive<u64>> {
+        assert!(shard_idx < self.shards.len(), "shard index out of bounds");
+
+        self.shard_select.hash_range(shard_idx, self.shards.len())
+    }
+
+    /// Changes the number of shards, moving the entries whose shard changed.
+    ///
+    /// With the default modulo selection of shards, nearly every entry moves. Build the map with
+    /// [`Builder::consistent_hashing`](crate::Builder::consistent_hashing) to only move about `1/N` of them.
+    ///
+    /// Waits for every handle into the map to be dropped, and cancelling it meanwhile leaves the map unchanged.
+    /// Views from `read_only_view` don't follow the resize, and miss the keys it moved, so they must be taken again.
+    ///
+    /// With [NUMA placement](crate::Builder::numa), shards are regrouped over the nodes for the new number of shards,
+    /// but not reallocated, so their memory may no longer be local to the node `shard_node` reports.
+    pub async fn resize_shards(&mut self, num_shards: usize)
+    where
+        S: Clone,
+    {
+        assert!(num_shards > 0, "a map needs at least one shard");
+
+        // every shard is locked before anything changes, so cancelling the resize while it waits for handles
+        // to be dropped leaves the map as it was
+        let mut locked = Vec::with_capacity(self.shards.len());
+
+        for shard in &self.shards {
+            locked.push(shard.clone().write_owned().await);
+        }
+
+        let shard_select = self.shard_select.with_num_shards(num_shards);
+        // views of the map keep the pins of the shards they share
+        let mut pins = self.pins.duplicate();
+        pins.retain_below(num_shards);
+        self.pins = Arc::new(pins);
+
+        let pins = &self.pins;
+        let shard_of = |hash| pins.get(hash).unwrap_or_else(|| shard_select.shard_of(hash, num_shards));
+
+        let mut added: Vec<Shard<K, T, S>> = (locked.len()..num_shards)
+            .map(|_| HashMap::with_hasher(self.hash_builder.clone()))
+            .collect();
+
+        let num_former_shards = locked.len();
+        let mut moved = Vec::new();
+
+        for (idx, shard) in locked.iter_mut().enumerate() {
+            self.cow.unshare(idx, shard);
+
+            moved.extend(shard.drain_filter(|key, _| shard_of(self.hash_builder.hash_one(key)) != idx));
+        }
+
+        for (key, value) in moved {
+            let hash = self.hash_builder.hash_one(&key);
+            let shard_idx = shard_of(hash);
+
+            let shard = match locked.get_mut(shard_idx) {
+                Some(shard) => &mut **shard,
+                None => &mut added[shard_idx - num_former_shards],
+            };
+
+            // keys are unique across shards, so there is nothing to compare against
+            if let RawEntryMut::Vacant(vacant) = shard.raw_entry_mut().from_hash(hash, |_| false) {
+                vacant.insert_hashed_nocheck(hash, key, value);
+            }
+        }
+
+        drop(locked);
+
+        // every entry left in the shards to remove was moved out above
+        self.shards.truncate(num_shards);
+        self.shards.extend(added.into_iter().map(|shard| Arc::new(RwLock::new(shard))));
+        self.shard_select = shard_select;
+        self.versions = (0..num_shards).map(|_| AtomicU64::new(0)).collect();
+        // the map is borrowed mutably, so no value can be being replaced
+        self.replacing = (0..num_shards).map(|_| AtomicU64::new(0)).collect();
+        self.invalidate_microcache();
+        // the map is borrowed mutably, so no key can be watched
+        self.watchers = Watchers::new(num_shards, &self.hash_builder);
+        // the pre-sized spread was for the former shards
+        self.expected_shares = None;
+        // generations restart along with the versions, so replicas have to resync anyway
+        self.change_logs = self.change_logs.as_ref().map(|logs| logs.renew(num_shards));
+        self.shedding = self.shedding.as_ref().map(|shedding| shedding.renew(num_shards));
+        // rebuilt from the shards by the next batch reads
+        #[cfg(feature = "bloom")]
+        {
+            self.filters = self.filters.as_ref().map(|filters| filters.renew(num_shards));
+        }
+        // every shard was copied into the snapshots sharing it above
+        self.cow = CowShares::new(num_shards);
+    }
+
+    /// Removes and returns every entry of a shard, e.g. to hand it over to another node when rebalancing a cluster.
+    ///
+    /// Panics if `shard_idx` is out of bounds.
+    pub async fn export_shard(&self, shard_idx: usize) -> Vec<(K, T)> {
+        let mut shard = self.shards[shard_idx].write().await;
+        self.record_write(shard_idx, &shard);
+
+        let entries: Vec<_> = shard.drain().collect();
+        self.shrink_size(entries.len());
+
+        entries
+    }
+
+    /// Inserts the entries exported from the same shard of another map, replacing any existing values.
+    ///
+    /// Only a map with the same number of shards, hasher, and shard selection partitions keys the same way,
+    /// so entries that don't belong to this shard are returned instead of inserted.
+    ///
+    /// Panics if `shard_idx` is out of bounds.
+    pub async fn import_shard(&self, shard_idx: usize, entries: impl IntoIterator<Item = (K, T)>) -> Vec<(K, T)> {
+        let mut shard = self.shards[shard_idx].write().await;
+        self.record_write(shard_idx, &shard);
+        let mut misplaced = Vec::new();
+
+        for (key, value) in entries {
+            let (hash, idx) = self.hash_and_shard(&key);
+
+            if idx != shard_idx {
+                misplaced.push((key, value));
+                continue;
+            }
+
+            match shard.raw_entry_mut().from_key_hashed_nocheck(hash, &key) {
+                RawEntryMut::Occupied(mut occupied) => {
+                    occupied.insert(value);
+                }
+                RawEntryMut::Vacant(vacant) => {
+                    vacant.insert_hashed_nocheck(hash, key, value);
+                    self.grow_size(1);
+                }
+            }
+        }
+
+        misplaced
+    }
+
+    /// Generation of the shard, incremented by every write to it, see `diff_since`.
+    ///
+    /// Panics if `shard_idx` is out of bounds.
+    pub fn shard_generation(&self, shard_idx: usize) -> u64 {
+        self.versions[shard_idx].load(Ordering::Relaxed)
+    }
+
+    /// Clones the shard's entries along with the generation they are at, for a replica to start pulling
+    /// changes from with `diff_since`, or to resync the shard.
+    ///
+    /// Panics if `shard_idx` is out of bounds.
+    pub async fn snapshot_shard(&self, shard_idx: usize) -> (Vec<(K, T)>, u64)
+    where
+        K: Clone,
+        T: Clone,
+    {
+        let shard = self.shards[shard_idx].read().await;
+        let entries = shard.iter().map(|(key, value)| (key.clone(), value.clone())).collect();
+
+        (entries, self.shard_generation(shard_idx))
+    }
+
+    /// Changes to the shard since `generation`, as returned by a previous `snapshot_shard` or `diff_since`,
+    /// for a replica to apply instead of copying the whole shard again. Requires `with_change_log`.
+    ///
+    /// Returns `Err(ResyncNeeded)` if some of the changes are no longer logged, as the log overflowed,
+    /// or the shard was written to by an operation that is not logged key by key, see `with_change_log`.
+    ///
+    /// Panics if `shard_idx` is out of bounds.
+    ///
+    /// ```
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let primary = CHashMap::new(1).with_change_log(64);
+    /// primary.insert("a", 1).await;
+    ///
+    /// let (entries, generation) = primary.snapshot_shard(0).await;
+    /// assert_eq!(entries, vec![("a", 1)]);
+    ///
+    /// primary.insert("b", 2).await;
+    /// primary.remove(&"a").await;
+    ///
+    /// let diff = primary.diff_since(0, generation).await.unwrap();
+    /// assert_eq!(diff.changes, vec![("b", Some(2)), ("a", None)]);
+    /// assert_eq!(diff.generation, primary.shard_generation(0));
+    ///
+    /// primary.clear().await;
+    /// assert!(primary.diff_since(0, diff.generation).await.is_err());
+    /// # }
+    /// ```
+    pub async fn diff_since(&self, shard_idx: usize, generation: u64) -> Result<ShardDiff<K, T>, ResyncNeeded>
+    where
+        K: Clone,
+        T: Clone,
+    {
+        let shard = self.shards[shard_idx].read().await;
+        let current = self.shard_generation(shard_idx);
+
+        if generation == current {
+            return Ok(ShardDiff {
+                changes: Vec::new(),
+                generation,
+            });
+        }
+
+        // a generation from the future was taken before `resize_shards` restarted them
+        let change_logs = match self.change_logs {
+            Some(ref change_logs) if generation < current => change_logs,
+            _ => return Err(ResyncNeeded),
+        };
+
+        let keys = change_logs.keys_since(shard_idx, generation).ok_or(ResyncNeeded)?;
+
+        // keys written several times are reported once, as only their current value matters
+        let mut seen = hashbrown::HashSet::with_capacity(keys.len());
+        let first_writes: Vec<bool> = keys.iter().map(|key| seen.insert(key)).collect();
+        drop(seen);
+
+        let changes = keys
+            .into_iter()
+            .zip(first_writes)
+            .filter_map(|(key, first)| first.then_some(key))
+            .map(|key| {
+                let hash = self.hash_builder.hash_one(&key);
+                let value = shard.raw_entry().from_key_hashed_nocheck(hash, &key).map(|(_, value)| value.clone());
+
+                (key, value)
+            })
+            .collect();
+
+        Ok(ShardDiff {
+            changes,
+            generation: current,
+        })
+    }
+
+    pub fn try_maybe_contains_hash(&self, hash: u64) -> bool {
+        let shard_idx = self.shard_of_hash(hash);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        if let Ok(shard) = shard.try_read() {
+            shard.raw_entry().from_hash(hash, |_| true).is_some()
+        } else {
+            false
+        }
+    }
+
+    pub async fn contains_hash(&self, hash: u64) -> bool {
+        let shard_idx = self.shard_of_hash(hash);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        shard.spin_read(self.spins).read().await.raw_entry().from_hash(hash, |_| true).is_some()
+    }
+
+    pub async fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.contains_hash(self.hash_and_shard(key).0).await
+    }
+
+    pub async fn remove<Q>(&self, key: &Q) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.remove_entry(key).await.map(|(_, value)| value)
+    }
+
+    /// Like `remove`, also returning the key stored in the map, e.g. to reuse its allocation
+    ///
+    /// ```
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let map = CHashMap::new(4);
+    /// map.insert(String::from("ferris"), 1).await;
+    ///
+    /// assert_eq!(map.remove_entry("ferris").await, Some((String::from("ferris"), 1)));
+    /// assert_eq!(map.remove_entry("ferris").await, None);
+    /// # }
+    /// ```
+    pub async fn remove_entry<Q>(&self, key: &Q) -> Option<(K, T)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(&key);
+        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).spin_write(self.spins).write().await };
+        self.cow.unshare_if(shard_idx, &shard, |shard| shard.raw_entry().from_key_hashed_nocheck(hash, key).is_some());
+
+        match shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(occupied) => {
+                self.record_key_write(shard_idx, hash, key);
+                let (key, value) = occupied.remove_entry();
+                self.log_key_write(shard_idx, &key);
+                self.shrink_size(1);
+                Some((key, value))
+            }
+            RawEntryMut::Vacant(_) => None,
+        }
+    }
+
+    /// Removes the value only if the predicate returns true for it, checked under the same lock as the removal
+    pub async fn remove_if<Q>(&self, key: &Q, predicate: impl FnOnce(&T) -> bool) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(&key);
+        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).spin_write(self.spins).write().await };
+
+        // checked before removing, so the shard is only copied into snapshots sharing it once it is written
+        match shard.raw_entry().from_key_hashed_nocheck(hash, key) {
+            Some((_, value)) if predicate(value) => {}
+            _ => return None,
+        }
+
+        self.cow.unshare(shard_idx, &shard);
+        self.record_key_write(shard_idx, hash, key);
+
+        let (key, value) = match shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(occupied) => occupied.remove_entry(),
+            RawEntryMut::Vacant(_) => unreachable!(),
+        };
+        self.log_key_write(shard_idx, &key);
+        self.shrink_size(1);
+
+        Some(value)
+    }
+
+    pub async fn insert(&self, key: K, value: T) -> Option<T> {
+        let (hash, shard_idx) = self.hash_and_shard(&key);
+        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).spin_write(self.spins).write().await };
+        self.cow.unshare(shard_idx, &shard);
+        self.record_key_write(shard_idx, hash, &key);
+        self.log_key_write(shard_idx, &key);
+
+        match shard.raw_entry_mut().from_key_hashed_nocheck(hash, &key) {
+            RawEntryMut::Occupied(mut occupied) => Some(occupied.insert(value)),
+            RawEntryMut::Vacant(vacant) => {
+                self.grow_size(1);
+                vacant.insert_hashed_nocheck(hash, key, value);
+                None
+            }
+        }
+    }
+
+    /// Inserts the value into the given shard instead of the one the key's hash selects, moving it there if present,
+    /// and keeps every later operation on the key in that shard, e.g. to isolate a few huge values in their own
+    /// shard, so that long write locks on them don't hold up other keys. Returns the value replaced, if any.
+    ///
+    /// Keys are pinned by hash, so other keys with the same 64-bit hash are pinned along. Pins are kept
+    /// after the key is removed, until `unpin`, or shrinking the map past the shard with `resize_shards`.
+    ///
+    /// Operations on the key that already selected its former shard when it is pinned may still complete there,
+    /// so pin keys before they are in use, or while nothing else writes to them. Watches of the key started before
+    /// it is pinned are no longer notified.
+    ///
+    /// Panics if `shard_idx` is out of bounds.
+    ///
+    /// ```
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let map = CHashMap::new(8);
+    ///
+    /// map.insert_pinned_to_shard(0, "huge", vec![0u8; 1 << 20]).await;
+    ///
+    /// assert_eq!(map.pinned_shard(&"huge"), Some(0));
+    /// assert_eq!(map.get(&"huge").await.unwrap().len(), 1 << 20);
+    /// # }
+    /// ```
+    pub async fn insert_pinned_to_shard(&self, shard_idx: usize, key: K, value: T) -> Option<T> {
+        assert!(shard_idx < self.shards.len(), "shard index out of bounds");
+
+        let hash = self.hash_builder.hash_one(&key);
+        let former_idx = self.shard_of_hash(hash);
+
+        if former_idx == shard_idx {
+            self.pins.pin(hash, shard_idx);
+            return self.insert(key, value).await;
+        }
+
+        // locked in index order, the only order the map holds several shard locks in
+        let (mut former, mut shard) = if former_idx < shard_idx {
+            let former = self.shards[former_idx].write().await;
+            (former, self.shards[shard_idx].write().await)
+        } else {
+            let shard = self.shards[shard_idx].write().await;
+            (self.shards[former_idx].write().await, shard)
+        };
+
+        self.cow.unshare_if(former_idx, &former, |former| {
+            former.raw_entry().from_key_hashed_nocheck(hash, &key).is_some()
+        });
+        self.cow.unshare(shard_idx, &shard);
+        self.record_key_write(shard_idx, hash, &key);
+        self.log_key_write(shard_idx, &key);
+
+        let moved = match former.raw_entry_mut().from_key_hashed_nocheck(hash, &key) {
+            RawEntryMut::Occupied(occupied) => {
+                self.record_key_write(former_idx, hash, &key);
+                self.log_key_write(former_idx, &key);
+                Some(occupied.remove())
+            }
+            RawEntryMut::Vacant(_) => None,
+        };
+
+        self.pins.pin(hash, shard_idx);
+
+        let replaced = match shard.raw_entry_mut().from_key_hashed_nocheck(hash, &key) {
+            RawEntryMut::Occupied(mut occupied) => Some(occupied.insert(value)),
+            RawEntryMut::Vacant(vacant) => {
+                vacant.insert_hashed_nocheck(hash, key, value);
+                None
+            }
+        };
+
+        if moved.is_none() && replaced.is_none() {
+            self.grow_size(1);
+        }
+
+        // a key can only be in both shards if pinned concurrently, which keeps the one in its new shard
+        if moved.is_some() && replaced.is_some() {
+            self.shrink_size(1);
+        }
+
+        replaced.or(moved)
+    }
+
+    /// Shard the key was pinned to with `insert_pinned_to_shard`, if any
+    pub fn pinned_shard<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        self.pins.get(self.hash_builder.hash_one(key))
+    }
+
+    /// Removes the key along with its pin, returning its value, so it is inserted in the shard its hash selects again
+    pub async fn unpin<Q>(&self, key: &Q) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+
+        self.pins.get(hash)?;
+
+        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).write().await };
+        self.cow.unshare_if(shard_idx, &shard, |shard| shard.raw_entry().from_key_hashed_nocheck(hash, key).is_some());
+        self.pins.unpin(hash);
+
+        match shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(occupied) => {
+                self.record_key_write(shard_idx, hash, key);
+                let (key, value) = occupied.remove_entry();
+                self.log_key_write(shard_idx, &key);
+                self.shrink_size(1);
+                Some(value)
+            }
+            RawEntryMut::Vacant(_) => None,
+        }
+    }
+
+    /// Like `insert`, but takes the key by reference and only converts it to an owned key if it is not present,
+    /// e.g. to insert by `&str` into a map of `String` keys without allocating when overwriting a value.
+    pub async fn insert_with_key<Q>(&self, key: &Q, value: T) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).spin_write(self.spins).write().await };
+        self.cow.unshare(shard_idx, &shard);
+        self.record_key_write(shard_idx, hash, key);
+
+        match shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(mut occupied) => {
+                self.log_key_write(shard_idx, occupied.key());
+                Some(occupied.insert(value))
+            }
+            RawEntryMut::Vacant(vacant) => {
+                self.grow_size(1);
+                let (key, _) = vacant.insert_hashed_nocheck(hash, key.to_owned(), value);
+                self.log_key_write(shard_idx, key);
+                None
+            }
+        }
+    }
+
+    pub async fn get<Q>(&self, key: &Q) -> Option<MapReadHandle<K, T, S>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).spin_read(self.spins).clone().read_owned().await };
+
+        OwnedRwLockReadGuard::try_map(shard, |shard| {
+            match shard.raw_entry().from_key_hashed_nocheck(hash, key) {
+                Some((_, value)) => Some(value),
+                None => None,
+            }
+        })
+        .ok()
+        .map(read_handle)
+    }
+
+    /// Like `get`, but fails with `Error::WouldBlock` instead of waiting if the key's shard is write-locked,
+    /// e.g. for latency-sensitive paths that would rather fall back to the source than queue behind a writer
+    pub fn try_get<Q>(&self, key: &Q) -> Result<Option<MapReadHandle<K, T, S>>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone() };
+        let shard = shard.try_read_owned().map_err(|_| Error::WouldBlock)?;
+
+        Ok(OwnedRwLockReadGuard::try_map(shard, |shard| {
+            shard.raw_entry().from_key_hashed_nocheck(hash, key).map(|(_, value)| value)
+        })
+        .ok()
+        .map(read_handle))
+    }
+
+    /// Like `get`, but fails with `Error::Timeout` if the key's shard can't be locked within `timeout`
+    pub async fn get_timeout<Q>(&self, key: &Q, timeout: Duration) -> Result<Option<MapReadHandle<K, T, S>>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        tokio::time::timeout(timeout, self.get(key)).await.map_err(|_| Error::Timeout)
+    }
+
+    /// Clones the value out of the map, or out of the calling thread's microcache if enabled,
+    /// see `with_microcache`
+    pub async fn get_cloned<Q>(&self, key: &Q) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        T: Clone,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+
+        let microcache = match self.microcache {
+            Some(ref microcache) => microcache,
+            None => {
+                let shard = self.read_or_shed(shard_idx).await?;
+
+                return shard
+                    .raw_entry()
+                    .from_key_hashed_nocheck(hash, key)
+                    .map(|(_, value)| value.clone());
+            }
+        };
+
+        if let Some(value) = microcache.get(hash, key) {
+            return Some(value);
+        }
+
+        // read before the value, so a write racing with the read leaves the cached entry stale
+        let epoch = microcache.epoch();
+        let shard = self.read_or_shed(shard_idx).await?;
+
+        let (key, value) = shard.raw_entry().from_key_hashed_nocheck(hash, key)?;
+        microcache.insert(epoch, hash, key, value);
+
+        Some(value.clone())
+    }
+
+    pub async fn get_mut<Q>(&self, key: &Q) -> Option<MapWriteHandle<K, T, S>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).spin_write(self.spins).clone().write_owned().await };
+        self.cow.unshare_if(shard_idx, &shard, |shard| shard.raw_entry().from_key_hashed_nocheck(hash, key).is_some());
+
+        OwnedRwLockWriteGuard::try_map(shard, |shard| {
+            match shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+                RawEntryMut::Occupied(occupied) => {
+                    // lent out mutably, so written for all the map can tell
+                    self.record_key_write(shard_idx, hash, key);
+                    let (key, value) = occupied.into_key_value();
+                    self.log_key_write(shard_idx, key);
+                    Some(value)
+                }
+                RawEntryMut::Vacant(_) => None,
+            }
+        })
+        .ok()
+        .map(write_handle)
+    }
+
+    /// Locks the key's entry to replace its value, returning `None` if there is none. The handle exposes the
+    /// current value, to compute the new one from, and either commits the new one or aborts, leaving the value as is.
+    ///
+    /// Unlike `rcu`, the shard stays locked while the new value is computed, so it is never computed twice,
+    /// but other tasks wait on it. They can tell the value is being replaced with `is_being_replaced`,
+    /// e.g. to serve something else than wait.
+    ///
+    /// ```
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let map = CHashMap::new(4);
+    /// map.insert("config", 1).await;
+    ///
+    /// let replace = map.begin_replace("config").await.unwrap();
+    /// assert!(map.is_being_replaced("config"));
+    ///
+    /// let new = *replace.old() + 1;
+    /// assert_eq!(replace.commit(new), 1);
+    ///
+    /// assert!(!map.is_being_replaced("config"));
+    /// assert_eq!(map.get_cloned("config").await, Some(2));
+    /// # }
+    /// ```
+    pub async fn begin_replace<Q>(&self, key: &Q) -> Option<ReplaceHandle<'_, K, T, S>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let value = self.get_mut(key).await?;
+
+        // only one handle can lock the shard at a time
+        self.replacing[shard_idx].store(hash | 1, Ordering::Release);
+
+        Some(ReplaceHandle {
+            map: self,
+            shard_idx,
+            value,
+        })
+    }
+
+    /// Whether the key's value is being replaced through a `ReplaceHandle`, without waiting on its shard.
+    ///
+    /// Keys are told apart by hash, so other keys with the same hash, but for its lowest bit, are reported along.
+    pub fn is_being_replaced<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        self.replacing[shard_idx].load(Ordering::Acquire) == hash | 1
+    }
+
+    /// Replaces the value with `f` applied to a clone of it, returning the value replaced, or `None` if there is none.
+    ///
+    /// `f` runs without holding any lock, so expensive updates don't hold up other tasks. If the shard is written
+    /// to meanwhile, the replacement is discarded and `f` runs again on the new value, so it should be free of
+    /// side effects. Values are cloned for `f`, so large values are best kept behind an `Arc`.
+    pub async fn rcu<Q>(&self, key: &Q, mut f: impl FnMut(&T) -> T) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        T: Clone,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        loop {
+            let (version, current) = {
+                let shard = shard.spin_read(self.spins).read().await;
+                let (_, value) = shard.raw_entry().from_key_hashed_nocheck(hash, key)?;
+
+                (self.versions[shard_idx].load(Ordering::Relaxed), value.clone())
+            };
+
+            let replacement = f(&current);
+
+            let mut shard = shard.spin_write(self.spins).write().await;
+
+            if self.versions[shard_idx].load(Ordering::Relaxed) != version {
+                continue;
+            }
+
+            self.cow.unshare(shard_idx, &shard);
+            self.record_key_write(shard_idx, hash, key);
+
+            // the shard is unchanged since the read, so the value is still there
+            if let RawEntryMut::Occupied(mut occupied) = shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+                self.log_key_write(shard_idx, occupied.key());
+                return Some(occupied.insert(replacement));
+            }
+        }
+    }
+
+    pub async fn get_or_insert(&self, key: &K, on_insert: impl FnOnce() -> T) -> MapReadHandle<K, T, S>
+    where
+        K: Clone,
+    {
+        self.get_or_insert_full(key, on_insert).await.0
+    }
+
+    /// Like `get_or_insert`, but also returns whether the value was inserted, i.e. `on_insert` was called,
+    /// for side effects to run only on first insertion
+    pub async fn get_or_insert_full(&self, key: &K, on_insert: impl FnOnce() -> T) -> (MapReadHandle<K, T, S>, bool)
+    where
+        K: Clone,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx) };
+        let mut shard = shard.spin_write(self.spins).clone().write_owned().await;
+        self.cow.unshare_if(shard_idx, &shard, |shard| shard.raw_entry().from_key_hashed_nocheck(hash, key).is_none());
+
+        let mut inserted = false;
+
+        if let RawEntryMut::Vacant(vacant) = shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            self.record_key_write(shard_idx, hash, key);
+            self.log_key_write(shard_idx, key);
+
+            // only count the entry once `on_insert` has returned without panicking
+            vacant.insert_hashed_nocheck(hash, key.clone(), on_insert());
+
+            self.grow_size(1);
+            inserted = true;
+        }
+
+        // TODO: Having to do another lookup for a read-reference is wasteful, maybe use an alternate custom ReadHandle?
+        let handle = read_handle(OwnedRwLockReadGuard::map(OwnedRwLockWriteGuard::downgrade(shard), |shard| {
+            match shard.raw_entry().from_key_hashed_nocheck(hash, key) {
+                Some((_, value)) => value,
+                None => unreachable!(),
+            }
+        }));
+
+        (handle, inserted)
+    }
+
+    /// Like `get_or_insert`, but computes the value without holding the shard lock, so an expensive
+    /// (or async) constructor does not block every other key of the shard.
+    ///
+    /// As the key may be inserted by another task in the meantime, `on_race` decides which value is kept.
+    pub async fn get_or_insert_with_race<F, Fut>(
+        &self,
+        key: &K,
+        on_insert: F,
+        on_race: RacePolicy,
+    ) -> MapReadHandle<K, T, S>
+    where
+        K: Clone,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let load = || async { Ok::<_, Infallible>(on_insert().await) };
+
+        match self.try_get_or_load(key, load, on_race).await {
+            Ok(handle) => handle,
+            Err(_) => unreachable!("infallible loader failed"),
+        }
+    }
+
+    /// Like `get_or_insert_with_race`, but with a fallible loader, whose error is returned as
+    /// `Error::LoaderFailed` without inserting anything, so a failed load can be retried on the next call.
+    ///
+    /// ```
+    /// # use quick_hash_cache::{CHashMap, Error, RacePolicy};
+    /// # #[tokio::main] async fn main() {
+    /// let map = CHashMap::<u32, String>::new(4);
+    ///
+    /// let failed = map.try_get_or_load(&1, || async { Err("source is down") }, RacePolicy::KeepFirst).await;
+    /// assert!(matches!(failed, Err(Error::LoaderFailed("source is down"))));
+    /// assert!(!map.contains(&1).await);
+    ///
+    /// let loaded = map.try_get_or_load(&1, || async { Ok::<_, &str>("one".to_owned()) }, RacePolicy::KeepFirst);
+    /// assert_eq!(*loaded.await.unwrap(), "one");
+    /// # }
+    /// ```
+    pub async fn try_get_or_load<F, Fut, E>(
+        &self,
+        key: &K,
+        loader: F,
+        on_race: RacePolicy,
+    ) -> Result<MapReadHandle<K, T, S>, Error<E>>
+    where
+        K: Clone,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx) };
+
+        let existing = OwnedRwLockReadGuard::try_map(shard.spin_read(self.spins).clone().read_owned().await, |shard| {
+            shard.raw_entry().from_key_hashed_nocheck(hash, key).map(|(_, value)| value)
+        });
+
+        match existing {
+            Ok(existing) => return Ok(read_handle(existing)),
+            // release the read lock before running the loader
+            Err(shard) => drop(shard),
+        }
+
+        let value = loader().await.map_err(Error::LoaderFailed)?;
+
+        let mut shard = shard.spin_write(self.spins).clone().write_owned().await;
+        self.cow.unshare_if(shard_idx, &shard, |shard| {
+            on_race == RacePolicy::Replace || shard.raw_entry().from_key_hashed_nocheck(hash, key).is_none()
+        });
+
+        match shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(mut occupied) => {
+                if on_race == RacePolicy::Replace {
+                    self.record_key_write(shard_idx, hash, key);
+                    self.log_key_write(shard_idx, key);
+                    occupied.insert(value);
+                }
+            }
+            RawEntryMut::Vacant(vacant) => {
+                self.record_key_write(shard_idx, hash, key);
+                self.log_key_write(shard_idx, key);
+                self.grow_size(1);
+
+                vacant.insert_hashed_nocheck(hash, key.clone(), value);
+            }
+        }
+
+        Ok(read_handle(OwnedRwLockReadGuard::map(OwnedRwLockWriteGuard::downgrade(shard), |shard| {
+            match shard.raw_entry().from_key_hashed_nocheck(hash, key) {
+                Some((_, value)) => value,
+                None => unreachable!(),
+            }
+        })))
+    }
+
+    pub async fn get_mut_or_insert(
+        &self,
+        key: &K,
+        on_insert: impl FnOnce() -> T,
+    ) -> MapWriteHandle<K, T, S>
+    where
+        K: Clone,
+    {
+        self.get_mut_or_insert_full(key, on_insert).await.0
+    }
+
+    /// Like `get_mut_or_insert`, but also returns whether the value was inserted, i.e. `on_insert` was called
+    pub async fn get_mut_or_insert_full(
+        &self,
+        key: &K,
+        on_insert: impl FnOnce() -> T,
+    ) -> (MapWriteHandle<K, T, S>, bool)
+    where
+        K: Clone,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).spin_write(self.spins).clone().write_owned().await };
+        self.cow.unshare(shard_idx, &shard);
+        self.record_key_write(shard_idx, hash, key);
+        self.log_key_write(shard_idx, key);
+
+        let mut inserted = false;
+
+        let handle = write_handle(OwnedRwLockWriteGuard::map(shard, |shard| {
+            shard
+                .raw_entry_mut()
+                .from_key_hashed_nocheck(hash, key)
+                .or_insert_with(|| {
+                    let entry = (key.clone(), on_insert());
+
+                    self.grow_size(1);
+                    inserted = true;
+
+                    entry
+                })
+                .1
+        }));
+
+        (handle, inserted)
+    }
+
+    pub async fn get_or_default(&self, key: &K) -> MapReadHandle<K, T, S>
+    where
+        K: Clone,
+        T: Default,
+    {
+        self.get_or_insert(key, Default::default).await
+    }
+
+    pub async fn get_mut_or_default(&self, key: &K) -> MapWriteHandle<K, T, S>
+    where
+        K: Clone,
+        T: Default,
+    {
+        self.get_mut_or_insert(key, Default::default).await
+    }
+
+    /// Write-locks the shard the key belongs to, for surgery on the whole shard, such as bulk edits of related keys
+    /// or rebuilding its table, with the map's size kept up to date by the guard when dropped.
+    ///
+    /// The guard's `insert` and `remove` keep entries in the shard they belong to. Through the raw table of
+    /// `shard_mut`, only entries of this shard (see `shard_of`) must be inserted, or lookups will not find them.
+    ///
+    /// ```
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let map = CHashMap::new(4);
+    /// map.insert(1, "a").await;
+    ///
+    /// let mut shard = map.shard_for(&1).await;
+    ///
+    /// assert_eq!(shard.insert(1, "b"), Ok(Some("a")));
+    /// shard.shard_mut().shrink_to_fit();
+    /// drop(shard);
+    ///
+    /// assert_eq!(map.size(), 1);
+    /// # }
+    /// ```
+    pub async fn shard_for<Q>(&self, key: &Q) -> ShardGuard<'_, K, T, S>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        let (_, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).write().await };
+        self.record_write(shard_idx, &shard);
+
+        ShardGuard {
+            map: self,
+            shard_idx,
+            len_at_lock: shard.len(),
+            shard,
+        }
+    }
+
+    /*
+    pub async fn entry<Q>(&self, key: &Q) -> WriteHandle<impl Erased, Entry<'_, K, T, S>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone().write_owned().await };
+        self.record_write(shard_idx, &shard);
+
+        OwnedRwLockWriteGuard::map(shard, |shard| {
+            shard.raw_entry_mut().from_key_hashed_nocheck(hash, key)
+        })
+    }
+    */
+
+    /// Hashes the keys of a batch into `cache` along with their shards, leaving out those of shards before the first.
+    ///
+    /// Keys are given as items from which `key_of` gets them, e.g. the keys themselves or their positions in a slice.
+    #[cfg(not(feature = "batch-prefetch"))]
+    fn hash_batch<'a, X, Q, I>(
+        &self,
+        items: I,
+        key_of: impl Fn(X) -> &'a Q,
+        first_shard: usize,
+        cache: &mut Vec<(X, u64, usize)>,
+    ) where
+        X: Copy,
+        Q: ?Sized + Hash + 'a,
+        I: IntoIterator<Item = X>,
+    {
+        cache.extend(items.into_iter().filter_map(|item| {
+            let (hash, shard) = self.hash_and_shard(key_of(item));
+            (shard >= first_shard).then_some((item, hash, shard))
+        }));
+    }
+
+    /// Hashes the keys of a batch into `cache` along with their shards, leaving out those of shards before the first.
+    ///
+    /// Keys are hashed one after the other in a pass of their own, before finding their shards, which are then
+    /// prefetched ahead of being locked.
+    #[cfg(feature = "batch-prefetch")]
+    fn hash_batch<'a, X, Q, I>(
+        &self,
+        items: I,
+        key_of: impl Fn(X) -> &'a Q,
+        first_shard: usize,
+        cache: &mut Vec<(X, u64, usize)>,
+    ) where
+        X: Copy,
+        Q: ?Sized + Hash + 'a,
+        I: IntoIterator<Item = X>,
+    {
+        cache.extend(items.into_iter().map(|item| (item, self.hash_builder.hash_one(key_of(item)), 0)));
+
+        for entry in cache.iter_mut() {
+            entry.2 = self.shard_of_hash(entry.1);
+            prefetch(&*self.shards[entry.2]);
+        }
+
+        if first_shard > 0 {
+            cache.retain(|&(_, _, shard)| shard >= first_shard);
+        }
+    }
+
+    /// Aggregates all the provided keys and batches together access to the underlying shards,
+    /// reducing locking overhead at the cost of memory to buffer keys/hashes.
+    pub async fn batch_read<'a, Q, I, F>(&self, keys: I, cache: Option<&mut Vec<(&'a Q, u64, usize)>>, f: F)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        I: IntoIterator<Item = &'a Q>,
+        F: FnMut(&'a Q, Option<(&K, &T)>),
+    {
+        let _ = self.batch_read_until(keys, cache, Cursor::START, &Deadline::NEVER, f).await;
+    }
+
+    /// Like `batch_read`, but gives up between shards once the deadline is reached, skipping the keys
+    /// of shards before the cursor's, so passing the same keys back with the cursor returned reads the rest.
+    pub async fn batch_read_until<'a, Q, I, F>(
+        &self,
+        keys: I,
+        cache: Option<&mut Vec<(&'a Q, u64, usize)>>,
+        from: Cursor,
+        deadline: &Deadline,
+        mut f: F,
+    ) -> Progress
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        I: IntoIterator<Item = &'a Q>,
+        F: FnMut(&'a Q, Option<(&K, &T)>),
+    {
+        let mut own_cache = Vec::new();
+        let cache = match cache {
+            Some(cache) => {
+                cache.clear();
+                cache
+            }
+            None => &mut own_cache,
+        };
+
+        self.hash_batch(keys, |key| key, deadline::first_shard(from), cache);
+
+        if cache.is_empty() {
+            return Progress::Done;
+        }
+
+        group_by_shard(cache, &mut Vec::new(), &mut Vec::new(), self.shards.len(), |&(_, _, shard)| shard);
+
+        let mut i = 0;
+        'outer: loop {
+            let current_shard = cache[i].2;
+
+            if deadline.is_reached() {
+                cache.clear();
+                return Progress::interrupted_at(current_shard);
+            }
+
+            let end = i + cache[i..].iter().take_while(|&&(_, _, shard)| shard == current_shard).count();
+
+            if !self.shard_may_hold(current_shard, cache[i..end].iter().map(|&(_, hash, _)| hash)) {
+                for &(key, _, _) in &cache[i..end] {
+                    f(key, None);
+                }
+
+                if end >= cache.len() {
+                    break 'outer;
+                }
+
+                i = end;
+                continue;
+            }
+
+            let shard = unsafe { self.shards.get_unchecked(current_shard).spin_read(self.spins).read().await };
+            self.refresh_filter(current_shard, &shard);
+
+            while cache[i].2 == current_shard {
+                f(
+                    cache[i].0,
+                    shard.raw_entry().from_key_hashed_nocheck(cache[i].1, cache[i].0),
+                );
+                i += 1;
+
+                if i >= cache.len() {
+                    break 'outer;
+                }
+            }
+        }
+
+        cache.clear();
+
+        Progress::Done
+    }
+
+    /// Like `batch_read`, cloning the entries found into `out`, e.g. a `HashMap<K, T>`, and pushing the keys
+    /// not found to `missing`, if provided, so cache-aside loaders only have to load those.
+    ///
+    /// ```
+    /// # use std::collections::HashMap;
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let map = CHashMap::new(4);
+    /// map.insert(1, "one").await;
+    ///
+    /// let (mut found, mut missing) = (HashMap::new(), Vec::new());
+    /// map.batch_read_into(&[1, 2], &mut found, Some(&mut missing)).await;
+    ///
+    /// assert_eq!(found, HashMap::from([(1, "one")]));
+    /// assert_eq!(missing, [&2]);
+    /// # }
+    /// ```
+    pub async fn batch_read_into<'a, Q, I, E>(&self, keys: I, out: &mut E, mut missing: Option<&mut Vec<&'a Q>>)
+    where
+        K: Borrow<Q> + Clone,
+        T: Clone,
+        Q: ?Sized + Hash + Eq,
+        I: IntoIterator<Item = &'a Q>,
+        E: Extend<(K, T)>,
+    {
+        self.batch_read(keys, None, |key, entry| match entry {
+            Some((k, v)) => out.extend(Some((k.clone(), v.clone()))),
+            None => {
+                if let Some(ref mut missing) = missing {
+                    missing.push(key);
+                }
+            }
+        })
+        .await
+    }
+
+    /// Gets the values of the keys, in the same order, loading the ones not in the map with a single call to
+    /// `loader`, whose entries are then inserted. Keys the loader returns no value for are `None`.
+    ///
+    /// Concurrent calls don't load the same key twice: keys already being loaded by another call are waited on
+    /// instead. If that call is cancelled, its keys are loaded by one of the waiters, with another call to `loader`,
+    /// or with `LoadCancellation::Propagate` (see `with_load_cancellation`), the waiters return `Err(LoadCancelled)`.
+    ///
+    /// ```
+    /// # use quick_hash_cache::CHashMap;
+    /// # #[tokio::main] async fn main() {
+    /// let users = CHashMap::new(4);
+    /// users.insert(1, "ferris").await;
+    ///
+    /// let values = users
+    ///     .get_or_load_batch(&[1, 2, 3], |missing| async move {
+    ///         assert_eq!(missing, [2, 3]);
+    ///         vec![(2, "corro")]
+    ///     })
+    ///     .await
+    ///     .unwrap();
+    ///
+    /// assert_eq!(values, [Some("ferris"), Some("corro"), None]);
+    /// assert_eq!(users.get_cloned(&2).await, Some("corro"));
+    /// # }
+    /// ```
+    pub async fn get_or_load_batch<L, F>(&self, keys: &[K], mut loader: L) -> Result<Vec<Option<T>>, LoadCancelled>
+    where
+        K: Clone,
+        T: Clone,
+        L: FnMut(Vec<K>) -> F,
+        F: Future<Output = Vec<(K, T)>>,
+    {
+        let mut found = HashMap::new();
+        let mut pending: Vec<&K> = keys.iter().collect();
+
+        while !pending.is_empty() {
+            let mut missing = Vec::new();
+            self.batch_read_into(pending.drain(..), &mut found, Some(&mut missing)).await;
+
+            let (load, waiting) = self.loads.claim(missing);
+
+            // another load may have finished between reading the map and claiming its keys
+            let mut unloaded = Vec::new();
+            self.batch_read_into(load.keys(), &mut found, Some(&mut unloaded)).await;
+
+            // load first, then wait, so that overlapping batches never wait on each other
+            if !unloaded.is_empty() {
+                let loaded: HashMap<K, T> = loader(unloaded.into_iter().cloned().collect()).await.into_iter().collect();
+
+                self.batch_write(loaded.keys(), None, |key, entry| match entry {
+                    RawEntryMut::Occupied(mut occupied) => {
+                        occupied.insert(loaded[key].clone());
+                    }
+                    RawEntryMut::Vacant(vacant) => {
+                        vacant.insert(key.clone(), loaded[key].clone());
+                        self.grow_size(1);
+                    }
+                })
+                .await;
+
+                load.finish();
+                found.extend(loaded);
+            }
+
+            for (key, load) in waiting {
+                if !loader::wait(load).await {
+                    match self.load_cancellation {
+                        LoadCancellation::TakeOver => pending.push(key),
+                        LoadCancellation::Propagate => return Err(LoadCancelled),
+                    }
+                } else if let Some(value) = self.get_cloned(key).await {
+                    found.insert(key.clone(), value);
+                }
+            }
+        }
+
+        Ok(keys.iter().map(|key| found.get(key).cloned()).collect())
+    }
+
+    /// Aggregates all the provided keys and batches together access to the underlying shards,
+    /// reducing locking overhead at the cost of memory to buffer keys/hashes
+    pub async fn batch_write<'a, Q, I, F>(&self, keys: I, cache: Option<&mut Vec<(&'a Q, u64, usize)>>, f: F)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        I: IntoIterator<Item = &'a Q>,
+        F: FnMut(&'a Q, hashbrown::hash_map::RawEntryMut<K, T, S>),
+    {
+        let _ = self.batch_write_until(keys, cache, Cursor::START, &Deadline::NEVER, f).await;
+    }
+
+    /// Like `batch_write`, but gives up between shards once the deadline is reached, skipping the keys
+    /// of shards before the cursor's, so passing the same keys back with the cursor returned writes the rest.
+    pub async fn batch_write_until<'a, Q, I, F>(
+        &self,
+        keys: I,
+        cache: Option<&mut Vec<(&'a Q, u64, usize)>>,
+        from: Cursor,
+        deadline: &Deadline,
+        mut f: F,
+    ) -> Progress
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        I: IntoIterator<Item = &'a Q>,
+        F: FnMut(&'a Q, hashbrown::hash_map::RawEntryMut<K, T, S>),
+    {
+        let mut own_cache = Vec::new();
+        let cache = match cache {
+            Some(cache) => {
+                cache.clear();
+                cache
+            }
+            None => &mut own_cache,
+        };
+
+        self.hash_batch(keys, |key| key, deadline::first_shard(from), cache);
+
+        if cache.is_empty() {
+            return Progress::Done;
+        }
+
+        group_by_shard(cache, &mut Vec::new(), &mut Vec::new(), self.shards.len(), |&(_, _, shard)| shard);
+
+        let mut i = 0;
+        'outer: loop {
+            let current_shard = cache[i].2;
+
+            if deadline.is_reached() {
+                cache.clear();
+                return Progress::interrupted_at(current_shard);
+            }
+
+            let mut shard = unsafe { self.shards.get_unchecked(current_shard).spin_write(self.spins).write().await };
+            self.record_write(current_shard, &shard);
+
+            while cache[i].2 == current_shard {
+                f(
+                    cache[i].0,
+                    shard
+                        .raw_entry_mut()
+                        .from_key_hashed_nocheck(cache[i].1, cache[i].0),
+                );
+                i += 1;
+
+                if i >= cache.len() {
+                    break 'outer;
+                }
+            }
+        }
+
+        cache.clear();
+
+        Progress::Done
+    }
+
+    /// Batch operation reusing the buffers of `ctx`, which unlike passing a `cache` to `batch_read` or `batch_write`
+    /// also keeps the buffers used to group keys by shard, see `BatchContext`
+    pub fn batch<'c>(&self, ctx: &'c mut BatchContext) -> Batch<'_, 'c, K, T, S> {
+        Batch::new(self, ctx)
+    }
+
+    /// Hashes and groups the keys of a batch by shard into `ctx`
+    fn group_batch<Q>(&self, keys: &[Q], ctx: &mut BatchContext, from: Cursor)
+    where
+        Q: Hash,
+    {
+        self.hash_batch(0..keys.len(), |idx| &keys[idx], deadline::first_shard(from), ctx.entries_mut());
+        ctx.group(self.shards.len());
+    }
+
+    /// See `Batch::read`
+    pub(crate) async fn batch_read_ctx<'k, Q, F>(
+        &self,
+        keys: &'k [Q],
+        ctx: &mut BatchContext,
+        from: Cursor,
+        deadline: &Deadline,
+        mut f: F,
+    ) -> Progress
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+        F: FnMut(&'k Q, Option<(&K, &T)>),
+    {
+        self.group_batch(keys, ctx, from);
+
+        for shard_idx in deadline::first_shard(from)..self.shards.len() {
+            let group = ctx.shard_range(shard_idx);
+
+            if group.is_empty() {
+                continue;
+            }
+
+            if deadline.is_reached() {
+                return Progress::interrupted_at(shard_idx);
+            }
+
+            if !self.shard_may_hold(shard_idx, group.clone().map(|idx| ctx.entry(idx).1)) {
+                for idx in group {
+                    let (key_idx, _, _) = ctx.entry(idx);
+                    ctx.push_missing(key_idx);
+                    f(&keys[key_idx], None);
+                }
+
+                continue;
+            }
+
+            let shard = unsafe { self.shards.get_unchecked(shard_idx).spin_read(self.spins).read().await };
+            self.refresh_filter(shard_idx, &shard);
+
+            for idx in group {
+                let (key_idx, hash, _) = ctx.entry(idx);
+                let entry = shard.raw_entry().from_key_hashed_nocheck(hash, &keys[key_idx]);
+
+                if entry.is_none() {
+                    ctx.push_missing(key_idx);
+                }
+
+                f(&keys[key_idx], entry);
+            }
+        }
+
+        Progress::Done
+    }
+
+    /// See `Batch::write`
+    pub(crate) async fn batch_write_ctx<'k, Q, F>(
+        &self,
+        keys: &'k [Q],
+        ctx: &mut BatchContext,
+        from: Cursor,
+        deadline: &Deadline,
+        mut f: F,
+    ) -> Progress
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+        F: FnMut(&'k Q, RawEntryMut<K, T, S>),
+    {
+        self.group_batch(keys, ctx, from);
+
+        for shard_idx in deadline::first_shard(from)..self.shards.len() {
+            let group = ctx.shard_range(shard_idx);
+
+            if group.is_empty() {
+                continue;
+            }
+
+            if deadline.is_reached() {
+                return Progress::interrupted_at(shard_idx);
+            }
+
+            let mut shard = unsafe { self.shards.get_unchecked(shard_idx).spin_write(self.spins).write().await };
+            self.record_write(shard_idx, &shard);
+
+            for idx in group {
+                let (key_idx, hash, _) = ctx.entry(idx);
+                f(&keys[key_idx], shard.raw_entry_mut().from_key_hashed_nocheck(hash, &keys[key_idx]));
+            }
+        }
+
+        Progress::Done
+    }
+}
+
+/// Values behind [`SeqLock`]s, read and updated with only the shard's read lock
+impl<K, T, S> CHashMap<K, SeqLock<T>, S>
+where
+    K: Hash + Eq,
+    T: Copy,
+    S: BuildHasher,
+{
+    /// Copies a value out of the map.
+    ///
+    /// Only the shard's read lock is taken, and only to find the entry, so this is never blocked by updates
+    /// through `update_copy`, only by writers locking the whole shard (`insert`, `get_mut`, ...).
+    pub async fn get_copy<Q>(&self, key: &Q) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).spin_read(self.spins).read().await };
+
+        shard
+            .raw_entry()
+            .from_key_hashed_nocheck(hash, key)
+            .map(|(_, value)| value.load())
+    }
+
+    /// Replaces a value with `f` of it, returning the new value, with only the shard's read lock taken,
+    /// e.g. to increment a hot counter without blocking readers of the shard.
+    ///
+    /// `f` runs while other updates of the same value spin, so it should be cheap.
+    pub async fn update_copy<Q>(&self, key: &Q, f: impl FnOnce(T) -> T) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).spin_read(self.spins).read().await };
+
+        // other values of the shard updated concurrently may be copied into snapshots along with their update
+        self.cow.unshare(shard_idx, &shard);
+
+        let updated = shard
+            .raw_entry()
+            .from_key_hashed_nocheck(hash, key)
+            .map(|(_, value)| value.update(f).1);
+
+        // written in place under a read lock, so recorded after the write rather than when locking
+        self.record_write(shard_idx, &shard);
+
+        updated
+    }
+}
+
+/// Write locks on every shard of a [`CHashMap`], see [`CHashMap::lock_all_shards`].
+///
+/// The map's size is brought up to date with the changes made through the guard when it is dropped.
+pub struct AllShardsGuard<'a, K, T, S = DefaultHashBuilder> {
+    map: &'a CHashMap<K, T, S>,
+    shards: Vec<RwLockWriteGuard<'a, Shard<K, T, S>>>,
+    /// Number of entries when locked, to update the map's size by the difference when dropped
+    len_at_lock: usize,
+}
+
+/// Read locks on every shard of a [`CHashMap`], see [`CHashMap::read_all_shards`]
+pub struct AllShardsReadGuard<'a, K, T, S = DefaultHashBuilder> {
+    map: &'a CHashMap<K, T, S>,
+    shards: Vec<RwLockReadGuard<'a, Shard<K, T, S>>>,
+}
+
+macro_rules! impl_all_shards_read {
+    ($guard:ident) => {
+        impl<'a, K, T, S> $guard<'a, K, T, S>
+        where
+            K: Hash + Eq,
+            S: BuildHasher,
+        {
+            /// Exact number of entries
+            pub fn len(&self) -> usize {
+                self.shards.iter().map(|shard| shard.len()).sum()
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.shards.iter().all(|shard| shard.is_empty())
+            }
+
+            pub fn shards(&self) -> impl Iterator<Item = &Shard<K, T, S>> {
+                self.shards.iter().map(|shard| &**shard)
+            }
+
+            pub fn iter(&self) -> impl Iterator<Item = (&K, &T)> {
+                self.shards.iter().flat_map(|shard| shard.iter())
+            }
+
+            pub fn get<Q>(&self, key: &Q) -> Option<&T>
+            where
+                K: Borrow<Q>,
+                Q: ?Sized + Hash + Eq,
+            {
+                let (hash, shard_idx) = self.map.hash_and_shard(key);
+
+                self.shards[shard_idx]
+                    .raw_entry()
+                    .from_key_hashed_nocheck(hash, key)
+                    .map(|(_, value)| value)
+            }
+        }
+    };
+}
+
+impl_all_shards_read!(AllShardsGuard);
+impl_all_shards_read!(AllShardsReadGuard);
+
+impl<'a, K, T, S> AllShardsGuard<'a, K, T, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn shards_mut(&mut self) -> Vec<&mut Shard<K, T, S>> {
+        self.shards.iter_mut().map(|shard| &mut **shard).collect()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut T)> {
+        self.shards_mut().into_iter().flat_map(|shard| shard.iter_mut())
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.map.hash_and_shard(key);
+
+        match self.shards[shard_idx].raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(occupied) => Some(occupied.into_mut()),
+            RawEntryMut::Vacant(_) => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: T) -> Option<T> {
+        let (hash, shard_idx) = self.map.hash_and_shard(&key);
+
+        match self.shards[shard_idx].raw_entry_mut().from_key_hashed_nocheck(hash, &key) {
+            RawEntryMut::Occupied(mut occupied) => Some(occupied.insert(value)),
+            RawEntryMut::Vacant(vacant) => {
+                vacant.insert_hashed_nocheck(hash, key, value);
+                None
+            }
+        }
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.map.hash_and_shard(key);
+
+        match self.shards[shard_idx].raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(occupied) => Some(occupied.remove()),
+            RawEntryMut::Vacant(_) => None,
+        }
+    }
+
+    /// Removes and returns every entry
+    pub fn drain(&mut self) -> Vec<(K, T)> {
+        let mut entries = Vec::with_capacity(self.len());
+
+        for shard in &mut self.shards {
+            entries.extend(shard.drain());
+        }
+
+        entries
+    }
+}
+
+/// Write lock on a single shard of a [`CHashMap`], see [`CHashMap::shard_for`].
+///
+/// The map's size is brought up to date with the changes made through the guard when it is dropped,
+/// even if a closure of `run_on_shard` using it panicked.
+pub struct ShardGuard<'a, K, T, S = DefaultHashBuilder> {
+    map: &'a CHashMap<K, T, S>,
+    shard: RwLockWriteGuard<'a, Shard<K, T, S>>,
+    shard_idx: usize,
+    /// Number of entries when locked, to update the map's size by the difference when dropped
+    len_at_lock: usize,
+}
+
+impl<'a, K, T, S> ShardGuard<'a, K, T, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn shard_idx(&self) -> usize {
+        self.shard_idx
+    }
+
+    /// Number of entries in the shard
+    pub fn len(&self) -> usize {
+        self.shard.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shard.is_empty()
+    }
+
+    pub fn shard(&self) -> &Shard<K, T, S> {
+        &self.shard
+    }
+
+    /// The shard's table, into which only keys of this shard must be inserted, see `CHashMap::shard_of`
+    pub fn shard_mut(&mut self) -> &mut Shard<K, T, S> {
+        &mut self.shard
+    }
+
+    /// Whether the key belongs to this shard, which `insert` requires
+    pub fn owns<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        self.map.shard_of(key) == self.shard_idx
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard.get(key)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard.get_mut(key)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard.contains_key(key)
+    }
+
+    /// Inserts the entry, returning the value replaced, if any, or the entry back if the key belongs to another shard
+    pub fn insert(&mut self, key: K, value: T) -> Result<Option<T>, (K, T)> {
+        let (hash, shard_idx) = self.map.hash_and_shard(&key);
+
+        if shard_idx != self.shard_idx {
+            return Err((key, value));
+        }
+
+        match self.shard.raw_entry_mut().from_key_hashed_nocheck(hash, &key) {
+            RawEntryMut::Occupied(mut occupied) => Ok(Some(occupied.insert(value))),
+            RawEntryMut::Vacant(vacant) => {
+                vacant.insert_hashed_nocheck(hash, key, value);
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.shard.remove(key)
+    }
+
+    pub fn retain(&mut self, f: impl FnMut(&K, &mut T) -> bool) {
+        self.shard.retain(f)
+    }
+}
+
+/// Lock on an entry of a [`CHashMap`] whose value is being replaced, see [`CHashMap::begin_replace`].
+///
+/// Dropping the handle without committing aborts the replacement, leaving the value as is.
+pub struct ReplaceHandle<'a, K, T, S = DefaultHashBuilder> {
+    map: &'a CHashMap<K, T, S>,
+    shard_idx: usize,
+    value: MapWriteHandle<K, T, S>,
+}
+
+impl<K, T, S> ReplaceHandle<'_, K, T, S> {
+    /// Value being replaced
+    pub fn old(&self) -> &T {
+        &self.value
+    }
+
+    /// Replaces the value, returning the old one
+    pub fn commit(mut self, value: T) -> T {
+        mem::replace(&mut *self.value, value)
+    }
+
+    /// Leaves the value as is, like dropping the handle
+    pub fn abort(self) {}
+}
+
+impl<K, T, S> Drop for ReplaceHandle<'_, K, T, S> {
+    fn drop(&mut self) {
+        // cleared before the shard is unlocked, so tasks waiting on it don't see the replacement as ongoing
+        self.map.replacing[self.shard_idx].store(0, Ordering::Release);
+    }
+}
+
+impl<K, T, S> Drop for ShardGuard<'_, K, T, S> {
+    fn drop(&mut self) {
+        let len = self.shard.len();
+
+        if len > self.len_at_lock {
+            self.map.grow_size(len - self.len_at_lock);
+        } else {
+            self.map.shrink_size(self.len_at_lock - len);
+        }
+    }
+}
+
+impl<K, T, S> Drop for AllShardsGuard<'_, K, T, S> {
+    fn drop(&mut self) {
+        // every other writer is locked out, so the difference is exactly the changes made through the guard
+        let len: usize = self.shards.iter().map(|shard| shard.len()).sum();
+
+        if len > self.len_at_lock {
+            self.map.grow_size(len - self.len_at_lock);
+        } else {
+            self.map.shrink_size(self.len_at_lock - len);
+        }
+    }
+}
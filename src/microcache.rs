@@ -0,0 +1,177 @@
+//! Tiny per-thread cache of hot entries in front of `CHashMap::get_cloned`, see `CHashMap::with_microcache`.
+
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::sync::{AtomicU64, Ordering};
+
+/// Number of stripes per CPU, so threads rarely share one
+const STRIPES_PER_CPU: usize = 2;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Index of this thread, for picking its stripe
+    static THREAD: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+fn thread_index() -> usize {
+    THREAD.with(|thread| match thread.get() {
+        Some(idx) => idx,
+        None => {
+            let idx = NEXT_THREAD.fetch_add(1, atomic::Ordering::Relaxed);
+            thread.set(Some(idx));
+            idx
+        }
+    })
+}
+
+struct Slot<K, T> {
+    hash: u64,
+    /// Write epoch of the map when the value was read, the slot being stale once it changed
+    epoch: u64,
+    key: K,
+    value: T,
+}
+
+struct Stripe<K, T> {
+    slots: Vec<Slot<K, T>>,
+    /// Slot to replace next once all are taken
+    next: usize,
+}
+
+/// Entries recently read by each thread, valid until the map's next write.
+///
+/// Each thread uses a stripe of its own (shared only when there are more threads than stripes), which is only
+/// ever `try_lock`ed, so a contended stripe is skipped rather than waited on.
+pub(crate) struct MicroCache<K, T> {
+    /// Bumped by every write to the map, invalidating all cached entries
+    epoch: AtomicU64,
+    stripes: Box<[Mutex<Stripe<K, T>>]>,
+    entries_per_thread: usize,
+    // the map's methods don't require `Clone`, so the cache clones through the functions it was created with
+    clone_key: fn(&K) -> K,
+    clone_value: fn(&T) -> T,
+}
+
+impl<K, T> MicroCache<K, T> {
+    pub(crate) fn new(entries_per_thread: usize) -> Self
+    where
+        K: Clone,
+        T: Clone,
+    {
+        let num_stripes = crate::num_cpus() * STRIPES_PER_CPU;
+
+        MicroCache {
+            epoch: AtomicU64::new(0),
+            stripes: (0..num_stripes)
+                .map(|_| {
+                    Mutex::new(Stripe {
+                        slots: Vec::with_capacity(entries_per_thread),
+                        next: 0,
+                    })
+                })
+                .collect(),
+            entries_per_thread,
+            clone_key: K::clone,
+            clone_value: T::clone,
+        }
+    }
+
+    /// Empty cache with the same configuration
+    pub(crate) fn fresh(&self) -> Self {
+        MicroCache {
+            epoch: AtomicU64::new(0),
+            stripes: (0..self.stripes.len())
+                .map(|_| {
+                    Mutex::new(Stripe {
+                        slots: Vec::with_capacity(self.entries_per_thread),
+                        next: 0,
+                    })
+                })
+                .collect(),
+            ..*self
+        }
+    }
+
+    /// Invalidates every cached entry. Must be called while holding a shard's write lock, or after writing
+    /// to a value in place, so that a value read before the write is never cached with the epoch after it.
+    #[inline]
+    pub(crate) fn invalidate(&self) {
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Epoch to cache a value with, to be read before the value itself
+    #[inline]
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    fn stripe(&self) -> Option<MutexGuard<'_, Stripe<K, T>>> {
+        let stripe = &self.stripes[thread_index() % self.stripes.len()];
+        stripe.try_lock().ok()
+    }
+
+    pub(crate) fn get<Q>(&self, hash: u64, key: &Q) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let stripe = self.stripe()?;
+        let epoch = self.epoch();
+
+        stripe
+            .slots
+            .iter()
+            .find(|slot| slot.epoch == epoch && slot.hash == hash && slot.key.borrow() == key)
+            .map(|slot| (self.clone_value)(&slot.value))
+    }
+
+    /// Caches a value read while the map was at `epoch`, replacing a stale slot, or the oldest one
+    pub(crate) fn insert(&self, epoch: u64, hash: u64, key: &K, value: &T) {
+        if self.entries_per_thread == 0 {
+            return;
+        }
+
+        let mut stripe = match self.stripe() {
+            Some(stripe) => stripe,
+            None => return,
+        };
+
+        let current = self.epoch();
+
+        if epoch != current {
+            return;
+        }
+
+        let slot = Slot {
+            hash,
+            epoch,
+            key: (self.clone_key)(key),
+            value: (self.clone_value)(value),
+        };
+
+        if let Some(stale) = stripe.slots.iter_mut().find(|slot| slot.epoch != current) {
+            *stale = slot;
+        } else if stripe.slots.len() < self.entries_per_thread {
+            stripe.slots.push(slot);
+        } else {
+            let next = stripe.next;
+            stripe.slots[next] = slot;
+            stripe.next = (next + 1) % self.entries_per_thread;
+        }
+    }
+}
+
+impl<K, T> fmt::Debug for MicroCache<K, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MicroCache")
+            .field("entries_per_thread", &self.entries_per_thread)
+            .field("epoch", &self.epoch())
+            .finish()
+    }
+}
@@ -0,0 +1,208 @@
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use hashbrown::hash_map::DefaultHashBuilder;
+use hashbrown::HashSet;
+
+use crate::{CHashMap, MapReadHandle, MapWriteHandle};
+
+/// Key of a [`NamespacedMap`], scoped to a namespace such as a tenant
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Namespaced<K> {
+    pub namespace: Arc<str>,
+    pub key: K,
+}
+
+impl<K> Namespaced<K> {
+    pub fn new(namespace: impl Into<Arc<str>>, key: K) -> Self {
+        Namespaced {
+            namespace: namespace.into(),
+            key,
+        }
+    }
+}
+
+/// `CHashMap` of keys scoped to namespaces, accessed through [`Namespace`] views.
+///
+/// Each namespace's keys are also tracked in a set of their own, so a namespace can be cleared
+/// without scanning every shard of the map.
+#[derive(Debug)]
+pub struct NamespacedMap<K, T, S = DefaultHashBuilder> {
+    map: CHashMap<Namespaced<K>, T, S>,
+    keys: CHashMap<Arc<str>, HashSet<K, S>, S>,
+}
+
+impl<K, T> NamespacedMap<K, T, DefaultHashBuilder> {
+    pub fn new(num_shards: usize) -> Self {
+        Self::with_hasher(num_shards, DefaultHashBuilder::default())
+    }
+}
+
+impl<K, T> Default for NamespacedMap<K, T, DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new(crate::default_num_shards())
+    }
+}
+
+impl<K, T, S> NamespacedMap<K, T, S>
+where
+    S: Clone,
+{
+    pub fn with_hasher(num_shards: usize, hash_builder: S) -> Self {
+        NamespacedMap {
+            map: CHashMap::with_hasher(num_shards, hash_builder.clone()),
+            keys: CHashMap::with_hasher(num_shards, hash_builder),
+        }
+    }
+}
+
+impl<K, T, S> NamespacedMap<K, T, S>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher + Clone,
+{
+    /// View of the entries in the given namespace
+    pub fn namespace(&self, namespace: impl Into<Arc<str>>) -> Namespace<'_, K, T, S> {
+        Namespace {
+            map: self,
+            namespace: namespace.into(),
+        }
+    }
+
+    /// Removes every entry of a namespace, see [`Namespace::clear`]
+    pub async fn clear_namespace(&self, namespace: &str) {
+        self.namespace(namespace).clear().await
+    }
+
+    /// Number of entries across all namespaces
+    pub fn size(&self) -> usize {
+        self.map.size()
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.map.num_shards()
+    }
+}
+
+/// View of a single namespace of a [`NamespacedMap`].
+///
+/// Writes to a namespace are serialized on its key set, so its entries and key set always agree.
+/// Reads only access the map itself.
+pub struct Namespace<'a, K, T, S = DefaultHashBuilder> {
+    map: &'a NamespacedMap<K, T, S>,
+    namespace: Arc<str>,
+}
+
+impl<K, T, S> Clone for Namespace<'_, K, T, S> {
+    fn clone(&self) -> Self {
+        Namespace {
+            map: self.map,
+            namespace: self.namespace.clone(),
+        }
+    }
+}
+
+impl<'a, K, T, S> Namespace<'a, K, T, S>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher + Clone,
+{
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.namespace
+    }
+
+    #[inline]
+    fn scoped(&self, key: &K) -> Namespaced<K> {
+        Namespaced {
+            namespace: self.namespace.clone(),
+            key: key.clone(),
+        }
+    }
+
+    async fn key_set(&self) -> MapWriteHandle<Arc<str>, HashSet<K, S>, S> {
+        let hash_builder = self.map.map.hash_builder().clone();
+
+        self.map
+            .keys
+            .get_mut_or_insert(&self.namespace, move || HashSet::with_hasher(hash_builder))
+            .await
+    }
+
+    pub async fn get(&self, key: &K) -> Option<MapReadHandle<Namespaced<K>, T, S>> {
+        self.map.map.get(&self.scoped(key)).await
+    }
+
+    pub async fn get_mut(&self, key: &K) -> Option<MapWriteHandle<Namespaced<K>, T, S>> {
+        self.map.map.get_mut(&self.scoped(key)).await
+    }
+
+    pub async fn get_cloned(&self, key: &K) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.map.map.get_cloned(&self.scoped(key)).await
+    }
+
+    pub async fn contains(&self, key: &K) -> bool {
+        self.map.map.contains(&self.scoped(key)).await
+    }
+
+    pub async fn insert(&self, key: K, value: T) -> Option<T> {
+        let mut keys = self.key_set().await;
+
+        let previous = self.map.map.insert(self.scoped(&key), value).await;
+
+        if previous.is_none() {
+            keys.insert(key);
+        }
+
+        previous
+    }
+
+    pub async fn remove(&self, key: &K) -> Option<T> {
+        // a namespace without a key set has no entries
+        let mut keys = self.map.keys.get_mut(&self.namespace).await?;
+
+        let value = self.map.map.remove(&self.scoped(key)).await;
+
+        if value.is_some() {
+            keys.remove(key);
+        }
+
+        value
+    }
+
+    /// Number of entries in this namespace
+    pub async fn len(&self) -> usize {
+        match self.map.keys.get(&self.namespace).await {
+            Some(keys) => keys.len(),
+            None => 0,
+        }
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Removes every entry of this namespace, by its key set rather than by scanning the whole map
+    pub async fn clear(&self) {
+        let mut key_set = match self.map.keys.get_mut(&self.namespace).await {
+            Some(key_set) => key_set,
+            None => return,
+        };
+
+        // replace rather than drain the set, to release its memory
+        let hash_builder = key_set.hasher().clone();
+        let keys = std::mem::replace(&mut *key_set, HashSet::with_hasher(hash_builder));
+
+        for key in keys {
+            let key = Namespaced {
+                namespace: self.namespace.clone(),
+                key,
+            };
+
+            self.map.map.remove(&key).await;
+        }
+    }
+}
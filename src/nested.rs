@@ -0,0 +1,159 @@
+use std::hash::{BuildHasher, Hash};
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::sync::{AtomicUsize, Ordering};
+use crate::{CHashMap, MapReadHandle, MapWriteHandle};
+
+/// `CHashMap` of `CHashMap`s, e.g. `user -> (session -> state)`, which drops inner maps once they are emptied.
+///
+/// Composing `CHashMap<K1, CHashMap<K2, V>>` by hand makes it easy to insert into an inner map right after
+/// another task removed it for being empty, losing the insert. Here, inner maps are only written to under a read
+/// lock of their outer shard, and only removed under its write lock once empty, so that can't happen.
+///
+/// Locks are always taken outer shard first, then inner shard. Handles returned by `get`/`get_mut` only hold
+/// the inner shard, but like any handle, must be dropped before calling other methods that may need it.
+///
+/// ```
+/// # use quick_hash_cache::NestedMap;
+/// # #[tokio::main] async fn main() {
+/// let sessions = NestedMap::new(4, 1);
+/// sessions.insert("ferris", 1, "active").await;
+///
+/// assert_eq!(sessions.get_cloned(&"ferris", &1).await, Some("active"));
+///
+/// sessions.remove(&"ferris", &1).await;
+/// assert_eq!(sessions.outer_len(), 0);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct NestedMap<K1, K2, V, S = DefaultHashBuilder> {
+    outer: CHashMap<K1, CHashMap<K2, V, S>, S>,
+    inner_shards: usize,
+    size: AtomicUsize,
+}
+
+impl<K1, K2, V> NestedMap<K1, K2, V, DefaultHashBuilder> {
+    pub fn new(outer_shards: usize, inner_shards: usize) -> Self {
+        Self::with_hasher(outer_shards, inner_shards, DefaultHashBuilder::default())
+    }
+}
+
+impl<K1, K2, V> Default for NestedMap<K1, K2, V, DefaultHashBuilder> {
+    /// Inner maps are usually small, so they get a single shard
+    fn default() -> Self {
+        Self::new(crate::default_num_shards(), 1)
+    }
+}
+
+impl<K1, K2, V, S> NestedMap<K1, K2, V, S>
+where
+    S: Clone,
+{
+    pub fn with_hasher(outer_shards: usize, inner_shards: usize, hash_builder: S) -> Self {
+        NestedMap {
+            outer: CHashMap::with_hasher(outer_shards, hash_builder),
+            inner_shards,
+            size: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<K1, K2, V, S> NestedMap<K1, K2, V, S>
+where
+    K1: Hash + Eq + Clone,
+    K2: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    /// Number of entries across all inner maps
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Number of inner maps
+    pub fn outer_len(&self) -> usize {
+        self.outer.size()
+    }
+
+    /// Number of entries in the inner map of `k1`
+    pub async fn inner_len(&self, k1: &K1) -> usize {
+        match self.outer.get(k1).await {
+            Some(inner) => inner.size(),
+            None => 0,
+        }
+    }
+
+    pub async fn get(&self, k1: &K1, k2: &K2) -> Option<MapReadHandle<K2, V, S>> {
+        let inner = self.outer.get(k1).await?;
+        inner.get(k2).await
+    }
+
+    pub async fn get_mut(&self, k1: &K1, k2: &K2) -> Option<MapWriteHandle<K2, V, S>> {
+        let inner = self.outer.get(k1).await?;
+        inner.get_mut(k2).await
+    }
+
+    pub async fn get_cloned(&self, k1: &K1, k2: &K2) -> Option<V>
+    where
+        V: Clone,
+    {
+        let inner = self.outer.get(k1).await?;
+        inner.get_cloned(k2).await
+    }
+
+    pub async fn contains(&self, k1: &K1, k2: &K2) -> bool {
+        match self.outer.get(k1).await {
+            Some(inner) => inner.contains(k2).await,
+            None => false,
+        }
+    }
+
+    pub async fn insert(&self, k1: K1, k2: K2, value: V) -> Option<V> {
+        let inner = match self.outer.get(&k1).await {
+            Some(inner) => inner,
+            None => {
+                let (inner_shards, hash_builder) = (self.inner_shards, self.outer.hash_builder().clone());
+
+                self.outer
+                    .get_or_insert(&k1, move || CHashMap::with_hasher(inner_shards, hash_builder))
+                    .await
+            }
+        };
+
+        // the outer read lock is held until the value is in, so the inner map can't be dropped meanwhile
+        let previous = inner.insert(k2, value).await;
+
+        if previous.is_none() {
+            self.size.fetch_add(1, Ordering::SeqCst);
+        }
+
+        previous
+    }
+
+    pub async fn remove(&self, k1: &K1, k2: &K2) -> Option<V> {
+        let inner = self.outer.get(k1).await?;
+        let value = inner.remove(k2).await?;
+
+        self.size.fetch_sub(1, Ordering::SeqCst);
+
+        if inner.size() == 0 {
+            drop(inner);
+            self.remove_if_empty(k1).await;
+        }
+
+        Some(value)
+    }
+
+    /// Removes the whole inner map of `k1`
+    pub async fn remove_outer(&self, k1: &K1) -> Option<CHashMap<K2, V, S>> {
+        let inner = self.outer.remove(k1).await?;
+        self.size.fetch_sub(inner.size(), Ordering::SeqCst);
+        Some(inner)
+    }
+
+    /// Drops the inner map of `k1` if it is empty. Inserts need the outer read lock, which the
+    /// outer write lock taken here excludes, so the map can't be refilled between the check and the removal.
+    async fn remove_if_empty(&self, k1: &K1) {
+        self.outer.remove_if(k1, |inner| inner.size() == 0).await;
+    }
+}
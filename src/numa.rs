@@ -0,0 +1,86 @@
+//! NUMA-aware placement of shards, see [`Builder::numa`](crate::Builder::numa).
+//!
+//! Shards are split into contiguous groups, one per NUMA node, and each shard's table is allocated by a thread
+//! running on its node, so that the operating system's first-touch policy backs it with node-local memory.
+//! Maintenance such as [`CHashMap::retain_local`](crate::CHashMap::retain_local) can then be run by one task
+//! per node, each only touching the memory of its own node.
+//!
+//! The crate does not depend on a NUMA library: the topology, and how to run code on a node, are provided by the
+//! embedder through [`NumaTopology`].
+//!
+//! ```
+//! # use quick_hash_cache::{numa::NumaTopology, Builder, CHashMap};
+//! /// Two nodes, with the calling thread always on the first one
+//! struct TwoNodes;
+//!
+//! impl NumaTopology for TwoNodes {
+//!     fn num_nodes(&self) -> usize {
+//!         2
+//!     }
+//!
+//!     fn current_node(&self) -> usize {
+//!         0
+//!     }
+//! }
+//!
+//! let map: CHashMap<u32, u32> = Builder::new().num_shards(8).numa(TwoNodes, 1024).build_map();
+//!
+//! assert_eq!(map.local_shards(), 0..4);
+//! assert_eq!(map.shard_node(5), Some(1));
+//! ```
+
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// NUMA topology of the machine, and how to run code on one of its nodes
+pub trait NumaTopology: Send + Sync {
+    /// Number of NUMA nodes, at least 1
+    fn num_nodes(&self) -> usize;
+
+    /// Node of the CPU the calling thread currently runs on
+    fn current_node(&self) -> usize;
+
+    /// Runs `f` on the calling thread while it is bound to `node`, so that memory `f` first touches is allocated there,
+    /// e.g. by setting the thread's CPU affinity to the node's CPUs and restoring it afterwards.
+    ///
+    /// The default implementation runs `f` as-is, leaving placement to the operating system.
+    fn run_on_node(&self, node: usize, f: &mut dyn FnMut()) {
+        let _ = node;
+        f()
+    }
+}
+
+/// Assignment of a map's shards to NUMA nodes
+#[derive(Clone)]
+pub(crate) struct NumaPlacement {
+    pub(crate) topology: Arc<dyn NumaTopology>,
+}
+
+impl NumaPlacement {
+    #[inline]
+    fn num_nodes(&self) -> usize {
+        self.topology.num_nodes().max(1)
+    }
+
+    /// Node of the shard, with shards split into contiguous groups of (nearly) equal size
+    pub(crate) fn node_of_shard(&self, shard_idx: usize, num_shards: usize) -> usize {
+        shard_idx * self.num_nodes() / num_shards
+    }
+
+    /// Shards of the node, the inverse of `node_of_shard`
+    pub(crate) fn shards_of_node(&self, node: usize, num_shards: usize) -> Range<usize> {
+        let num_nodes = self.num_nodes();
+        let first_shard = |node: usize| (node.min(num_nodes) * num_shards).div_ceil(num_nodes);
+
+        first_shard(node)..first_shard(node + 1)
+    }
+}
+
+impl fmt::Debug for NumaPlacement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NumaPlacement")
+            .field("num_nodes", &self.num_nodes())
+            .finish()
+    }
+}
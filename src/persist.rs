@@ -0,0 +1,212 @@
+//! Shard-parallel snapshots of a `CHashMap`, see `CHashMap::serialize_shards`.
+//!
+//! A snapshot starts with a header of the magic bytes `QHCS`, the format version (`u32`), the id of the hasher
+//! of the map it was taken of (`u64`, see `hasher_id`) and its number of shards (`u64`). Each shard follows,
+//! as its length (`u64`), the CRC-32 of its bytes (`u32`), then the bytes, encoded by the caller, e.g. with
+//! any serde format. Integers are little-endian.
+
+use std::any::type_name;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::sync::Mutex;
+use std::thread;
+
+const MAGIC: [u8; 4] = *b"QHCS";
+
+/// Version of the snapshot format, bumped on any change to its layout
+pub(crate) const VERSION: u32 = 1;
+
+/// Error of `CHashMap::deserialize_shards`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError<E> {
+    /// The bytes don't start with the snapshot magic, so they are not a snapshot at all
+    NotASnapshot,
+    /// The snapshot is of a format version this build can't read
+    UnsupportedVersion(u32),
+    /// The snapshot was taken of a map with another type of hasher
+    HasherMismatch,
+    /// The snapshot is cut short, or its framing is otherwise invalid
+    Malformed,
+    /// The bytes of the shard at `shard_idx` of the snapshot don't match their checksum
+    Corrupted { shard_idx: usize },
+    /// Deserializing the shard at `shard_idx` of the snapshot failed
+    Shard { shard_idx: usize, error: E },
+}
+
+impl<E: fmt::Display> fmt::Display for SnapshotError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => f.write_str("not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::HasherMismatch => f.write_str("snapshot was taken with another type of hasher"),
+            SnapshotError::Malformed => f.write_str("snapshot is truncated or malformed"),
+            SnapshotError::Corrupted { shard_idx } => write!(f, "shard {} of the snapshot is corrupted", shard_idx),
+            SnapshotError::Shard { shard_idx, error } => {
+                write!(f, "failed to deserialize shard {} of the snapshot: {}", shard_idx, error)
+            }
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for SnapshotError<E> {}
+
+/// Id of the hasher type `S`, as FNV-1a of its name.
+///
+/// Seeds are left out, as they may differ per process while entries are rehashed on restore anyway,
+/// so this only tells apart maps hashing keys in different ways, whose snapshots were likely taken for another map.
+pub(crate) fn hasher_id<S>() -> u64 {
+    type_name::<S>()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// CRC-32 (IEEE) lookup table, by the low byte of the running checksum
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut idx = 0;
+
+    while idx < 256 {
+        let mut crc = idx as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+
+        table[idx] = crc;
+        idx += 1;
+    }
+
+    table
+};
+
+/// CRC-32 (IEEE) of the bytes
+pub(crate) fn checksum(bytes: &[u8]) -> u32 {
+    !bytes
+        .iter()
+        .fold(!0, |crc: u32, &byte| CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Shard of a snapshot, whose checksum is only verified along with deserializing it, in parallel
+pub(crate) struct Frame<'a> {
+    pub(crate) checksum: u32,
+    pub(crate) bytes: &'a [u8],
+}
+
+/// Concatenates the shards' bytes, each along with their checksum, into a snapshot
+pub(crate) fn stitch(hasher_id: u64, shards: Vec<(u32, Vec<u8>)>) -> Vec<u8> {
+    let len = 24 + shards.iter().map(|(_, shard)| 12 + shard.len()).sum::<usize>();
+    let mut snapshot = Vec::with_capacity(len);
+
+    snapshot.extend_from_slice(&MAGIC);
+    snapshot.extend_from_slice(&VERSION.to_le_bytes());
+    snapshot.extend_from_slice(&hasher_id.to_le_bytes());
+    snapshot.extend_from_slice(&(shards.len() as u64).to_le_bytes());
+
+    for (checksum, shard) in shards {
+        snapshot.extend_from_slice(&(shard.len() as u64).to_le_bytes());
+        snapshot.extend_from_slice(&checksum.to_le_bytes());
+        snapshot.extend_from_slice(&shard);
+    }
+
+    snapshot
+}
+
+/// Checks the snapshot's header and splits it into its shards
+pub(crate) fn split<E>(mut snapshot: &[u8], hasher_id: u64) -> Result<Vec<Frame<'_>>, SnapshotError<E>> {
+    fn take<'a, E>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], SnapshotError<E>> {
+        if bytes.len() < len {
+            return Err(SnapshotError::Malformed);
+        }
+
+        let (head, tail) = bytes.split_at(len);
+        *bytes = tail;
+        Ok(head)
+    }
+
+    fn take_array<E, const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], SnapshotError<E>> {
+        take(bytes, N)?.try_into().map_err(|_| SnapshotError::Malformed)
+    }
+
+    fn take_len<E>(bytes: &mut &[u8]) -> Result<usize, SnapshotError<E>> {
+        usize::try_from(u64::from_le_bytes(take_array(bytes)?)).map_err(|_| SnapshotError::Malformed)
+    }
+
+    if take_array::<E, 4>(&mut snapshot).ok() != Some(MAGIC) {
+        return Err(SnapshotError::NotASnapshot);
+    }
+
+    match u32::from_le_bytes(take_array(&mut snapshot)?) {
+        VERSION => {}
+        version => return Err(SnapshotError::UnsupportedVersion(version)),
+    }
+
+    if u64::from_le_bytes(take_array(&mut snapshot)?) != hasher_id {
+        return Err(SnapshotError::HasherMismatch);
+    }
+
+    let num_shards = take_len(&mut snapshot)?;
+
+    // every shard takes at least its prefix, so a bogus count can't make us allocate more than the input
+    let mut shards = Vec::with_capacity(num_shards.min(snapshot.len() / 12));
+
+    for _ in 0..num_shards {
+        let len = take_len(&mut snapshot)?;
+        let checksum = u32::from_le_bytes(take_array(&mut snapshot)?);
+
+        shards.push(Frame {
+            checksum,
+            bytes: take(&mut snapshot, len)?,
+        });
+    }
+
+    // trailing bytes are as much a sign of corruption as missing ones
+    if !snapshot.is_empty() {
+        return Err(SnapshotError::Malformed);
+    }
+
+    Ok(shards)
+}
+
+/// Runs `f` on each job across a thread per available CPU, at most one per job, returning the results in order
+pub(crate) fn run_parallel<J, R, F>(jobs: Vec<J>, f: F) -> Vec<R>
+where
+    J: Send,
+    R: Send,
+    F: Fn(J) -> R + Sync,
+{
+    let num_jobs = jobs.len();
+    let num_threads = thread::available_parallelism().map_or(1, |n| n.get()).min(num_jobs);
+
+    if num_threads <= 1 {
+        return jobs.into_iter().map(f).collect();
+    }
+
+    let jobs = Mutex::new(jobs.into_iter().enumerate());
+    let next_job = || jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).next();
+
+    let mut results: Vec<(usize, R)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..num_threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+
+                    while let Some((job_idx, job)) = next_job() {
+                        results.push((job_idx, f(job)));
+                    }
+
+                    results
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            .collect()
+    });
+
+    results.sort_unstable_by_key(|(job_idx, _)| *job_idx);
+    results.into_iter().map(|(_, result)| result).collect()
+}
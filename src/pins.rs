@@ -0,0 +1,77 @@
+//! Keys pinned to a shard other than the one their hash selects, see `CHashMap::insert_pinned_to_shard`.
+
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use hashbrown::HashMap;
+
+use crate::sync::{AtomicUsize, Ordering};
+
+/// Shard of each pinned key, by the key's hash, as lookups only have the hash of the borrowed key at hand
+#[derive(Debug)]
+pub(crate) struct ShardPins {
+    by_hash: RwLock<HashMap<u64, usize>>,
+    /// Number of pinned hashes, so lookups of maps without pins skip the table
+    num_pinned: AtomicUsize,
+}
+
+impl ShardPins {
+    pub(crate) fn new() -> Self {
+        ShardPins {
+            by_hash: RwLock::new(HashMap::new()),
+            num_pinned: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<u64, usize>> {
+        self.by_hash.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    #[inline]
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<u64, usize>> {
+        self.by_hash.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Shard the hash is pinned to, if any
+    #[inline]
+    pub(crate) fn get(&self, hash: u64) -> Option<usize> {
+        if self.num_pinned.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+
+        self.read().get(&hash).copied()
+    }
+
+    pub(crate) fn pin(&self, hash: u64, shard_idx: usize) {
+        let mut by_hash = self.write();
+
+        if by_hash.insert(hash, shard_idx).is_none() {
+            self.num_pinned.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    pub(crate) fn unpin(&self, hash: u64) -> Option<usize> {
+        let mut by_hash = self.write();
+        let shard_idx = by_hash.remove(&hash)?;
+
+        self.num_pinned.fetch_sub(1, Ordering::Release);
+        Some(shard_idx)
+    }
+
+    /// Unpins the hashes pinned to shards past `num_shards`, when shrinking the map
+    pub(crate) fn retain_below(&mut self, num_shards: usize) {
+        let by_hash = self.by_hash.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        by_hash.retain(|_, shard_idx| *shard_idx < num_shards);
+        self.num_pinned = AtomicUsize::new(by_hash.len());
+    }
+
+    pub(crate) fn duplicate(&self) -> Self {
+        let by_hash = self.read().clone();
+
+        ShardPins {
+            num_pinned: AtomicUsize::new(by_hash.len()),
+            by_hash: RwLock::new(by_hash),
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//! Detection of a task locking a shard that one of its own handles already holds, which would otherwise hang
+//! forever, enabled by the `debug-guard-tracking` feature.
+//!
+//! Tokio's task IDs are unstable, so tasks are identified by their waker instead: every owned guard of a shard lock
+//! is acquired through [`RwLock`], which registers the shard and the acquiring task's waker until the guard, or the
+//! handle made from it, is dropped. When a lock is unavailable, the waiting task checks whether it is among the
+//! holders of that shard.
+//!
+//! That alone does not prove a deadlock: branches of `join!`/`select!` share their task's waker, and handles can be
+//! moved to other tasks, so the guard may still be released. The task is only reported once it waited
+//! `guard::reentrant_wait` for the lock with the guard still held.
+
+use std::future::{poll_fn, Future};
+use std::ops::{Deref, DerefMut};
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Once};
+use std::task::{Poll, Waker};
+use std::thread;
+use std::time::Instant;
+
+use tokio::sync::{RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+use crate::guard;
+
+struct Holder {
+    id: u64,
+    lock: usize,
+    /// Task that acquired the lock, unknown for guards acquired outside of a task's poll, e.g. by `try_get`
+    task: Option<Waker>,
+}
+
+/// Number of stripes the live guards are split into by shard lock, so that guards of different shards
+/// rarely contend on registering, and each stripe only has the guards of a few shards to scan
+const STRIPES: usize = 64;
+
+/// All live owned guards, along with the shard lock and task they belong to, in the stripe of their shard lock
+static HOLDERS: [Mutex<Vec<Holder>>; STRIPES] = [const { Mutex::new(Vec::new()) }; STRIPES];
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Live guards in the stripe of the shard lock
+#[inline]
+fn holders(lock: usize) -> MutexGuard<'static, Vec<Holder>> {
+    // shard locks are allocated separately, so their addresses differ above the allocator's alignment
+    HOLDERS[(lock >> 4) % STRIPES].lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Registration of a guard as holding its shard, removed when dropped
+pub(crate) struct HeldShard {
+    id: u64,
+    lock: usize,
+}
+
+impl HeldShard {
+    fn register(lock: usize, task: Option<Waker>) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        holders(lock).push(Holder { id, lock, task });
+
+        HeldShard { id, lock }
+    }
+}
+
+impl Drop for HeldShard {
+    fn drop(&mut self) {
+        let mut holders = holders(self.lock);
+
+        if let Some(idx) = holders.iter().position(|holder| holder.id == self.id) {
+            holders.swap_remove(idx);
+        }
+    }
+}
+
+async fn current_task() -> Waker {
+    poll_fn(|cx| Poll::Ready(cx.waker().clone())).await
+}
+
+/// Whether a guard acquired by the task holds the shard behind `lock`
+fn held_by(lock: usize, task: &Waker) -> bool {
+    holders(lock)
+        .iter()
+        .any(|holder| holder.lock == lock && holder.task.as_ref().is_some_and(|holder| holder.will_wake(task)))
+}
+
+/// Tasks waiting on a shard that one of their own guards holds, along with when to wake them to check again
+static TIMERS: Mutex<Vec<(Instant, Waker)>> = Mutex::new(Vec::new());
+static TIMERS_CHANGED: Condvar = Condvar::new();
+
+/// Wakes the task at the deadline from the watchdog thread, as the lock won't wake it if it is deadlocked
+fn wake_at(deadline: Instant, task: Waker) {
+    static WATCHDOG: Once = Once::new();
+
+    WATCHDOG.call_once(|| {
+        thread::Builder::new()
+            .name("quick-hash-cache-reentrancy-watchdog".into())
+            .spawn(watchdog)
+            .expect("failed to spawn reentrancy watchdog thread");
+    });
+
+    TIMERS.lock().unwrap_or_else(|e| e.into_inner()).push((deadline, task));
+    TIMERS_CHANGED.notify_one();
+}
+
+fn watchdog() {
+    let mut timers = TIMERS.lock().unwrap_or_else(|e| e.into_inner());
+
+    loop {
+        let now = Instant::now();
+
+        timers.retain(|(deadline, task)| match *deadline <= now {
+            true => {
+                task.wake_by_ref();
+                false
+            }
+            false => true,
+        });
+
+        timers = match timers.iter().map(|&(deadline, _)| deadline).min() {
+            Some(next) => {
+                let (timers, _) = TIMERS_CHANGED
+                    .wait_timeout(timers, next - now)
+                    .unwrap_or_else(|e| e.into_inner());
+                timers
+            }
+            None => TIMERS_CHANGED.wait(timers).unwrap_or_else(|e| e.into_inner()),
+        };
+    }
+}
+
+/// Waits for the lock behind `lock` to be acquired, reporting the current task if it held a guard of its shard
+/// throughout the last `guard::reentrant_wait` of the wait, as the task is then most likely waiting on itself
+async fn wait_for<G>(lock: usize, acquire: impl Future<Output = G>) -> G {
+    let mut acquire = pin!(acquire);
+    let mut deadline = None;
+
+    poll_fn(|cx| {
+        if let Poll::Ready(guard) = acquire.as_mut().poll(cx) {
+            return Poll::Ready(guard);
+        }
+
+        match (deadline, held_by(lock, cx.waker())) {
+            (Some(at), true) if Instant::now() >= at => {
+                guard::report_violation(format!(
+                    "this task waited {:?} to lock a shard that it holds a `ReadHandle`/`WriteHandle` into, \
+                     and is most likely deadlocked. Drop the handle (from `get`, `get_mut`, `peek`, ...) before \
+                     calling other methods that may access the same shard",
+                    guard::reentrant_wait()
+                ));
+
+                // reported once per wait
+                deadline = Some(Instant::now() + guard::reentrant_wait() * 1_000_000);
+            }
+            (Some(_), true) => {}
+            (None, true) => {
+                let at = Instant::now() + guard::reentrant_wait();
+                deadline = Some(at);
+                wake_at(at, cx.waker().clone());
+            }
+            (_, false) => deadline = None,
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+/// Owned guard of a shard lock, registered as held by the task that acquired it until dropped
+pub(crate) struct Owned<G> {
+    guard: G,
+    held: HeldShard,
+}
+
+pub(crate) type OwnedRwLockReadGuard<T, U = T> = Owned<tokio::sync::OwnedRwLockReadGuard<T, U>>;
+pub(crate) type OwnedRwLockWriteGuard<T> = Owned<tokio::sync::OwnedRwLockWriteGuard<T>>;
+pub(crate) type OwnedRwLockMappedWriteGuard<T, U = T> = Owned<tokio::sync::OwnedRwLockMappedWriteGuard<T, U>>;
+
+impl<G> Owned<G> {
+    /// The tokio guard, along with its registration, for a handle to keep
+    pub(crate) fn into_parts(this: Self) -> (G, HeldShard) {
+        (this.guard, this.held)
+    }
+
+    #[inline]
+    fn with<H>(this: Self, f: impl FnOnce(G) -> H) -> Owned<H> {
+        Owned {
+            guard: f(this.guard),
+            held: this.held,
+        }
+    }
+
+    #[inline]
+    fn try_with<H>(this: Self, f: impl FnOnce(G) -> Result<H, G>) -> Result<Owned<H>, Self> {
+        let Owned { guard, held } = this;
+
+        match f(guard) {
+            Ok(guard) => Ok(Owned { guard, held }),
+            Err(guard) => Err(Owned { guard, held }),
+        }
+    }
+}
+
+impl<T: ?Sized, U: ?Sized> OwnedRwLockReadGuard<T, U> {
+    pub(crate) fn map<F, V: ?Sized>(this: Self, f: F) -> OwnedRwLockReadGuard<T, V>
+    where
+        F: FnOnce(&U) -> &V,
+    {
+        Owned::with(this, |guard| tokio::sync::OwnedRwLockReadGuard::map(guard, f))
+    }
+
+    pub(crate) fn try_map<F, V: ?Sized>(this: Self, f: F) -> Result<OwnedRwLockReadGuard<T, V>, Self>
+    where
+        F: FnOnce(&U) -> Option<&V>,
+    {
+        Owned::try_with(this, |guard| tokio::sync::OwnedRwLockReadGuard::try_map(guard, f))
+    }
+}
+
+impl<T: ?Sized> OwnedRwLockWriteGuard<T> {
+    pub(crate) fn map<F, U: ?Sized>(this: Self, f: F) -> OwnedRwLockMappedWriteGuard<T, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        Owned::with(this, |guard| tokio::sync::OwnedRwLockWriteGuard::map(guard, f))
+    }
+
+    pub(crate) fn try_map<F, U: ?Sized>(this: Self, f: F) -> Result<OwnedRwLockMappedWriteGuard<T, U>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        Owned::try_with(this, |guard| tokio::sync::OwnedRwLockWriteGuard::try_map(guard, f))
+    }
+
+    pub(crate) fn downgrade(this: Self) -> OwnedRwLockReadGuard<T> {
+        Owned::with(this, tokio::sync::OwnedRwLockWriteGuard::downgrade)
+    }
+}
+
+impl<G: Deref> Deref for Owned<G> {
+    type Target = G::Target;
+
+    #[inline]
+    fn deref(&self) -> &G::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for Owned<G> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut G::Target {
+        &mut self.guard
+    }
+}
+
+/// tokio `RwLock` that checks for re-entrant locking by the current task while waiting on an unavailable lock
+#[derive(Debug, Default)]
+pub struct RwLock<T> {
+    // shared, as owned guards take an `Arc` of the tokio lock itself
+    inner: Arc<tokio::sync::RwLock<T>>,
+}
+
+impl<T> RwLock<T> {
+    pub(crate) fn new(value: T) -> Self {
+        RwLock {
+            inner: Arc::new(tokio::sync::RwLock::new(value)),
+        }
+    }
+
+    #[inline]
+    fn addr(&self) -> usize {
+        Arc::as_ptr(&self.inner) as usize
+    }
+
+    pub(crate) async fn read(&self) -> RwLockReadGuard<'_, T> {
+        match self.inner.try_read() {
+            Ok(guard) => guard,
+            Err(_) => wait_for(self.addr(), self.inner.read()).await,
+        }
+    }
+
+    pub(crate) async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        match self.inner.try_write() {
+            Ok(guard) => guard,
+            Err(_) => wait_for(self.addr(), self.inner.write()).await,
+        }
+    }
+
+    pub(crate) async fn read_owned(self: Arc<Self>) -> OwnedRwLockReadGuard<T> {
+        let guard = match self.inner.clone().try_read_owned() {
+            Ok(guard) => guard,
+            Err(_) => wait_for(self.addr(), self.inner.clone().read_owned()).await,
+        };
+
+        Owned {
+            guard,
+            held: HeldShard::register(self.addr(), Some(current_task().await)),
+        }
+    }
+
+    pub(crate) async fn write_owned(self: Arc<Self>) -> OwnedRwLockWriteGuard<T> {
+        let guard = match self.inner.clone().try_write_owned() {
+            Ok(guard) => guard,
+            Err(_) => wait_for(self.addr(), self.inner.clone().write_owned()).await,
+        };
+
+        Owned {
+            guard,
+            held: HeldShard::register(self.addr(), Some(current_task().await)),
+        }
+    }
+
+    /// Like `read_owned`, but fails instead of waiting, so outside of a task's poll, which the guard is then
+    /// registered without
+    pub(crate) fn try_read_owned(self: Arc<Self>) -> Result<OwnedRwLockReadGuard<T>, TryLockError> {
+        let guard = self.inner.clone().try_read_owned()?;
+
+        Ok(Owned {
+            guard,
+            held: HeldShard::register(self.addr(), None),
+        })
+    }
+
+    /// The tokio lock, for exposing shards publicly
+    pub(crate) fn inner(&self) -> &Arc<tokio::sync::RwLock<T>> {
+        &self.inner
+    }
+}
+
+impl<T> Deref for RwLock<T> {
+    type Target = tokio::sync::RwLock<T>;
+
+    #[inline]
+    fn deref(&self) -> &tokio::sync::RwLock<T> {
+        &self.inner
+    }
+}
@@ -0,0 +1,154 @@
+//! Diagnostics of how entries are spread over the shards of a map.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// Number of equal-width buckets in `LoadReport::histogram`
+const HISTOGRAM_BUCKETS: usize = 8;
+
+/// Occupancy of a single shard
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShardLoad {
+    /// Number of entries
+    pub len: usize,
+    /// Number of entries the shard's table can hold without reallocating
+    pub capacity: usize,
+    /// `len / capacity`, or 0 for a shard that never allocated
+    pub load_factor: f64,
+}
+
+/// Snapshot of how evenly entries are spread over the shards, as returned by `load_report`.
+///
+/// The shards are locked one at a time, so under concurrent writes the snapshot is not atomic.
+/// Its `Display` implementation prints a human-readable summary.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadReport {
+    /// Load of each shard, by shard index
+    pub shards: Vec<ShardLoad>,
+    /// Number of shards by length, in equal-width ranges from the emptiest to the fullest shard
+    pub histogram: Vec<(RangeInclusive<usize>, usize)>,
+    /// Index of the fullest shard
+    pub fullest_shard: usize,
+    /// Length of the fullest shard relative to the mean, 1.0 being perfectly even.
+    ///
+    /// With random hashes this stays close to 1.0 once there are many more entries than shards,
+    /// so a large skew hints at a poorly-distributing hasher or adversarial keys.
+    pub max_skew: f64,
+}
+
+impl LoadReport {
+    pub(crate) fn new(shards: impl IntoIterator<Item = (usize, usize)>) -> Self {
+        let shards: Vec<ShardLoad> = shards
+            .into_iter()
+            .map(|(len, capacity)| ShardLoad {
+                len,
+                capacity,
+                load_factor: if capacity == 0 { 0.0 } else { len as f64 / capacity as f64 },
+            })
+            .collect();
+
+        let total: usize = shards.iter().map(|shard| shard.len).sum();
+        let min = shards.iter().map(|shard| shard.len).min().unwrap_or(0);
+
+        let (fullest_shard, max) = shards
+            .iter()
+            .enumerate()
+            .map(|(idx, shard)| (idx, shard.len))
+            .max_by_key(|&(_, len)| len)
+            .unwrap_or((0, 0));
+
+        let max_skew = match total {
+            0 => 1.0,
+            _ => max as f64 * shards.len() as f64 / total as f64,
+        };
+
+        let width = ((max - min) / HISTOGRAM_BUCKETS + 1).max(1);
+        let mut histogram: Vec<_> = (0..HISTOGRAM_BUCKETS)
+            .map(|bucket| min + bucket * width)
+            .take_while(|&start| start <= max)
+            .map(|start| (start..=start + width - 1, 0))
+            .collect();
+
+        for shard in &shards {
+            histogram[(shard.len - min) / width].1 += 1;
+        }
+
+        LoadReport {
+            shards,
+            histogram,
+            fullest_shard,
+            max_skew,
+        }
+    }
+
+    /// Total number of entries over all shards
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} entries in {} shards, fullest shard #{} at {:.2}x the mean",
+            self.len(),
+            self.shards.len(),
+            self.fullest_shard,
+            self.max_skew
+        )?;
+
+        for (range, count) in &self.histogram {
+            writeln!(f, "{:>8}..={:<8} {}", range.start(), range.end(), "#".repeat(*count))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Shard whose share of the entries deviates the most from the share it was pre-sized for,
+/// as returned by `CHashMap::rebalance_hint`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RebalanceHint {
+    pub shard: usize,
+    /// Share of the entries the shard was pre-sized for, between 0 and 1
+    pub expected_share: f64,
+    /// Share of the entries the shard holds, between 0 and 1
+    pub measured_share: f64,
+}
+
+impl RebalanceHint {
+    pub(crate) fn new(expected_shares: &[f64], report: &LoadReport) -> Option<Self> {
+        let total = report.len();
+
+        if total == 0 {
+            return None;
+        }
+
+        expected_shares
+            .iter()
+            .zip(&report.shards)
+            .enumerate()
+            .map(|(shard, (&expected_share, load))| RebalanceHint {
+                shard,
+                expected_share,
+                measured_share: load.len as f64 / total as f64,
+            })
+            .max_by(|a, b| a.deviation().total_cmp(&b.deviation()))
+    }
+
+    /// Absolute difference between the measured and expected shares
+    pub fn deviation(&self) -> f64 {
+        (self.measured_share - self.expected_share).abs()
+    }
+
+    /// Whether the shard's measured share differs from the expected one by more than `tolerance`,
+    /// relative to the expected share, e.g. 0.5 for 50%
+    pub fn exceeds(&self, tolerance: f64) -> bool {
+        self.deviation() > self.expected_share * tolerance
+    }
+}
@@ -0,0 +1,144 @@
+use core::fmt;
+
+/// Position of a `scan` through a map or cache, to resume from on the next page.
+///
+/// Within a shard, entries are paged through in the order of their hashes, which unlike their positions in the shard
+/// does not change as other entries come and go. So no lock is held between pages, yet entries present for the
+/// whole scan are returned exactly once, and entries inserted or removed during it at most once.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cursor {
+    shard: usize,
+    /// Smallest hash not yet returned from the shard
+    next_hash: u64,
+}
+
+impl Cursor {
+    /// Cursor at the start of the first shard, same as `Cursor::default()`
+    pub const START: Cursor = Cursor { shard: 0, next_hash: 0 };
+
+    /// Shard index and hash the cursor resumes from, e.g. to encode it in a URL
+    #[inline]
+    pub fn into_raw(self) -> (usize, u64) {
+        (self.shard, self.next_hash)
+    }
+
+    /// Rebuilds a cursor from `into_raw`. Any value is safe to pass, though not necessarily meaningful.
+    #[inline]
+    pub fn from_raw(shard: usize, next_hash: u64) -> Self {
+        Cursor { shard, next_hash }
+    }
+}
+
+/// Position of a `scan_positions` through an `LruCache`, to resume from on the next page.
+///
+/// Within a shard, entries are paged through in the order they are stored in, which is cheaper than `scan`'s hash
+/// order, but removing an entry moves the shard's last entry into its place. The cursor records how many times the
+/// shard's entries were removed from, its generation, so the next page can tell whether entries moved meanwhile.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PositionCursor {
+    pub(crate) shard: usize,
+    pub(crate) index: usize,
+    /// Generation of the shard when the previous page ended
+    pub(crate) generation: u64,
+}
+
+impl PositionCursor {
+    /// Cursor at the start of the first shard, same as `PositionCursor::default()`
+    pub const START: PositionCursor = PositionCursor {
+        shard: 0,
+        index: 0,
+        generation: 0,
+    };
+}
+
+/// What `scan_positions` does when resuming in a shard whose entries were removed from since the previous page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnInvalidated {
+    /// Carry on from the same position. Entries moved back into already visited positions are skipped, but no entry
+    /// is ever returned twice, as entries only ever move back.
+    Continue,
+    /// Return `Err(Invalidated)`, e.g. to restart the scan when it must not miss entries
+    Fail,
+}
+
+/// Error of `scan_positions` with `OnInvalidated::Fail`, when entries of the shard being paged through moved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Invalidated {
+    pub shard: usize,
+}
+
+impl fmt::Display for Invalidated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "entries of shard {} moved since the previous page", self.shard)
+    }
+}
+
+impl std::error::Error for Invalidated {}
+
+/// Pages through shards one at a time from the cursor, with `page` returning the entries of a locked shard with
+/// a hash of at least the given one, up to the given count, along with the smallest hash left out, if any
+pub(crate) async fn scan<E, P, Fut>(
+    num_shards: usize,
+    cursor: Cursor,
+    limit: usize,
+    mut page: P,
+) -> (Vec<E>, Option<Cursor>)
+where
+    P: FnMut(usize, u64, usize) -> Fut,
+    Fut: core::future::Future<Output = (Vec<E>, Option<u64>)>,
+{
+    let mut entries = Vec::new();
+    let Cursor { mut shard, mut next_hash } = cursor;
+
+    while shard < num_shards {
+        if entries.len() >= limit {
+            return (entries, Some(Cursor { shard, next_hash }));
+        }
+
+        let (page, left_out) = page(shard, next_hash, limit - entries.len()).await;
+        entries.extend(page);
+
+        match left_out {
+            Some(hash) => return (entries, Some(Cursor { shard, next_hash: hash })),
+            None => {
+                shard += 1;
+                next_hash = 0;
+            }
+        }
+    }
+
+    (entries, None)
+}
+
+/// Selects the (at least) `limit` entries with the smallest hashes of at least `from`, never splitting entries
+/// of equal hashes across pages, and returns the smallest hash left out, if any
+pub(crate) fn page_by_hash<'a, K, V, E>(
+    shard: impl Iterator<Item = (u64, &'a K, &'a V)>,
+    from: u64,
+    limit: usize,
+    mut clone: impl FnMut(&K, &V) -> E,
+) -> (Vec<E>, Option<u64>)
+where
+    K: 'a,
+    V: 'a,
+{
+    let mut candidates: Vec<_> = shard.filter(|&(hash, _, _)| hash >= from).collect();
+
+    if candidates.len() <= limit {
+        return (candidates.into_iter().map(|(_, key, value)| clone(key, value)).collect(), None);
+    }
+
+    if limit == 0 {
+        return (Vec::new(), Some(from));
+    }
+
+    let (_, &mut (last, _, _), _) = candidates.select_nth_unstable_by_key(limit - 1, |&(hash, _, _)| hash);
+
+    let page = candidates
+        .into_iter()
+        .filter(|&(hash, _, _)| hash <= last)
+        .map(|(_, key, value)| clone(key, value))
+        .collect();
+
+    (page, last.checked_add(1))
+}
@@ -0,0 +1,201 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hint::spin_loop;
+use core::mem::MaybeUninit;
+use core::ptr;
+
+use crate::sync::{fence, AtomicU64, Ordering};
+
+/// Value that can be read and replaced through a shared reference, as a sequence lock.
+///
+/// Meant as the value type of maps holding small `Copy` values, such as counters, so they can be read and
+/// updated with only the shard's read lock, see `CHashMap::get_copy` and `CHashMap::update_copy`.
+/// Readers never block writers: they copy the value optimistically and retry if it was written meanwhile,
+/// while writers are serialized among themselves by the sequence number.
+///
+/// Every write bumps the value's version, see `load_versioned` and `store_if_version`.
+///
+/// Optimistic reads copy the value while a writer may be replacing it, like crossbeam's `AtomicCell` does for
+/// types without a native atomic of their size. The copy is a volatile read into a `MaybeUninit<T>`, so the
+/// compiler neither elides nor repeats it, and assumes nothing of the copied bytes, which are only taken as a `T`
+/// once the unchanged sequence number proves no write overlapped the copy: a torn copy is discarded without ever
+/// being read as a `T`. Rust's memory model still formally calls the overlapping read a data race, so like
+/// crossbeam, this relies on volatile accesses behaving as the hardware's loads. Copying word by word through
+/// atomics instead would not be sound either, as it would read the padding bytes of `T`, which may be uninitialized.
+pub struct SeqLock<T> {
+    /// Odd while a write is in progress, and twice the version otherwise
+    seq: AtomicU64,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: values are only ever copied out or replaced whole, with writes serialized by `seq`
+unsafe impl<T: Send> Send for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T> SeqLock<T> {
+    pub fn new(value: T) -> Self {
+        SeqLock {
+            seq: AtomicU64::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// Mutable access, which needs no synchronization. Does not bump the version.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: Copy> SeqLock<T> {
+    #[inline]
+    pub fn load(&self) -> T {
+        self.load_versioned().1
+    }
+
+    /// Copies the value, along with its version
+    pub fn load_versioned(&self) -> (u64, T) {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+
+            if seq & 1 == 0 {
+                // SAFETY: the copy may race with a writer, so it is kept uninitialized until the unchanged
+                // sequence number proves it was not torn, see the type's docs
+                let value = unsafe { ptr::read_volatile(self.value.get() as *const MaybeUninit<T>) };
+
+                fence(Ordering::Acquire);
+
+                if self.seq.load(Ordering::Relaxed) == seq {
+                    return (seq >> 1, unsafe { value.assume_init() });
+                }
+            }
+
+            spin_loop();
+        }
+    }
+
+    #[inline]
+    pub fn version(&self) -> u64 {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+
+            if seq & 1 == 0 {
+                return seq >> 1;
+            }
+
+            spin_loop();
+        }
+    }
+
+    /// Replaces the value, returning the previous one
+    pub fn store(&self, value: T) -> T {
+        self.update(|_| value).0
+    }
+
+    /// Replaces the value with `f` of it, returning the previous and new values.
+    ///
+    /// `f` runs while other writers spin, so it should be cheap.
+    pub fn update(&self, f: impl FnOnce(T) -> T) -> (T, T) {
+        let seq = self.lock();
+        let _unlock = Unlock(&self.seq, seq + 2);
+
+        // SAFETY: the odd sequence number excludes other writers, and readers discard what they copy meanwhile
+        unsafe {
+            let previous = ptr::read_volatile(self.value.get());
+            let value = f(previous);
+            ptr::write_volatile(self.value.get(), value);
+
+            (previous, value)
+        }
+    }
+
+    /// Replaces the value only if its version is still `version`, e.g. from `load_versioned`,
+    /// otherwise returns the current version
+    pub fn store_if_version(&self, version: u64, value: T) -> Result<T, u64> {
+        let seq = version << 1;
+
+        if self
+            .seq
+            .compare_exchange(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(self.version());
+        }
+
+        fence(Ordering::Release);
+        let _unlock = Unlock(&self.seq, seq + 2);
+
+        // SAFETY: as in `update`
+        unsafe {
+            let previous = ptr::read_volatile(self.value.get());
+            ptr::write_volatile(self.value.get(), value);
+
+            Ok(previous)
+        }
+    }
+
+    /// Waits out any other writer and marks a write in progress, returning the even sequence number before it
+    fn lock(&self) -> u64 {
+        loop {
+            let seq = self.seq.load(Ordering::Relaxed);
+
+            if seq & 1 == 0
+                && self
+                    .seq
+                    .compare_exchange_weak(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                // keep the value's write from being reordered before the odd sequence number
+                fence(Ordering::Release);
+
+                return seq;
+            }
+
+            spin_loop();
+        }
+    }
+}
+
+/// Ends a write by publishing the next even sequence number, even if the update function panicked
+struct Unlock<'a>(&'a AtomicU64, u64);
+
+impl Drop for Unlock<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.store(self.1, Ordering::Release);
+    }
+}
+
+impl<T: Default> Default for SeqLock<T> {
+    fn default() -> Self {
+        SeqLock::new(T::default())
+    }
+}
+
+impl<T> From<T> for SeqLock<T> {
+    fn from(value: T) -> Self {
+        SeqLock::new(value)
+    }
+}
+
+impl<T: Copy> Clone for SeqLock<T> {
+    fn clone(&self) -> Self {
+        SeqLock::new(self.load())
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SeqLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (version, value) = self.load_versioned();
+
+        f.debug_struct("SeqLock")
+            .field("version", &version)
+            .field("value", &value)
+            .finish()
+    }
+}
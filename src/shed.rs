@@ -0,0 +1,76 @@
+//! Shedding of reads of shards whose lock is starved, see `CHashMap::with_load_shedding`.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::clock::{Clock, DefaultClock};
+use crate::sync::{AtomicU64, Ordering};
+
+/// Circuit breaker of each shard of a map: once a read waited longer than `max_wait` for the shard's lock,
+/// the breaker opens for `cooldown`, during which reads finding the lock busy are shed instead of waiting.
+/// After the cooldown, reads wait for the lock again, reopening the breaker if they wait too long as well.
+pub(crate) struct LoadShedding {
+    max_wait: u64,
+    cooldown: u64,
+    /// `DefaultClock` reading each shard's breaker stays open until
+    open_until: Box<[AtomicU64]>,
+    /// Number of reads shed so far, across shards
+    shed: AtomicU64,
+}
+
+impl LoadShedding {
+    pub(crate) fn new(num_shards: usize, max_wait: Duration, cooldown: Duration) -> Self {
+        LoadShedding {
+            max_wait: max_wait.as_nanos().min(u64::MAX as u128) as u64,
+            cooldown: cooldown.as_nanos().min(u64::MAX as u128) as u64,
+            open_until: (0..num_shards).map(|_| AtomicU64::new(0)).collect(),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    /// Closed breakers with the same settings and nothing shed, for a map with `num_shards` shards
+    pub(crate) fn renew(&self, num_shards: usize) -> Self {
+        LoadShedding {
+            max_wait: self.max_wait,
+            cooldown: self.cooldown,
+            open_until: (0..num_shards).map(|_| AtomicU64::new(0)).collect(),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_open(&self, shard_idx: usize) -> bool {
+        self.open_until[shard_idx].load(Ordering::Relaxed) > DefaultClock::now()
+    }
+
+    /// Counts a read shed from a shard with an open breaker
+    #[inline]
+    pub(crate) fn record_shed(&self) {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records how long a read waited for the shard's lock since `waiting_since`, a `DefaultClock` reading,
+    /// opening its breaker if it waited too long
+    pub(crate) fn record_wait(&self, shard_idx: usize, waiting_since: u64) {
+        let now = DefaultClock::now();
+
+        if now.saturating_sub(waiting_since) > self.max_wait {
+            self.open_until[shard_idx].store(now.saturating_add(self.cooldown), Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn num_shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for LoadShedding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoadShedding")
+            .field("max_wait", &Duration::from_nanos(self.max_wait))
+            .field("cooldown", &Duration::from_nanos(self.cooldown))
+            .field("num_shed", &self.num_shed())
+            .finish()
+    }
+}
@@ -0,0 +1,56 @@
+//! Background threads periodically sweeping a map, e.g. `ExpiringMap::spawn_purger`.
+//!
+//! The crate does not depend on a tokio runtime, so sweepers are plain threads, driving the map's async methods
+//! to completion themselves. They only hold a weak reference to the map, exiting once it is dropped.
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Weak;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::Duration;
+
+use crate::sync::Arc;
+
+/// Starts a thread running `sweep` on the map every `interval`, until the map is dropped
+pub(crate) fn spawn<M, F>(name: &str, map: &Arc<M>, interval: Duration, sweep: F) -> JoinHandle<()>
+where
+    M: Send + Sync + 'static,
+    F: Fn(&M) + Send + 'static,
+{
+    let map: Weak<M> = Arc::downgrade(map);
+
+    thread::Builder::new()
+        .name(name.into())
+        .spawn(move || loop {
+            thread::sleep(interval);
+
+            match map.upgrade() {
+                Some(map) => sweep(&map),
+                None => return,
+            }
+        })
+        .unwrap_or_else(|err| panic!("failed to spawn {} thread: {}", name, err))
+}
+
+/// Polls the future to completion on the calling thread, parking it while pending
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
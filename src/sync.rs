@@ -0,0 +1,93 @@
+//! Internal synchronization primitives.
+//!
+//! Everything in the crate that needs atomics or shard locks goes through here, so that the
+//! counters can be swapped for `loom` substitutes and model-checked with `RUSTFLAGS="--cfg loom"`.
+//!
+//! Shard locks stay on tokio's `RwLock` in both configurations, as the owned guards require `std::sync::Arc`.
+//! Its semaphore is built on atomics loom does not see, so lock handoffs are not scheduling points of the model:
+//! the loom tests only check the counters updated around the locks, not the locking itself.
+//!
+//! `LockFreeMap`'s writer mutexes are swapped as well, and its epochs are modeled by crossbeam itself when also
+//! built with `--cfg crossbeam_loom`.
+
+// not every configuration uses every primitive
+#![allow(unused_imports)]
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
+
+#[cfg(not(loom))]
+pub(crate) use core::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
+
+#[cfg(loom)]
+pub(crate) use loom::sync::Mutex;
+
+#[cfg(all(feature = "std", not(loom)))]
+pub(crate) use std::sync::Mutex;
+
+#[cfg(feature = "tokio")]
+pub(crate) use alloc::sync::Arc;
+
+#[cfg(all(feature = "tokio", not(feature = "debug-guard-tracking")))]
+pub(crate) use tokio::sync::{OwnedRwLockMappedWriteGuard, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+// `debug-guard-tracking` checks for a task re-entrantly locking a shard it holds a handle into, see `reentrancy`
+#[cfg(feature = "debug-guard-tracking")]
+pub(crate) use crate::reentrancy::{OwnedRwLockMappedWriteGuard, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+/// The underlying tokio lock, for exposing shards publicly
+#[cfg(feature = "tokio")]
+#[inline]
+pub(crate) fn tokio_lock<T>(lock: &RwLock<T>) -> &tokio::sync::RwLock<T> {
+    lock
+}
+
+/// The underlying tokio lock of a shared shard, for handing shards to other tasks
+#[cfg(feature = "tokio")]
+#[inline]
+pub(crate) fn tokio_lock_owned<T>(lock: Arc<RwLock<T>>) -> Arc<tokio::sync::RwLock<T>> {
+    #[cfg(feature = "debug-guard-tracking")]
+    return lock.inner().clone();
+
+    #[cfg(not(feature = "debug-guard-tracking"))]
+    return lock;
+}
+
+/// Bounded spinning on a shard lock before awaiting it, see `Builder::spin_before_await`
+#[cfg(feature = "tokio")]
+pub(crate) trait SpinLock {
+    /// Spins until the lock can be read-locked, at most `spins` times, so that awaiting it next likely won't wait
+    fn spin_read(&self, spins: u32) -> &Self;
+
+    /// Spins until the lock can be write-locked, at most `spins` times, so that awaiting it next likely won't wait
+    fn spin_write(&self, spins: u32) -> &Self;
+}
+
+#[cfg(feature = "tokio")]
+impl<T> SpinLock for Arc<RwLock<T>> {
+    #[inline]
+    fn spin_read(&self, spins: u32) -> &Self {
+        for _ in 0..spins {
+            if self.try_read().is_ok() {
+                break;
+            }
+
+            core::hint::spin_loop();
+        }
+
+        self
+    }
+
+    #[inline]
+    fn spin_write(&self, spins: u32) -> &Self {
+        for _ in 0..spins {
+            if self.try_write().is_ok() {
+                break;
+            }
+
+            core::hint::spin_loop();
+        }
+
+        self
+    }
+}
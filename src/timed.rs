@@ -0,0 +1,236 @@
+//! `TimedCache`, recording how long each operation on a map waits and works, for attributing latency.
+
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::pin::pin;
+use std::sync::{Mutex, MutexGuard};
+
+use hdrhistogram::Histogram;
+
+use crate::clock::{Clock, DefaultClock};
+use crate::AsyncMap;
+
+/// Significant decimal digits the histograms keep, i.e. a relative error of 0.1%
+const SIGNIFICANT_DIGITS: u8 = 3;
+
+/// Operation of [`AsyncMap`] measured by a [`TimedCache`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Get,
+    Insert,
+    Remove,
+    Contains,
+}
+
+impl Operation {
+    const ALL: [Operation; 4] = [Operation::Get, Operation::Insert, Operation::Remove, Operation::Contains];
+
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Get => "get",
+            Operation::Insert => "insert",
+            Operation::Remove => "remove",
+            Operation::Contains => "contains",
+        }
+    }
+}
+
+/// Latencies of one operation, in nanoseconds
+#[derive(Debug, Clone)]
+pub struct OpLatency {
+    /// Time spent waiting, between the operation yielding and being polled again, which for the crate's maps is
+    /// waiting on a shard lock, along with the scheduling delay once it is acquired
+    pub wait: Histogram<u64>,
+    /// Time spent running the operation itself, i.e. polling it
+    pub work: Histogram<u64>,
+    /// End-to-end latency, the sum of `wait` and `work`
+    pub total: Histogram<u64>,
+}
+
+impl OpLatency {
+    fn new() -> Self {
+        // auto-resizing histograms, which can't fail to be created
+        let histogram = || Histogram::new(SIGNIFICANT_DIGITS).expect("invalid histogram precision");
+
+        OpLatency {
+            wait: histogram(),
+            work: histogram(),
+            total: histogram(),
+        }
+    }
+
+    /// Number of operations recorded
+    pub fn count(&self) -> u64 {
+        self.total.len()
+    }
+
+    fn record(&mut self, wait: u64, work: u64) {
+        // the histograms grow to fit the values, so recording them can't fail
+        self.wait += wait;
+        self.work += work;
+        self.total += wait + work;
+    }
+}
+
+/// Latencies of every operation of a [`TimedCache`], as returned by `latency_report`.
+///
+/// Its `Display` implementation prints the median, 99th percentile and maximum of each.
+#[derive(Debug, Clone)]
+pub struct LatencyReport {
+    pub get: OpLatency,
+    pub insert: OpLatency,
+    pub remove: OpLatency,
+    pub contains: OpLatency,
+}
+
+impl LatencyReport {
+    pub fn op(&self, op: Operation) -> &OpLatency {
+        match op {
+            Operation::Get => &self.get,
+            Operation::Insert => &self.insert,
+            Operation::Remove => &self.remove,
+            Operation::Contains => &self.contains,
+        }
+    }
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn quantiles(histogram: &Histogram<u64>) -> String {
+            format!(
+                "p50 {:>9}ns p99 {:>9}ns max {:>9}ns",
+                histogram.value_at_quantile(0.5),
+                histogram.value_at_quantile(0.99),
+                histogram.max()
+            )
+        }
+
+        for op in Operation::ALL {
+            let latency = self.op(op);
+
+            if latency.count() == 0 {
+                continue;
+            }
+
+            writeln!(f, "{} ({} ops)", op.name(), latency.count())?;
+            writeln!(f, "    wait  {}", quantiles(&latency.wait))?;
+            writeln!(f, "    work  {}", quantiles(&latency.work))?;
+            writeln!(f, "    total {}", quantiles(&latency.total))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decorator of an [`AsyncMap`], such as a `CHashMap` or an `LruCache`, recording the latency of each of
+/// its operations, split into the time spent waiting and the time spent working, so latency can be attributed
+/// to lock contention or to the operations themselves without instrumenting every call site.
+///
+/// The time an operation waits is the time between it yielding and being polled again, which for the crate's maps
+/// only happens when waiting on a shard lock. Histograms are behind a mutex per operation, so recording adds
+/// a little contention of its own, which is best kept to diagnostics or sampled traffic.
+///
+/// ```
+/// # use quick_hash_cache::{AsyncMap, CHashMap, TimedCache};
+/// # #[tokio::main] async fn main() {
+/// let cache = TimedCache::new(CHashMap::<u32, u32>::new(4));
+///
+/// cache.insert(1, 1).await;
+/// cache.get_cloned(&1).await;
+///
+/// let report = cache.latency_report();
+/// assert_eq!(report.insert.count(), 1);
+/// println!("{}", report);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TimedCache<C> {
+    inner: C,
+    latencies: [Mutex<OpLatency>; 4],
+}
+
+impl<C> TimedCache<C> {
+    pub fn new(inner: C) -> Self {
+        TimedCache {
+            inner,
+            latencies: [(); 4].map(|_| Mutex::new(OpLatency::new())),
+        }
+    }
+
+    /// The map being timed, to call operations that aren't recorded
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    #[inline]
+    fn latency(&self, op: Operation) -> MutexGuard<'_, OpLatency> {
+        self.latencies[op as usize].lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Latencies recorded since the cache was created or last reset
+    pub fn latency_report(&self) -> LatencyReport {
+        LatencyReport {
+            get: self.latency(Operation::Get).clone(),
+            insert: self.latency(Operation::Insert).clone(),
+            remove: self.latency(Operation::Remove).clone(),
+            contains: self.latency(Operation::Contains).clone(),
+        }
+    }
+
+    /// Clears the latencies recorded, e.g. at the start of each reporting interval
+    pub fn reset(&self) {
+        for op in Operation::ALL {
+            *self.latency(op) = OpLatency::new();
+        }
+    }
+
+    async fn time<F: Future>(&self, op: Operation, future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut work = 0;
+        let start = DefaultClock::now();
+
+        let output = poll_fn(|cx| {
+            let poll_start = DefaultClock::now();
+            let poll = future.as_mut().poll(cx);
+            work += DefaultClock::now().saturating_sub(poll_start);
+            poll
+        })
+        .await;
+
+        let total = DefaultClock::now().saturating_sub(start);
+        self.latency(op).record(total.saturating_sub(work), work);
+
+        output
+    }
+}
+
+impl<K, V, C> AsyncMap<K, V> for TimedCache<C>
+where
+    K: Send + Sync,
+    V: Send,
+    C: AsyncMap<K, V> + Sync,
+{
+    async fn get_cloned(&self, key: &K) -> Option<V> {
+        self.time(Operation::Get, self.inner.get_cloned(key)).await
+    }
+
+    async fn insert(&self, key: K, value: V) -> Option<V> {
+        self.time(Operation::Insert, self.inner.insert(key, value)).await
+    }
+
+    async fn remove(&self, key: &K) -> Option<V> {
+        self.time(Operation::Remove, self.inner.remove(key)).await
+    }
+
+    async fn contains(&self, key: &K) -> bool {
+        self.time(Operation::Contains, self.inner.contains(key)).await
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
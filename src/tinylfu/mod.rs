@@ -0,0 +1,228 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::hash::ShardSelect;
+use crate::sync::{Arc, AtomicUsize, Ordering, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+use crate::{read_handle, write_handle, CapacityFull, ReadHandle, WriteHandle};
+
+mod shard;
+mod sketch;
+
+use shard::TinyLfuShard;
+
+/// `ReadHandle` to a value of a `WTinyLfuCache<K, V, _>`, as returned by `get` and `peek`
+pub type TinyLfuReadHandle<K, V> = ReadHandle<TinyLfuShard<K, V>, V>;
+
+/// `WriteHandle` to a value of a `WTinyLfuCache<K, V, _>`, as returned by `get_mut`
+pub type TinyLfuWriteHandle<K, V> = WriteHandle<TinyLfuShard<K, V>, V>;
+
+/// Bounded cache using the W-TinyLFU policy: new entries go through a small window LRU (1% of capacity),
+/// and only enter the segmented LRU main space (probation + 80% protected) if their estimated
+/// access frequency beats that of the main space's victim.
+///
+/// Compared to the sampled `LruCache`, this resists scans and achieves better hit rates on skewed
+/// (e.g. Zipfian) workloads, at the cost of every access taking the shard's write lock to update recency.
+///
+/// Each shard holds `capacity / num_shards` entries (rounded up) and tracks frequencies independently.
+#[derive(Debug)]
+pub struct WTinyLfuCache<K, V, S = DefaultHashBuilder> {
+    hash_builder: S,
+    shards: Vec<Arc<RwLock<TinyLfuShard<K, V>>>>,
+    size: AtomicUsize,
+    capacity: usize,
+    pub(crate) shard_select: ShardSelect,
+}
+
+impl<K, V> WTinyLfuCache<K, V, DefaultHashBuilder> {
+    pub fn new(num_shards: usize, capacity: usize) -> Self {
+        Self::with_hasher(num_shards, capacity, DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V, S> WTinyLfuCache<K, V, S> {
+    pub fn with_hasher(num_shards: usize, capacity: usize, hash_builder: S) -> Self {
+        let shard_capacity = (capacity + num_shards - 1) / num_shards.max(1);
+
+        WTinyLfuCache {
+            shards: (0..num_shards)
+                .map(|_| Arc::new(RwLock::new(TinyLfuShard::new(shard_capacity))))
+                .collect(),
+            hash_builder,
+            size: AtomicUsize::new(0),
+            capacity,
+            shard_select: ShardSelect::Modulo,
+        }
+    }
+}
+
+impl<K, V, S> WTinyLfuCache<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    #[inline]
+    pub fn hash_builder(&self) -> &S {
+        &self.hash_builder
+    }
+
+    #[inline]
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    #[inline]
+    fn hash_and_shard<Q>(&self, key: &Q) -> (u64, usize)
+    where
+        Q: ?Sized + Hash,
+    {
+        let hash = self.hash_builder.hash_one(key);
+        (hash, self.shard_select.shard_of(hash, self.shards.len()))
+    }
+
+    /// Clears all entries, along with their frequency history
+    pub async fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.write().await;
+            let len = shard.len();
+            shard.clear();
+
+            self.size.fetch_sub(len, Ordering::SeqCst);
+        }
+    }
+
+    /// Looks up a value without recording the access
+    pub async fn peek<Q>(&self, key: &Q) -> Option<TinyLfuReadHandle<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone().read_owned().await };
+
+        OwnedRwLockReadGuard::try_map(shard, |shard| shard.peek(hash, key))
+            .ok()
+            .map(read_handle)
+    }
+
+    /// Looks up a value, recording the access.
+    ///
+    /// NOTE: Unlike `LruCache::get`, this acquires the shard's write lock, as recency is tracked by reordering.
+    pub async fn get<Q>(&self, key: &Q) -> Option<TinyLfuReadHandle<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).clone().write_owned().await };
+
+        let idx = shard.access(hash, key)?;
+
+        Some(read_handle(OwnedRwLockReadGuard::map(
+            OwnedRwLockWriteGuard::downgrade(shard),
+            |shard| shard.value_at(idx),
+        )))
+    }
+
+    pub async fn get_mut<Q>(&self, key: &Q) -> Option<TinyLfuWriteHandle<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone().write_owned().await };
+
+        OwnedRwLockWriteGuard::try_map(shard, |shard| shard.get_mut(hash, key))
+            .ok()
+            .map(write_handle)
+    }
+
+    pub async fn get_cloned<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).write().await };
+
+        shard.access(hash, key).map(|idx| shard.value_at(idx).clone())
+    }
+
+    /// Checks if the key is present, without recording the access
+    pub async fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.peek(key).await.is_some()
+    }
+
+    /// Inserts a value, returning the previous value if one existed.
+    ///
+    /// If the shard is at capacity, either the new entry or an existing one will be evicted and dropped,
+    /// see `insert_full` to retrieve it.
+    pub async fn insert(&self, key: K, value: V) -> Option<V> {
+        self.insert_full(key, value).await.0
+    }
+
+    /// Inserts a value, returning the previous value if one existed and the entry evicted to make room, if any.
+    ///
+    /// The evicted entry may be the one just inserted, if the admission policy rejected it.
+    pub async fn insert_full(&self, key: K, value: V) -> (Option<V>, Option<(K, V)>) {
+        let (hash, shard_idx) = self.hash_and_shard(&key);
+        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).write().await };
+
+        let (previous, evicted) = shard.insert(hash, key, value);
+
+        if previous.is_none() && evicted.is_none() {
+            self.size.fetch_add(1, Ordering::SeqCst);
+        }
+
+        (previous, evicted)
+    }
+
+    /// Like `insert`, but if the key is new and its shard is full, gives the value back instead of
+    /// evicting an entry, for callers who would rather shed new work than drop warm entries.
+    pub async fn try_insert_within_capacity(&self, key: K, value: V) -> Result<Option<V>, CapacityFull<V>> {
+        let (hash, shard_idx) = self.hash_and_shard(&key);
+        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).write().await };
+
+        if shard.len() >= shard.capacity() && shard.peek(hash, &key).is_none() {
+            return Err(CapacityFull(value));
+        }
+
+        let (previous, evicted) = shard.insert(hash, key, value);
+
+        if previous.is_none() && evicted.is_none() {
+            self.size.fetch_add(1, Ordering::SeqCst);
+        }
+
+        Ok(previous)
+    }
+
+    pub async fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let mut shard = unsafe { self.shards.get_unchecked(shard_idx).write().await };
+
+        shard.remove(hash, key).map(|(_, value)| {
+            self.size.fetch_sub(1, Ordering::SeqCst);
+            value
+        })
+    }
+}
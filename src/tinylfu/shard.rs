@@ -0,0 +1,349 @@
+use std::{borrow::Borrow, fmt};
+
+use hashbrown::raw::RawTable;
+
+use super::sketch::FrequencySketch;
+
+const NIL: usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    /// Admission window, recently inserted entries
+    Window,
+    /// Main space, entries seen once since admission
+    Probation,
+    /// Main space, entries accessed again while on probation
+    Protected,
+}
+
+#[derive(Debug)]
+struct Node<K, V> {
+    hash: u64,
+    key: K,
+    value: V,
+    segment: Segment,
+    prev: usize,
+    next: usize,
+}
+
+/// Intrusive list through `Node::prev`/`Node::next`, most-recently-used at the head
+#[derive(Debug, Clone, Copy)]
+struct List {
+    head: usize,
+    tail: usize,
+    len: usize,
+}
+
+impl List {
+    const EMPTY: List = List {
+        head: NIL,
+        tail: NIL,
+        len: 0,
+    };
+}
+
+/// A single W-TinyLFU shard: a window LRU in front of a segmented LRU main space,
+/// with admission from the window into the main space decided by the frequency sketch.
+pub struct TinyLfuShard<K, V> {
+    indices: RawTable<usize>,
+    nodes: Vec<Option<Node<K, V>>>,
+    free: Vec<usize>,
+
+    window: List,
+    probation: List,
+    protected: List,
+
+    capacity: usize,
+    window_capacity: usize,
+    protected_capacity: usize,
+
+    sketch: FrequencySketch,
+}
+
+impl<K, V> fmt::Debug for TinyLfuShard<K, V>
+where
+    K: fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TinyLfuShard")
+            .field("capacity", &self.capacity)
+            .field("window", &self.window)
+            .field("probation", &self.probation)
+            .field("protected", &self.protected)
+            .field("nodes", &self.nodes)
+            .finish()
+    }
+}
+
+impl<K, V> TinyLfuShard<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        let window_capacity = (capacity / 100).max(1);
+
+        TinyLfuShard {
+            indices: RawTable::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            window: List::EMPTY,
+            probation: List::EMPTY,
+            protected: List::EMPTY,
+            capacity,
+            window_capacity,
+            protected_capacity: (capacity - window_capacity) * 4 / 5,
+            sketch: FrequencySketch::new(capacity),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    #[inline]
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.indices.clear();
+        self.nodes.clear();
+        self.free.clear();
+        self.window = List::EMPTY;
+        self.probation = List::EMPTY;
+        self.protected = List::EMPTY;
+        self.sketch.clear();
+    }
+
+    #[inline]
+    fn node(&self, idx: usize) -> &Node<K, V> {
+        match self.nodes[idx] {
+            Some(ref node) => node,
+            None => unreachable!("dangling node index"),
+        }
+    }
+
+    #[inline]
+    fn node_mut(&mut self, idx: usize) -> &mut Node<K, V> {
+        match self.nodes[idx] {
+            Some(ref mut node) => node,
+            None => unreachable!("dangling node index"),
+        }
+    }
+
+    #[inline]
+    fn list_mut(&mut self, segment: Segment) -> &mut List {
+        match segment {
+            Segment::Window => &mut self.window,
+            Segment::Probation => &mut self.probation,
+            Segment::Protected => &mut self.protected,
+        }
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let (segment, prev, next) = {
+            let node = self.node(idx);
+            (node.segment, node.prev, node.next)
+        };
+
+        match prev {
+            NIL => self.list_mut(segment).head = next,
+            _ => self.node_mut(prev).next = next,
+        }
+
+        match next {
+            NIL => self.list_mut(segment).tail = prev,
+            _ => self.node_mut(next).prev = prev,
+        }
+
+        self.list_mut(segment).len -= 1;
+    }
+
+    fn push_front(&mut self, segment: Segment, idx: usize) {
+        let head = self.list_mut(segment).head;
+
+        {
+            let node = self.node_mut(idx);
+            node.segment = segment;
+            node.prev = NIL;
+            node.next = head;
+        }
+
+        match head {
+            NIL => self.list_mut(segment).tail = idx,
+            _ => self.node_mut(head).prev = idx,
+        }
+
+        let list = self.list_mut(segment);
+        list.head = idx;
+        list.len += 1;
+    }
+
+    #[inline]
+    fn find<Q>(&self, hash: u64, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.indices
+            .get(hash, |&idx| self.node(idx).key.borrow() == key)
+            .copied()
+    }
+
+    /// Move an accessed entry towards the protected segment
+    fn on_access(&mut self, idx: usize) {
+        self.unlink(idx);
+
+        match self.node(idx).segment {
+            Segment::Window => self.push_front(Segment::Window, idx),
+            Segment::Probation | Segment::Protected => {
+                self.push_front(Segment::Protected, idx);
+
+                if self.protected.len > self.protected_capacity {
+                    let demoted = self.protected.tail;
+                    self.unlink(demoted);
+                    self.push_front(Segment::Probation, demoted);
+                }
+            }
+        }
+    }
+
+    fn take(&mut self, idx: usize) -> (K, V) {
+        self.unlink(idx);
+
+        let node = match self.nodes[idx].take() {
+            Some(node) => node,
+            None => unreachable!("dangling node index"),
+        };
+
+        self.indices.erase_entry(node.hash, |&i| i == idx);
+        self.free.push(idx);
+
+        (node.key, node.value)
+    }
+
+    /// Value of an entry by its index, as returned by `access`
+    #[inline]
+    pub(crate) fn value_at(&self, idx: usize) -> &V {
+        &self.node(idx).value
+    }
+
+    /// Looks up an entry without recording the access
+    pub(crate) fn peek<Q>(&self, hash: u64, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.find(hash, key).map(|idx| &self.node(idx).value)
+    }
+
+    /// Records an access to the key in the frequency sketch, and returns the index of its entry if present
+    pub(crate) fn access<Q>(&mut self, hash: u64, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.sketch.increment(hash);
+
+        let idx = self.find(hash, key)?;
+        self.on_access(idx);
+        Some(idx)
+    }
+
+    pub(crate) fn get_mut<Q>(&mut self, hash: u64, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let idx = self.access(hash, key)?;
+        Some(&mut self.node_mut(idx).value)
+    }
+
+    /// Inserts or replaces an entry, returning the previous value and the entry evicted to make room, if any.
+    pub(crate) fn insert(&mut self, hash: u64, key: K, value: V) -> (Option<V>, Option<(K, V)>)
+    where
+        K: Eq,
+    {
+        if let Some(idx) = self.access(hash, &key) {
+            return (Some(std::mem::replace(&mut self.node_mut(idx).value, value)), None);
+        }
+
+        let node = Node {
+            hash,
+            key,
+            value,
+            segment: Segment::Window,
+            prev: NIL,
+            next: NIL,
+        };
+
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = Some(node);
+                idx
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+
+        let TinyLfuShard {
+            ref mut indices,
+            ref nodes,
+            ..
+        } = *self;
+
+        indices.insert(hash, idx, |&i| match nodes[i] {
+            Some(ref node) => node.hash,
+            None => unreachable!("dangling node index"),
+        });
+
+        self.push_front(Segment::Window, idx);
+
+        if self.window.len <= self.window_capacity {
+            return (None, None);
+        }
+
+        // window overflowed, its LRU entry is a candidate for admission into the main space
+        let candidate = self.window.tail;
+        self.unlink(candidate);
+
+        // the main space's own victim, if the candidate has to compete for a spot
+        let victim = match self.probation.tail {
+            NIL => self.protected.tail,
+            tail => tail,
+        };
+
+        self.push_front(Segment::Probation, candidate);
+
+        if self.len() <= self.capacity {
+            return (None, None);
+        }
+
+        let evicted = match victim {
+            NIL => candidate,
+            _ => {
+                let candidate_freq = self.sketch.frequency(self.node(candidate).hash);
+                let victim_freq = self.sketch.frequency(self.node(victim).hash);
+
+                if candidate_freq > victim_freq {
+                    victim
+                } else {
+                    candidate
+                }
+            }
+        };
+
+        (None, Some(self.take(evicted)))
+    }
+
+    pub(crate) fn remove<Q>(&mut self, hash: u64, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let idx = self.find(hash, key)?;
+        Some(self.take(idx))
+    }
+}
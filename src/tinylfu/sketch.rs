@@ -0,0 +1,85 @@
+/// Count-min sketch of 4-bit saturating counters, estimating how often a hash has been seen recently.
+///
+/// Aging: once the number of increments reaches the sample size, every counter is halved,
+/// so the sketch tracks recent frequency rather than all-time popularity.
+#[derive(Debug, Clone)]
+pub(crate) struct FrequencySketch {
+    table: Vec<u8>,
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+const ROWS: usize = 4;
+const MAX_COUNT: u8 = 15;
+
+const SEEDS: [u64; ROWS] = [
+    0x97cb_3127_e3f9_0c49,
+    0xc3a5_c85c_97cb_3127,
+    0xb492_b66f_be98_f273,
+    0x9ae1_6a3b_2f90_404f,
+];
+
+impl FrequencySketch {
+    pub(crate) fn new(capacity: usize) -> Self {
+        let width = capacity.max(16).next_power_of_two();
+
+        FrequencySketch {
+            table: vec![0; width * ROWS],
+            mask: width - 1,
+            additions: 0,
+            sample_size: capacity.max(1).saturating_mul(10),
+        }
+    }
+
+    #[inline]
+    fn index_of(&self, hash: u64, row: usize) -> usize {
+        let h = (hash ^ SEEDS[row]).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+
+        row * (self.mask + 1) + ((h >> 32) as usize & self.mask)
+    }
+
+    /// Estimated number of recent occurrences of the hash, at most 15
+    pub(crate) fn frequency(&self, hash: u64) -> u8 {
+        (0..ROWS)
+            .map(|row| unsafe { *self.table.get_unchecked(self.index_of(hash, row)) })
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn increment(&mut self, hash: u64) {
+        let mut added = false;
+
+        for row in 0..ROWS {
+            let idx = self.index_of(hash, row);
+            let counter = unsafe { self.table.get_unchecked_mut(idx) };
+
+            if *counter < MAX_COUNT {
+                *counter += 1;
+                added = true;
+            }
+        }
+
+        if added {
+            self.additions += 1;
+
+            if self.additions >= self.sample_size {
+                self.reset();
+            }
+        }
+    }
+
+    /// Halve all counters
+    fn reset(&mut self) {
+        for counter in &mut self.table {
+            *counter >>= 1;
+        }
+
+        self.additions /= 2;
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.table.iter_mut().for_each(|counter| *counter = 0);
+        self.additions = 0;
+    }
+}
@@ -0,0 +1,122 @@
+use std::any::{Any, TypeId};
+use std::hash::{BuildHasher, Hash};
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::lru::{AtomicInstant, AtomicTimestamp, IndexedShard, LruCache, TimestampedValue};
+use crate::{ReadHandle, WriteHandle};
+
+/// Type-erased value of a [`TypedCache`]
+pub type AnyValue = Box<dyn Any + Send + Sync>;
+
+/// Key of a [`TypedCache`]'s underlying `LruCache`, the type of the value being part of the key
+pub type TypedKey<K> = (K, TypeId);
+
+/// Shard of a `TypedCache<K, T, _>`, locked by its handles
+type TypedShard<K, T> = IndexedShard<TypedKey<K>, TimestampedValue<AnyValue, T>>;
+
+/// `ReadHandle` to a value of a `TypedCache<K, T, _>`, as returned by `get::<V>`
+pub type TypedReadHandle<K, V, T = AtomicInstant> = ReadHandle<TypedShard<K, T>, V>;
+
+/// `WriteHandle` to a value of a `TypedCache<K, T, _>`, as returned by `get_mut::<V>`
+pub type TypedWriteHandle<K, V, T = AtomicInstant> = WriteHandle<TypedShard<K, T>, V>;
+
+/// Cache of values of any type, e.g. for plugins each storing their own types, keyed by `(K, TypeId)`.
+///
+/// Values of different types are separate entries under the same key, and every lookup names the type it expects,
+/// so values are downcast without `unsafe`, and never to the wrong type. Entries are stored in an
+/// [`LruCache`], so sharding and eviction work as usual through [`inner`](Self::inner).
+///
+/// ```
+/// # use quick_hash_cache::TypedCache;
+/// # #[tokio::main] async fn main() {
+/// let cache = TypedCache::new(4);
+/// cache.insert("user:1", 42u32).await;
+/// cache.insert("user:1", String::from("Ferris")).await;
+///
+/// assert_eq!(cache.get_cloned::<u32>(&"user:1").await, Some(42));
+/// assert_eq!(*cache.get::<String>(&"user:1").await.unwrap(), "Ferris");
+/// assert!(cache.get::<u64>(&"user:1").await.is_none());
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TypedCache<K, T = AtomicInstant, S = DefaultHashBuilder> {
+    inner: LruCache<TypedKey<K>, AnyValue, T, S>,
+}
+
+impl<K> TypedCache<K, AtomicInstant, DefaultHashBuilder> {
+    pub fn new(num_shards: usize) -> Self {
+        TypedCache::from(LruCache::new(num_shards))
+    }
+}
+
+impl<K> Default for TypedCache<K, AtomicInstant, DefaultHashBuilder> {
+    fn default() -> Self {
+        TypedCache::from(LruCache::default())
+    }
+}
+
+impl<K, T, S> From<LruCache<TypedKey<K>, AnyValue, T, S>> for TypedCache<K, T, S> {
+    /// Wraps a cache configured through its own builder methods, e.g. `with_max_capacity`
+    fn from(inner: LruCache<TypedKey<K>, AnyValue, T, S>) -> Self {
+        TypedCache { inner }
+    }
+}
+
+impl<K, T, S> TypedCache<K, T, S>
+where
+    K: Hash + Eq + Clone,
+    T: AtomicTimestamp,
+    S: BuildHasher,
+{
+    #[inline]
+    fn typed_key<V: Any>(key: &K) -> TypedKey<K> {
+        (key.clone(), TypeId::of::<V>())
+    }
+
+    /// Number of values, of all types
+    pub fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    pub async fn get<V: Any>(&self, key: &K) -> Option<TypedReadHandle<K, V, T>> {
+        let value = self.inner.get(&Self::typed_key::<V>(key)).await?;
+
+        // the type is part of the key, so the downcast only fails if `V` was not what was stored
+        ReadHandle::try_map(value, |value| value.downcast_ref::<V>()).ok()
+    }
+
+    pub async fn get_mut<V: Any>(&self, key: &K) -> Option<TypedWriteHandle<K, V, T>> {
+        let value = self.inner.get_mut(&Self::typed_key::<V>(key)).await?;
+        WriteHandle::try_map(value, |value| value.downcast_mut::<V>()).ok()
+    }
+
+    pub async fn get_cloned<V: Any + Clone>(&self, key: &K) -> Option<V> {
+        self.get::<V>(key).await.map(|value| value.clone())
+    }
+
+    pub async fn contains<V: Any>(&self, key: &K) -> bool {
+        self.inner.contains(&Self::typed_key::<V>(key)).await
+    }
+
+    /// Inserts the value under the key and its type, returning the previous value of that type, if any
+    pub async fn insert<V: Any + Send + Sync>(&self, key: K, value: V) -> Option<V> {
+        let previous = self.inner.insert((key, TypeId::of::<V>()), Box::new(value)).await?;
+        previous.downcast().ok().map(|previous| *previous)
+    }
+
+    /// Removes the value of type `V` under the key, leaving values of other types
+    pub async fn remove<V: Any>(&self, key: &K) -> Option<V> {
+        let value = self.inner.remove(&Self::typed_key::<V>(key)).await?;
+        value.downcast().ok().map(|value| *value)
+    }
+
+    /// The underlying `LruCache`, e.g. for eviction, whose entries are keyed by `(key, TypeId::of::<V>())`
+    pub fn inner(&self) -> &LruCache<TypedKey<K>, AnyValue, T, S> {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> LruCache<TypedKey<K>, AnyValue, T, S> {
+        self.inner
+    }
+}
@@ -0,0 +1,147 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::{CHashMap, ReadHandle, Shard};
+
+/// `ReadHandle` to a value of a `VersionedMap<K, V, S>`, as returned by `get`
+pub type VersionedReadHandle<K, V, S = DefaultHashBuilder> = ReadHandle<Shard<K, Versioned<V>, S>, V>;
+
+/// Value of a [`VersionedMap`] along with its version
+#[doc(hidden)]
+#[derive(Debug, Clone)]
+pub struct Versioned<V> {
+    value: V,
+    version: u64,
+}
+
+/// Which entry [`VersionedMap::insert_if_newer`] kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VersionWinner {
+    /// The new value was inserted, the key being absent or at a lower version
+    New,
+    /// The existing value was kept, being at the same or a higher version
+    Existing,
+}
+
+/// `CHashMap` whose values carry user-defined versions, for replicated caches receiving updates out of order.
+///
+/// `insert_if_newer` compares versions and replaces the value under the shard's write lock, so concurrent
+/// updates always leave the highest version in place. Removing a key forgets its version, so an older update
+/// arriving afterwards is inserted again: keep a tombstone value, e.g. with `V = Option<T>`, if that matters.
+///
+/// ```
+/// # use quick_hash_cache::{VersionWinner, VersionedMap};
+/// # #[tokio::main] async fn main() {
+/// let replica = VersionedMap::new(4);
+///
+/// assert_eq!(replica.insert_if_newer("user:1", "bob", 2).await, VersionWinner::New);
+/// assert_eq!(replica.insert_if_newer("user:1", "alice", 1).await, VersionWinner::Existing);
+///
+/// assert_eq!(replica.get_cloned(&"user:1").await, Some("bob"));
+/// assert_eq!(replica.version(&"user:1").await, Some(2));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct VersionedMap<K, V, S = DefaultHashBuilder> {
+    map: CHashMap<K, Versioned<V>, S>,
+}
+
+impl<K, V> VersionedMap<K, V, DefaultHashBuilder> {
+    pub fn new(num_shards: usize) -> Self {
+        Self::with_hasher(num_shards, DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V> Default for VersionedMap<K, V, DefaultHashBuilder> {
+    fn default() -> Self {
+        Self::new(crate::default_num_shards())
+    }
+}
+
+impl<K, V, S> VersionedMap<K, V, S>
+where
+    S: Clone,
+{
+    pub fn with_hasher(num_shards: usize, hash_builder: S) -> Self {
+        VersionedMap {
+            map: CHashMap::with_hasher(num_shards, hash_builder),
+        }
+    }
+}
+
+impl<K, V, S> VersionedMap<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    pub fn size(&self) -> usize {
+        self.map.size()
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.map.num_shards()
+    }
+
+    pub async fn get<Q>(&self, key: &Q) -> Option<VersionedReadHandle<K, V, S>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let entry = self.map.get(key).await?;
+
+        Some(ReadHandle::map(entry, |entry| &entry.value))
+    }
+
+    pub async fn get_cloned<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        self.get(key).await.map(|value| value.clone())
+    }
+
+    /// Version of the key's value, `None` if absent
+    pub async fn version<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.map.get(key).await.map(|entry| entry.version)
+    }
+
+    /// Inserts the value unless the key already has one at the same or a higher version,
+    /// returning which of them was kept. Equal versions keep the existing value, so replayed updates are no-ops.
+    pub async fn insert_if_newer(&self, key: K, value: V, version: u64) -> VersionWinner
+    where
+        K: Clone,
+    {
+        let mut new = Some(Versioned { value, version });
+
+        let mut entry = self.map.get_mut_or_insert(&key, || new.take().unwrap()).await;
+
+        match new {
+            None => VersionWinner::New,
+            Some(new) if new.version > entry.version => {
+                *entry = new;
+                VersionWinner::New
+            }
+            Some(_) => VersionWinner::Existing,
+        }
+    }
+
+    /// Removes the value, returning it along with its version
+    pub async fn remove<Q>(&self, key: &Q) -> Option<(V, u64)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.map.remove(key).await.map(|entry| (entry.value, entry.version))
+    }
+
+    pub async fn clear(&self) {
+        self.map.clear().await
+    }
+}
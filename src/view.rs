@@ -0,0 +1,162 @@
+//! Lookup-only views of a `CHashMap`, see `CHashMap::read_only_view`.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::hash::ShardSelect;
+use crate::map::MapReadHandle;
+use crate::pins::ShardPins;
+use crate::sync::{Arc, OwnedRwLockReadGuard, RwLock};
+use crate::{read_handle, Shard};
+
+/// Lookup-only access to the entries of a [`CHashMap`](crate::CHashMap), as returned by `read_only_view`,
+/// for handing to components that must not write to the map.
+///
+/// The view shares the map's shards, so it sees every write to the map, and can be cloned cheaply.
+///
+/// The view does not follow `resize_shards`, and gives wrong answers, not just stale ones, once the map is resized:
+/// it still looks keys up in the shards the map had before, while the map moved most of its keys to other shards,
+/// so lookups through the view return `None` for keys that are in the map. While the resize runs, keys being moved
+/// are missing for every view. Take a new view with `read_only_view` after resizing.
+///
+/// ```
+/// # use quick_hash_cache::CHashMap;
+/// # #[tokio::main] async fn main() {
+/// let map = CHashMap::new(4);
+/// let view = map.read_only_view();
+///
+/// map.insert("a", 1).await;
+///
+/// assert_eq!(view.get_cloned(&"a").await, Some(1));
+/// # }
+/// ```
+pub struct ReadOnlyCHashMap<K, T, S = DefaultHashBuilder> {
+    hash_builder: S,
+    shard_select: ShardSelect,
+    pins: Arc<ShardPins>,
+    shards: Vec<Arc<RwLock<Shard<K, T, S>>>>,
+}
+
+impl<K, T, S> ReadOnlyCHashMap<K, T, S> {
+    pub(crate) fn new(
+        hash_builder: S,
+        shard_select: ShardSelect,
+        pins: Arc<ShardPins>,
+        shards: Vec<Arc<RwLock<Shard<K, T, S>>>>,
+    ) -> Self {
+        ReadOnlyCHashMap {
+            hash_builder,
+            shard_select,
+            pins,
+            shards,
+        }
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Number of entries, counted by read-locking the shards one at a time,
+    /// so under concurrent writes the count is not of a single point in time
+    pub async fn len(&self) -> usize {
+        let mut len = 0;
+
+        for shard in &self.shards {
+            len += shard.read().await.len();
+        }
+
+        len
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        for shard in &self.shards {
+            if !shard.read().await.is_empty() {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+impl<K, T, S> ReadOnlyCHashMap<K, T, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    fn hash_and_shard<Q>(&self, key: &Q) -> (u64, usize)
+    where
+        Q: ?Sized + Hash,
+    {
+        let hash = self.hash_builder.hash_one(key);
+        let shard_idx = match self.pins.get(hash) {
+            Some(shard_idx) => shard_idx,
+            None => self.shard_select.shard_of(hash, self.shards.len()),
+        };
+
+        (hash, shard_idx)
+    }
+
+    pub async fn get<Q>(&self, key: &Q) -> Option<MapReadHandle<K, T, S>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).clone().read_owned().await };
+
+        OwnedRwLockReadGuard::try_map(shard, |shard| {
+            shard.raw_entry().from_key_hashed_nocheck(hash, key).map(|(_, value)| value)
+        })
+        .ok()
+        .map(read_handle)
+    }
+
+    pub async fn get_cloned<Q>(&self, key: &Q) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        T: Clone,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).read().await };
+
+        shard.raw_entry().from_key_hashed_nocheck(hash, key).map(|(_, value)| value.clone())
+    }
+
+    pub async fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let (hash, shard_idx) = self.hash_and_shard(key);
+        let shard = unsafe { self.shards.get_unchecked(shard_idx).read().await };
+
+        shard.raw_entry().from_key_hashed_nocheck(hash, key).is_some()
+    }
+}
+
+impl<K, T, S> Clone for ReadOnlyCHashMap<K, T, S>
+where
+    S: Clone,
+{
+    fn clone(&self) -> Self {
+        ReadOnlyCHashMap {
+            hash_builder: self.hash_builder.clone(),
+            shard_select: self.shard_select.clone(),
+            pins: self.pins.clone(),
+            shards: self.shards.clone(),
+        }
+    }
+}
+
+impl<K, T, S> fmt::Debug for ReadOnlyCHashMap<K, T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadOnlyCHashMap")
+            .field("num_shards", &self.shards.len())
+            .finish()
+    }
+}
@@ -0,0 +1,196 @@
+//! Notification of writes to watched keys, see `CHashMap::watch`.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::pin::Pin;
+use std::sync::{Mutex, MutexGuard};
+use std::task::{ready, Context, Poll};
+
+use futures_core::Stream;
+use hashbrown::hash_map::{DefaultHashBuilder, HashMap, RawEntryMut};
+use tokio::sync::watch;
+
+use crate::sync::{AtomicUsize, Ordering};
+use crate::CHashMap;
+
+type Registry<K, S> = HashMap<K, watch::Sender<()>, S>;
+
+/// Channels of the watched keys of each shard, notified whenever the shard is write-locked for the key.
+///
+/// Writers only send a notification, under the write lock: watchers then read the value themselves, which waits
+/// for the write to complete, so writes through handles such as `get_mut` are seen too.
+pub(crate) struct Watchers<K, S> {
+    shards: Box<[Mutex<Registry<K, S>>]>,
+    /// Number of channels across all shards, so writes skip the registry when nothing is watched
+    num_watched: AtomicUsize,
+}
+
+impl<K, S> Watchers<K, S> {
+    pub(crate) fn new(num_shards: usize, hash_builder: &S) -> Self
+    where
+        S: Clone,
+    {
+        Watchers {
+            shards: (0..num_shards)
+                .map(|_| Mutex::new(HashMap::with_hasher(hash_builder.clone())))
+                .collect(),
+            num_watched: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    fn shard(&self, shard_idx: usize) -> MutexGuard<'_, Registry<K, S>> {
+        self.shards[shard_idx].lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Notifies every watcher of the shard, for writes that may touch any of its keys
+    pub(crate) fn notify_shard(&self, shard_idx: usize) {
+        if self.num_watched.load(Ordering::Acquire) == 0 {
+            return;
+        }
+
+        let mut shard = self.shard(shard_idx);
+        let len = shard.len();
+
+        // channels of dropped watches are only cleaned up here, by the next write to their shard
+        shard.retain(|_, sender| {
+            sender.send_replace(());
+            sender.receiver_count() > 0
+        });
+
+        self.num_watched.fetch_sub(len - shard.len(), Ordering::Release);
+    }
+
+    /// Notifies the watchers of the key, if any
+    pub(crate) fn notify_key<Q>(&self, shard_idx: usize, hash: u64, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq,
+        S: BuildHasher,
+    {
+        if self.num_watched.load(Ordering::Acquire) == 0 {
+            return;
+        }
+
+        let mut shard = self.shard(shard_idx);
+
+        if let RawEntryMut::Occupied(occupied) = shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            if occupied.get().receiver_count() > 0 {
+                occupied.get().send_replace(());
+            } else {
+                occupied.remove();
+                self.num_watched.fetch_sub(1, Ordering::Release);
+            }
+        }
+    }
+
+    /// Subscribes to the key's channel, creating it if it is not watched yet
+    pub(crate) fn subscribe(&self, shard_idx: usize, hash: u64, key: &K) -> watch::Receiver<()>
+    where
+        K: Hash + Eq + Clone,
+        S: BuildHasher,
+    {
+        let mut shard = self.shard(shard_idx);
+
+        match shard.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(occupied) => occupied.get().subscribe(),
+            RawEntryMut::Vacant(vacant) => {
+                let (sender, receiver) = watch::channel(());
+                vacant.insert_hashed_nocheck(hash, key.clone(), sender);
+                self.num_watched.fetch_add(1, Ordering::Release);
+
+                receiver
+            }
+        }
+    }
+}
+
+impl<K, S> fmt::Debug for Watchers<K, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watchers")
+            .field("num_watched", &self.num_watched.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+type Step<'a, K, T> = Pin<Box<dyn Future<Output = Option<(watch::Receiver<()>, K, Option<T>)>> + Send + 'a>>;
+
+/// Stream of the values of a key of a [`CHashMap`], as returned by [`CHashMap::watch`]
+pub struct Watch<'a, K, T, S = DefaultHashBuilder> {
+    map: &'a CHashMap<K, T, S>,
+    /// Reads the value once notified, or right away for the first one
+    step: Option<Step<'a, K, T>>,
+}
+
+impl<'a, K, T, S> Watch<'a, K, T, S>
+where
+    K: Hash + Eq + Send + Sync,
+    T: Clone + Send + Sync,
+    S: BuildHasher + Send + Sync,
+{
+    pub(crate) fn new(map: &'a CHashMap<K, T, S>, receiver: watch::Receiver<()>, key: K) -> Self {
+        Watch {
+            map,
+            step: Some(Self::step(map, receiver, key, false)),
+        }
+    }
+
+    /// Waits for the value after the next write, same as `StreamExt::next`
+    pub async fn next(&mut self) -> Option<Option<T>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    fn step(map: &'a CHashMap<K, T, S>, mut receiver: watch::Receiver<()>, key: K, wait: bool) -> Step<'a, K, T> {
+        Box::pin(async move {
+            // the sender lives as long as the map, so this only fails once it is dropped
+            if wait && receiver.changed().await.is_err() {
+                return None;
+            }
+
+            let value = map.get_cloned(&key).await;
+
+            Some((receiver, key, value))
+        })
+    }
+}
+
+// nothing is pinned structurally, the pending read being boxed
+impl<K, T, S> Unpin for Watch<'_, K, T, S> {}
+
+impl<K, T, S> Stream for Watch<'_, K, T, S>
+where
+    K: Hash + Eq + Send + Sync,
+    T: Clone + Send + Sync,
+    S: BuildHasher + Send + Sync,
+{
+    type Item = Option<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Option<T>>> {
+        let this = self.get_mut();
+
+        let step = match this.step {
+            Some(ref mut step) => step,
+            None => return Poll::Ready(None),
+        };
+
+        let (receiver, key, value) = match ready!(step.as_mut().poll(cx)) {
+            Some(read) => read,
+            None => {
+                this.step = None;
+                return Poll::Ready(None);
+            }
+        };
+
+        this.step = Some(Self::step(this.map, receiver, key, true));
+
+        Poll::Ready(Some(value))
+    }
+}
+
+impl<K, T, S> fmt::Debug for Watch<'_, K, T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watch").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,57 @@
+//! Notifications of the size of a map crossing thresholds, see `CHashMap::on_size_threshold`.
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Direction in which the size of a map crossed a threshold, see `CHashMap::on_size_threshold`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SizeCrossing {
+    /// The size reached the threshold from below it
+    Rising,
+    /// The size dropped below the threshold
+    Falling,
+}
+
+type Callback = Arc<dyn Fn(SizeCrossing, usize) + Send + Sync>;
+
+/// Thresholds of a map's size, in ascending order, each with the callback to run when it is crossed
+#[derive(Clone, Default)]
+pub(crate) struct Watermarks {
+    thresholds: Vec<(usize, Callback)>,
+}
+
+impl Watermarks {
+    pub(crate) fn add(&mut self, threshold: usize, callback: Callback) {
+        let idx = self.thresholds.partition_point(|&(other, _)| other <= threshold);
+        self.thresholds.insert(idx, (threshold, callback));
+    }
+
+    /// Runs the callbacks of the thresholds between the sizes before and after a change
+    #[inline]
+    pub(crate) fn check(&self, before: usize, after: usize) {
+        if self.thresholds.is_empty() {
+            return;
+        }
+
+        let (crossing, low, high) = match after.cmp(&before) {
+            std::cmp::Ordering::Greater => (SizeCrossing::Rising, before, after),
+            std::cmp::Ordering::Less => (SizeCrossing::Falling, after, before),
+            std::cmp::Ordering::Equal => return,
+        };
+
+        // a threshold is crossed when reached from below, or left from above
+        for (threshold, callback) in &self.thresholds {
+            if low < *threshold && *threshold <= high {
+                callback(crossing, after);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Watermarks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.thresholds.iter().map(|&(threshold, _)| threshold))
+            .finish()
+    }
+}
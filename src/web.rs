@@ -0,0 +1,297 @@
+//! Caching of the responses of web services built on the `http` types, e.g. with axum, see [`HttpResponseCache`].
+
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::{Method, Request, Response, StatusCode, Uri};
+
+use crate::lru::LruCache;
+
+/// Key of a cached response: the method, path and query of the request,
+/// along with the values of the headers the cache varies on, see [`HttpResponseCache::key`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResponseKey {
+    method: Method,
+    path_and_query: String,
+    /// Value of each of the cache's `vary` headers in the request, in the same order
+    vary: Vec<Option<HeaderValue>>,
+}
+
+impl ResponseKey {
+    #[inline]
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    #[inline]
+    pub fn path_and_query(&self) -> &str {
+        &self.path_and_query
+    }
+}
+
+struct CachedParts {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// Response stored in an [`HttpResponseCache`], cheap to clone and `'static`, so it can be handed to
+/// or returned from handlers as is, e.g. turned into a `Response<Bytes>` with `into_response`
+#[derive(Clone)]
+pub struct CachedResponse(Arc<CachedParts>);
+
+impl CachedResponse {
+    #[inline]
+    pub fn status(&self) -> StatusCode {
+        self.0.status
+    }
+
+    #[inline]
+    pub fn headers(&self) -> &HeaderMap {
+        &self.0.headers
+    }
+
+    #[inline]
+    pub fn body(&self) -> &Bytes {
+        &self.0.body
+    }
+
+    /// Rebuilds the response, sharing the bytes of its body
+    pub fn into_response(self) -> Response<Bytes> {
+        let mut response = Response::new(self.0.body.clone());
+
+        *response.status_mut() = self.0.status;
+        *response.headers_mut() = self.0.headers.clone();
+
+        response
+    }
+}
+
+impl From<CachedResponse> for Response<Bytes> {
+    fn from(cached: CachedResponse) -> Self {
+        cached.into_response()
+    }
+}
+
+impl fmt::Debug for CachedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedResponse")
+            .field("status", &self.0.status)
+            .field("headers", &self.0.headers)
+            .field("body_len", &self.0.body.len())
+            .finish()
+    }
+}
+
+/// Cache of the responses of a web service, built on an [`LruCache`], keyed by the method, path and query
+/// of each request, along with the request headers responses vary on, see `vary_on`.
+///
+/// Responses are cached for as long as their `Cache-Control` header allows (`s-maxage`, then `max-age`),
+/// or for the default TTL, if any, for successful responses without one. Only responses to `GET` and `HEAD`
+/// requests are cached, and never those marked `no-store`, `no-cache` or `private`, nor those varying on
+/// request headers the cache does not vary on. `Expires` headers are ignored.
+///
+/// ```
+/// # use quick_hash_cache::web::HttpResponseCache;
+/// # use http::{header, Request, Response};
+/// # #[tokio::main] async fn main() {
+/// let cache = HttpResponseCache::new(1000).vary_on(header::ACCEPT_LANGUAGE);
+///
+/// let request = Request::get("/greeting?name=ferris").header(header::ACCEPT_LANGUAGE, "en").body(()).unwrap();
+/// let key = cache.key_for(&request);
+///
+/// let response = cache
+///     .get_or_render(key.clone(), || async {
+///         Response::builder()
+///             .header(header::CACHE_CONTROL, "public, max-age=60")
+///             .body("hello ferris".into())
+///             .unwrap()
+///     })
+///     .await;
+///
+/// assert_eq!(response.body(), "hello ferris");
+/// assert_eq!(cache.get(&key).await.unwrap().body(), "hello ferris");
+/// # }
+/// ```
+pub struct HttpResponseCache {
+    cache: LruCache<ResponseKey, CachedResponse>,
+    /// Request headers responses vary on, besides the method, path and query
+    vary: Vec<HeaderName>,
+    /// TTL of successful responses without one of their own, if they are cached at all
+    default_ttl: Option<Duration>,
+}
+
+impl HttpResponseCache {
+    /// Cache of about `capacity` responses, see `LruCache::with_max_capacity`
+    pub fn new(capacity: usize) -> Self {
+        HttpResponseCache {
+            cache: LruCache::default().with_max_capacity(capacity),
+            vary: Vec::new(),
+            default_ttl: None,
+        }
+    }
+
+    /// Keys responses by the value of the request header as well, e.g. `Accept-Encoding`,
+    /// so requests with different values of it get their own response
+    pub fn vary_on(mut self, header: HeaderName) -> Self {
+        if !self.vary.contains(&header) {
+            self.vary.push(header);
+        }
+
+        self
+    }
+
+    /// Caches successful responses without a `max-age` or `s-maxage` for `ttl`, instead of not at all
+    pub fn with_default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Underlying cache, e.g. to inspect it or evict responses
+    #[inline]
+    pub fn cache(&self) -> &LruCache<ResponseKey, CachedResponse> {
+        &self.cache
+    }
+
+    /// Key of the response to a request, from its parts, as given by extractors
+    pub fn key(&self, method: &Method, uri: &Uri, headers: &HeaderMap) -> ResponseKey {
+        ResponseKey {
+            method: method.clone(),
+            path_and_query: uri.path_and_query().map_or_else(|| uri.path(), |pq| pq.as_str()).to_owned(),
+            vary: self.vary.iter().map(|name| headers.get(name).cloned()).collect(),
+        }
+    }
+
+    /// Key of the response to the request
+    pub fn key_for<B>(&self, request: &Request<B>) -> ResponseKey {
+        self.key(request.method(), request.uri(), request.headers())
+    }
+
+    /// Cached response to the key, unless expired
+    pub async fn get(&self, key: &ResponseKey) -> Option<CachedResponse> {
+        self.cache.get_cloned(key).await
+    }
+
+    /// Caches the response to the key, if it may be cached, returning the cached response
+    pub async fn insert(
+        &self,
+        key: ResponseKey,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) -> Option<CachedResponse> {
+        let ttl = self.ttl_of(&key.method, status, headers)?;
+
+        let cached = CachedResponse(Arc::new(CachedParts {
+            status,
+            headers: headers.clone(),
+            body,
+        }));
+
+        self.cache.insert_with_ttl(key, cached.clone(), ttl).await;
+
+        Some(cached)
+    }
+
+    /// Serves the cached response to the key, or renders the response and caches it, if it may be cached
+    pub async fn get_or_render<F, Fut>(&self, key: ResponseKey, render: F) -> Response<Bytes>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Response<Bytes>>,
+    {
+        if let Some(cached) = self.get(&key).await {
+            return cached.into_response();
+        }
+
+        let response = render().await;
+
+        match self.ttl_of(&key.method, response.status(), response.headers()) {
+            Some(ttl) => {
+                let (parts, body) = response.into_parts();
+
+                let cached = CachedResponse(Arc::new(CachedParts {
+                    status: parts.status,
+                    headers: parts.headers,
+                    body,
+                }));
+
+                self.cache.insert_with_ttl(key, cached.clone(), ttl).await;
+                cached.into_response()
+            }
+            None => response,
+        }
+    }
+
+    /// Drops the cached response to the key, returning whether there was one
+    pub async fn invalidate(&self, key: &ResponseKey) -> bool {
+        self.cache.remove(key).await.is_some()
+    }
+
+    pub async fn clear(&self) {
+        self.cache.clear().await;
+    }
+
+    /// How long a response may be cached for, or `None` if it must not be
+    fn ttl_of(&self, method: &Method, status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+        if method != Method::GET && method != Method::HEAD {
+            return None;
+        }
+
+        // a response varying on headers the key leaves out would be served to requests it wasn't meant for
+        for name in header_tokens(headers, &header::VARY) {
+            if name == "*" || !self.vary.iter().any(|vary| vary.as_str().eq_ignore_ascii_case(name)) {
+                return None;
+            }
+        }
+
+        let (mut max_age, mut s_maxage) = (None, None);
+
+        for directive in header_tokens(headers, &header::CACHE_CONTROL) {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim().trim_matches('"').parse::<u64>().ok()),
+                None => (directive, None),
+            };
+
+            if ["no-store", "no-cache", "private"].iter().any(|no| name.eq_ignore_ascii_case(no)) {
+                return None;
+            } else if name.eq_ignore_ascii_case("max-age") {
+                max_age = value;
+            } else if name.eq_ignore_ascii_case("s-maxage") {
+                s_maxage = value;
+            }
+        }
+
+        let ttl = match s_maxage.or(max_age) {
+            Some(secs) => Duration::from_secs(secs),
+            None if status.is_success() => self.default_ttl?,
+            None => return None,
+        };
+
+        Some(ttl).filter(|ttl| !ttl.is_zero())
+    }
+}
+
+impl fmt::Debug for HttpResponseCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpResponseCache")
+            .field("size", &self.cache.size())
+            .field("vary", &self.vary)
+            .field("default_ttl", &self.default_ttl)
+            .finish()
+    }
+}
+
+/// Comma-separated tokens of every value of the header, trimmed, skipping values that aren't visible ASCII
+fn header_tokens<'a>(headers: &'a HeaderMap, name: &HeaderName) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
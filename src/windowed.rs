@@ -0,0 +1,249 @@
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::collections::{vec_deque, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::iter::FusedIterator;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use hashbrown::hash_map::DefaultHashBuilder;
+
+use crate::clock::{Clock, DefaultClock};
+use crate::sweeper;
+use crate::sync::Arc;
+use crate::CHashMap;
+
+/// Samples of a key, oldest first, each with the `DefaultClock` reading of when it was recorded
+#[derive(Debug)]
+struct Window<V> {
+    samples: VecDeque<(u64, V)>,
+}
+
+impl<V> Window<V> {
+    /// Drops the samples recorded before `cutoff`, returning how many were dropped
+    fn expire(&mut self, cutoff: u64) -> usize {
+        let expired = self.samples.partition_point(|(at, _)| *at < cutoff);
+        self.samples.drain(..expired);
+        expired
+    }
+
+    fn live(&self, cutoff: u64) -> Samples<'_, V> {
+        let expired = self.samples.partition_point(|(at, _)| *at < cutoff);
+
+        Samples {
+            inner: self.samples.range(expired..),
+        }
+    }
+}
+
+/// Map of per-key sliding windows of recent samples, e.g. the last 60s of latencies of each endpoint.
+///
+/// Samples older than the window are ignored by reads, and dropped on the key's next `record`, by `sweep`,
+/// or by a single sweeper thread started with `spawn_sweeper` for the whole map, which also removes the keys
+/// left without samples. Each key can also be capped to its most recent samples with `with_max_samples`.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use quick_hash_cache::WindowedMap;
+/// # #[tokio::main] async fn main() {
+/// let latencies = WindowedMap::new(4, Duration::from_secs(60));
+///
+/// for ms in [12, 30, 18] {
+///     latencies.record("/login", ms).await;
+/// }
+///
+/// let mean = latencies
+///     .with_samples(&"/login", |samples| {
+///         let count = samples.len() as u32;
+///         samples.sum::<u32>() / count
+///     })
+///     .await;
+///
+/// assert_eq!(mean, Some(20));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct WindowedMap<K, V, S = DefaultHashBuilder> {
+    map: CHashMap<K, Window<V>, S>,
+    /// Length of the window, in nanoseconds
+    window: u64,
+    max_samples: usize,
+}
+
+impl<K, V> WindowedMap<K, V, DefaultHashBuilder> {
+    pub fn new(num_shards: usize, window: Duration) -> Self {
+        Self::with_hasher(num_shards, window, DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V, S> WindowedMap<K, V, S>
+where
+    S: Clone,
+{
+    pub fn with_hasher(num_shards: usize, window: Duration, hash_builder: S) -> Self {
+        WindowedMap {
+            map: CHashMap::with_hasher(num_shards, hash_builder),
+            window: window.as_nanos().min(u64::MAX as u128) as u64,
+            max_samples: usize::MAX,
+        }
+    }
+}
+
+impl<K, V, S> WindowedMap<K, V, S> {
+    /// Keeps at most the `max_samples` most recent samples of each key, even if older ones are still in the window
+    pub fn with_max_samples(mut self, max_samples: usize) -> Self {
+        self.max_samples = max_samples;
+        self
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_nanos(self.window)
+    }
+
+    /// Earliest `DefaultClock` reading of samples still in the window
+    #[inline]
+    fn cutoff(&self) -> u64 {
+        DefaultClock::now().saturating_sub(self.window)
+    }
+}
+
+impl<K, V, S> WindowedMap<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher,
+{
+    /// Number of keys, including ones whose samples all expired but were not swept yet
+    pub fn size(&self) -> usize {
+        self.map.size()
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.map.num_shards()
+    }
+
+    /// Records a sample for the key, dropping its samples that left the window
+    pub async fn record(&self, key: K, value: V)
+    where
+        K: Clone,
+    {
+        let now = DefaultClock::now();
+        let cutoff = self.cutoff();
+
+        let mut window = self.map.get_mut_or_insert(&key, || Window { samples: VecDeque::new() }).await;
+
+        window.expire(cutoff);
+
+        if window.samples.len() >= self.max_samples {
+            let excess = window.samples.len() + 1 - self.max_samples;
+            window.samples.drain(..excess);
+        }
+
+        if self.max_samples > 0 {
+            window.samples.push_back((now, value));
+        }
+    }
+
+    /// Calls `f` with the key's samples still in the window, oldest first, or returns `None` if the key is absent
+    pub async fn with_samples<Q, R>(&self, key: &Q, f: impl FnOnce(Samples<'_, V>) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let window = self.map.get(key).await?;
+
+        Some(f(window.live(self.cutoff())))
+    }
+
+    /// Number of the key's samples still in the window
+    pub async fn count<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.with_samples(key, |samples| samples.len()).await.unwrap_or(0)
+    }
+
+    /// Clones the key's samples still in the window, oldest first
+    pub async fn samples_cloned<Q>(&self, key: &Q) -> Vec<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+        V: Clone,
+    {
+        self.with_samples(key, |samples| samples.cloned().collect()).await.unwrap_or_default()
+    }
+
+    /// Removes the key, returning its samples still in the window
+    pub async fn remove<Q>(&self, key: &Q) -> Option<Vec<V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let mut window = self.map.remove(key).await?;
+        window.expire(self.cutoff());
+
+        Some(window.samples.into_iter().map(|(_, value)| value).collect())
+    }
+
+    /// Drops the samples that left the window, and the keys left without samples, returning how many samples
+    /// were dropped
+    pub async fn sweep(&self) -> usize {
+        let cutoff = self.cutoff();
+        let dropped = Cell::new(0);
+
+        self.map
+            .retain(|_, window| {
+                dropped.set(dropped.get() + window.expire(cutoff));
+                !window.samples.is_empty()
+            })
+            .await;
+
+        dropped.get()
+    }
+
+    pub async fn clear(&self) {
+        self.map.clear().await
+    }
+
+    /// Starts a thread sweeping the map every `interval`, which exits once the map is dropped, at its next wakeup
+    pub fn spawn_sweeper(self: &Arc<Self>, interval: Duration) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+        S: Send + Sync + 'static,
+    {
+        sweeper::spawn("quick-hash-cache-sweeper", self, interval, |map| {
+            sweeper::block_on(map.sweep());
+        })
+    }
+}
+
+/// Samples of a key of a [`WindowedMap`] still in the window, oldest first
+#[derive(Debug, Clone)]
+pub struct Samples<'a, V> {
+    inner: vec_deque::Iter<'a, (u64, V)>,
+}
+
+impl<'a, V> Iterator for Samples<'a, V> {
+    type Item = &'a V;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a V> {
+        self.inner.next().map(|(_, value)| value)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<V> DoubleEndedIterator for Samples<'_, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(_, value)| value)
+    }
+}
+
+impl<V> ExactSizeIterator for Samples<'_, V> {}
+impl<V> FusedIterator for Samples<'_, V> {}
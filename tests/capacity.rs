@@ -0,0 +1,73 @@
+//! `try_insert_within_capacity` refuses new keys of full shards and eviction domains instead of evicting.
+
+use quick_hash_cache::lru::LruCache;
+use quick_hash_cache::tinylfu::WTinyLfuCache;
+use quick_hash_cache::CapacityFull;
+
+#[tokio::test]
+async fn lru_refuses_new_keys_of_full_shard() {
+    // 100 entries per shard, 80 plus slack
+    let cache = LruCache::<u32, u32>::new(1).with_max_capacity(80);
+
+    for key in 0..100 {
+        assert_eq!(cache.try_insert_within_capacity(key, key).await, Ok(None));
+    }
+
+    assert_eq!(cache.try_insert_within_capacity(100, 100).await, Err(CapacityFull(100)));
+    assert_eq!(cache.size(), 100);
+
+    for key in 0..100 {
+        assert_eq!(cache.get_cloned(&key).await, Some(key));
+    }
+
+    // existing keys are still overwritten
+    assert_eq!(cache.try_insert_within_capacity(0, 1).await, Ok(Some(0)));
+}
+
+#[tokio::test]
+async fn lru_refuses_new_keys_of_full_domain() {
+    // tenant 0 gets 75 of the 100 entries of the shard, and tenant 1 gets 25
+    let cache = LruCache::<(u8, u32), u32>::new(1)
+        .with_max_capacity(80)
+        .with_eviction_domains(|&(tenant, _)| tenant as usize, &[3, 1]);
+
+    for key in 0..25 {
+        assert_eq!(cache.try_insert_within_capacity((1, key), key).await, Ok(None));
+    }
+
+    assert_eq!(cache.try_insert_within_capacity((1, 25), 25).await, Err(CapacityFull(25)));
+    assert_eq!(cache.domain_len(1).await, 25);
+
+    for key in 0..25 {
+        assert_eq!(cache.get_cloned(&(1, key)).await, Some(key));
+    }
+
+    // the other domain still has room, even though the first is full
+    assert_eq!(cache.try_insert_within_capacity((0, 0), 0).await, Ok(None));
+    assert_eq!(cache.try_insert_within_capacity((1, 0), 1).await, Ok(Some(0)));
+}
+
+#[tokio::test]
+async fn tinylfu_refuses_new_keys_of_full_shard() {
+    let cache = WTinyLfuCache::<u32, u32>::new(1, 10);
+
+    let mut inserted = 0;
+    let mut refused = None;
+
+    for key in 0..100 {
+        match cache.try_insert_within_capacity(key, key).await {
+            Ok(_) => inserted += 1,
+            Err(CapacityFull(value)) => {
+                refused = Some(value);
+                break;
+            }
+        }
+    }
+
+    let refused = refused.expect("a full cache refuses new keys");
+    assert_eq!(cache.size(), inserted);
+
+    for key in 0..refused {
+        assert_eq!(cache.get_cloned(&key).await, Some(key));
+    }
+}
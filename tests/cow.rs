@@ -0,0 +1,22 @@
+//! Snapshots from `CHashMap::duplicate_cow` only get a copy of a shard once the map actually writes to it.
+
+use quick_hash_cache::CHashMap;
+
+#[tokio::test]
+async fn misses_copy_no_shard() {
+    let map = CHashMap::<u32, u32>::new(1);
+    map.insert(0, 0).await;
+
+    let snapshot = map.duplicate_cow().await;
+
+    assert_eq!(map.remove(&1).await, None);
+    assert_eq!(map.remove_if(&0, |_| false).await, None);
+    assert!(map.get_mut(&1).await.is_none());
+    assert_eq!(*map.get_or_insert(&0, || 1).await, 0);
+
+    assert_eq!(snapshot.num_copied_shards(), 0);
+
+    assert_eq!(map.remove_if(&0, |_| true).await, Some(0));
+    assert_eq!(snapshot.num_copied_shards(), 1);
+    assert_eq!(snapshot.get_cloned(&0).await, Some(0));
+}
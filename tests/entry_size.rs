@@ -0,0 +1,28 @@
+//! `LruCache` entries only carry the metadata of TTLs, weighers, eviction domains and `retain_async` once they use
+//! them, so caches without these features pay a pointer per entry for them.
+
+use std::mem::size_of;
+
+use quick_hash_cache::lru::{AtomicInstant, LruCache, TimestampedValue};
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn entries_without_metadata_stay_small() {
+    // value, LRU timestamp, insertion time and the metadata pointer
+    assert_eq!(size_of::<TimestampedValue<u64, AtomicInstant>>(), 32);
+}
+
+#[tokio::test]
+async fn metadata_is_kept_once_set() {
+    let cache = LruCache::<u32, u32>::new(1).with_weigher(|_, &value| value);
+
+    cache.insert(0, 1).await;
+    cache.insert(1, 5).await;
+    assert_eq!(cache.weight(), 6);
+
+    cache.insert(1, 1).await;
+    assert_eq!(cache.weight(), 2);
+
+    cache.remove(&0).await;
+    assert_eq!(cache.weight(), 1);
+}
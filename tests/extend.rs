@@ -0,0 +1,37 @@
+//! The synchronous `Extend` of `CHashMap` never blocks on a live handle: it panics instead, leaving the map as it was.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use quick_hash_cache::CHashMap;
+
+#[tokio::test]
+async fn extend_inserts_without_waiting() {
+    let mut map = CHashMap::<u32, u32>::new(4);
+
+    Extend::extend(&mut map, (0..100).map(|key| (key, key)));
+    Extend::extend(&mut map, [(&100, &100)]);
+
+    assert_eq!(map.size(), 101);
+    assert_eq!(map.get_cloned(&100).await, Some(100));
+}
+
+#[tokio::test]
+async fn extend_with_live_handle_panics_instead_of_deadlocking() {
+    let mut map = CHashMap::<u32, u32>::new(4);
+    map.insert(0, 0).await;
+
+    let handle = map.get(&0).await.unwrap();
+
+    let extended = panic::catch_unwind(AssertUnwindSafe(|| {
+        Extend::extend(&mut map, (1..100).map(|key| (key, key)));
+    }));
+
+    assert!(extended.is_err());
+    drop(handle);
+
+    // no shard was written to before the panic
+    assert_eq!(map.size(), 1);
+
+    Extend::extend(&mut map, (1..100).map(|key| (key, key)));
+    assert_eq!(map.size(), 100);
+}
@@ -0,0 +1,41 @@
+//! Writes to a single key that turn out to be misses leave no trace: no new generation and no watch notification.
+
+use std::time::Duration;
+
+use quick_hash_cache::CHashMap;
+
+#[tokio::test]
+async fn misses_keep_the_generation() {
+    let map = CHashMap::<u32, u32>::new(1);
+    map.insert(0, 0).await;
+
+    let generation = map.shard_generation(0);
+
+    assert_eq!(map.remove_entry(&1).await, None);
+    assert_eq!(map.remove_if(&0, |_| false).await, None);
+    assert!(map.get_mut(&1).await.is_none());
+    assert_eq!(*map.get_or_insert(&0, || 1).await, 0);
+
+    assert_eq!(map.shard_generation(0), generation);
+
+    assert_eq!(map.remove(&0).await, Some(0));
+    assert_ne!(map.shard_generation(0), generation);
+}
+
+#[tokio::test]
+async fn misses_notify_no_watch() {
+    let map = CHashMap::<u32, u32>::new(1);
+    map.insert(0, 0).await;
+
+    let mut watch = map.watch(&0);
+    assert_eq!(watch.next().await, Some(Some(0)));
+
+    assert_eq!(map.remove_if(&0, |_| false).await, None);
+    assert_eq!(*map.get_or_insert(&0, || 1).await, 0);
+
+    assert!(tokio::time::timeout(Duration::from_millis(20), watch.next()).await.is_err());
+
+    // the same value, but written
+    map.insert(0, 0).await;
+    assert_eq!(watch.next().await, Some(Some(0)));
+}
@@ -0,0 +1,133 @@
+//! `LockFreeMap` under writers inserting, removing, updating and growing shards while readers traverse them.
+
+#![cfg(feature = "lockfree")]
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use quick_hash_cache::lockfree::LockFreeMap;
+
+const KEYS: u32 = 4096;
+
+/// Values of a key are always `key + KEYS * n`, so readers can tell a value belongs to its key
+const fn value(key: u32, n: u32) -> u32 {
+    key + KEYS * n
+}
+
+/// Completes the future, which `LockFreeMap`'s methods do without ever yielding
+fn now<F: Future>(future: F) -> F::Output {
+    match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("`LockFreeMap` yielded"),
+    }
+}
+
+#[test]
+fn readers_see_consistent_entries_while_writers_grow_shards() {
+    // few shards, so each one grows from 8 buckets many times over
+    let map = LockFreeMap::<u32, u32>::new(2);
+    let writing = AtomicBool::new(true);
+
+    thread::scope(|scope| {
+        let writers: Vec<_> = (0..2)
+            .map(|writer| {
+                let map = &map;
+
+                scope.spawn(move || {
+                    // each writer owns the keys of its parity
+                    let keys = (writer..KEYS).step_by(2);
+
+                    for key in keys.clone() {
+                        assert_eq!(now(map.insert(key, value(key, 0))), None);
+                    }
+
+                    for key in keys.clone() {
+                        assert_eq!(now(map.update(&key, |&v| v + KEYS)), Some(value(key, 1)));
+                    }
+
+                    for key in keys.clone().filter(|key| key % 4 == writer) {
+                        assert_eq!(now(map.remove(&key)), Some(value(key, 1)));
+                    }
+
+                    for key in keys.filter(|key| key % 4 == writer).take(100) {
+                        assert_eq!(now(map.insert(key, value(key, 2))), None);
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..2 {
+            scope.spawn(|| {
+                let mut key = 0;
+
+                while writing.load(Ordering::Relaxed) {
+                    if let Some(found) = now(map.get(&key)) {
+                        assert_eq!(*found.key(), key);
+                        assert_eq!(*found % KEYS, key);
+                    }
+
+                    key = (key + 7) % KEYS;
+                }
+            });
+        }
+
+        scope.spawn(|| {
+            while writing.load(Ordering::Relaxed) {
+                let mut seen = HashSet::new();
+
+                now(map.for_each(|&key, &found| {
+                    assert_eq!(found % KEYS, key);
+                    assert!(seen.insert(key), "key {} seen twice", key);
+                }));
+            }
+        });
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        writing.store(false, Ordering::Relaxed);
+    });
+
+    let mut len = 0;
+
+    now(map.for_each(|&key, &found| {
+        let expected = if key % 4 < 2 { value(key, 2) } else { value(key, 1) };
+        assert_eq!(found, expected);
+        len += 1;
+    }));
+
+    // half the keys are left after the removals, along with the first 100 reinserted by each writer
+    assert_eq!(len, KEYS as usize / 2 + 200);
+    assert_eq!(map.size(), len);
+}
+
+#[test]
+fn refs_outlive_removal_and_grow() {
+    let map = LockFreeMap::<u32, String>::new(1);
+    now(map.insert(0, "zero".to_owned()));
+
+    let zero = now(map.get(&0)).unwrap();
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            assert_eq!(now(map.remove(&0)), Some("zero".to_owned()));
+
+            // grows the table the reference was taken from
+            for key in 1..100 {
+                now(map.insert(key, key.to_string()));
+            }
+        });
+    });
+
+    assert_eq!(*zero, "zero");
+    assert_eq!(*zero.key(), 0);
+    drop(zero);
+
+    assert_eq!(now(map.get_cloned(&0)), None);
+    assert_eq!(map.size(), 99);
+}
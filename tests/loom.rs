@@ -0,0 +1,193 @@
+//! Model-checked interleavings of the size counters and the unsafe index paths.
+//!
+//! Run with `RUSTFLAGS="--cfg loom" cargo test --test loom --release`, and the `LockFreeMap` models with
+//! `RUSTFLAGS="--cfg loom --cfg crossbeam_loom" cargo test --test loom --release --no-default-features
+//! --features tokio,num_cpus,lockfree`, leaving out `quanta`, which does not build with `crossbeam_loom`.
+
+#![cfg(loom)]
+
+use loom::future::block_on;
+use loom::sync::Arc;
+use loom::thread;
+
+use rand::rngs::mock::StepRng;
+
+#[cfg(feature = "lockfree")]
+use quick_hash_cache::lockfree::LockFreeMap;
+use quick_hash_cache::lru::LruCache;
+use quick_hash_cache::CHashMap;
+
+/// Deterministic RNG, as loom requires every execution of the model to be reproducible
+fn rng() -> StepRng {
+    StepRng::new(0, 0x9E37_79B9_7F4A_7C15)
+}
+
+#[test]
+fn chashmap_concurrent_insert_remove() {
+    loom::model(|| {
+        let map = Arc::new(CHashMap::<u32, u32>::new(2));
+
+        block_on(map.insert(0, 0));
+
+        let a = {
+            let map = map.clone();
+            thread::spawn(move || {
+                block_on(map.insert(1, 1));
+                block_on(map.insert(2, 2));
+            })
+        };
+
+        let b = {
+            let map = map.clone();
+            thread::spawn(move || block_on(map.remove(&0)).is_some())
+        };
+
+        a.join().unwrap();
+        assert!(b.join().unwrap());
+
+        assert_eq!(map.size(), 2);
+        assert!(block_on(map.contains(&1)));
+        assert!(block_on(map.contains(&2)));
+    });
+}
+
+#[test]
+fn chashmap_racing_inserts_same_key() {
+    loom::model(|| {
+        let map = Arc::new(CHashMap::<u32, u32>::new(2));
+
+        let threads: Vec<_> = (0..2)
+            .map(|i| {
+                let map = map.clone();
+                thread::spawn(move || block_on(map.insert(7, i)))
+            })
+            .collect();
+
+        let replaced = threads.into_iter().filter_map(|t| t.join().unwrap()).count();
+
+        assert_eq!(replaced, 1);
+        assert_eq!(map.size(), 1);
+    });
+}
+
+#[test]
+fn lru_evict_races_remove() {
+    loom::model(|| {
+        let cache = Arc::new(LruCache::<u32, u32>::new(2));
+
+        for i in 0..4 {
+            block_on(cache.insert(i, i));
+        }
+
+        let evictor = {
+            let cache = cache.clone();
+            thread::spawn(move || block_on(cache.evict_many(2, rng())))
+        };
+
+        let remover = {
+            let cache = cache.clone();
+            thread::spawn(move || block_on(cache.remove(&1)))
+        };
+
+        let evicted = evictor.join().unwrap();
+        let removed = remover.join().unwrap();
+
+        // an entry is either evicted or removed, never both
+        let evicted_one = evicted.iter().any(|(k, _)| *k == 1);
+        assert!(!(evicted_one && removed.is_some()));
+
+        let gone = evicted.len() + removed.is_some() as usize;
+        assert_eq!(cache.size(), 4 - gone);
+
+        let remaining = (0..4).filter(|k| block_on(cache.peek(k)).is_some()).count();
+        assert_eq!(remaining, cache.size());
+    });
+}
+
+#[test]
+fn lru_concurrent_evict_one() {
+    loom::model(|| {
+        let cache = Arc::new(LruCache::<u32, u32>::new(2));
+
+        for i in 0..3 {
+            block_on(cache.insert(i, i));
+        }
+
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let cache = cache.clone();
+                thread::spawn(move || block_on(cache.evict_one(rng())))
+            })
+            .collect();
+
+        let evicted: Vec<_> = threads.into_iter().filter_map(|t| t.join().unwrap()).collect();
+
+        assert_eq!(evicted.len(), 2);
+        assert_ne!(evicted[0].0, evicted[1].0);
+        assert_eq!(cache.size(), 1);
+    });
+}
+
+#[test]
+#[cfg(feature = "lockfree")]
+fn lockfree_grow_races_remove_and_read() {
+    // growing copies every entry, too many steps to explore every preemption of
+    let mut model = loom::model::Builder::new();
+    model.preemption_bound = Some(2);
+
+    model.check(|| {
+        let map = Arc::new(LockFreeMap::<u32, u32>::new(1));
+
+        // a full table of 8 buckets, so the next insert grows it
+        for i in 0..8 {
+            block_on(map.insert(i, i));
+        }
+
+        let grower = {
+            let map = map.clone();
+            thread::spawn(move || block_on(map.insert(8, 8)))
+        };
+
+        let remover = {
+            let map = map.clone();
+            thread::spawn(move || block_on(map.remove(&0)))
+        };
+
+        // read from whichever table is current, and kept alive by the reference if retired meanwhile
+        if let Some(value) = block_on(map.get(&3)) {
+            assert_eq!(*value, 3);
+        }
+
+        assert_eq!(grower.join().unwrap(), None);
+        assert_eq!(remover.join().unwrap(), Some(0));
+
+        assert_eq!(map.size(), 8);
+        assert_eq!(block_on(map.get_cloned(&0)), None);
+
+        for i in 1..9 {
+            assert_eq!(block_on(map.get_cloned(&i)), Some(i));
+        }
+    });
+}
+
+#[test]
+#[cfg(feature = "lockfree")]
+fn lockfree_ref_outlives_racing_removal() {
+    loom::model(|| {
+        let map = Arc::new(LockFreeMap::<u32, String>::new(1));
+        block_on(map.insert(0, "zero".to_owned()));
+
+        let remover = {
+            let map = map.clone();
+            thread::spawn(move || block_on(map.remove(&0)))
+        };
+
+        // the entry's destruction is deferred until the reference unpins
+        if let Some(value) = block_on(map.get(&0)) {
+            assert_eq!(*value, "zero");
+        }
+
+        assert_eq!(remover.join().unwrap().as_deref(), Some("zero"));
+        assert!(map.is_empty());
+    });
+}
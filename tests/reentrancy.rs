@@ -0,0 +1,131 @@
+//! `debug-guard-tracking` reports a task locking a shard that one of its own handles holds, instead of deadlocking,
+//! without mistaking other waits for it.
+
+#![cfg(feature = "debug-guard-tracking")]
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+
+use quick_hash_cache::{guard, CHashMap};
+
+// the same in every test, as they share the settings: handles are never held long enough to be reported themselves
+fn panic_on_reentrancy() {
+    guard::set_max_hold(Duration::from_secs(60));
+    guard::set_reentrant_wait(Duration::from_millis(200));
+    guard::set_panic_on_violation(true);
+}
+
+#[tokio::test]
+async fn try_get_handle_holds_its_own_shard() {
+    panic_on_reentrancy();
+    let map = Arc::new(CHashMap::<u32, u32>::new(2));
+
+    // `missing` and `held_by_other` share a shard, `present` is in the other one
+    let held_by_other = 0;
+    let missing = (1..).find(|key| map.shard_of(key) == map.shard_of(&held_by_other)).unwrap();
+    let present = (1..).find(|key| map.shard_of(key) != map.shard_of(&held_by_other)).unwrap();
+
+    map.insert(held_by_other, 0).await;
+    map.insert(present, 0).await;
+
+    // the miss creates no handle, and the handle from `try_get` must not be registered in its place
+    assert!(map.get(&missing).await.is_none());
+    let handle = map.try_get(&present).unwrap().unwrap();
+
+    let (locked_tx, locked_rx) = oneshot::channel();
+
+    let other = tokio::spawn({
+        let map = map.clone();
+
+        async move {
+            let handle = map.get_mut(&held_by_other).await;
+            locked_tx.send(()).unwrap();
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(handle);
+        }
+    });
+
+    locked_rx.await.unwrap();
+
+    // waits on the other task, which this one holds no handle into the shard of
+    map.insert(missing, 0).await;
+
+    drop(handle);
+    other.await.unwrap();
+}
+
+#[tokio::test]
+#[should_panic(expected = "most likely deadlocked")]
+async fn locking_a_shard_held_by_own_handle_panics() {
+    panic_on_reentrancy();
+
+    let map = CHashMap::<u32, u32>::new(1);
+    map.insert(0, 0).await;
+
+    let _handle = map.get_mut(&0).await;
+    map.insert(1, 1).await;
+}
+
+#[tokio::test]
+async fn join_branches_may_wait_on_each_other() {
+    panic_on_reentrancy();
+
+    let map = CHashMap::<u32, u32>::new(1);
+    map.insert(0, 0).await;
+
+    // both branches share the task's waker, but the second one only waits for the first to drop its handle
+    tokio::join!(
+        async {
+            let mut handle = map.get_mut(&0).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            *handle += 1;
+        },
+        async {
+            tokio::task::yield_now().await;
+            map.insert(1, 1).await;
+        },
+    );
+
+    assert_eq!(map.get_cloned(&0).await, Some(1));
+    assert_eq!(map.get_cloned(&1).await, Some(1));
+}
+
+#[tokio::test]
+async fn handles_moved_to_other_tasks_are_waited_on() {
+    panic_on_reentrancy();
+
+    let map = Arc::new(CHashMap::<u32, u32>::new(1));
+    map.insert(0, 0).await;
+
+    let handle = map.get_mut(&0).await.unwrap();
+
+    let other = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(handle);
+    });
+
+    map.insert(1, 1).await;
+    other.await.unwrap();
+}
+
+#[tokio::test]
+async fn reentrant_wait_is_configurable() {
+    panic_on_reentrancy();
+
+    let map = CHashMap::<u32, u32>::new(1);
+    map.insert(0, 0).await;
+
+    let started = Instant::now();
+
+    // spawned, so the panic surfaces as a `JoinError` instead of failing the test
+    let deadlocked = tokio::spawn(async move {
+        let _handle = map.get_mut(&0).await;
+        map.insert(1, 1).await;
+    });
+
+    assert!(deadlocked.await.unwrap_err().is_panic());
+    assert!(started.elapsed() < guard::reentrant_wait() * 5);
+}
@@ -0,0 +1,39 @@
+//! `CHashMap::resize_shards` is cancellation-safe: cancelled while waiting for a handle, it loses no entry.
+
+use std::time::Duration;
+
+use quick_hash_cache::CHashMap;
+
+#[tokio::test]
+async fn cancelled_resize_keeps_every_entry() {
+    let mut map = CHashMap::<u32, u32>::new(4);
+
+    for key in 0..1000 {
+        map.insert(key, key).await;
+    }
+
+    // a shard past the first one, so the resize is cancelled halfway through the shards
+    let held = (0..).find(|key| map.shard_of(key) == 2).unwrap();
+    let handle = map.get(&held).await.unwrap();
+
+    let resize = tokio::time::timeout(Duration::from_millis(20), map.resize_shards(8));
+    assert!(resize.await.is_err());
+
+    drop(handle);
+
+    assert_eq!(map.size(), 1000);
+    assert_eq!(map.num_shards(), 4);
+
+    for key in 0..1000 {
+        assert_eq!(map.get_cloned(&key).await, Some(key));
+    }
+
+    // and can still be resized afterwards
+    map.resize_shards(8).await;
+
+    assert_eq!(map.size(), 1000);
+
+    for key in 0..1000 {
+        assert_eq!(map.get_cloned(&key).await, Some(key));
+    }
+}
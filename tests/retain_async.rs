@@ -0,0 +1,56 @@
+//! `LruCache::retain_async` keeps entries written while its predicate ran, since their new value was not evaluated.
+
+use quick_hash_cache::lru::LruCache;
+
+#[tokio::test]
+async fn retain_async_keeps_entries_written_in_place() {
+    let cache = LruCache::<u32, u32>::new(1);
+
+    for key in 0..10 {
+        cache.insert(key, key).await;
+    }
+
+    cache
+        .retain_async(|key, _, _| {
+            let cache = &cache;
+
+            async move {
+                if key == 3 {
+                    *cache.get_mut(&3).await.unwrap() = 30;
+                }
+
+                false
+            }
+        })
+        .await;
+
+    assert_eq!(cache.size(), 1);
+    assert_eq!(cache.get_cloned(&3).await, Some(30));
+}
+
+#[tokio::test]
+async fn retain_async_keeps_entries_overwritten() {
+    let cache = LruCache::<u32, u32>::new(1);
+
+    for key in 0..10 {
+        cache.insert(key, key).await;
+    }
+
+    cache
+        .retain_async(|key, value, _| {
+            let cache = &cache;
+
+            // overwritten within the same clock reading, for all the predicate can tell
+            async move {
+                if key == 3 {
+                    cache.insert(3, value).await;
+                }
+
+                false
+            }
+        })
+        .await;
+
+    assert_eq!(cache.size(), 1);
+    assert_eq!(cache.get_cloned(&3).await, Some(3));
+}